@@ -0,0 +1,92 @@
+//! Time reading a large graph of local crates from cargo metadata, and from the cached metadata. The crates are in
+//! layers where every crate depends on a few crates of the next layer, so the graph has many shared packages like a
+//! real lock file.
+
+use std::{fs, path::Path};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use nbuild_core::models::cargo::{MetadataOptions, Package};
+
+/// How many layers of crates the generated workspace has
+const LAYERS: usize = 3;
+
+/// How many crates are in every layer
+const WIDTH: usize = 200;
+
+/// Write a package in `dir` which depends on the first layer of crates, each depending on some of the next layer
+fn write_workspace(dir: &Path) {
+    for layer in 0..LAYERS {
+        for i in 0..WIDTH {
+            let name = format!("crate{layer}_{i}");
+            let path = dir.join(&name);
+            let dependencies: Vec<_> = if layer + 1 < LAYERS {
+                (0..3)
+                    .map(|offset| (i * 3 + offset) % WIDTH)
+                    .map(|next| {
+                        let next = format!("crate{}_{next}", layer + 1);
+                        format!("{next} = {{ path = \"../{next}\" }}")
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
+            fs::create_dir_all(path.join("src")).unwrap();
+            fs::write(path.join("src").join("lib.rs"), "").unwrap();
+            fs::write(
+                path.join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{name}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n{}\n",
+                    dependencies.join("\n")
+                ),
+            )
+            .unwrap();
+        }
+    }
+
+    let dependencies: Vec<_> = (0..WIDTH)
+        .map(|i| format!("crate0_{i} = {{ path = \"crate0_{i}\" }}"))
+        .collect();
+
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src").join("main.rs"), "fn main() {}").unwrap();
+
+    // The old `rust-version` of the package keeps cargo writing a version 3 lock file, which can be read by older
+    // versions of nbuild too
+    fs::write(
+        dir.join("Cargo.toml"),
+        format!(
+            "[package]\nname = \"bench\"\nversion = \"0.1.0\"\nedition = \"2021\"\nrust-version = \"1.68\"\n\n[workspace]\n\n[dependencies]\n{}\n",
+            dependencies.join("\n")
+        ),
+    )
+    .unwrap();
+}
+
+fn resolve(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    write_workspace(dir.path());
+
+    let mut group = c.benchmark_group("resolve");
+    group.sample_size(20);
+
+    group.bench_function("from_current_dir", |b| {
+        b.iter(|| Package::from_current_dir(dir.path()).unwrap())
+    });
+
+    // With the metadata cached, only reading it back and building the graph is timed
+    let options = MetadataOptions {
+        cache: true,
+        ..Default::default()
+    };
+    Package::from_current_dir_with_options(dir.path(), &options).unwrap();
+
+    group.bench_function("from_cached_metadata", |b| {
+        b.iter(|| Package::from_current_dir_with_options(dir.path(), &options).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, resolve);
+criterion_main!(benches);
@@ -0,0 +1,1333 @@
+//! Entry points which run the whole flow of reading a package, writing its derivation and building it with nix
+
+use std::{
+    env::current_dir,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use similar::TextDiff;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    models::{cargo, nix, plan},
+    Error,
+};
+
+/// The file the derivation is written to when no other path is given
+pub const DERIVATION_FILE: &str = ".nbuild.nix";
+
+/// The link nix makes to the build output when no other path is given
+const DEFAULT_OUT_LINK: &str = "result";
+
+/// Options for [build]
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// Path to the Cargo.toml of the package to build. The current directory is used when it is not set.
+    pub manifest_path: Option<PathBuf>,
+
+    /// Build this crate from crates.io instead of a local package. The derivation then has no lock file hash, since
+    /// the crate is resolved on its own.
+    pub published: Option<cargo::CrateSpec>,
+
+    /// Options for reading the cargo metadata
+    pub metadata: cargo::MetadataOptions,
+
+    /// Options for the derivation, which are put on top of the ones read from the config with
+    /// [nix::DerivationOptions::layer]. The `lock_hash` is filled in by [build].
+    pub derivation: nix::DerivationOptions,
+
+    /// Where to write the derivation
+    pub derivation_path: PathBuf,
+
+    /// Write a derivation with an attribute for every member of the workspace, like `cargo build --workspace`. Each
+    /// member is resolved on its own with the cargo config of the workspace, and the crates they share are only built
+    /// once.
+    pub workspace: bool,
+
+    /// Members to leave out of the attributes of a `workspace` derivation. They are still built when another member
+    /// depends on them.
+    pub exclude: Vec<String>,
+
+    /// Leave the members of a `workspace` derivation which fail to resolve out of it instead of failing, and report
+    /// them in the [BuildOutcome]
+    pub keep_going: bool,
+
+    /// Target triples to resolve the package for one by one, which gives a single file with an output for each of
+    /// them. The outputs are keyed by nix system, or by the triple for those which are cross-compiled. The
+    /// `metadata` platforms and target are replaced for each triple.
+    pub targets: Vec<String>,
+
+    /// Run `cargo generate-lockfile` when the package does not have a Cargo.lock yet
+    pub generate_lockfile: bool,
+
+    /// Crates to swap for another version after resolving
+    pub replacements: Vec<cargo::Replacement>,
+
+    /// Features to turn on for crates after resolving, even when cargo would not enable them
+    pub forced_features: Vec<cargo::ForcedFeature>,
+
+    /// Leave the build dependencies out of the graph, which gives a derivation that is only good for inspecting the
+    /// crates needed at runtime
+    pub no_build_deps: bool,
+
+    /// Check that the rust toolchain is new enough for the `rust-version` of every crate
+    pub check_msrv: bool,
+
+    /// Check the crates.io checksums in Cargo.lock against the locally downloaded crates
+    pub verify_checksums: bool,
+
+    /// Parse the derivation with `nix-instantiate --parse` after writing it, to catch a broken expression before
+    /// starting a long build
+    pub check_eval: bool,
+
+    /// Build the derivation with nix after writing it
+    pub build: bool,
+
+    /// The nix binary to build with
+    pub nix_bin: PathBuf,
+
+    /// Where nix links the build output. Nix uses `result` in the current directory when it is not set.
+    pub out_link: Option<PathBuf>,
+
+    /// Do not let nix link the build output, like for runs which only fill the nix store
+    pub no_link: bool,
+
+    /// Extra arguments to pass to `nix build`
+    pub nix_args: Vec<String>,
+
+    /// Only let nix print errors
+    pub quiet: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            manifest_path: None,
+            published: None,
+            metadata: Default::default(),
+            derivation: Default::default(),
+            derivation_path: DERIVATION_FILE.into(),
+            workspace: false,
+            exclude: Default::default(),
+            keep_going: false,
+            targets: Default::default(),
+            generate_lockfile: false,
+            replacements: Default::default(),
+            forced_features: Default::default(),
+            no_build_deps: false,
+            check_msrv: false,
+            verify_checksums: false,
+            check_eval: false,
+            build: true,
+            nix_bin: "nix".into(),
+            out_link: None,
+            no_link: false,
+            nix_args: Default::default(),
+            quiet: false,
+        }
+    }
+}
+
+impl BuildOptions {
+    /// The absolute directory of the package to build, so that its ancestors can be searched
+    pub fn package_dir(&self) -> Result<PathBuf, Error> {
+        let current_dir = current_dir()?;
+
+        match self.manifest_path.as_deref().and_then(Path::parent) {
+            Some(dir) => Ok(current_dir.join(dir)),
+            None => Ok(current_dir),
+        }
+    }
+
+    /// Get the hash of the package's lock file. Unless another lock file is used, it lives in the workspace root,
+    /// which is the package directory or one of its ancestors.
+    pub fn lock_hash(&self) -> Result<Option<String>, Error> {
+        if self.published.is_some() {
+            return Ok(None);
+        }
+
+        let package_dir = self.package_dir()?;
+
+        self.metadata
+            .lock_file
+            .clone()
+            .or_else(|| {
+                package_dir
+                    .ancestors()
+                    .map(|dir| dir.join("Cargo.lock"))
+                    .find(|path| path.exists())
+            })
+            .map(nix::lock_file_hash)
+            .transpose()
+    }
+
+    /// Read the derivation options from the `[package.metadata.nbuild]` table of the package's manifest, and from the
+    /// `.nbuild.toml` file next to it, which wins over the manifest. Only the file in the current directory is read for
+    /// a published crate.
+    pub fn read_derivation_options(&self) -> Result<nix::DerivationOptions, Error> {
+        let package_dir = self.package_dir()?;
+
+        if self.published.is_some() {
+            return nix::DerivationOptions::from_file(package_dir.join(".nbuild.toml"));
+        }
+
+        let metadata =
+            cargo::nbuild_metadata(&package_dir, self.manifest_path.as_deref(), &self.metadata)?;
+
+        nix::DerivationOptions::from_metadata_and_file(&metadata, package_dir.join(".nbuild.toml"))
+    }
+
+    /// Get the workspace the package is in
+    fn workspace(&self) -> Result<cargo::Workspace, Error> {
+        cargo::workspace(
+            &self.package_dir()?,
+            self.manifest_path.as_deref(),
+            &self.metadata,
+        )
+    }
+
+    /// Get the names of the members of the workspace with the options to read each of them, leaving out the
+    /// `exclude`d ones
+    fn members(&self) -> Result<Vec<(String, Self)>, Error> {
+        let members = self.workspace()?.members;
+
+        if let Some(name) = self
+            .exclude
+            .iter()
+            .find(|name| !members.iter().any(|(member, _)| member == *name))
+        {
+            return Err(Error::UnknownMember(name.clone()));
+        }
+
+        let members: Vec<_> = members
+            .into_iter()
+            .filter(|(name, _)| !self.exclude.contains(name))
+            .map(|(name, manifest_path)| {
+                let member = Self {
+                    manifest_path: Some(manifest_path),
+                    workspace: false,
+                    exclude: Default::default(),
+                    ..self.clone()
+                };
+
+                (name, member)
+            })
+            .collect();
+
+        if members.is_empty() {
+            return Err(Error::NoMembers);
+        }
+
+        Ok(members)
+    }
+
+    /// Get these options with the `derivation` options put on top of the ones read from the config
+    fn with_config(&self) -> Result<Self, Error> {
+        Ok(Self {
+            derivation: self.read_derivation_options()?.layer(&self.derivation),
+            ..self.clone()
+        })
+    }
+
+    /// Get the output of a per-system file which is built, namely the one for the current system, and the `default`
+    /// attribute of a workspace derivation which has one. Files for a single system of a single package do not need an
+    /// attribute.
+    fn build_attribute(&self) -> Result<Option<String>, Error> {
+        let default = (self.workspace && self.with_config()?.derivation.workspace_default)
+            .then_some("default");
+
+        if self.targets.is_empty() {
+            return Ok(default.map(String::from));
+        }
+
+        let current = cargo::current_platform()?;
+        let system = nix::nix_system(current.triple_str())
+            .unwrap_or_else(|| current.triple_str().to_string());
+
+        if !self
+            .targets
+            .iter()
+            .any(|triple| output_name(triple) == system)
+        {
+            return Err(Error::MissingSystem(system));
+        }
+
+        Ok(Some(match default {
+            Some(default) => format!("{system}.{default}"),
+            None => system,
+        }))
+    }
+}
+
+/// What came out of [build]
+#[derive(Debug, PartialEq)]
+pub struct BuildOutcome {
+    /// Where the derivation was written to
+    pub derivation_path: PathBuf,
+
+    /// The nix store path of the build output. It is only set when the derivation was built.
+    pub out_path: Option<PathBuf>,
+
+    /// The link nix made to the build output
+    pub out_link: Option<PathBuf>,
+
+    /// The members which are left out of a `workspace` derivation with `keep_going`, since they failed to resolve
+    pub failed_members: Vec<FailedMember>,
+}
+
+/// A member of the workspace which failed to resolve
+#[derive(Debug, PartialEq)]
+pub struct FailedMember {
+    pub name: String,
+    pub error: String,
+}
+
+/// Read the package and resolve its features. This is the first part of [build], and is useful on its own to inspect
+/// the dependency tree.
+#[instrument(skip_all)]
+pub fn load(options: &BuildOptions) -> Result<cargo::Package, Error> {
+    let options = options.with_config()?;
+    let config = cargo::CargoConfig::discover(options.package_dir()?)?;
+
+    load_with_config(&options, &config)
+}
+
+/// Read the package for the targets of the cargo config, unless other platforms were asked for
+fn load_with_config(
+    options: &BuildOptions,
+    config: &cargo::CargoConfig,
+) -> Result<cargo::Package, Error> {
+    let mut metadata = options.metadata.clone();
+
+    if metadata.platforms.is_empty() {
+        metadata.platforms = config.targets.clone();
+    }
+
+    let mut package = match read_package(options, &metadata) {
+        Err(Error::MissingLockFile(_))
+            if options.generate_lockfile && metadata.lock_file.is_none() =>
+        {
+            generate_lockfile(options.manifest_path.as_deref())?;
+            read_package(options, &metadata)?
+        }
+        result => result?,
+    };
+
+    if options.no_build_deps {
+        package.prune_build_dependencies();
+    }
+
+    package.resolve();
+    package.force_features(&options.forced_features)?;
+    package.replace(&options.replacements)?;
+    package.warn_unified_defaults();
+
+    if options.check_msrv && options.derivation.is_nightly() {
+        warn!("skipping the msrv check, since the version of a nightly toolchain is not known");
+    } else if options.check_msrv {
+        let toolchain = options.derivation.toolchain_version();
+
+        package.check_msrv(
+            &toolchain
+                .parse()
+                .map_err(|_| Error::InvalidRustVersion(toolchain.to_string()))?,
+        )?;
+    }
+
+    Ok(package)
+}
+
+/// Write the derivation of a package and optionally build it with nix
+#[instrument(skip_all)]
+pub fn build(options: &BuildOptions) -> Result<BuildOutcome, Error> {
+    let (derivation, failed_members) = generate_with_failures(options)?;
+
+    fs::write(&options.derivation_path, derivation)?;
+
+    if options.check_eval {
+        nix_parse(options)?;
+    }
+
+    let (out_path, out_link) = if options.build {
+        let (out_path, out_link) = nix_build(options)?;
+
+        (Some(out_path), out_link)
+    } else {
+        (None, None)
+    };
+
+    Ok(BuildOutcome {
+        derivation_path: options.derivation_path.clone(),
+        out_path,
+        out_link,
+        failed_members,
+    })
+}
+
+/// Get the derivation of a package without writing it. Local sources are still relative to the `derivation_path`.
+/// The members which are left out with `keep_going` are only logged, while [build] reports them.
+#[instrument(skip_all)]
+pub fn generate(options: &BuildOptions) -> Result<String, Error> {
+    generate_with_failures(options).map(|(derivation, _)| derivation)
+}
+
+/// Get the derivation of a package, together with the members which are left out of it with `keep_going`
+fn generate_with_failures(options: &BuildOptions) -> Result<(String, Vec<FailedMember>), Error> {
+    let options = options.with_config()?;
+
+    if !options.targets.is_empty() {
+        return generate_systems(&options);
+    }
+
+    let prepared = prepare(&options)?;
+
+    Ok((
+        into_derivative(&options, prepared.packages, &prepared.derivation)?,
+        prepared.failed_members,
+    ))
+}
+
+/// Compare the derivation of a package with the file at the `derivation_path`, without writing it. Gives a unified
+/// diff from the file to the new derivation when they differ, and `None` when the file is up to date.
+pub fn diff(options: &BuildOptions) -> Result<Option<String>, Error> {
+    let derivation = generate(options)?;
+    let current = match fs::read_to_string(&options.derivation_path) {
+        Ok(current) => current,
+        Err(error) if error.kind() == ErrorKind::NotFound => Default::default(),
+        Err(error) => return Err(error.into()),
+    };
+
+    if current == derivation {
+        return Ok(None);
+    }
+
+    let path = options.derivation_path.display().to_string();
+
+    Ok(Some(
+        TextDiff::from_lines(&current, &derivation)
+            .unified_diff()
+            .header(&path, &path)
+            .to_string(),
+    ))
+}
+
+/// Get a derivation with an output for each of the `targets`. The package is resolved for every triple by itself, so
+/// each output only has the dependencies of its own platform. Triples without a nix system are cross-compiled from
+/// the system nix evaluates on. A member which fails to resolve for any of the triples is reported once per triple.
+fn generate_systems(options: &BuildOptions) -> Result<(String, Vec<FailedMember>), Error> {
+    let mut failed_members = Vec::new();
+    let derivations = options
+        .targets
+        .iter()
+        .map(|triple| {
+            let mut options = options.clone();
+            options.targets = Default::default();
+
+            match nix::nix_system(triple) {
+                Some(system) => {
+                    options.metadata.platforms = vec![triple.clone()];
+                    options.metadata.target = None;
+                    options.derivation.system = Some(system);
+                }
+                None => {
+                    options.metadata.platforms = Default::default();
+                    options.metadata.target = Some(triple.clone());
+                }
+            }
+
+            let mut prepared = prepare(&options)?;
+            prepared.derivation.lock_hash = None;
+            failed_members.extend(prepared.failed_members);
+
+            Ok((
+                output_name(triple),
+                into_derivative(&options, prepared.packages, &prepared.derivation)?,
+            ))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    Ok((
+        nix::combine_systems(derivations, options.lock_hash()?.as_deref()),
+        failed_members,
+    ))
+}
+
+/// Get the name of the output for a triple in a per-system file
+fn output_name(triple: &str) -> String {
+    nix::nix_system(triple).unwrap_or_else(|| triple.to_string())
+}
+
+/// Get the crates which are built for a package, after writing its derivation. When nix would build the derivation,
+/// it is asked which crates are not in the nix store yet.
+#[instrument(skip_all)]
+pub fn plan(options: &BuildOptions) -> Result<plan::BuildPlan, Error> {
+    let options = options.with_config()?;
+    let prepared = prepare(&options)?;
+    let mut plan = plan::BuildPlan::of(&prepared.packages);
+
+    fs::write(
+        &options.derivation_path,
+        into_derivative(&options, prepared.packages, &prepared.derivation)?,
+    )?;
+
+    if options.build {
+        plan.mark_fresh(&nix_dry_run(&options)?);
+    }
+
+    Ok(plan)
+}
+
+/// What [prepare] read for a derivation
+struct Prepared {
+    packages: Vec<nix::Package>,
+    derivation: nix::DerivationOptions,
+    failed_members: Vec<FailedMember>,
+}
+
+/// Read the package, or every member of a `workspace` derivation, and get the options for the derivation
+fn prepare(options: &BuildOptions) -> Result<Prepared, Error> {
+    // Crane lets cargo resolve the crates again from the lock file, so changes to the resolved graph are lost
+    if options.derivation.backend == nix::Backend::Crane {
+        if !options.replacements.is_empty() {
+            return Err(Error::UnsupportedCraneOption("replacements"));
+        }
+        if !options.forced_features.is_empty() {
+            return Err(Error::UnsupportedCraneOption("forced features"));
+        }
+    }
+
+    let config = cargo::CargoConfig::discover(options.package_dir()?)?;
+    let (packages, failed_members) = if options.workspace {
+        load_members(options, &config)?
+    } else {
+        (vec![load_nix(options, &config)?], Default::default())
+    };
+
+    // The flags of the first target are used, since nix only builds for one target
+    let triple = match options
+        .metadata
+        .target
+        .as_ref()
+        .or(options.metadata.platforms.first())
+        .or(config.targets.first())
+    {
+        Some(triple) => triple.clone(),
+        None => cargo::current_platform()?.triple_str().to_string(),
+    };
+
+    let mut derivation = options.derivation.clone();
+    derivation.lock_hash = options.lock_hash()?;
+    derivation.rustflags = config.rustflags(&triple);
+    derivation.features = options.metadata.features.clone();
+    derivation.no_default_features = options.metadata.no_default_features;
+    derivation.target = options.metadata.cross_target()?.map(String::from);
+    derivation.source_root = options
+        .derivation_path
+        .parent()
+        .map(|dir| current_dir().map(|current_dir| current_dir.join(dir)))
+        .transpose()?;
+    derivation.target_dir = config
+        .target_dir
+        .as_ref()
+        .map(|dir| current_dir().map(|current_dir| current_dir.join(dir)))
+        .transpose()?;
+
+    // Crane builds from the workspace root, which has the lock file and the other members
+    if derivation.backend == nix::Backend::Crane && options.published.is_none() {
+        derivation.workspace_root = Some(options.workspace()?.root);
+    }
+
+    Ok(Prepared {
+        packages,
+        derivation,
+        failed_members,
+    })
+}
+
+/// Read every member of the workspace. With `keep_going`, the members which fail are left out, unless all of them
+/// fail.
+fn load_members(
+    options: &BuildOptions,
+    config: &cargo::CargoConfig,
+) -> Result<(Vec<nix::Package>, Vec<FailedMember>), Error> {
+    let mut packages = Vec::new();
+    let mut failed_members = Vec::new();
+
+    for (name, member) in options.members()? {
+        match load_nix(&member, config) {
+            Ok(package) => packages.push(package),
+            Err(error) if options.keep_going => {
+                warn!(%name, %error, "leaving out the workspace member");
+                failed_members.push((name, error));
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    if packages.is_empty() && !failed_members.is_empty() {
+        return Err(failed_members.swap_remove(0).1);
+    }
+
+    let failed_members = failed_members
+        .into_iter()
+        .map(|(name, error)| FailedMember {
+            name,
+            error: error.to_string(),
+        })
+        .collect();
+
+    Ok((packages, failed_members))
+}
+
+/// Read a package and convert it for nix, which builds the crates for the host apart when cross-compiling
+fn load_nix(options: &BuildOptions, config: &cargo::CargoConfig) -> Result<nix::Package, Error> {
+    let package = load_with_config(options, config)?;
+    let package = match options.metadata.cross_target()? {
+        Some(_) => nix::Package::from_cross(package),
+        None => package.into(),
+    };
+
+    if options.verify_checksums {
+        package.verify_checksums()?;
+    }
+
+    Ok(package)
+}
+
+/// Make the derivation of the packages from [prepare]. A `workspace` derivation has an attribute for every member.
+fn into_derivative(
+    options: &BuildOptions,
+    packages: Vec<nix::Package>,
+    derivation: &nix::DerivationOptions,
+) -> Result<String, Error> {
+    if options.workspace {
+        return nix::Package::merge_into_derivative(packages, derivation);
+    }
+
+    packages
+        .into_iter()
+        .next()
+        .expect("a package to be read")
+        .into_derivative_with_options(derivation)
+}
+
+/// Read the published crate, or the package from its manifest path or the current directory
+fn read_package(
+    options: &BuildOptions,
+    metadata: &cargo::MetadataOptions,
+) -> Result<cargo::Package, Error> {
+    match (&options.published, &options.manifest_path) {
+        (Some(spec), _) => cargo::Package::from_crates_io_with_options(spec, metadata),
+        (None, Some(manifest_path)) => {
+            cargo::Package::from_manifest_path_with_options(manifest_path, metadata)
+        }
+        (None, None) => cargo::Package::from_current_dir_with_options(current_dir()?, metadata),
+    }
+}
+
+/// Create a missing Cargo.lock for the package
+fn generate_lockfile(manifest_path: Option<&Path>) -> Result<(), Error> {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("generate-lockfile");
+
+    if let Some(manifest_path) = manifest_path {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+
+    let status = cmd.status()?;
+
+    if !status.success() {
+        return Err(Error::Command {
+            command: "cargo generate-lockfile".to_string(),
+            status,
+        });
+    }
+
+    Ok(())
+}
+
+/// Parse the derivation without evaluating it, which needs neither nixpkgs nor the network. `nix-instantiate` is
+/// looked up next to the nix binary.
+fn nix_parse(options: &BuildOptions) -> Result<(), Error> {
+    let nix_instantiate = match options.nix_bin.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.join("nix-instantiate"),
+        _ => "nix-instantiate".into(),
+    };
+    let output = Command::new(nix_instantiate)
+        .arg("--parse")
+        .arg(&options.derivation_path)
+        .output()?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(parse_error(
+        &String::from_utf8_lossy(&output.stderr),
+        &options.derivation_path,
+        &fs::read_to_string(&options.derivation_path)?,
+    ))
+}
+
+/// Turn the `stderr` of a failed parse into an error holding the offending line of the `derivation`. Nix points at it
+/// with `<path>:<line>:<column>`.
+fn parse_error(stderr: &str, path: &Path, derivation: &str) -> Error {
+    let message = stderr
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("error: "))
+        .unwrap_or(stderr.trim())
+        .to_string();
+    let line = stderr
+        .split(&format!("{}:", path.display()))
+        .nth(1)
+        .and_then(|location| location.split(':').next()?.parse::<usize>().ok());
+
+    Error::InvalidDerivation {
+        message,
+        line: line.and_then(|number| {
+            let source = derivation.lines().nth(number.checked_sub(1)?)?;
+
+            Some(format!("\n{number}| {source}"))
+        }),
+    }
+}
+
+/// Ask nix what it would do to build the derivation, which it prints to stderr
+fn nix_dry_run(options: &BuildOptions) -> Result<String, Error> {
+    let output = Command::new(&options.nix_bin)
+        .arg("build")
+        .arg("--file")
+        .arg(&options.derivation_path)
+        .arg("--dry-run")
+        .args(&options.nix_args)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::Command {
+            command: "nix build --dry-run".to_string(),
+            status: output.status,
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+/// Build the derivation and get its output path, together with the link to it when there is one. Nix prints its
+/// progress to stderr, which is passed through.
+fn nix_build(options: &BuildOptions) -> Result<(PathBuf, Option<PathBuf>), Error> {
+    let mut cmd = Command::new(&options.nix_bin);
+    cmd.arg("build")
+        .arg("--file")
+        .arg(&options.derivation_path)
+        .args(options.build_attribute()?)
+        .args(["--max-jobs", "auto", "--cores", "0", "--print-out-paths"])
+        .args(options.quiet.then_some("--quiet"));
+
+    let out_link = if options.no_link {
+        cmd.arg("--no-link");
+        None
+    } else {
+        let out_link = options
+            .out_link
+            .clone()
+            .unwrap_or_else(|| DEFAULT_OUT_LINK.into());
+        cmd.arg("--out-link").arg(&out_link);
+        Some(out_link)
+    };
+
+    let output = cmd
+        .args(&options.nix_args)
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::Command {
+            command: "nix build".to_string(),
+            status: output.status,
+        });
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    debug!(%stdout, "nix build done");
+
+    // Report where the link points to, since that is the output the user will find. Otherwise the `out` output is
+    // the first of the printed paths.
+    match out_link {
+        Some(out_link) => Ok((fs::read_link(&out_link)?, Some(out_link))),
+        None => Ok((stdout.lines().next().unwrap_or_default().into(), None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        str::FromStr,
+    };
+
+    use crate::models::{cargo, nix};
+
+    use super::{
+        build, diff, generate, parse_error, plan, BuildOptions, BuildOutcome, FailedMember,
+    };
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn generate_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple")
+            .join("Cargo.toml");
+        let derivation_path = dir.path().join("simple.nix");
+
+        let options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            derivation_path: derivation_path.clone(),
+            build: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            build(&options).unwrap(),
+            BuildOutcome {
+                derivation_path: derivation_path.clone(),
+                out_path: None,
+                out_link: None,
+                failed_members: Vec::new(),
+            }
+        );
+
+        let derivation = fs::read_to_string(derivation_path).unwrap();
+        let lock_hash = options.lock_hash().unwrap().unwrap();
+
+        assert!(nix::is_up_to_date(&derivation, &lock_hash));
+        assert!(derivation.contains("  simple = buildRustCrate"));
+
+        // The same derivation can be had without writing it
+        assert_eq!(generate(&options).unwrap(), derivation);
+    }
+
+    #[test]
+    fn diff_against_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple")
+            .join("Cargo.toml");
+        let derivation_path = dir.path().join("simple.nix");
+
+        let options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            derivation_path: derivation_path.clone(),
+            build: false,
+            ..Default::default()
+        };
+
+        build(&options).unwrap();
+        assert_eq!(diff(&options).unwrap(), None);
+
+        // A stale file is left as is
+        let derivation = fs::read_to_string(&derivation_path).unwrap();
+        let stale = derivation.replace("edition = \"2021\";", "edition = \"2018\";");
+        fs::write(&derivation_path, &stale).unwrap();
+
+        let changes = diff(&options).unwrap().unwrap();
+        let path = derivation_path.display();
+
+        assert!(changes.starts_with(&format!("--- {path}\n+++ {path}\n@@ ")));
+        assert!(changes.contains("\n-    edition = \"2018\";\n+    edition = \"2021\";\n"));
+        assert_eq!(fs::read_to_string(&derivation_path).unwrap(), stale);
+    }
+
+    #[test]
+    fn manifest_config() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("manifest_config");
+
+        let mut options = BuildOptions {
+            manifest_path: Some(path.join("Cargo.toml")),
+            ..Default::default()
+        };
+
+        let derivation = options.read_derivation_options().unwrap();
+
+        // The config file wins over the manifest, but only for the keys it has
+        assert_eq!(derivation.rust_version.as_deref(), Some("1.70.0"));
+        assert_eq!(derivation.ignore, vec!["*.log".to_string()]);
+        assert_eq!(derivation.profile.opt_level.as_deref(), Some("3"));
+        assert_eq!(derivation.profile.debug, Some(false));
+        assert_eq!(
+            derivation.crates["member"].extra_rustc_opts,
+            vec!["--cap-lints=warn".to_string()]
+        );
+        assert_eq!(
+            derivation.crates["member"].build_inputs,
+            vec!["zlib".to_string()]
+        );
+
+        // The config is read when generating, with the options of the caller on top of it
+        let generated = generate(&options).unwrap();
+
+        assert!(generated.contains(r#"rustVersion = pkgs.rust-bin.stable."1.70.0".default;"#));
+        assert!(generated.contains(r#"buildInputs = (old.buildInputs or [ ]) ++ [ pkgs.zlib ];"#));
+
+        options.derivation = nix::DerivationOptions {
+            rust_version: Some("1.72.0".to_string()),
+            ignore: vec!["*.tmp".to_string()],
+            ..Default::default()
+        };
+
+        let layered = options
+            .read_derivation_options()
+            .unwrap()
+            .layer(&options.derivation);
+
+        assert_eq!(layered.rust_version.as_deref(), Some("1.72.0"));
+        assert_eq!(
+            layered.ignore,
+            vec!["*.log".to_string(), "*.tmp".to_string()]
+        );
+        assert_eq!(layered.profile.opt_level.as_deref(), Some("3"));
+        assert!(generate(&options)
+            .unwrap()
+            .contains(r#"rustVersion = pkgs.rust-bin.stable."1.72.0".default;"#));
+
+        // The member has no metadata of its own
+        options.manifest_path = Some(path.join("member").join("Cargo.toml"));
+
+        assert_eq!(
+            options.read_derivation_options().unwrap(),
+            Default::default()
+        );
+    }
+
+    #[test]
+    fn workspace() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace");
+
+        let mut options = BuildOptions {
+            manifest_path: Some(path.join("Cargo.toml")),
+            workspace: true,
+            exclude: vec!["child".to_string()],
+            build: false,
+            ..Default::default()
+        };
+
+        let derivation = generate(&options).unwrap();
+
+        // The excluded member is only built as a dependency of the parent
+        assert!(derivation.ends_with("{\n  inherit parent rename targets;\n}\n"));
+        assert!(derivation.contains("\n  child_0_1_0 = buildRustCrate rec {\n"));
+        assert!(
+            derivation.contains("dependencies = [ child_0_1_0 itoa_0_4_8 libc_0_2_144 targets ];")
+        );
+        assert!(!derivation.contains("\n  child = buildRustCrate rec {\n"));
+
+        // The members which are built are not built again as dependencies
+        assert!(!derivation.contains("rename_0_1_0"));
+        assert!(!derivation.contains("targets_0_1_0"));
+
+        // The `default` attribute is what gets built when there is one
+        assert_eq!(options.build_attribute().unwrap(), None);
+
+        options.derivation.workspace_default = true;
+        assert_eq!(
+            options.build_attribute().unwrap(),
+            Some("default".to_string())
+        );
+        assert!(generate(&options)
+            .unwrap()
+            .ends_with("{\n  inherit parent rename targets;\n  default = parent;\n}\n"));
+
+        options.derivation.workspace_default = false;
+        options.exclude = vec!["missing".to_string()];
+
+        assert_eq!(
+            generate(&options).unwrap_err().to_string(),
+            "no workspace member named missing"
+        );
+
+        options.exclude = vec![
+            "child".to_string(),
+            "parent".to_string(),
+            "rename".to_string(),
+            "targets".to_string(),
+        ];
+
+        assert_eq!(
+            generate(&options).unwrap_err().to_string(),
+            "every member of the workspace is excluded"
+        );
+    }
+
+    #[test]
+    fn keep_going() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace");
+
+        // Only the members with fnv in their graph can have its feature forced on
+        let mut options = BuildOptions {
+            manifest_path: Some(path.join("Cargo.toml")),
+            derivation_path: dir.path().join("workspace.nix"),
+            workspace: true,
+            forced_features: vec!["fnv/std".parse().unwrap()],
+            build: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            build(&options).unwrap_err().to_string(),
+            "no crate in the dependency tree has the forced feature fnv/std"
+        );
+
+        options.keep_going = true;
+        let outcome = build(&options).unwrap();
+
+        assert_eq!(
+            outcome.failed_members,
+            vec![
+                FailedMember {
+                    name: "rename".to_string(),
+                    error: "no crate in the dependency tree has the forced feature fnv/std"
+                        .to_string(),
+                },
+                FailedMember {
+                    name: "targets".to_string(),
+                    error: "no crate in the dependency tree has the forced feature fnv/std"
+                        .to_string(),
+                },
+            ]
+        );
+        assert!(fs::read_to_string(&options.derivation_path)
+            .unwrap()
+            .ends_with("{\n  inherit child parent;\n}\n"));
+
+        // The error of a member is given when none of them resolve
+        options.exclude = vec!["child".to_string(), "parent".to_string()];
+
+        assert_eq!(
+            build(&options).unwrap_err().to_string(),
+            "no crate in the dependency tree has the forced feature fnv/std"
+        );
+    }
+
+    #[test]
+    fn crane_workspace_root() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace");
+
+        let options = BuildOptions {
+            manifest_path: Some(path.join("parent").join("Cargo.toml")),
+            derivation: nix::DerivationOptions {
+                backend: nix::Backend::Crane,
+                ..Default::default()
+            },
+            build: false,
+            ..Default::default()
+        };
+
+        // The sibling path dependencies are found in the workspace root, which has the lock file
+        let derivation = generate(&options).unwrap();
+
+        assert!(derivation.contains(&format!(
+            "      filter = sourceFilter;\n      src = {};\n",
+            path.display()
+        )));
+        assert!(derivation.contains(r#"cargoExtraArgs = "--locked --package parent";"#));
+
+        // The features of the package are passed on to cargo
+        let derivation = generate(&BuildOptions {
+            metadata: cargo::MetadataOptions {
+                features: vec!["child/two".to_string()],
+                no_default_features: true,
+                ..Default::default()
+            },
+            ..options.clone()
+        })
+        .unwrap();
+
+        assert!(derivation.contains(
+            r#"cargoExtraArgs = "--locked --package parent --features child/two --no-default-features";"#
+        ));
+
+        // Changes to the resolved crates would be lost when cargo resolves them again
+        let error = generate(&BuildOptions {
+            forced_features: vec!["itoa/std".parse().unwrap()],
+            ..options
+        })
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "the crane backend does not support forced features"
+        );
+    }
+
+    #[test]
+    fn targets() {
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("platforms")
+            .join("Cargo.toml");
+
+        let mut options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            targets: vec![
+                "x86_64-unknown-linux-gnu".to_string(),
+                "aarch64-apple-darwin".to_string(),
+                "x86_64-unknown-linux-musl".to_string(),
+            ],
+            build: false,
+            ..Default::default()
+        };
+
+        let derivation = generate(&options).unwrap();
+        let lock_hash = options.lock_hash().unwrap().unwrap();
+
+        assert!(nix::is_up_to_date(&derivation, &lock_hash));
+
+        // Every output only has the dependencies of its own platform
+        let outputs: Vec<_> = derivation.split("\n  ) { };\n").collect();
+
+        assert_eq!(outputs.len(), 4);
+        assert!(outputs[0].contains("\n  x86_64-linux = (\n"));
+        assert!(outputs[0].contains("\n      system = \"x86_64-linux\";\n"));
+        assert!(outputs[0].contains("dependencies = [ common_0_1_0 linux_0_1_0 ];"));
+        assert!(outputs[1].starts_with("  aarch64-darwin = (\n"));
+        assert!(outputs[1].contains("\n      system = \"aarch64-darwin\";\n"));
+        assert!(outputs[1].contains("dependencies = [ common_0_1_0 mac_0_1_0 ];"));
+        assert!(outputs[2].starts_with("  x86_64-unknown-linux-musl = (\n"));
+        assert!(
+            outputs[2].contains("\n      crossSystem.config = \"x86_64-unknown-linux-musl\";\n")
+        );
+        assert!(outputs[2].contains("dependencies = [ common_0_1_0 linux_0_1_0 ];"));
+        assert_eq!(outputs[3], "}\n");
+
+        // Only the output of the current system is built
+        let current = target_spec::Platform::current().unwrap();
+        let current_system = nix::nix_system(current.triple_str())
+            .unwrap_or_else(|| current.triple_str().to_string());
+
+        options.targets = vec![current.triple_str().to_string()];
+        assert_eq!(
+            options.build_attribute().unwrap(),
+            Some(current_system.clone())
+        );
+
+        options.targets = vec!["wasm32-unknown-unknown".to_string()];
+        assert_eq!(
+            options.build_attribute().unwrap_err().to_string(),
+            format!("the derivation has no output for the current system {current_system}. Use --no-build to only write it")
+        );
+
+        options.targets = Default::default();
+        assert_eq!(options.build_attribute().unwrap(), None);
+    }
+
+    #[test]
+    fn out_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple")
+            .join("Cargo.toml");
+
+        // A stand-in for nix which links the output when asked to, and prints its arguments
+        let nix_bin = dir.path().join("nix");
+        fs::write(
+            &nix_bin,
+            r#"#!/bin/sh
+echo "$@" > "$(dirname "$0")/args"
+while [ $# -gt 0 ]; do
+    if [ "$1" = "--out-link" ]; then ln -s /nix/store/linked-simple "$2"; fi
+    shift
+done
+echo /nix/store/printed-simple
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&nix_bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let out_link = dir.path().join("simple-result");
+        let mut options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            derivation_path: dir.path().join("simple.nix"),
+            nix_bin,
+            out_link: Some(out_link.clone()),
+            ..Default::default()
+        };
+
+        let outcome = build(&options).unwrap();
+        let args = || fs::read_to_string(dir.path().join("args")).unwrap();
+
+        assert_eq!(
+            outcome.out_path,
+            Some(PathBuf::from("/nix/store/linked-simple"))
+        );
+        assert_eq!(outcome.out_link, Some(out_link.clone()));
+        assert!(args().contains(&format!("--out-link {}", out_link.display())));
+
+        options.no_link = true;
+
+        let outcome = build(&options).unwrap();
+
+        assert_eq!(
+            outcome.out_path,
+            Some(PathBuf::from("/nix/store/printed-simple"))
+        );
+        assert_eq!(outcome.out_link, None);
+        assert!(args().contains("--no-link"));
+    }
+
+    #[test]
+    fn check_eval() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple")
+            .join("Cargo.toml");
+
+        // A stand-in for nix-instantiate which fails to parse the third line, like newer nix versions report it
+        let nix_instantiate = dir.path().join("nix-instantiate");
+        fs::write(
+            &nix_instantiate,
+            r#"#!/bin/sh
+echo "error: syntax error, unexpected '{'" >&2
+echo "       at $2:3:3:" >&2
+exit 1
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&nix_instantiate, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            derivation_path: dir.path().join("simple.nix"),
+            nix_bin: dir.path().join("nix"),
+            check_eval: true,
+            build: false,
+            ..Default::default()
+        };
+
+        let derivation = generate(&options).unwrap();
+
+        assert_eq!(
+            build(&options).unwrap_err().to_string(),
+            format!(
+                "the derivation does not parse: syntax error, unexpected '{{'\n3| {}",
+                derivation.lines().nth(2).unwrap()
+            )
+        );
+
+        // Older nix versions put the location on the same line
+        assert_eq!(
+            parse_error(
+                "error: syntax error, unexpected $end, expecting ';', at /tmp/a.nix:2:1",
+                Path::new("/tmp/a.nix"),
+                "{\n  a = 1\n",
+            )
+            .to_string(),
+            "the derivation does not parse: syntax error, unexpected $end, expecting ';', at /tmp/a.nix:2:1\n2|   a = 1"
+        );
+    }
+
+    #[test]
+    fn build_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple")
+            .join("Cargo.toml");
+
+        // A stand-in for nix which only has to build the core crate
+        let nix_bin = dir.path().join("nix");
+        fs::write(
+            &nix_bin,
+            "#!/bin/sh\necho 'this derivation will be built:' >&2\necho '  /nix/store/abc-rust_simple-0.1.0.drv' >&2\n",
+        )
+        .unwrap();
+        fs::set_permissions(&nix_bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut options = BuildOptions {
+            manifest_path: Some(manifest_path),
+            derivation_path: dir.path().join("simple.nix"),
+            nix_bin,
+            ..Default::default()
+        };
+
+        let plan = plan(&options).unwrap();
+        let (core, dependencies) = plan.crates.split_last().unwrap();
+
+        assert!(options.derivation_path.exists());
+        assert_eq!(core.identifier, "simple_0_1_0");
+        assert_eq!(core.fresh, Some(true));
+        assert!(dependencies.iter().all(|c| c.fresh == Some(false)));
+
+        // Without building, nix is not asked
+        options.build = false;
+
+        assert!(super::plan(&options)
+            .unwrap()
+            .crates
+            .iter()
+            .all(|c| c.fresh.is_none()));
+    }
+
+    #[test]
+    fn cargo_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cargo_config")
+            .join("member")
+            .join("Cargo.toml");
+        let derivation_path = dir.path().join("member.nix");
+
+        let options = BuildOptions {
+            manifest_path: Some(manifest_path.clone()),
+            derivation_path: derivation_path.clone(),
+            build: false,
+            ..Default::default()
+        };
+
+        build(&options).unwrap();
+
+        let derivation = fs::read_to_string(derivation_path).unwrap();
+
+        // The dependency is only kept for the target in the cargo config
+        assert!(derivation.contains("  itoa_1_0_6 = buildRustCrate"));
+        assert!(derivation.contains(
+            r#"extraRustcOpts = [ "-C embed-bitcode=no" "-C" "target-cpu=neoverse-n1" ];"#
+        ));
+
+        // The target directory of the workspace is left out of the member's source, relative to the derivation
+        assert!(derivation.contains(
+            "|| (type == \"directory\" && builtins.toString name == builtins.toString (../"
+        ));
+        assert!(derivation.contains("/tests/cargo_config/build-out))"));
+    }
+}
@@ -2,17 +2,169 @@
 
 use thiserror::Error;
 
+pub mod build;
 pub mod models;
 
-/// Errors that can happen while reading cargo metadata
+/// Errors that can happen while reading cargo metadata or writing the derivation
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("target spec failed: {0}")]
     TargetSpec(#[from] target_spec::Error),
 
+    #[error("the host platform {0} is not known, pass the triple to keep the dependencies of with `--platform`")]
+    UnknownHostPlatform(String),
+
     #[error("failed to read cargo metadata: {0}")]
     Metadata(#[from] cargo_metadata::Error),
 
     #[error("failed to read cargo lock file: {0}")]
     LockFile(#[from] cargo_lock::Error),
+
+    #[error("no lock file found at {}. Run `cargo generate-lockfile` to create one", .0.display())]
+    MissingLockFile(std::path::PathBuf),
+
+    #[error("cannot read the version {version} lock file at {}: {source}. Regenerate it in version 3 with `cargo +1.82 generate-lockfile`", .path.display())]
+    UnsupportedLockFile {
+        path: std::path::PathBuf,
+        version: i64,
+        source: cargo_lock::Error,
+    },
+
+    #[error("cargo metadata did not finish within {0:?}")]
+    MetadataTimeout(std::time::Duration),
+
+    #[error("lock file is out of date: {0}")]
+    OutdatedLockFile(String),
+
+    #[error("`{command}` failed: {status}")]
+    Command {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+
+    #[error("the derivation does not parse: {message}{}", .line.as_deref().unwrap_or_default())]
+    InvalidDerivation {
+        message: String,
+        line: Option<String>,
+    },
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to parse manifest: {0}")]
+    Manifest(#[from] toml::de::Error),
+
+    #[error("failed to parse config file: {0}")]
+    Config(toml::de::Error),
+
+    #[error("invalid [package.metadata.nbuild] in Cargo.toml: {0}")]
+    ManifestConfig(serde_json::Error),
+
+    #[error("invalid rust version `{0}`, expected a version like 1.68.0")]
+    InvalidRustVersion(String),
+
+    #[error("failed to parse cargo config {}: {source}", .path.display())]
+    CargoConfig {
+        path: std::path::PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("dependency cycle detected: {0}")]
+    DependencyCycle(String),
+
+    #[error("the crates {first} and {second} both use the identifier {identifier}")]
+    IdentifierCollision {
+        identifier: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("no workspace member named {0}")]
+    UnknownMember(String),
+
+    #[error("every member of the workspace is excluded")]
+    NoMembers,
+
+    #[error("cannot pick the default package: {0}")]
+    DefaultPackage(String),
+
+    #[error("no bin target named `{name}`. Available bins: {available}")]
+    UnknownBin { name: String, available: String },
+
+    #[error("no example target named `{name}`. Available examples: {available}")]
+    UnknownExample { name: String, available: String },
+
+    #[error("{name} requires rust {rust_version}, but the toolchain is {toolchain}")]
+    Msrv {
+        name: String,
+        rust_version: String,
+        toolchain: String,
+    },
+
+    #[error("the codegen backend {backend} needs a nightly rust version, but the rust version is {toolchain}")]
+    CodegenBackendNeedsNightly { backend: String, toolchain: String },
+
+    #[error("the argument pattern of the fetch-crate template does not take the `{0}` argument of fetchCrate")]
+    InvalidFetchCrate(&'static str),
+
+    #[error("invalid replacement `{0}`, expected NAME@VERSION=VERSION[:SHA256]")]
+    InvalidReplacement(String),
+
+    #[error(
+        "no checksum for {name} {version}. Download it with `cargo fetch` first or give its sha256"
+    )]
+    MissingChecksum { name: String, version: String },
+
+    #[error("the replacement of {0} did not match any crate in the dependency tree")]
+    UnusedReplacement(String),
+
+    #[error("the crane backend only finds crates from crates.io, git and the workspace's directory, but {name} {version} comes from {origin}")]
+    UnsupportedCraneSource {
+        name: String,
+        version: String,
+        origin: String,
+    },
+
+    #[error("the crane backend does not support {0}")]
+    UnsupportedCraneOption(&'static str),
+
+    #[error("invalid forced feature `{0}`, expected CRATE/FEATURE")]
+    InvalidForcedFeature(String),
+
+    #[error("invalid crate `{0}`, expected NAME@VERSION")]
+    InvalidCrateSpec(String),
+
+    #[error("no crate in the dependency tree has the forced feature {0}")]
+    UnusedForcedFeature(String),
+
+    #[error("package {name} does not have the feature {feature}")]
+    UnknownFeature { name: String, feature: String },
+
+    #[error("invalid cargo config `{0}`, expected KEY=VALUE")]
+    InvalidCargoConfig(String),
+
+    #[error(
+        "the derivation has no output for the current system {0}. Use --no-build to only write it"
+    )]
+    MissingSystem(String),
+
+    #[error("checksum of {name} {version} is not a valid sha256: {checksum}")]
+    InvalidChecksum {
+        name: String,
+        version: String,
+        checksum: String,
+    },
+
+    #[error("checksum mismatch for {name} {version}: Cargo.lock has {expected}, but the downloaded crate has {actual}")]
+    ChecksumMismatch {
+        name: String,
+        version: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(
+        "{name} {version} is not in the local cargo cache, run `cargo fetch` to download it first"
+    )]
+    MissingLocalCrate { name: String, version: String },
 }
@@ -0,0 +1,287 @@
+//! Read the settings from the `.cargo/config.toml` files which change how `cargo build` builds a package
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use tracing::trace;
+
+use crate::Error;
+
+/// The settings of the [cargo config][config] files which apply to a package
+///
+/// [config]: https://doc.rust-lang.org/cargo/reference/config.html
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CargoConfig {
+    /// Triples from `build.target`
+    pub targets: Vec<String>,
+
+    /// Flags from `build.rustflags`
+    build_rustflags: Option<Vec<String>>,
+
+    /// Flags from `target.<triple>.rustflags`
+    target_rustflags: BTreeMap<String, Vec<String>>,
+
+    /// Flags from the `CARGO_ENCODED_RUSTFLAGS` or `RUSTFLAGS` environment variables
+    env_rustflags: Option<Vec<String>>,
+
+    /// Directory from `CARGO_TARGET_DIR` relative to the current directory, or else `build.target-dir` relative to
+    /// the directory holding its `.cargo`
+    pub target_dir: Option<PathBuf>,
+}
+
+/// The parts of a config file we care about
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    build: BuildConfig,
+    target: BTreeMap<String, TargetConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct BuildConfig {
+    target: Option<StringOrList>,
+    rustflags: Option<StringOrList>,
+    target_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TargetConfig {
+    rustflags: Option<StringOrList>,
+}
+
+/// Cargo allows most lists to also be a space separated string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+impl From<StringOrList> for Vec<String> {
+    fn from(value: StringOrList) -> Self {
+        match value {
+            StringOrList::String(value) => value.split_whitespace().map(String::from).collect(),
+            StringOrList::List(list) => list,
+        }
+    }
+}
+
+impl CargoConfig {
+    /// Read the config files in `dir` and all its ancestors, and the environment of the process. Like cargo, a setting
+    /// in a deeper directory wins over the same setting higher up.
+    pub fn discover(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::discover_in(dir, "/", |name| env::var_os(name))
+    }
+
+    /// Read the config files in `dir` and its ancestors up to and including `stop_at`, which keeps the files of the
+    /// user, like the ones in their home directory, out. Every ancestor is read when `stop_at` is not one of them. The
+    /// environment variables are looked up with `env`.
+    pub fn discover_in(
+        dir: impl AsRef<Path>,
+        stop_at: impl AsRef<Path>,
+        env: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, Error> {
+        let mut config = Self::default();
+        let mut dirs = Vec::new();
+
+        for dir in dir.as_ref().ancestors() {
+            dirs.push(dir);
+
+            if dir == stop_at.as_ref() {
+                break;
+            }
+        }
+
+        // Go from the root down so that deeper files override
+
+        for dir in dirs.into_iter().rev() {
+            let cargo_dir = dir.join(".cargo");
+
+            // `config` is the older name of the file, and is only used when there is no `config.toml`
+            if let Some(path) = ["config.toml", "config"]
+                .into_iter()
+                .map(|name| cargo_dir.join(name))
+                .find(|path| path.is_file())
+            {
+                config.merge(Self::read(&path)?, dir);
+            }
+        }
+
+        let env_string = |name| env(name).and_then(|value| value.into_string().ok());
+
+        config.env_rustflags = env_rustflags(
+            env_string("CARGO_ENCODED_RUSTFLAGS"),
+            env_string("RUSTFLAGS"),
+        );
+
+        // Like cargo, a relative `CARGO_TARGET_DIR` is relative to where it is run from
+        if let Some(target_dir) = env("CARGO_TARGET_DIR") {
+            config.target_dir = Some(env::current_dir()?.join(target_dir));
+        }
+
+        Ok(config)
+    }
+
+    /// Read a single config file
+    fn read(path: &Path) -> Result<ConfigFile, Error> {
+        trace!(?path, "reading cargo config");
+
+        toml::from_str(&fs::read_to_string(path)?).map_err(|source| Error::CargoConfig {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Add the settings of a deeper config file, which is in the `.cargo` directory of `dir`
+    fn merge(&mut self, file: ConfigFile, dir: &Path) {
+        if let Some(target) = file.build.target {
+            self.targets = target.into();
+        }
+
+        if let Some(target_dir) = file.build.target_dir {
+            self.target_dir = Some(dir.join(target_dir));
+        }
+
+        if let Some(rustflags) = file.build.rustflags {
+            self.build_rustflags = Some(rustflags.into());
+        }
+
+        for (triple, target) in file.target {
+            if let Some(rustflags) = target.rustflags {
+                self.target_rustflags.insert(triple, rustflags.into());
+            }
+        }
+    }
+
+    /// Get the flags to pass to rustc when building for `triple`. Like for cargo, the flags from the environment win
+    /// over the flags for the specific target, which win over the flags in the `build` table.
+    pub fn rustflags(&self, triple: &str) -> Vec<String> {
+        self.env_rustflags
+            .as_ref()
+            .or(self.target_rustflags.get(triple))
+            .or(self.build_rustflags.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Get the flags from the values of `CARGO_ENCODED_RUSTFLAGS` and `RUSTFLAGS`. The encoded flags are separated by the
+/// unit separator, which lets a flag have spaces, and win over the plain ones.
+fn env_rustflags(encoded: Option<String>, plain: Option<String>) -> Option<Vec<String>> {
+    match (encoded, plain) {
+        (Some(encoded), _) if encoded.is_empty() => Some(Default::default()),
+        (Some(encoded), _) => Some(encoded.split('\x1f').map(String::from).collect()),
+        (None, Some(plain)) => Some(plain.split_whitespace().map(String::from).collect()),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, str::FromStr};
+
+    use super::{env_rustflags, CargoConfig};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn discover() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cargo_config");
+
+        let config = CargoConfig::discover_in(path.join("member"), &path, |_| None).unwrap();
+
+        // The member overrides the target of the workspace, but keeps its rustflags
+        assert_eq!(
+            config.targets,
+            vec!["aarch64-unknown-linux-gnu".to_string()]
+        );
+        assert_eq!(
+            config.rustflags("aarch64-unknown-linux-gnu"),
+            vec!["-C".to_string(), "target-cpu=neoverse-n1".to_string()]
+        );
+        assert_eq!(
+            config.rustflags("x86_64-unknown-linux-gnu"),
+            vec!["--cfg".to_string(), "nbuild".to_string()]
+        );
+
+        // The target directory is relative to the workspace config
+        assert_eq!(config.target_dir, Some(path.join("build-out")));
+
+        let config = CargoConfig::discover_in(&path, &path, |_| None).unwrap();
+
+        assert_eq!(config.targets, vec!["x86_64-unknown-linux-gnu".to_string()]);
+
+        // The files above `stop_at` are left out
+        let config =
+            CargoConfig::discover_in(path.join("member"), path.join("member"), |_| None).unwrap();
+
+        assert_eq!(
+            config.targets,
+            vec!["aarch64-unknown-linux-gnu".to_string()]
+        );
+        assert_eq!(
+            config.rustflags("aarch64-unknown-linux-gnu"),
+            Vec::<String>::new()
+        );
+        assert_eq!(config.target_dir, None);
+
+        // `CARGO_TARGET_DIR` wins over the config files
+        let config = CargoConfig::discover_in(path.join("member"), &path, |name| {
+            (name == "CARGO_TARGET_DIR").then(|| "env-out".into())
+        })
+        .unwrap();
+
+        assert_eq!(
+            config.target_dir,
+            Some(env::current_dir().unwrap().join("env-out"))
+        );
+    }
+
+    #[test]
+    fn env_rustflags_win() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cargo_config");
+
+        let config = CargoConfig::discover_in(path.join("member"), &path, |name| {
+            (name == "RUSTFLAGS").then(|| "-C  target-cpu=native".into())
+        })
+        .unwrap();
+
+        assert_eq!(
+            config.rustflags("aarch64-unknown-linux-gnu"),
+            vec!["-C".to_string(), "target-cpu=native".to_string()]
+        );
+
+        // The encoded flags win, and can have spaces
+        assert_eq!(
+            env_rustflags(
+                Some("--cfg\x1ffeature=\"a b\"".to_string()),
+                Some("-C target-cpu=native".to_string())
+            ),
+            Some(vec!["--cfg".to_string(), "feature=\"a b\"".to_string()])
+        );
+
+        // Even when empty, which turns off the flags of the config files
+        assert_eq!(
+            env_rustflags(
+                Some("".to_string()),
+                Some("-C target-cpu=native".to_string())
+            ),
+            Some(vec![])
+        );
+        assert_eq!(env_rustflags(None, None), None);
+    }
+}
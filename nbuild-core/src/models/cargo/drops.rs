@@ -0,0 +1,120 @@
+//! Explain which dependencies of the manifests are left out of the resolved graph, since a missing crate is hard to
+//! track down otherwise
+
+use std::fmt;
+
+use cargo_metadata::DependencyKind;
+
+use super::Package;
+
+/// A dependency in a manifest which did not make it into the graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DroppedDependency {
+    /// The package whose manifest has the dependency
+    pub parent: String,
+    pub parent_version: String,
+    pub name: String,
+    pub reason: DropReason,
+}
+
+/// Why a [DroppedDependency] was left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// The dependency is under a `[target]` table which none of the platforms match
+    Platform {
+        kind: DependencyKind,
+        target: String,
+    },
+
+    /// The dependency is only a dev-dependency on the platforms, which are not needed to build the package
+    DevOnly,
+}
+
+impl fmt::Display for DroppedDependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {}: {}",
+            self.parent, self.parent_version, self.name, self.reason
+        )
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Platform { kind, target } => {
+                write!(f, "the {kind} dependency only applies to {target}")
+            }
+            Self::DevOnly => write!(f, "only a dev-dependency"),
+        }
+    }
+}
+
+impl Package {
+    /// The dependencies which are left out of the graph of this package and the dependencies it turns on, and why.
+    /// They are collected while the package is read, so only the platforms it was read for are looked at.
+    pub fn dropped_dependencies(&self) -> Vec<DroppedDependency> {
+        let mut dropped = Vec::new();
+
+        // The host and target variants of a crate come from the same manifest
+        self.for_each_package(|package| {
+            for dependency in &package.dropped {
+                if !dropped.contains(dependency) {
+                    dropped.push(dependency.clone());
+                }
+            }
+        });
+
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use crate::models::cargo::{MetadataOptions, Package};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn platform_and_dev_only() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("platforms");
+
+        let package = Package::from_current_dir_with_options(
+            &path,
+            &MetadataOptions {
+                platforms: vec!["x86_64-unknown-linux-gnu".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let dropped = package.dropped_dependencies();
+
+        assert_eq!(
+            dropped.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec![
+                r#"platforms 0.1.0 -> mac: the normal dependency only applies to cfg(target_os = "macos")"#,
+            ]
+        );
+
+        // Nothing is dropped when every platform is asked for
+        let package = Package::from_current_dir_with_options(
+            &path,
+            &MetadataOptions {
+                platforms: vec![
+                    "x86_64-unknown-linux-gnu".to_string(),
+                    "aarch64-apple-darwin".to_string(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(package.dropped_dependencies(), vec![]);
+    }
+}
@@ -0,0 +1,260 @@
+//! Dump a resolved package tree as a [Graphviz DOT][dot] digraph, or summarize it.
+//!
+//! [dot]: https://graphviz.org/doc/info/lang.html
+
+use std::{collections::HashSet, fmt};
+
+use cargo_lock::Version;
+
+use crate::models::Source;
+
+use super::Package;
+
+/// Summary of the crates a resolved package tree pulls in
+#[derive(Debug, Default, PartialEq)]
+pub struct GraphStats {
+    /// Number of unique `(name, version)` crates, including the root
+    pub unique_crates: usize,
+
+    /// Number of those crates which come from crates.io
+    pub crates_io_count: usize,
+
+    /// Number of those crates which come from a local path
+    pub local_count: usize,
+
+    /// Sum of the enabled features of all the crates
+    pub total_features: usize,
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "unique crates:  {}", self.unique_crates)?;
+        writeln!(f, "from crates.io: {}", self.crates_io_count)?;
+        writeln!(f, "local crates:   {}", self.local_count)?;
+        write!(f, "total features: {}", self.total_features)
+    }
+}
+
+impl Package {
+    /// Print the resolved dependency tree as a DOT digraph. Each unique `(name, version)` becomes one node. Normal
+    /// dependencies are solid edges and build dependencies are dashed edges. Optional dependencies which were
+    /// activated by a feature are colored blue.
+    pub fn to_dot(&self) -> String {
+        let mut lines = Vec::new();
+
+        self.for_each_package(|package| {
+            for line in dot_lines(package) {
+                // The host and target variants of a crate are one node
+                if !lines.contains(&line) {
+                    lines.push(line);
+                }
+            }
+        });
+
+        format!("digraph dependencies {{\n{}\n}}\n", lines.join("\n"))
+    }
+
+    /// Count the crates of the resolved dependency tree. Each unique `(name, version)` is only counted once.
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        let mut counted = HashSet::new();
+
+        // The host and target variants of a crate are one crate
+        self.for_each_package(|package| {
+            if counted.insert((package.name.clone(), package.version.clone())) {
+                stats.add(package);
+            }
+        });
+
+        stats
+    }
+
+    /// Check if the dependency with `name` was declared as optional by this package. Cargo metadata always lists a
+    /// `dep:name` entry in the features table for optional dependencies.
+    pub(super) fn is_optional_dependency(&self, name: &str) -> bool {
+        let dep_feature = format!("dep:{name}");
+
+        self.features
+            .values()
+            .flatten()
+            .any(|feature| feature == &dep_feature)
+    }
+}
+
+/// Get the node of a package, followed by the edges to its dependencies
+fn dot_lines(package: &Package) -> Vec<String> {
+    let from = node_id(&package.name, &package.version);
+    let mut lines = vec![format!("    {from};")];
+
+    let dependencies = package
+        .dependencies
+        .iter()
+        .map(|d| (d, false))
+        .chain(package.build_dependencies.iter().map(|d| (d, true)))
+        .filter(|(d, _)| !d.optional);
+
+    for (dependency, is_build) in dependencies {
+        let child = dependency.package.borrow();
+        let to = node_id(&child.name, &child.version);
+
+        let mut attributes = Vec::new();
+
+        if is_build {
+            attributes.push("style=dashed");
+        }
+
+        if package.is_optional_dependency(&dependency.name) {
+            attributes.push("color=blue");
+        }
+
+        if attributes.is_empty() {
+            lines.push(format!("    {from} -> {to};"));
+        } else {
+            lines.push(format!("    {from} -> {to} [{}];", attributes.join(", ")));
+        }
+    }
+
+    lines
+}
+
+impl GraphStats {
+    /// Add a single crate to the counts
+    fn add(&mut self, package: &Package) {
+        self.unique_crates += 1;
+        self.total_features += package.enabled_features.len();
+
+        match package.source {
+            Source::CratesIo(_) => self.crates_io_count += 1,
+            Source::Local(_) => self.local_count += 1,
+            Source::Registry { .. } | Source::Git { .. } => {}
+        }
+    }
+}
+
+/// Helper to get the quoted identifier of a node
+fn node_id(name: &str, version: &Version) -> String {
+    format!("\"{name} {version}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use crate::models::cargo::{GraphStats, Package};
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn simple_package() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.to_dot(),
+            r#"digraph dependencies {
+    "simple 0.1.0";
+    "simple 0.1.0" -> "itoa 1.0.6";
+    "simple 0.1.0" -> "arbitrary 1.3.0" [style=dashed];
+    "itoa 1.0.6";
+    "arbitrary 1.3.0";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace")
+            .join("parent");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.stats(),
+            GraphStats {
+                unique_crates: 9,
+                crates_io_count: 5,
+                local_count: 4,
+                total_features: 9,
+            }
+        );
+    }
+
+    #[test]
+    fn split_crate() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("resolver");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        // The v2 resolver gives the child a host variant with other features, which is still the same crate
+        assert_eq!(
+            package.to_dot(),
+            r#"digraph dependencies {
+    "resolver 0.1.0";
+    "resolver 0.1.0" -> "child 0.1.0";
+    "resolver 0.1.0" -> "child 0.1.0" [style=dashed];
+    "child 0.1.0";
+}
+"#
+        );
+        assert_eq!(
+            package.stats(),
+            GraphStats {
+                unique_crates: 2,
+                crates_io_count: 0,
+                local_count: 2,
+                total_features: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn workspace() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace")
+            .join("parent");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.to_dot(),
+            r#"digraph dependencies {
+    "parent 0.1.0";
+    "parent 0.1.0" -> "child 0.1.0";
+    "parent 0.1.0" -> "itoa 0.4.8";
+    "parent 0.1.0" -> "libc 0.2.144";
+    "parent 0.1.0" -> "targets 0.1.0";
+    "child 0.1.0";
+    "child 0.1.0" -> "fnv 1.0.7";
+    "child 0.1.0" -> "itoa 1.0.6";
+    "child 0.1.0" -> "libc 0.2.144";
+    "child 0.1.0" -> "rename 0.1.0" [color=blue];
+    "child 0.1.0" -> "rustversion 1.0.12";
+    "fnv 1.0.7";
+    "itoa 1.0.6";
+    "libc 0.2.144";
+    "rename 0.1.0";
+    "rustversion 1.0.12";
+    "itoa 0.4.8";
+    "targets 0.1.0";
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! Run `cargo metadata` so that a slow network can neither hang nbuild nor fail it on the first hiccup, and keep its
+//! output around so that an unchanged package does not need to run it again
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Stdio,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use cargo_metadata::{Metadata, MetadataCommand};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::Error;
+
+use super::CargoConfig;
+
+/// How many times a failure which looks like a network problem is retried
+const RETRIES: u32 = 2;
+
+/// How long to wait before the first retry. The wait doubles for every next retry.
+const BACKOFF: Duration = Duration::from_secs(1);
+
+/// How often to check whether cargo is done when there is a timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Parts of cargo's errors which mean the same command could work when it is run again
+const TRANSIENT_ERRORS: [&str; 6] = [
+    "spurious network error",
+    "failed to download",
+    "failed to fetch",
+    "timed out",
+    "Could not resolve host",
+    "Connection reset",
+];
+
+/// The directory inside the target directory to keep the cached metadata in
+const CACHE_DIR: &str = "nbuild-cache";
+
+/// The output of `cargo metadata` for one set of arguments, kept in the target directory between runs
+#[derive(Debug)]
+pub(super) struct Cache {
+    path: PathBuf,
+}
+
+/// What is stored for a [Cache]. The hashes of the local manifests catch changes which do not touch the lock file,
+/// like turning on a feature of a path dependency. The modification times of the directories cargo finds targets in
+/// catch an added or removed target, like a new file in `src/bin` or a `build.rs`.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    manifests: BTreeMap<PathBuf, String>,
+    target_dirs: BTreeMap<PathBuf, SystemTime>,
+    metadata: Metadata,
+}
+
+impl Cache {
+    /// Get the cache for running `cargo metadata` in `dir` with the `args`. The cache is keyed on the lock file of the
+    /// workspace, so there is none before cargo has written one. It is kept in `cache_dir`, or else in the target
+    /// directory of the cargo config next to the lock file.
+    pub(super) fn new(dir: &Path, args: &[String], cache_dir: Option<&Path>) -> Option<Self> {
+        let dir = fs::canonicalize(dir).ok()?;
+        let lock_file = dir
+            .ancestors()
+            .map(|dir| dir.join("Cargo.lock"))
+            .find(|path| path.is_file())?;
+        let lock = fs::read(&lock_file).ok()?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        hasher.update([0]);
+        hasher.update(dir.to_string_lossy().as_bytes());
+        for arg in args {
+            hasher.update([0]);
+            hasher.update(arg);
+        }
+        hasher.update([0]);
+        hasher.update(lock);
+
+        let cache_dir = match cache_dir {
+            Some(cache_dir) => cache_dir.to_path_buf(),
+            None => CargoConfig::discover(&dir)
+                .ok()?
+                .target_dir
+                .unwrap_or_else(|| lock_file.with_file_name("target"))
+                .join(CACHE_DIR),
+        };
+
+        Some(Self {
+            path: cache_dir.join(format!("{:x}.json", hasher.finalize())),
+        })
+    }
+
+    /// Get the cached metadata, or run the `command` like [exec] and cache its output
+    pub(super) fn exec(
+        &self,
+        command: &MetadataCommand,
+        timeout: Option<Duration>,
+    ) -> Result<Metadata, Error> {
+        if let Some(metadata) = self.read() {
+            debug!(path = ?self.path, "using cached metadata");
+
+            return Ok(metadata);
+        }
+
+        let metadata = exec(command, timeout)?;
+
+        if let Err(error) = self.write(&metadata) {
+            warn!(%error, path = ?self.path, "could not cache metadata");
+        }
+
+        Ok(metadata)
+    }
+
+    /// Read the cached metadata, unless it is missing or one of its manifests or target directories changed since
+    fn read(&self) -> Option<Metadata> {
+        let entry: CacheEntry = serde_json::from_slice(&fs::read(&self.path).ok()?).ok()?;
+
+        (local_manifests(&entry.metadata) == entry.manifests
+            && target_dirs(&entry.metadata) == entry.target_dirs)
+            .then_some(entry.metadata)
+    }
+
+    fn write(&self, metadata: &Metadata) -> std::io::Result<()> {
+        let entry = CacheEntry {
+            manifests: local_manifests(metadata),
+            target_dirs: target_dirs(metadata),
+            metadata: metadata.clone(),
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(&entry)?)?;
+
+        Ok(())
+    }
+}
+
+/// Hash the manifests of the workspace root and the local packages of the `metadata`. A manifest which cannot be
+/// read is left out, so that it no longer matches an entry which had it.
+fn local_manifests(metadata: &Metadata) -> BTreeMap<PathBuf, String> {
+    metadata
+        .packages
+        .iter()
+        .filter(|package| package.source.is_none())
+        .map(|package| package.manifest_path.clone().into_std_path_buf())
+        .chain([metadata
+            .workspace_root
+            .join("Cargo.toml")
+            .into_std_path_buf()])
+        .filter_map(|path| {
+            let hash = format!("{:x}", Sha256::digest(fs::read(&path).ok()?));
+
+            Some((path, hash))
+        })
+        .collect()
+}
+
+/// Get the modification times of the directories of the local packages of the `metadata` which cargo finds targets
+/// in. A directory changes when an entry is added or removed, like a `build.rs` in the package directory or a file in
+/// `src/bin` or `examples`. Directories which do not exist are left out, which shows up in their parent.
+fn target_dirs(metadata: &Metadata) -> BTreeMap<PathBuf, SystemTime> {
+    metadata
+        .packages
+        .iter()
+        .filter(|package| package.source.is_none())
+        .filter_map(|package| package.manifest_path.parent())
+        .flat_map(|dir| {
+            ["", "src", "src/bin", "examples", "tests", "benches"]
+                .map(|target_dir| dir.join(target_dir).into_std_path_buf())
+        })
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+
+            Some((path, modified))
+        })
+        .collect()
+}
+
+/// Run the `command`, and kill it when it takes longer than the `timeout`. Network failures are retried a few times.
+pub(super) fn exec(
+    command: &MetadataCommand,
+    timeout: Option<Duration>,
+) -> Result<Metadata, Error> {
+    exec_with_backoff(command, timeout, BACKOFF)
+}
+
+/// Run the `command`, waiting `backoff` before the first retry
+fn exec_with_backoff(
+    command: &MetadataCommand,
+    timeout: Option<Duration>,
+    backoff: Duration,
+) -> Result<Metadata, Error> {
+    let mut attempt = 0;
+
+    loop {
+        match run_once(command, timeout) {
+            Ok(Some(metadata)) => return Ok(metadata),
+            Ok(None) => {
+                return Err(Error::MetadataTimeout(
+                    timeout.expect("only a timeout to stop cargo"),
+                ))
+            }
+            Err(cargo_metadata::Error::CargoMetadata { stderr })
+                if attempt < RETRIES && is_transient(&stderr) =>
+            {
+                let wait = backoff * 2u32.pow(attempt);
+                attempt += 1;
+
+                warn!(attempt, ?wait, %stderr, "cargo metadata failed, retrying");
+                thread::sleep(wait);
+            }
+            Err(error) => return Err(super::metadata_error(error)),
+        }
+    }
+}
+
+/// Run the `command` once. Gives `None` when it was killed for taking longer than the `timeout`.
+fn run_once(
+    command: &MetadataCommand,
+    timeout: Option<Duration>,
+) -> cargo_metadata::Result<Option<Metadata>> {
+    let Some(timeout) = timeout else {
+        return command.exec().map(Some);
+    };
+
+    let mut child = command
+        .cargo_command()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read the output while waiting, so that cargo does not block on a full pipe
+    let stdout = read_in_background(child.stdout.take().expect("stdout to be piped"));
+    let stderr = read_in_background(child.stderr.take().expect("stderr to be piped"));
+    let start = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+
+            return Ok(None);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let stdout = stdout.join().expect("reading stdout not to panic")?;
+    let stderr = stderr.join().expect("reading stderr not to panic")?;
+
+    if !status.success() {
+        return Err(cargo_metadata::Error::CargoMetadata {
+            stderr: String::from_utf8(stderr)?,
+        });
+    }
+
+    // Same as `MetadataCommand::exec`, which skips anything a cargo wrapper prints before the JSON
+    let stdout = String::from_utf8(stdout)?;
+    let json = stdout
+        .lines()
+        .find(|line| line.starts_with('{'))
+        .ok_or(cargo_metadata::Error::NoJson)?;
+
+    MetadataCommand::parse(json).map(Some)
+}
+
+/// Read all of `pipe` on another thread
+fn read_in_background(
+    mut pipe: impl Read + Send + 'static,
+) -> thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        pipe.read_to_end(&mut buffer)?;
+
+        Ok(buffer)
+    })
+}
+
+/// Check if cargo failed in a way which could go away when trying again
+fn is_transient(stderr: &str) -> bool {
+    TRANSIENT_ERRORS.iter().any(|error| stderr.contains(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+
+    use cargo_metadata::MetadataCommand;
+
+    use super::exec_with_backoff;
+
+    use pretty_assertions::assert_eq;
+
+    const METADATA: &str = r#"{"packages":[],"workspace_members":[],"resolve":null,"workspace_root":"/w","target_directory":"/w/target","version":1}"#;
+
+    /// Get a metadata command which runs the `script` instead of cargo
+    fn fake_cargo(dir: &Path, script: &str) -> MetadataCommand {
+        let path = dir.join("cargo");
+        fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut command = MetadataCommand::new();
+        command.cargo_path(path);
+        command
+    }
+
+    #[test]
+    fn timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = fake_cargo(dir.path(), "sleep 10");
+
+        assert_eq!(
+            exec_with_backoff(&command, Some(Duration::from_millis(200)), Duration::ZERO)
+                .unwrap_err()
+                .to_string(),
+            "cargo metadata did not finish within 200ms"
+        );
+
+        let command = fake_cargo(
+            dir.path(),
+            &format!("echo 'warning: wrapped'\necho '{METADATA}'"),
+        );
+
+        assert_eq!(
+            exec_with_backoff(&command, Some(Duration::from_secs(10)), Duration::ZERO)
+                .unwrap()
+                .workspace_root,
+            "/w"
+        );
+    }
+
+    #[test]
+    fn retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let attempts = dir.path().join("attempts");
+
+        // Fails with a network error on the first two attempts
+        let command = fake_cargo(
+            dir.path(),
+            &format!(
+                r#"echo x >> {attempts}
+if [ $(wc -l < {attempts}) -le 2 ]; then
+    echo 'error: failed to download from `https://static.crates.io`' >&2
+    exit 101
+fi
+echo '{METADATA}'"#,
+                attempts = attempts.display()
+            ),
+        );
+
+        for timeout in [None, Some(Duration::from_secs(10))] {
+            fs::write(&attempts, "").unwrap();
+
+            exec_with_backoff(&command, timeout, Duration::ZERO).unwrap();
+            assert_eq!(fs::read_to_string(&attempts).unwrap().lines().count(), 3);
+        }
+
+        // Other errors are not retried
+        let command = fake_cargo(
+            dir.path(),
+            &format!(
+                "echo x >> {}\necho 'error: invalid manifest' >&2\nexit 101",
+                attempts.display()
+            ),
+        );
+        fs::write(&attempts, "").unwrap();
+
+        assert!(exec_with_backoff(&command, None, Duration::ZERO).is_err());
+        assert_eq!(fs::read_to_string(&attempts).unwrap().lines().count(), 1);
+    }
+}
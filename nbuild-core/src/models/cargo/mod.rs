@@ -4,23 +4,48 @@
 use std::{
     cell::RefCell,
     collections::{BTreeMap, HashMap, HashSet},
-    path::PathBuf,
+    env, fs,
+    path::{Path, PathBuf},
     rc::Rc,
+    str::FromStr,
+    time::Duration,
 };
 
-use cargo_lock::{Lockfile, Version};
-use cargo_metadata::{camino::Utf8PathBuf, DependencyKind, MetadataCommand, PackageId};
-use target_spec::{Platform, TargetSpec};
-use tracing::{instrument, trace};
+use cargo_lock::{package::Checksum, Lockfile, Version};
+use cargo_metadata::semver::VersionReq;
+use cargo_metadata::{
+    camino::{Utf8Path, Utf8PathBuf},
+    DependencyKind, Metadata, MetadataCommand, PackageId,
+};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use target_spec::{Platform, TargetFeatures, TargetSpec};
+use tracing::{instrument, trace, warn};
 
 use crate::Error;
 
-use super::Source;
+use super::{crate_cache_dirs, Bin, Source};
 
+mod config;
+mod drops;
+mod graph;
+mod metadata;
 mod visitor;
 
+pub use config::CargoConfig;
+pub use drops::{DropReason, DroppedDependency};
+pub use graph::GraphStats;
 pub use visitor::Visitor;
 
+/// The crates.io index for both the git and sparse protocols
+const CRATES_IO_INDEXES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// The newest lock file version cargo-lock can read
+const LOCK_FILE_VERSION: i64 = 3;
+
 /// Details of a package / crate
 #[derive(Debug, PartialEq, Clone)]
 pub struct Package {
@@ -32,6 +57,13 @@ pub struct Package {
     pub(super) build_path: Option<Utf8PathBuf>,
     pub(super) proc_macro: bool,
 
+    /// The crate types of the library target, like `lib` or `cdylib`
+    pub(super) crate_types: Vec<String>,
+    pub(super) bins: Vec<Bin>,
+
+    /// The example targets, which can be built instead of the bins
+    pub(super) examples: Vec<Bin>,
+
     /// List of possible features for a package
     pub(super) features: HashMap<String, Vec<String>>,
 
@@ -40,6 +72,15 @@ pub struct Package {
     pub(super) dependencies: Vec<Dependency>,
     pub(super) build_dependencies: Vec<Dependency>,
     pub(super) edition: String,
+
+    /// The minimum supported Rust version of the package
+    pub(super) rust_version: Option<Version>,
+
+    /// The native library the package links, from the `links` key of its manifest
+    pub(super) links: Option<String>,
+
+    /// The dependencies in the manifest which were left out of the graph on these platforms
+    pub(super) dropped: Vec<DroppedDependency>,
 }
 
 /// A dependency of a package. This model is used to keep track of [renames][rename], [optional][optional] dependencies,
@@ -56,27 +97,358 @@ pub struct Dependency {
     pub(super) optional: bool,
     pub(super) uses_default_features: bool,
     pub(super) features: Vec<String>,
+
+    /// The target triples this dependency is limited to. It is empty when the dependency is used on all platforms.
+    pub(super) platforms: Vec<String>,
+}
+
+/// Options for reading the cargo metadata of a package
+#[derive(Debug, Default, Clone)]
+pub struct MetadataOptions {
+    /// Directories with vendored crates, like those created by `cargo vendor`. Crates inside them become local
+    /// sources. Directories from the [source replacement][replacement] configuration are always used.
+    ///
+    /// [replacement]: https://doc.rust-lang.org/cargo/reference/source-replacement.html
+    pub vendor_dirs: Vec<PathBuf>,
+
+    /// Read the lock file from this path instead of the `Cargo.lock` in the workspace root
+    pub lock_file: Option<PathBuf>,
+
+    /// Pass `--frozen` to cargo so that it cannot touch the network or update the lock file
+    pub frozen: bool,
+
+    /// Pass `--locked` to cargo so that it fails when the lock file needs to be updated
+    pub locked: bool,
+
+    /// Pass `--offline` to cargo so that it cannot touch the network
+    pub offline: bool,
+
+    /// Target triples to keep the dependencies of. Dependencies which are only used on some of them are kept with
+    /// the list of platforms they apply to. Defaults to only the current platform.
+    pub platforms: Vec<String>,
+
+    /// Cross-compile for this triple instead of the `platforms`. Normal dependencies are then kept for this triple,
+    /// while build dependencies and proc-macros are kept for the current platform they run on.
+    pub target: Option<String>,
+
+    /// Stop `cargo metadata` when it takes longer than this, like when fetching the index on a slow network
+    pub timeout: Option<Duration>,
+
+    /// Settings to pass to cargo with `--config`, which override those of the cargo config files
+    pub config: Vec<ConfigOverride>,
+
+    /// Features to turn on for the package, like cargo's `--features`
+    pub features: Vec<String>,
+
+    /// Leave the default features of the package off, like cargo's `--no-default-features`
+    pub no_default_features: bool,
+
+    /// Keep the output of `cargo metadata` in `nbuild-cache` inside the target directory, and reuse it while the lock
+    /// file, the local manifests and targets, and these options stay the same
+    pub cache: bool,
+
+    /// Keep the cached metadata in this directory instead of the target directory
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl MetadataOptions {
+    /// The extra flags to pass to `cargo metadata`
+    fn cargo_flags(&self) -> Vec<String> {
+        [
+            (self.frozen, "--frozen"),
+            (self.locked, "--locked"),
+            (self.offline, "--offline"),
+            (self.no_default_features, "--no-default-features"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, flag)| flag.to_string())
+        .chain(
+            (!self.features.is_empty())
+                .then(|| ["--features".to_string(), self.features.join(",")])
+                .into_iter()
+                .flatten(),
+        )
+        .chain(self.config.iter().flat_map(|config| {
+            [
+                "--config".to_string(),
+                format!("{}={}", config.key, config.value),
+            ]
+        }))
+        .collect()
+    }
+
+    /// Get the `target` when it differs from the current platform, which means the package is cross-compiled
+    pub fn cross_target(&self) -> Result<Option<&str>, Error> {
+        let Some(target) = self.target.as_deref() else {
+            return Ok(None);
+        };
+        let host = current_platform()?;
+
+        Ok(Some(target).filter(|target| *target != host.triple_str()))
+    }
+}
+
+/// Get the platform of the host, with an error which tells how to go on when target-spec does not know it
+pub(crate) fn current_platform() -> Result<Platform, Error> {
+    host_platform(Platform::current())
+}
+
+/// Turn an unknown host `platform` into an [Error::UnknownHostPlatform]
+fn host_platform(platform: Result<Platform, target_spec::Error>) -> Result<Platform, Error> {
+    platform.map_err(|error| match error {
+        target_spec::Error::UnknownPlatformTriple(error) => {
+            Error::UnknownHostPlatform(error.triple_str().to_string())
+        }
+        error => error.into(),
+    })
+}
+
+/// A crate to swap for another crates.io version after resolving, like to apply a security fix without editing every
+/// manifest. The dependencies and features of the resolved version are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replacement {
+    pub name: String,
+    pub from: Version,
+    pub to: Version,
+
+    /// The sha256 of the new version's `.crate` file. It is read from the local cargo cache when it is not given.
+    pub checksum: Option<String>,
+}
+
+/// Parse a replacement from `name@version=version`, with an optional `:sha256` at the end
+impl FromStr for Replacement {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidReplacement(s.to_string());
+
+        let (from, to) = s.split_once('=').ok_or_else(invalid)?;
+        let (name, from) = from.split_once('@').ok_or_else(invalid)?;
+        let (to, checksum) = match to.split_once(':') {
+            Some((to, checksum)) => (to, Some(checksum.to_string())),
+            None => (to, None),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            from: from.parse().map_err(|_| invalid())?,
+            to: to.parse().map_err(|_| invalid())?,
+            checksum,
+        })
+    }
+}
+
+/// A feature to turn on for a crate after resolving, whether or not anything in the graph asks for it. This is an escape
+/// hatch for crates which need a feature cargo would not enable, so the build can end up different from `cargo build`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForcedFeature {
+    pub name: String,
+    pub feature: String,
+}
+
+/// Parse a forced feature from `crate/feature`
+impl FromStr for ForcedFeature {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((name, feature)) if !name.is_empty() && !feature.is_empty() => Ok(Self {
+                name: name.to_string(),
+                feature: feature.to_string(),
+            }),
+            _ => Err(Error::InvalidForcedFeature(s.to_string())),
+        }
+    }
+}
+
+/// A cargo setting to override while reading the metadata, like `net.git-fetch-with-cli=true`. The value is TOML, so
+/// strings need to be quoted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOverride {
+    pub key: String,
+    pub value: String,
+}
+
+/// Parse a config override from `key=value`
+impl FromStr for ConfigOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => Ok(Self {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            _ => Err(Error::InvalidCargoConfig(s.to_string())),
+        }
+    }
+}
+
+/// A crate published on crates.io, to build without a local checkout of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrateSpec {
+    pub name: String,
+    pub version: Version,
+}
+
+/// Parse a published crate from `name@version`
+impl FromStr for CrateSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('@') {
+            Some((name, version)) if !name.is_empty() => Ok(Self {
+                name: name.to_string(),
+                version: version
+                    .parse()
+                    .map_err(|_| Error::InvalidCrateSpec(s.to_string()))?,
+            }),
+            _ => Err(Error::InvalidCrateSpec(s.to_string())),
+        }
+    }
+}
+
+impl Replacement {
+    /// Get the checksum of the new version, either the given one or from its `.crate` file in one of `cache_dirs`
+    fn checksum_in(&self, cache_dirs: &[PathBuf]) -> Result<String, Error> {
+        if let Some(checksum) = &self.checksum {
+            return Ok(checksum.clone());
+        }
+
+        let file_name = format!("{}-{}.crate", self.name, self.to);
+
+        match cache_dirs
+            .iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file())
+        {
+            Some(path) => Ok(format!("{:x}", Sha256::digest(fs::read(path)?))),
+            None => Err(Error::MissingChecksum {
+                name: self.name.clone(),
+                version: self.to.to_string(),
+            }),
+        }
+    }
 }
 
 impl Package {
     /// Get a package from a path with a `Cargo.toml` file
     pub fn from_current_dir(path: impl Into<PathBuf>) -> Result<Self, Error> {
-        let platform = Platform::current()?;
+        Self::from_current_dir_with_options(path, &Default::default())
+    }
 
-        let metadata = MetadataCommand::new()
-            .current_dir(path)
-            .other_options(vec![
-                "--filter-platform".to_string(),
-                platform.triple_str().to_string(),
-            ])
-            .exec()?;
-        let lock_file = metadata.workspace_root.join("Cargo.lock");
-        let lock_file = Lockfile::load(lock_file)?;
+    /// Get a package from a path with a `Cargo.toml` file using custom options
+    pub fn from_current_dir_with_options(
+        path: impl Into<PathBuf>,
+        options: &MetadataOptions,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let mut command = MetadataCommand::new();
+        command.current_dir(&path);
+
+        Self::from_metadata_command(command, &path, options)
+    }
+
+    /// Get a package published on crates.io. Cargo resolves it as the only dependency of a throwaway package in the
+    /// temp directory, which needs the network unless the crates are in the cargo cache. The `features` of the
+    /// `options` are turned on for the published crate.
+    pub fn from_crates_io_with_options(
+        spec: &CrateSpec,
+        options: &MetadataOptions,
+    ) -> Result<Self, Error> {
+        let dir = env::temp_dir().join(format!(
+            "nbuild-{}-{}-{}",
+            spec.name,
+            spec.version,
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("src"))?;
+        fs::write(dir.join("src").join("lib.rs"), "")?;
+        fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                r#"[package]
+name = "nbuild-published-crate"
+version = "0.0.0"
+edition = "2021"
+rust-version = "1.68"
+
+[workspace]
+
+[dependencies]
+{} = {{ version = "={}", default-features = {}, features = {:?} }}
+"#,
+                spec.name, spec.version, !options.no_default_features, options.features
+            ),
+        )?;
+
+        // The old `rust-version` keeps cargo writing a lock file in the version 3 format, which is the newest one that
+        // can be read. The features are asked for on the dependency, so the throwaway package itself has none.
+        let wrapper = Self::from_current_dir_with_options(
+            &dir,
+            &MetadataOptions {
+                features: Default::default(),
+                no_default_features: false,
+                lock_file: None,
+                cache: false,
+                ..options.clone()
+            },
+        );
+        fs::remove_dir_all(&dir)?;
+
+        let mut package = wrapper?
+            .dependencies
+            .iter()
+            .find(|d| d.package.borrow().name == spec.name)
+            .expect("the published crate to be a dependency")
+            .package
+            .borrow()
+            .clone();
+        package.enable_features(&options.features, !options.no_default_features)?;
+
+        Ok(package)
+    }
+
+    /// Get a package from the path to its `Cargo.toml` file, like cargo's `--manifest-path`
+    pub fn from_manifest_path(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::from_manifest_path_with_options(path, &Default::default())
+    }
+
+    /// Get a package from the path to its `Cargo.toml` file using custom options
+    pub fn from_manifest_path_with_options(
+        path: impl Into<PathBuf>,
+        options: &MetadataOptions,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut command = MetadataCommand::new();
+        command.manifest_path(path);
 
-        trace!(?platform, ?metadata, ?lock_file, "have metadata");
+        Self::from_metadata_command(command, &dir, options)
+    }
+
+    /// Get a package using the metadata from `command`, which runs in `dir`. The lock file is read from the workspace
+    /// root, or the nearest directory above it with one, unless another one is set in the `options`.
+    fn from_metadata_command(
+        command: MetadataCommand,
+        dir: &Path,
+        options: &MetadataOptions,
+    ) -> Result<Self, Error> {
+        let (metadata, platforms, host_platforms) = read_metadata(command, dir, options)?;
+        let lock_file = load_lock_file(
+            options
+                .lock_file
+                .clone()
+                .unwrap_or_else(|| workspace_lock_file(metadata.workspace_root.as_std_path())),
+        )?;
 
-        let packages =
-            BTreeMap::from_iter(metadata.packages.iter().map(|p| (p.id.clone(), p.clone())));
+        trace!(?platforms, ?metadata, ?lock_file, "have metadata");
+
+        let packages = BTreeMap::from_iter(metadata.packages.iter().map(|p| (&p.id, p)));
         let nodes = BTreeMap::from_iter(
             metadata
                 .resolve
@@ -84,13 +456,20 @@ impl Package {
                 .expect("metadata to have a resolve section")
                 .nodes
                 .iter()
-                .map(|n| (n.id.clone(), n.clone())),
+                .map(|n| (&n.id, n)),
         );
+        // The same crate name and version can come from more than one registry, so the source is part of the key
         let checksums = BTreeMap::from_iter(lock_file.packages.iter().filter_map(|p| {
+            let source = p.source.as_ref()?.to_string();
+
             p.checksum.as_ref().map(|checksum| {
                 (
-                    (p.name.to_string(), p.version.to_string()),
-                    checksum.to_string(),
+                    (
+                        p.name.as_str(),
+                        &p.version,
+                        source.trim_end_matches('/').to_string(),
+                    ),
+                    checksum,
                 )
             })
         }));
@@ -101,161 +480,442 @@ impl Package {
             .expect("metadata to have a resolve section")
             .root
             .as_ref()
-            .expect("a root from metadata")
-            .clone();
+            .expect("a root from metadata");
+
+        let resolver = Resolver::from_manifest(metadata.workspace_root.join("Cargo.toml"))?;
+
+        trace!(?resolver, "using feature resolver");
+
+        let mut vendor_dirs = vendor_dirs(metadata.workspace_root.as_std_path())?;
+        vendor_dirs.extend(
+            options
+                .vendor_dirs
+                .iter()
+                .map(|dir| fs::canonicalize(dir).unwrap_or_else(|_| dir.clone())),
+        );
+
+        trace!(?vendor_dirs, "using vendored crates");
+
+        let context = Context {
+            packages: &packages,
+            nodes: &nodes,
+            checksums: &checksums,
+            platforms: &platforms,
+            vendor_dirs: &vendor_dirs,
+        };
+        let host_context = Context {
+            platforms: &host_platforms,
+            ..context
+        };
+        let cross = !host_platforms.is_empty();
 
+        // The graph is made of `Rc`s which cannot cross threads. So first prepare everything about each package in
+        // parallel and only link them together at the end. A cross-compiled package is prepared a second time for
+        // the host, since its dependencies can differ between the platforms.
+        let prepared = Prepared {
+            packages: nodes
+                .par_iter()
+                .flat_map(|(id, _)| {
+                    let host = cross.then(|| {
+                        PreparedPackage::new(id, &host_context)
+                            .map(|package| ((*id, true), package))
+                    });
+
+                    [
+                        Some(
+                            PreparedPackage::new(id, &context)
+                                .map(|package| ((*id, false), package)),
+                        ),
+                        host,
+                    ]
+                })
+                .flatten()
+                .collect::<Result<_, _>>()?,
+            resolver,
+            cross,
+        };
         let mut resolved_packages = Default::default();
 
-        Ok(Self::get_package(
+        let mut package = Self::get_package(
             root_id,
-            &packages,
-            &nodes,
-            &checksums,
+            false,
+            &prepared,
             &mut resolved_packages,
-            &platform,
-        ))
+            &mut Vec::new(),
+        )?;
+        package.enable_features(&options.features, !options.no_default_features)?;
+
+        Ok(package)
     }
 
     /// Recursively get a package and its dependencies. Use the `resolved_packages` to make sure we only
-    /// have one reverence to re-occuring packages.
-    fn get_package(
-        id: PackageId,
-        packages: &BTreeMap<PackageId, cargo_metadata::Package>,
-        nodes: &BTreeMap<PackageId, cargo_metadata::Node>,
-        checksums: &BTreeMap<(String, String), String>,
-        resolved_packages: &mut BTreeMap<PackageId, Rc<RefCell<Package>>>,
-        platform: &Platform,
-    ) -> Self {
-        let node = nodes.get(&id).expect("node to exist").clone();
-        let package = packages.get(&id).expect("package to exist");
+    /// have one reverence to re-occuring packages. The `host` flag is set for packages which are only built for the
+    /// host as part of a build dependency, so that the v2 resolver can keep their features separate. The `path` holds
+    /// the packages which are still being linked, so that a package which depends on itself is caught instead of
+    /// recursing forever.
+    fn get_package<'a>(
+        id: &'a PackageId,
+        host: bool,
+        prepared: &Prepared<'a>,
+        resolved_packages: &mut BTreeMap<(&'a PackageId, bool), Rc<RefCell<Package>>>,
+        path: &mut Vec<(&'a PackageId, bool)>,
+    ) -> Result<Self, Error> {
+        path.push((id, host));
 
-        trace!(
-            package.name,
-            ?package.features,
-            ?node,
-            "found package and node"
-        );
+        let mut package = prepared
+            .packages
+            .get(&(id, false))
+            .expect("package to be prepared");
 
-        let features = package.features.clone();
-        let package_dependencies: Vec<_> = package
-            .dependencies
-            .iter()
-            .filter(|d| d.kind == DependencyKind::Normal)
-            .cloned()
-            .collect();
-        let package_build_dependencies: Vec<_> = package
-            .dependencies
-            .iter()
-            .filter(|d| d.kind == DependencyKind::Build)
-            .cloned()
-            .collect();
+        // A proc-macro runs inside the compiler, so it is always built for the host
+        if prepared.cross && (host || package.proc_macro) {
+            package = prepared
+                .packages
+                .get(&(id, true))
+                .expect("package to be prepared for the host");
+        }
+
+        // The v2 resolver does not unify the features of build dependencies (and proc-macros) with the features of
+        // normal dependencies. A cross-compiled package always builds them apart since they are for another platform.
+        // https://doc.rust-lang.org/cargo/reference/resolver.html#feature-resolver-version-2
+        let separate_host = prepared.resolver == Resolver::V2 || prepared.cross;
 
-        let dependencies = node
+        let dependencies = package
             .dependencies
             .iter()
-            .filter_map(|id| {
+            .map(|dependency| {
                 Dependency::get_dependency(
-                    id,
-                    &package_dependencies,
-                    packages,
-                    nodes,
-                    checksums,
+                    dependency,
+                    host || (separate_host && package.proc_macro),
+                    prepared,
                     resolved_packages,
-                    platform,
+                    path,
                 )
             })
-            .collect();
-        let build_dependencies = node
-            .dependencies
+            .collect::<Result<_, _>>()?;
+        let build_dependencies = package
+            .build_dependencies
             .iter()
-            .filter_map(|id| {
+            .map(|dependency| {
                 Dependency::get_dependency(
-                    id,
-                    &package_build_dependencies,
-                    packages,
-                    nodes,
-                    checksums,
+                    dependency,
+                    host || separate_host,
+                    prepared,
                     resolved_packages,
-                    platform,
-                )
-            })
-            .collect();
-
-        // Safe to unwrap since the manifest has to be in some directory
-        let package_path: PathBuf = package.manifest_path.parent().unwrap().into();
-
-        let (lib_path, lib_name) = package
-            .targets
-            .iter()
-            .find(|t| {
-                t.kind.iter().any(|k| {
-                    matches!(
-                        k.as_str(),
-                        "lib" | "cdylib" | "dylib" | "rlib" | "proc-macro"
-                    )
-                })
-            })
-            .map(|t| {
-                (
-                    t.src_path
-                        .strip_prefix(&package_path)
-                        .unwrap() // Safe to unwrap since the src has to be in the package path
-                        .to_path_buf(),
-                    t.name.clone(),
+                    path,
                 )
             })
-            .unzip();
-        let build_path = package
-            .targets
-            .iter()
-            .find(|t| t.kind.iter().any(|k| k == "custom-build"))
-            .map(|t| {
-                t.src_path
-                    .strip_prefix(&package_path)
-                    .unwrap() // Safe to unwrap since the src has to be in the package path
-                    .to_path_buf()
-            });
-        let proc_macro = package
-            .targets
-            .iter()
-            .any(|t| t.kind.iter().any(|k| k == "proc-macro"));
+            .collect::<Result<_, _>>()?;
 
-        let source = if package.source.is_some() {
-            let checksum = checksums
-                .get(&(package.name.to_string(), package.version.to_string()))
-                .expect("to have a checksum");
-            Source::CratesIo(checksum.to_string())
-        } else {
-            Source::Local(package_path)
-        };
+        path.pop();
 
-        Self {
+        Ok(Self {
             name: package.name.clone(),
             version: package.version.clone(),
-            source,
-            lib_name,
-            lib_path,
-            build_path,
-            proc_macro,
+            source: package.source.clone(),
+            lib_name: package.lib_name.clone(),
+            lib_path: package.lib_path.clone(),
+            build_path: package.build_path.clone(),
+            proc_macro: package.proc_macro,
+            crate_types: package.crate_types.clone(),
+            bins: package.bins.clone(),
+            examples: package.examples.clone(),
             dependencies,
             build_dependencies,
-            features,
+            features: package.features.clone(),
             enabled_features: Default::default(),
-            edition: package.edition.to_string(),
+            edition: package.edition.clone(),
+            rust_version: package.rust_version.clone(),
+            links: package.links.clone(),
+            dropped: package.dropped.clone(),
+        })
+    }
+
+    /// Turn on `features` of this package, and its default features when `default_features` is set, like cargo's
+    /// `--features` and `--no-default-features` do. A `dependency/feature` turns on the feature of a dependency, and
+    /// the dependency itself when it is optional, while `dependency?/feature` leaves an optional dependency off. This
+    /// is done for the top level package when it is read, and should be followed by [Package::resolve]. Fails when the
+    /// package does not have one of the features or dependencies.
+    pub fn enable_features(
+        &mut self,
+        features: &[String],
+        default_features: bool,
+    ) -> Result<(), Error> {
+        for feature in features {
+            if let Some((dependency_name, dependency_feature)) = feature.split_once('/') {
+                self.enable_dependency_feature(dependency_name, dependency_feature)
+                    .ok_or_else(|| Error::UnknownFeature {
+                        name: self.name.clone(),
+                        feature: feature.clone(),
+                    })?;
+
+                continue;
+            }
+
+            if !self.features.contains_key(feature) {
+                return Err(Error::UnknownFeature {
+                    name: self.name.clone(),
+                    feature: feature.clone(),
+                });
+            }
+
+            self.enabled_features.insert(feature.clone());
+        }
+
+        if default_features && self.features.contains_key("default") {
+            self.enabled_features.insert("default".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Turn on `feature` on the edges to the dependency named `dependency_name`, which are activated unless the name
+    /// ends with a `?`. The features of the edges are passed on to the dependency by [Package::resolve]. Returns
+    /// `None` when there is no such dependency.
+    fn enable_dependency_feature(&mut self, dependency_name: &str, feature: &str) -> Option<()> {
+        let (dependency_name, weak) = match dependency_name.strip_suffix('?') {
+            Some(dependency_name) => (dependency_name, true),
+            None => (dependency_name, false),
+        };
+        let mut found = false;
+
+        for dependency in self
+            .dependencies
+            .iter_mut()
+            .chain(self.build_dependencies.iter_mut())
+            .filter(|d| d.name == dependency_name)
+        {
+            if !dependency.features.iter().any(|f| f == feature) {
+                dependency.features.push(feature.to_string());
+            }
+
+            if !weak {
+                dependency.optional = false;
+            }
+
+            found = true;
+        }
+
+        // An optional dependency can have a feature of the same name, which other features check for
+        if found && !weak && self.features.contains_key(dependency_name) {
+            self.enabled_features.insert(dependency_name.to_string());
         }
+
+        found.then_some(())
     }
 
     /// Resolve all the optional dependencies and enabled features of a package. This is done recursively and only
-    /// needed on the top level package.
+    /// needed on the top level package. Resolving a package again leaves it as it is, since every step only adds what
+    /// is not there yet.
     pub fn resolve(&mut self) {
         self.visit(&mut visitor::ResolveVisitor);
     }
 
+    /// Warn about the crates which have their default features turned on even though some of their parents set
+    /// `default-features = false`. Features are unified across all the parents of a crate, like cargo does, so this
+    /// only explains the result and does not change it. Should be called after [Package::resolve].
+    pub fn warn_unified_defaults(&mut self) {
+        let mut visitor = visitor::UnifiedDefaultsVisitor::default();
+        self.visit(&mut visitor);
+
+        for ((name, version), with, without) in visitor.unified() {
+            warn!(
+                name,
+                %version,
+                enabled_by = ?with,
+                disabled_by = ?without,
+                "default features are on because another crate uses them, even though some crates turned them off"
+            );
+        }
+    }
+
+    /// Check that the `toolchain` is new enough for every crate in the graph. Fails with the crate having the highest
+    /// `rust-version` when it is newer than the toolchain. Should be called after [Package::resolve] so that only
+    /// the enabled optional dependencies are checked.
+    pub fn check_msrv(&mut self, toolchain: &Version) -> Result<(), Error> {
+        let mut visitor = visitor::MsrvVisitor::default();
+        self.visit(&mut visitor);
+
+        match visitor.highest {
+            Some((name, rust_version)) if &rust_version > toolchain => Err(Error::Msrv {
+                name,
+                rust_version: rust_version.to_string(),
+                toolchain: toolchain.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop the build dependencies of every crate, which leaves the crates needed at runtime. Should be called before
+    /// [Package::resolve] so that the features asked for by build dependencies are left out too. The derivation of
+    /// the result cannot build crates with build scripts, so this is only meant for inspecting the graph.
+    pub fn prune_build_dependencies(&mut self) {
+        self.visit(&mut visitor::PruneBuildVisitor);
+    }
+
+    /// Swap the crates matching the `replacements` for their new crates.io version. Should be called after
+    /// [Package::resolve]. Fails when a replacement does not match any crate, so that a fix is never silently skipped.
+    pub fn replace(&mut self, replacements: &[Replacement]) -> Result<(), Error> {
+        self.replace_in(replacements, &crate_cache_dirs())
+    }
+
+    /// Turn on the `features` for every crate with a matching name, and resolve again so that the features they imply
+    /// are turned on too. Should be called after [Package::resolve]. Fails when no crate has one of the features.
+    pub fn force_features(&mut self, features: &[ForcedFeature]) -> Result<(), Error> {
+        let mut visitor = visitor::ForceFeatureVisitor {
+            features: features
+                .iter()
+                .map(|f| (f.name.clone(), f.feature.clone()))
+                .collect(),
+            forced: Default::default(),
+        };
+        self.visit(&mut visitor);
+
+        if let Some((name, feature)) = visitor
+            .features
+            .iter()
+            .find(|key| !visitor.forced.contains(*key))
+        {
+            return Err(Error::UnusedForcedFeature(format!("{name}/{feature}")));
+        }
+
+        self.resolve();
+
+        Ok(())
+    }
+
+    /// Swap the crates, reading missing checksums from the `.crate` files in `cache_dirs`
+    fn replace_in(
+        &mut self,
+        replacements: &[Replacement],
+        cache_dirs: &[PathBuf],
+    ) -> Result<(), Error> {
+        let replacements = replacements
+            .iter()
+            .map(|r| {
+                Ok((
+                    (r.name.clone(), r.from.clone()),
+                    (r.to.clone(), r.checksum_in(cache_dirs)?),
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let mut visitor = visitor::ReplaceVisitor {
+            replacements,
+            replaced: Default::default(),
+        };
+        self.visit(&mut visitor);
+
+        match visitor
+            .replacements
+            .keys()
+            .find(|key| !visitor.replaced.contains(*key))
+        {
+            Some((name, version)) => Err(Error::UnusedReplacement(format!("{name}@{version}"))),
+            None => Ok(()),
+        }
+    }
+
+    /// The name of the crate
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The version of the crate
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// The features which are enabled on this crate. Only complete after [Package::resolve].
+    pub fn enabled_features(&self) -> &HashSet<String> {
+        &self.enabled_features
+    }
+
+    /// Get the enabled features of every crate named `name` in the dependency tree, including this package itself.
+    /// A crate can be in the tree with more than one version, so the features are given for each version. The features
+    /// of a crate which is built apart for build scripts and proc-macros are merged with its other build. Optional
+    /// dependencies which were not turned on are left out.
+    pub fn enabled_features_of(&self, name: &str) -> BTreeMap<Version, HashSet<String>> {
+        let mut found = BTreeMap::new();
+        let mut seen = HashSet::new();
+
+        self.find_enabled_features(name, &mut seen, &mut found);
+
+        found
+    }
+
+    /// Recursively add the features of the crates named `name`. The `seen` set holds the addresses of the visited
+    /// packages, so that shared packages are only processed once while the host builds of a crate are still found.
+    fn find_enabled_features(
+        &self,
+        name: &str,
+        seen: &mut HashSet<*const Package>,
+        found: &mut BTreeMap<Version, HashSet<String>>,
+    ) {
+        if !seen.insert(self) {
+            return;
+        }
+
+        if self.name == name {
+            found
+                .entry(self.version.clone())
+                .or_default()
+                .extend(self.enabled_features.iter().cloned());
+        }
+
+        for dependency in self.dependencies_iter().filter(|d| !d.optional) {
+            dependency
+                .package
+                .borrow()
+                .find_enabled_features(name, seen, found);
+        }
+    }
+
     /// Helper to call visitor easier.
     fn visit(&mut self, visitor: &mut impl visitor::Visitor) {
         visitor.visit(self);
     }
 
+    /// Run a custom `visitor` over the package graph, like to ban a crate with [Package::retain_dependencies] before
+    /// the package is turned into a derivation.
+    ///
+    /// The traversal is depth first. A package is visited before its dependencies, and each dependency is visited right
+    /// before its own package. The normal dependencies come first, in the order of the graph, followed by the build
+    /// dependencies. Optional dependencies which are not turned on are skipped along with everything below them, so
+    /// the visitor should run after [Package::resolve] to see the whole build. A crate which is a dependency of more
+    /// than one package is visited once for each of them.
+    pub fn accept(&mut self, mut visitor: &mut dyn Visitor) {
+        self.visit(&mut visitor);
+    }
+
+    /// Call `f` once for every package in the graph, starting with this one and going depth first through the
+    /// dependencies which are turned on, the normal ones before the build ones. A package shared by more than one
+    /// dependent is only passed once, while the host and target variants of a crate which the v2 resolver split are
+    /// passed apart, since they are separate packages.
+    pub(super) fn for_each_package(&self, mut f: impl FnMut(&Package)) {
+        fn walk(
+            package: &Package,
+            seen: &mut HashSet<*const RefCell<Package>>,
+            f: &mut impl FnMut(&Package),
+        ) {
+            f(package);
+
+            for dependency in package.dependencies_iter().filter(|d| !d.optional) {
+                if seen.insert(Rc::as_ptr(&dependency.package)) {
+                    walk(&dependency.package.borrow(), seen, f);
+                }
+            }
+        }
+
+        walk(self, &mut HashSet::new(), &mut f);
+    }
+
+    /// Only keep the normal and build dependencies for which `keep` returns `true`
+    pub fn retain_dependencies(&mut self, mut keep: impl FnMut(&Dependency) -> bool) {
+        self.dependencies.retain(&mut keep);
+        self.build_dependencies.retain(keep);
+    }
+
     /// Get an iter for all the dependencies of a package. This is both normal dependencies and build dependencies.
     pub fn dependencies_iter(&self) -> impl Iterator<Item = &Dependency> {
         self.dependencies
@@ -272,57 +932,337 @@ impl Package {
 }
 
 impl Dependency {
+    /// The name of the dependency in the manifest of its parent, which differs from the crate name when it is renamed
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The package of the dependency
+    pub fn package(&self) -> std::cell::Ref<'_, Package> {
+        self.package.borrow()
+    }
+
+    /// Whether the dependency is optional and not turned on by any feature
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+
     /// Recursively get a dependency and its package. Use the `resolved_packages` to make sure we only
     /// have one reverence to re-occuring packages - this is needed during feature resolution
-    #[instrument(skip_all, fields(%id))]
-    fn get_dependency(
-        id: &PackageId,
-        parent_dependencies: &[cargo_metadata::Dependency],
-        packages: &BTreeMap<PackageId, cargo_metadata::Package>,
-        nodes: &BTreeMap<PackageId, cargo_metadata::Node>,
-        checksums: &BTreeMap<(String, String), String>,
-        resolved_packages: &mut BTreeMap<PackageId, Rc<RefCell<Package>>>,
-        platform: &Platform,
-    ) -> Option<Self> {
-        let package = match resolved_packages.get(id) {
-            Some(package) => Rc::clone(package),
-            None => {
+    #[instrument(skip_all, fields(id = %dependency.id, host))]
+    fn get_dependency<'a>(
+        dependency: &PreparedDependency<'a>,
+        host: bool,
+        prepared: &Prepared<'a>,
+        resolved_packages: &mut BTreeMap<(&'a PackageId, bool), Rc<RefCell<Package>>>,
+        path: &mut Vec<(&'a PackageId, bool)>,
+    ) -> Result<Self, Error> {
+        let key = (dependency.id, host);
+
+        if let Some(start) = path.iter().position(|k| *k == key) {
+            let cycle: Vec<_> = path[start..]
+                .iter()
+                .chain([&key])
+                .map(|key| {
+                    let package = prepared.packages.get(key).expect("package to be prepared");
+
+                    format!("{} {}", package.name, package.version)
+                })
+                .collect();
+
+            return Err(Error::DependencyCycle(cycle.join(" -> ")));
+        }
+
+        let package = match resolved_packages.get(&key) {
+            Some(package) => Rc::clone(package),
+            None => {
                 let package = RefCell::new(Package::get_package(
-                    id.clone(),
-                    packages,
-                    nodes,
-                    checksums,
+                    dependency.id,
+                    host,
+                    prepared,
                     resolved_packages,
-                    platform,
-                ))
+                    path,
+                )?)
                 .into();
 
-                resolved_packages.insert(id.clone(), Rc::clone(&package));
+                resolved_packages.insert(key, Rc::clone(&package));
 
                 package
             }
         };
 
+        Ok(Self {
+            name: dependency.name.clone(),
+            package,
+            optional: dependency.optional,
+            uses_default_features: dependency.uses_default_features,
+            features: dependency.features.clone(),
+            platforms: dependency.platforms.clone(),
+        })
+    }
+}
+
+/// Lookups which are shared by the whole traversal over the cargo metadata
+struct Context<'a> {
+    packages: &'a BTreeMap<&'a PackageId, &'a cargo_metadata::Package>,
+    nodes: &'a BTreeMap<&'a PackageId, &'a cargo_metadata::Node>,
+    checksums: &'a BTreeMap<(&'a str, &'a Version, String), &'a Checksum>,
+    platforms: &'a [Platform],
+    vendor_dirs: &'a [PathBuf],
+}
+
+/// All the prepared packages which still need to be linked into a graph. They are keyed by whether they were
+/// prepared for the host of a cross-compiled package.
+struct Prepared<'a> {
+    packages: BTreeMap<(&'a PackageId, bool), PreparedPackage<'a>>,
+    resolver: Resolver,
+    cross: bool,
+}
+
+/// The parts of a [Package] which are the same wherever it appears in the graph
+struct PreparedPackage<'a> {
+    name: String,
+    version: Version,
+    source: Source,
+    lib_name: Option<String>,
+    lib_path: Option<Utf8PathBuf>,
+    build_path: Option<Utf8PathBuf>,
+    proc_macro: bool,
+    crate_types: Vec<String>,
+    bins: Vec<Bin>,
+    examples: Vec<Bin>,
+    features: HashMap<String, Vec<String>>,
+    edition: String,
+    rust_version: Option<Version>,
+    links: Option<String>,
+    dependencies: Vec<PreparedDependency<'a>>,
+    build_dependencies: Vec<PreparedDependency<'a>>,
+    dropped: Vec<DroppedDependency>,
+}
+
+impl<'a> PreparedPackage<'a> {
+    /// Read the details of a package from its metadata and find which of its dependencies are active on this
+    /// platform. Fails when the lock file has no checksum for a registry crate, which means it does not match the
+    /// metadata.
+    #[instrument(skip_all, fields(%id))]
+    fn new(id: &PackageId, context: &Context<'a>) -> Result<Self, Error> {
+        let node = context.nodes.get(id).expect("node to exist");
+        let package = context.packages.get(id).expect("package to exist");
+
+        trace!(
+            package.name,
+            ?package.features,
+            ?node,
+            "found package and node"
+        );
+
+        let package_dependencies: Vec<_> = package
+            .dependencies
+            .iter()
+            .filter(|d| d.kind == DependencyKind::Normal)
+            .collect();
+        let package_build_dependencies: Vec<_> = package
+            .dependencies
+            .iter()
+            .filter(|d| d.kind == DependencyKind::Build)
+            .collect();
+
+        let drop = |name: &str, reason| DroppedDependency {
+            parent: package.name.clone(),
+            parent_version: package.version.to_string(),
+            name: name.to_string(),
+            reason,
+        };
+        let mut dropped: Vec<_> = package_dependencies
+            .iter()
+            .chain(&package_build_dependencies)
+            .filter_map(|dependency| {
+                let target = dependency.target.as_ref()?;
+
+                active_platforms(dependency, context.platforms)
+                    .is_empty()
+                    .then(|| {
+                        drop(
+                            &dependency.name,
+                            DropReason::Platform {
+                                kind: dependency.kind,
+                                target: target.to_string(),
+                            },
+                        )
+                    })
+            })
+            .collect();
+        let mut dependencies = Vec::new();
+        let mut build_dependencies = Vec::new();
+
+        for id in &node.dependencies {
+            let dependency = PreparedDependency::new(id, &package_dependencies, context);
+            let build_dependency =
+                PreparedDependency::new(id, &package_build_dependencies, context);
+
+            // Cargo keeps every kind of a dependency in the graph, so one which is neither kept as a normal nor a
+            // build dependency is only needed for the tests, unless it was already dropped for its platforms
+            if dependency.is_none() && build_dependency.is_none() {
+                let name = &context.packages.get(id).expect("package to exist").name;
+
+                if !dropped.iter().any(|d| &d.name == name) {
+                    dropped.push(drop(name, DropReason::DevOnly));
+                }
+            }
+
+            dependencies.extend(dependency);
+            build_dependencies.extend(build_dependency);
+        }
+
+        let proc_macro = package
+            .targets
+            .iter()
+            .any(|t| t.kind.iter().any(|k| k == "proc-macro"));
+
+        // Safe to unwrap since the manifest has to be in some directory
+        let package_dir = package.manifest_path.parent().unwrap();
+        let package_path: PathBuf = package_dir.into();
+
+        // Every target is read as cargo reports it, so explicit `[lib]` and `[[bin]]` sections with unusual paths or
+        // names, and turning off the autodiscovery of targets, are already accounted for
+        let src_path = |target: &cargo_metadata::Target| {
+            let path = relative_target_path(package_dir, &target.src_path);
+
+            if path.starts_with("..") {
+                warn!(
+                    name = package.name,
+                    %path,
+                    "the target is outside of its package, so it is not part of the crate's source"
+                );
+            }
+
+            path
+        };
+        let lib_target = package.targets.iter().find(|t| {
+            t.kind.iter().any(|k| {
+                matches!(
+                    k.as_str(),
+                    "lib" | "cdylib" | "dylib" | "rlib" | "staticlib" | "proc-macro"
+                )
+            })
+        });
+        let crate_types = lib_target
+            .map(|t| t.crate_types.clone())
+            .unwrap_or_default();
+        let (lib_path, lib_name) = lib_target.map(|t| (src_path(t), t.name.clone())).unzip();
+        let targets_of_kind = |kind: &str| {
+            package
+                .targets
+                .iter()
+                .filter(|t| t.kind.iter().any(|k| k == kind))
+                .map(|t| Bin {
+                    name: t.name.clone(),
+                    path: src_path(t),
+                })
+                .collect()
+        };
+        let bins = targets_of_kind("bin");
+        let examples = targets_of_kind("example");
+        let build_path = package
+            .targets
+            .iter()
+            .find(|t| t.kind.iter().any(|k| k == "custom-build"))
+            .map(src_path);
+        // Trust the source cargo resolved to since a `[patch]` section can redirect a crate away from crates.io
+        let source = match &package.source {
+            // Vendored crates still report their original source, which is a git one for vendored git crates
+            Some(_)
+                if context
+                    .vendor_dirs
+                    .iter()
+                    .any(|dir| package_path.starts_with(dir)) =>
+            {
+                Source::Local(package_path)
+            }
+            Some(source) if source.repr.starts_with("git+") => git_source(source, &package_path),
+            Some(source) => {
+                let checksum = context
+                    .checksums
+                    .get(&(
+                        package.name.as_str(),
+                        &package.version,
+                        source.repr.trim_end_matches('/').to_string(),
+                    ))
+                    .ok_or_else(|| {
+                        Error::OutdatedLockFile(format!(
+                            "it has no checksum for {} {} from {}",
+                            package.name, package.version, source
+                        ))
+                    })?
+                    .to_string();
+
+                match registry_index(source) {
+                    Some(index) => Source::Registry {
+                        index,
+                        sha256: checksum,
+                    },
+                    None => Source::CratesIo(checksum),
+                }
+            }
+            None => Source::Local(package_path),
+        };
+
+        Ok(Self {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source,
+            lib_name,
+            lib_path,
+            build_path,
+            proc_macro,
+            crate_types,
+            bins,
+            examples,
+            features: package.features.clone(),
+            edition: package.edition.to_string(),
+            rust_version: package.rust_version.as_ref().and_then(min_version),
+            links: package.links.clone(),
+            dependencies,
+            build_dependencies,
+            dropped,
+        })
+    }
+}
+
+/// A [Dependency] which still needs to be linked to its package
+struct PreparedDependency<'a> {
+    id: &'a PackageId,
+    name: String,
+    optional: bool,
+    uses_default_features: bool,
+    features: Vec<String>,
+    platforms: Vec<String>,
+}
+
+impl<'a> PreparedDependency<'a> {
+    /// Combine all the entries for a dependency in the parent's manifest. Returns `None` when the dependency is not
+    /// part of the `parent_dependencies` kind or not active on this platform.
+    fn new(
+        id: &'a PackageId,
+        parent_dependencies: &[&cargo_metadata::Dependency],
+        context: &Context<'a>,
+    ) -> Option<Self> {
+        let metadata_package = context.packages.get(id).expect("package to exist");
+
         // Handle renames
-        let name = package.borrow().name.clone();
-        let version = package.borrow().version.clone();
+        let name = &metadata_package.name;
+        let version = &metadata_package.version;
 
-        // A dependency may appear more than once because of targets. So only get those that match the current target.
+        // A dependency may appear more than once because of targets. So only get those that match one of the platforms,
+        // and keep track of which platforms they match.
         //
         // https://doc.rust-lang.org/cargo/reference/config.html#target
         let dependencies: Vec<_> = parent_dependencies
             .iter()
-            .filter(|d| d.name == name)
-            .filter(|d| d.req.matches(&version))
-            .filter(|d| match &d.target {
-                Some(target_spec) => {
-                    // Safe to unwrap since cargo would have failed if the target spec was not valid
-                    let target_spec = TargetSpec::new(target_spec.to_string()).unwrap();
-
-                    target_spec.eval(platform).unwrap_or(false)
-                }
-                None => true,
-            })
+            .filter(|d| &d.name == name)
+            .filter(|d| d.req.matches(version))
+            .map(|d| (d, active_platforms(d, context.platforms)))
+            .filter(|(_, platforms)| !platforms.is_empty())
             .collect();
 
         // It could happen that this kind of dependency is not part of the kind passed into this function,
@@ -337,8 +1277,11 @@ impl Dependency {
         let mut features: Vec<String> = Default::default();
         let mut dependency_name: String = Default::default();
         let mut dependency_rename = None;
+        let mut active = HashSet::new();
+
+        for (dependency, platforms) in dependencies {
+            active.extend(platforms);
 
-        for dependency in dependencies {
             if !dependency.optional {
                 optional = false;
             }
@@ -363,32 +1306,445 @@ impl Dependency {
             dependency_name = dependency_rename;
         };
 
+        // Only keep the platforms when the dependency is not used on all of them
+        let platforms = if active.len() == context.platforms.len() {
+            Default::default()
+        } else {
+            context
+                .platforms
+                .iter()
+                .map(|p| p.triple_str())
+                .filter(|triple| active.contains(triple))
+                .map(str::to_string)
+                .collect()
+        };
+
         trace!(
             name,
             dependency_name,
             optional,
             uses_default_features,
             ?features,
+            ?platforms,
             "done with dependency"
         );
 
         Some(Self {
+            id,
             name: dependency_name,
-            package,
             optional,
             uses_default_features,
             features,
+            platforms,
+        })
+    }
+}
+
+/// The [feature resolver][resolver] version used by the workspace
+///
+/// [resolver]: https://doc.rust-lang.org/cargo/reference/resolver.html#resolver-versions
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Resolver {
+    V1,
+    V2,
+}
+
+impl Resolver {
+    /// Read the resolver from the workspace's root manifest. An explicit `resolver` key wins, otherwise the 2021
+    /// edition of a root package implies the v2 resolver. The root package can inherit its edition from the
+    /// `workspace.package` table of the same manifest. Virtual workspaces default to v1.
+    fn from_manifest(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let manifest: toml::Table = toml::from_str(&fs::read_to_string(path)?)?;
+
+        let resolver = manifest
+            .get("workspace")
+            .and_then(|w| w.get("resolver"))
+            .or_else(|| manifest.get("package").and_then(|p| p.get("resolver")))
+            .and_then(|r| r.as_str());
+
+        let edition = manifest
+            .get("package")
+            .and_then(|p| p.get("edition"))
+            .and_then(|edition| match edition.get("workspace") {
+                Some(toml::Value::Boolean(true)) => {
+                    manifest.get("workspace")?.get("package")?.get("edition")
+                }
+                _ => Some(edition),
+            })
+            .and_then(|e| e.as_str());
+
+        match (resolver, edition) {
+            (Some("1"), _) => Ok(Self::V1),
+            (Some(_), _) => Ok(Self::V2),
+            (None, Some("2015" | "2018")) | (None, None) => Ok(Self::V1),
+            (None, Some(_)) => Ok(Self::V2),
+        }
+    }
+}
+
+/// Find the vendor directories from the source replacement configuration. Like cargo, this looks for a
+/// `.cargo/config.toml` (or `.cargo/config`) in the workspace root and all its parents. The `directory` of a source is
+/// relative to the directory containing the `.cargo` directory.
+fn vendor_dirs(workspace_root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut dirs = Vec::new();
+
+    for ancestor in workspace_root.ancestors() {
+        let Some(config) = ["config.toml", "config"]
+            .into_iter()
+            .map(|name| ancestor.join(".cargo").join(name))
+            .find(|path| path.is_file())
+        else {
+            continue;
+        };
+
+        let config: toml::Table = toml::from_str(&fs::read_to_string(config)?)?;
+
+        let directories = config
+            .get("source")
+            .and_then(|s| s.as_table())
+            .into_iter()
+            .flat_map(|sources| sources.values())
+            .filter_map(|source| source.get("directory").and_then(|d| d.as_str()));
+
+        for directory in directories {
+            let directory = ancestor.join(directory);
+
+            dirs.push(fs::canonicalize(&directory).unwrap_or(directory));
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Get the triples of the `platforms` a dependency is active on
+fn active_platforms<'a>(
+    dependency: &cargo_metadata::Dependency,
+    platforms: &'a [Platform],
+) -> Vec<&'a str> {
+    platforms
+        .iter()
+        .filter(|platform| match &dependency.target {
+            Some(target) => target_matches(&dependency.name, &target.to_string(), platform),
+            None => true,
+        })
+        .map(|platform| platform.triple_str())
+        .collect()
+}
+
+/// Check if the `target` of dependency `name` holds on `platform`. A target which cannot be evaluated, like one using
+/// `target_feature` on a platform with unknown features, keeps the dependency since dropping one cargo needs fails the
+/// build, while an extra one only costs build time.
+fn target_matches(name: &str, target: &str, platform: &Platform) -> bool {
+    let matches = match TargetSpec::new(target.to_string()) {
+        Ok(target_spec) => target_spec.eval(platform),
+        Err(error) => {
+            warn!(name, target, %error, "could not parse the target of a dependency");
+            None
+        }
+    };
+
+    matches.unwrap_or_else(|| {
+        warn!(
+            name,
+            target,
+            platform = platform.triple_str(),
+            "could not evaluate the target of a dependency, so keeping it"
+        );
+        true
+    })
+}
+
+/// Run the metadata `command`, which runs in `dir`, for the platforms of the `options`. Gives the metadata with the
+/// platforms to keep the dependencies of, and the platforms of the host when cross-compiling.
+fn read_metadata(
+    mut command: MetadataCommand,
+    dir: &Path,
+    options: &MetadataOptions,
+) -> Result<(Metadata, Vec<Platform>, Vec<Platform>), Error> {
+    let platforms = match &options.target {
+        Some(target) => vec![Platform::new(target.clone(), TargetFeatures::Unknown)?],
+        None if options.platforms.is_empty() => vec![current_platform()?],
+        None => options
+            .platforms
+            .iter()
+            .map(|triple| Platform::new(triple.clone(), TargetFeatures::Unknown))
+            .collect::<Result<_, _>>()?,
+    };
+
+    // The build dependencies of a cross-compiled package are resolved for the platform doing the build
+    let host_platforms = match options.cross_target()? {
+        Some(_) => vec![current_platform()?],
+        None => Default::default(),
+    };
+
+    let mut other_options: Vec<_> = platforms
+        .iter()
+        .chain(host_platforms.iter())
+        .flat_map(|platform| {
+            [
+                "--filter-platform".to_string(),
+                platform.triple_str().to_string(),
+            ]
+        })
+        .collect();
+    other_options.extend(options.cargo_flags());
+
+    let cache = options
+        .cache
+        .then(|| metadata::Cache::new(dir, &other_options, options.cache_dir.as_deref()))
+        .flatten();
+
+    command.other_options(other_options);
+
+    let metadata = match cache {
+        Some(cache) => cache.exec(&command, options.timeout)?,
+        None => metadata::exec(&command, options.timeout)?,
+    };
+
+    Ok((metadata, platforms, host_platforms))
+}
+
+/// Turn the error from cargo about an out of date lock file into a clean error
+fn metadata_error(error: cargo_metadata::Error) -> Error {
+    match error {
+        cargo_metadata::Error::CargoMetadata { stderr }
+            if stderr.contains("needs to be updated") =>
+        {
+            let message = stderr
+                .lines()
+                .find(|line| line.contains("needs to be updated"))
+                .unwrap_or_default();
+
+            Error::OutdatedLockFile(message.trim_start_matches("error: ").to_string())
+        }
+        error => error.into(),
+    }
+}
+
+/// Get the `nbuild` table from the `[package.metadata]` of the package in `dir`, or at `manifest_path` when it is
+/// given. Gives `Null` when the package has no such table. Only the workspace members are read, so this is quick
+/// and does not touch the network.
+pub fn nbuild_metadata(
+    dir: &Path,
+    manifest_path: Option<&Path>,
+    options: &MetadataOptions,
+) -> Result<serde_json::Value, Error> {
+    let mut command = MetadataCommand::new();
+    command.current_dir(dir).no_deps();
+
+    if let Some(manifest_path) = manifest_path {
+        command.manifest_path(manifest_path);
+    }
+
+    command.other_options(options.cargo_flags());
+
+    let metadata = metadata::exec(&command, options.timeout)?;
+
+    // Like cargo, the package is the one with the closest manifest to the starting directory
+    let start = fs::canonicalize(manifest_path.and_then(Path::parent).unwrap_or(dir))?;
+    let package = start.ancestors().find_map(|ancestor| {
+        metadata.packages.iter().find(|package| {
+            package
+                .manifest_path
+                .parent()
+                .and_then(|dir| fs::canonicalize(dir).ok())
+                .is_some_and(|dir| dir == ancestor)
+        })
+    });
+
+    Ok(package
+        .and_then(|package| package.metadata.get("nbuild"))
+        .cloned()
+        .unwrap_or_default())
+}
+
+/// The root and members of a workspace, as returned by [workspace]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workspace {
+    /// The directory with the manifest of the workspace
+    pub root: PathBuf,
+
+    /// The names and manifest paths of the members, sorted by name
+    pub members: Vec<(String, PathBuf)>,
+}
+
+/// Get the workspace of the package in `dir`, or of the one at `manifest_path` when it is given. Like
+/// [nbuild_metadata], only the workspace members are read.
+pub fn workspace(
+    dir: &Path,
+    manifest_path: Option<&Path>,
+    options: &MetadataOptions,
+) -> Result<Workspace, Error> {
+    let mut command = MetadataCommand::new();
+    command.current_dir(dir).no_deps();
+
+    if let Some(manifest_path) = manifest_path {
+        command.manifest_path(manifest_path);
+    }
+
+    command.other_options(options.cargo_flags());
+
+    let metadata = metadata::exec(&command, options.timeout)?;
+    let mut members: Vec<_> = metadata
+        .workspace_packages()
+        .into_iter()
+        .map(|package| {
+            (
+                package.name.to_string(),
+                package.manifest_path.clone().into_std_path_buf(),
+            )
         })
+        .collect();
+
+    members.sort();
+
+    Ok(Workspace {
+        root: metadata.workspace_root.into_std_path_buf(),
+        members,
+    })
+}
+
+/// Get the path of a target relative to the directory of its package. A target outside of it, like one with
+/// `path = "../shared/main.rs"`, gets a path going up with `..` like the one in its manifest.
+fn relative_target_path(package_dir: &Utf8Path, src_path: &Utf8Path) -> Utf8PathBuf {
+    let common = package_dir
+        .components()
+        .zip(src_path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    package_dir
+        .components()
+        .skip(common)
+        .map(|_| "..")
+        .chain(src_path.components().skip(common).map(|c| c.as_str()))
+        .collect()
+}
+
+/// Get the lowest version matching a `rust-version` requirement, since a `rust-version` of `1.60` means `1.60.0`
+fn min_version(req: &VersionReq) -> Option<Version> {
+    req.comparators
+        .first()
+        .map(|c| Version::new(c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0)))
+}
+
+/// Find the lock file of a workspace. It is normally in the workspace root, but a nested workspace can share the lock
+/// file of a super-project above it. The path in the workspace root is given when there is none, to report it missing.
+fn workspace_lock_file(workspace_root: &Path) -> PathBuf {
+    workspace_root
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| workspace_root.join("Cargo.lock"))
+}
+
+/// Load the lock file, with a clear error when it does not exist or is in a format which cannot be read. Newer
+/// versions than [LOCK_FILE_VERSION] are read as that version, since they only change how git and path sources are
+/// written while the checksums stay where they were.
+fn load_lock_file(path: PathBuf) -> Result<Lockfile, Error> {
+    if !path.exists() {
+        return Err(Error::MissingLockFile(path));
+    }
+
+    let contents = fs::read_to_string(&path)?;
+    let error = match contents.parse::<Lockfile>() {
+        Ok(lock_file) => return Ok(lock_file),
+        Err(error) => error,
+    };
+    let Ok(mut table) = toml::from_str::<toml::Table>(&contents) else {
+        return Err(error.into());
+    };
+    let version = lock_file_version(&table);
+
+    trace!(version, %error, "lock file did not parse");
+
+    if version > LOCK_FILE_VERSION {
+        table.insert("version".to_string(), LOCK_FILE_VERSION.into());
+
+        if let Ok(lock_file) = table.to_string().parse() {
+            warn!(
+                version,
+                "reading the lock file as version {LOCK_FILE_VERSION}"
+            );
+
+            return Ok(lock_file);
+        }
+    }
+
+    Err(Error::UnsupportedLockFile {
+        path,
+        version,
+        source: error,
+    })
+}
+
+/// Get the version of a lock file. Only version 3 and newer have it at the top, before that version 1 is told apart
+/// by its `[metadata]` table holding the checksums.
+fn lock_file_version(table: &toml::Table) -> i64 {
+    match table.get("version").and_then(toml::Value::as_integer) {
+        Some(version) => version,
+        None if table.contains_key("metadata") => 1,
+        None => 2,
+    }
+}
+
+/// Get the index of an alternative registry. Returns `None` when the source is crates.io.
+fn registry_index(source: &cargo_metadata::Source) -> Option<String> {
+    if CRATES_IO_INDEXES.contains(&source.repr.as_str()) {
+        return None;
+    }
+
+    Some(source.repr.trim_end_matches('/').to_string())
+}
+
+/// Get the git details of a package. Cargo checks out git dependencies at
+/// `$CARGO_HOME/git/checkouts/<repo>-<hash>/<short rev>/`, so anything after that is the crate's subdirectory.
+fn git_source(source: &cargo_metadata::Source, package_path: &Path) -> Source {
+    // Cargo always records the resolved commit after the `#`
+    let (url, rev) = source
+        .repr
+        .trim_start_matches("git+")
+        .split_once('#')
+        .expect("git source to have a commit");
+    let url = url.split('?').next().unwrap_or(url);
+
+    let components: Vec<_> = package_path.components().collect();
+    let subdir = components
+        .windows(2)
+        .position(|w| w[0].as_os_str() == "git" && w[1].as_os_str() == "checkouts")
+        .map(|index| components[index + 4..].iter().collect::<PathBuf>())
+        .filter(|subdir| subdir.components().next().is_some());
+
+    Source::Git {
+        url: url.to_string(),
+        rev: rev.to_string(),
+        subdir,
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, collections::HashMap, path::PathBuf, str::FromStr};
+    use std::{
+        cell::RefCell,
+        collections::{BTreeMap, HashMap, HashSet},
+        fs,
+        path::PathBuf,
+        str::FromStr,
+    };
 
-    use crate::models::cargo::{Dependency, Package};
+    use crate::models::{
+        cargo::{
+            git_source, host_platform, load_lock_file, metadata_error, registry_index,
+            relative_target_path, target_matches, workspace_lock_file, ConfigOverride, CrateSpec,
+            Dependency, DropReason, DroppedDependency, ForcedFeature, MetadataOptions, Package,
+            Prepared, PreparedDependency, PreparedPackage, Replacement, Resolver, Visitor,
+        },
+        nix, Bin, Source,
+    };
 
+    use cargo_metadata::{DependencyKind, PackageId};
     use pretty_assertions::assert_eq;
+    use target_spec::{Platform, TargetFeatures};
 
     #[test]
     fn simple_package() {
@@ -408,6 +1764,12 @@ mod tests {
                 lib_path: None,
                 build_path: None,
                 proc_macro: false,
+                crate_types: Default::default(),
+                bins: vec![Bin {
+                    name: "simple".to_string(),
+                    path: "src/main.rs".into(),
+                }],
+                examples: Default::default(),
                 version: "0.1.0".parse().unwrap(),
                 dependencies: vec![Dependency {
                     name: "itoa".to_string(),
@@ -420,6 +1782,9 @@ mod tests {
                         lib_path: Some("src/lib.rs".into()),
                         build_path: None,
                         proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: Default::default(),
                         dependencies: Default::default(),
                         build_dependencies: Default::default(),
                         features: HashMap::from([(
@@ -427,12 +1792,16 @@ mod tests {
                             vec!["dep:no-panic".to_string()]
                         )]),
                         enabled_features: Default::default(),
+                        rust_version: Some("1.36.0".parse().unwrap()),
                         edition: "2018".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
                     })
                     .into(),
                     optional: false,
                     uses_default_features: true,
                     features: Default::default(),
+                    platforms: Default::default(),
                 },],
                 build_dependencies: vec![Dependency {
                     name: "arbitrary".to_string(),
@@ -445,6 +1814,12 @@ mod tests {
                         lib_path: Some("src/lib.rs".into()),
                         build_path: None,
                         proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: vec![Bin {
+                            name: "derive_enum".to_string(),
+                            path: "examples/derive_enum.rs".into(),
+                        }],
                         dependencies: Default::default(),
                         build_dependencies: Default::default(),
                         features: HashMap::from([
@@ -455,16 +1830,23 @@ mod tests {
                             ),
                         ]),
                         enabled_features: Default::default(),
+                        rust_version: Some("1.63.0".parse().unwrap()),
                         edition: "2018".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
                     })
                     .into(),
                     optional: false,
                     uses_default_features: true,
                     features: Default::default(),
+                    platforms: Default::default(),
                 },],
                 features: Default::default(),
                 enabled_features: Default::default(),
+                rust_version: None,
                 edition: "2021".to_string(),
+                links: None,
+                dropped: Vec::new(),
             }
         );
     }
@@ -489,6 +1871,12 @@ mod tests {
                 lib_path: None,
                 build_path: None,
                 proc_macro: false,
+                crate_types: Default::default(),
+                bins: vec![Bin {
+                    name: "parent".to_string(),
+                    path: "src/main.rs".into(),
+                }],
+                examples: Default::default(),
                 dependencies: vec![
                     Dependency {
                         name: "child".to_string(),
@@ -500,6 +1888,9 @@ mod tests {
                             lib_path: Some("src/lib.rs".into()),
                             build_path: None,
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: vec![
                                 Dependency {
                                     name: "fnv".to_string(),
@@ -511,6 +1902,9 @@ mod tests {
                                         lib_path: Some("lib.rs".into()),
                                         build_path: None,
                                         proc_macro: false,
+                                        crate_types: vec!["lib".to_string()],
+                                        bins: Default::default(),
+                                        examples: Default::default(),
                                         dependencies: Default::default(),
                                         build_dependencies: Default::default(),
                                         features: HashMap::from([
@@ -518,12 +1912,16 @@ mod tests {
                                             ("std".to_string(), vec![]),
                                         ]),
                                         enabled_features: Default::default(),
+                                        rust_version: None,
                                         edition: "2015".to_string(),
+                                        links: None,
+                                        dropped: Vec::new(),
                                     })
                                     .into(),
                                     optional: false,
                                     uses_default_features: true,
                                     features: Default::default(),
+                                    platforms: Default::default(),
                                 },
                                 Dependency {
                                     name: "itoa".to_string(),
@@ -535,6 +1933,9 @@ mod tests {
                                         lib_path: Some("src/lib.rs".into()),
                                         build_path: None,
                                         proc_macro: false,
+                                        crate_types: vec!["lib".to_string()],
+                                        bins: Default::default(),
+                                        examples: Default::default(),
                                         dependencies: Default::default(),
                                         build_dependencies: Default::default(),
                                         features: HashMap::from([(
@@ -542,12 +1943,16 @@ mod tests {
                                             vec!["dep:no-panic".to_string()]
                                         )]),
                                         enabled_features: Default::default(),
+                                        rust_version: Some("1.36.0".parse().unwrap()),
                                         edition: "2018".to_string(),
+                                        links: None,
+                                        dropped: Vec::new(),
                                     })
                                     .into(),
                                     optional: false,
                                     uses_default_features: true,
                                     features: Default::default(),
+                                    platforms: Default::default(),
                                 },
                                 Dependency {
                                     name: "libc".to_string(),
@@ -559,6 +1964,9 @@ mod tests {
                                         lib_path: Some("src/lib.rs".into()),
                                         build_path: Some("build.rs".into()),
                                         proc_macro: false,
+                                        crate_types: vec!["lib".to_string()],
+                                        bins: Default::default(),
+                                        examples: Default::default(),
                                         dependencies: Default::default(),
                                         build_dependencies: Default::default(),
                                         features: HashMap::from([
@@ -581,12 +1989,16 @@ mod tests {
                                             ),
                                         ]),
                                         enabled_features: Default::default(),
+                                        rust_version: None,
                                         edition: "2015".to_string(),
+                                        links: None,
+                                        dropped: Vec::new(),
                                     })
                                     .into(),
                                     optional: false,
                                     uses_default_features: true,
                                     features: Default::default(),
+                                    platforms: Default::default(),
                                 },
                                 Dependency {
                                     name: "new_name".to_string(),
@@ -598,16 +2010,23 @@ mod tests {
                                         lib_path: Some("src/lib.rs".into()),
                                         build_path: None,
                                         proc_macro: false,
+                                        crate_types: vec!["lib".to_string()],
+                                        bins: Default::default(),
+                                        examples: Default::default(),
                                         dependencies: Default::default(),
                                         build_dependencies: Default::default(),
                                         features: Default::default(),
                                         enabled_features: Default::default(),
+                                        rust_version: None,
                                         edition: "2021".to_string(),
+                                        links: None,
+                                        dropped: Vec::new(),
                                     })
                                     .into(),
                                     optional: true,
                                     uses_default_features: true,
                                     features: Default::default(),
+                                    platforms: Default::default(),
                                 },
                                 Dependency {
                                     name: "rustversion".to_string(),
@@ -619,16 +2038,23 @@ mod tests {
                                         lib_path: Some("src/lib.rs".into()),
                                         build_path: Some("build/build.rs".into()),
                                         proc_macro: true,
+                                        crate_types: vec!["proc-macro".to_string()],
+                                        bins: Default::default(),
+                                        examples: Default::default(),
                                         dependencies: Default::default(),
                                         build_dependencies: Default::default(),
                                         features: Default::default(),
                                         enabled_features: Default::default(),
+                                        rust_version: Some("1.31.0".parse().unwrap()),
                                         edition: "2018".to_string(),
+                                        links: None,
+                                        dropped: Vec::new(),
                                     })
                                     .into(),
                                     optional: false,
                                     uses_default_features: true,
                                     features: Default::default(),
+                                    platforms: Default::default(),
                                 },
                             ],
                             build_dependencies: Default::default(),
@@ -642,12 +2068,16 @@ mod tests {
                                 ("new_name".to_string(), vec!["dep:new_name".to_string()]),
                             ]),
                             enabled_features: Default::default(),
+                            rust_version: None,
                             edition: "2021".to_string(),
+                            links: None,
+                            dropped: Vec::new(),
                         })
                         .into(),
                         optional: false,
                         uses_default_features: false,
                         features: vec!["one".to_string()],
+                        platforms: Default::default(),
                     },
                     Dependency {
                         name: "itoa".to_string(),
@@ -659,6 +2089,9 @@ mod tests {
                             lib_path: Some("src/lib.rs".into()),
                             build_path: None,
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: HashMap::from([
@@ -667,12 +2100,16 @@ mod tests {
                                 ("i128".to_string(), vec![]),
                             ]),
                             enabled_features: Default::default(),
+                            rust_version: None,
                             edition: "2015".to_string(),
+                            links: None,
+                            dropped: Vec::new(),
                         })
                         .into(),
                         optional: false,
                         uses_default_features: true,
                         features: Default::default(),
+                        platforms: Default::default(),
                     },
                     Dependency {
                         name: "libc".to_string(),
@@ -684,6 +2121,9 @@ mod tests {
                             lib_path: Some("src/lib.rs".into()),
                             build_path: Some("build.rs".into()),
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: HashMap::from([
@@ -706,12 +2146,16 @@ mod tests {
                                 ),
                             ]),
                             enabled_features: Default::default(),
+                            rust_version: None,
                             edition: "2015".to_string(),
+                            links: None,
+                            dropped: Vec::new(),
                         })
                         .into(),
                         optional: false,
                         uses_default_features: true,
                         features: Default::default(),
+                        platforms: Default::default(),
                     },
                     Dependency {
                         name: "targets".to_string(),
@@ -723,6 +2167,9 @@ mod tests {
                             lib_path: Some("src/lib.rs".into()),
                             build_path: None,
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: HashMap::from([
@@ -730,19 +2177,906 @@ mod tests {
                                 ("windows".to_string(), vec![]),
                             ]),
                             enabled_features: Default::default(),
+                            rust_version: None,
                             edition: "2021".to_string(),
+                            links: None,
+                            dropped: Vec::new(),
                         })
                         .into(),
                         optional: false,
                         uses_default_features: true,
                         features: vec!["unix".to_string()],
+                        platforms: Default::default(),
                     },
                 ],
                 build_dependencies: Default::default(),
                 features: Default::default(),
                 enabled_features: Default::default(),
+                rust_version: None,
+                edition: "2021".to_string(),
+                links: None,
+                dropped: vec![DroppedDependency {
+                    parent: "parent".to_string(),
+                    parent_version: "0.1.0".to_string(),
+                    name: "targets".to_string(),
+                    reason: DropReason::Platform {
+                        kind: DependencyKind::Normal,
+                        target: "cfg(windows)".to_string(),
+                    },
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn patch() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("patch");
+
+        let package = Package::from_current_dir(path.clone()).unwrap();
+
+        assert_eq!(
+            package,
+            Package {
+                name: "patch".to_string(),
+                version: "0.1.0".parse().unwrap(),
+                source: path.clone().into(),
+                lib_name: None,
+                lib_path: None,
+                build_path: None,
+                proc_macro: false,
+                crate_types: Default::default(),
+                bins: vec![Bin {
+                    name: "patch".to_string(),
+                    path: "src/main.rs".into(),
+                }],
+                examples: Default::default(),
+                dependencies: vec![Dependency {
+                    name: "itoa".to_string(),
+                    package: RefCell::new(Package {
+                        name: "itoa".to_string(),
+                        version: "1.0.6".parse().unwrap(),
+                        source: path.join("itoa").into(),
+                        lib_name: Some("itoa".to_string()),
+                        lib_path: Some("src/lib.rs".into()),
+                        build_path: None,
+                        proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: Default::default(),
+                        dependencies: Default::default(),
+                        build_dependencies: Default::default(),
+                        features: Default::default(),
+                        enabled_features: Default::default(),
+                        rust_version: None,
+                        edition: "2018".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
+                    })
+                    .into(),
+                    optional: false,
+                    uses_default_features: true,
+                    features: Default::default(),
+                    platforms: Default::default(),
+                }],
+                build_dependencies: Default::default(),
+                features: Default::default(),
+                enabled_features: Default::default(),
+                rust_version: None,
                 edition: "2021".to_string(),
+                links: None,
+                dropped: Vec::new(),
             }
         );
     }
+
+    #[test]
+    fn implicit_feature() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("implicit");
+
+        let mut package = Package::from_current_dir(path.clone()).unwrap();
+        package.resolve();
+
+        let child = package.dependencies[0].package.borrow();
+
+        assert_eq!(
+            child.enabled_features,
+            HashSet::from(["default".to_string(), "itoa".to_string()])
+        );
+        assert_eq!(child.dependencies[0].name, "itoa");
+        assert!(!child.dependencies[0].optional);
+        drop(child);
+
+        // Cargo metadata lists the implicit `itoa = ["dep:itoa"]` feature of the child. The dependency should still be
+        // activated when the features table does not have it, with nothing in the manifest using `dep:`.
+        let mut package = Package::from_current_dir(path).unwrap();
+        {
+            let mut child = package.dependencies[0].package.borrow_mut();
+
+            assert_eq!(
+                child.features.remove("itoa"),
+                Some(vec!["dep:itoa".to_string()])
+            );
+            assert!(!child
+                .features
+                .values()
+                .flatten()
+                .any(|f| f.starts_with("dep:")));
+        }
+        package.resolve();
+
+        let child = package.dependencies[0].package.borrow();
+
+        assert_eq!(
+            child.enabled_features,
+            HashSet::from(["default".to_string(), "itoa".to_string()])
+        );
+        assert!(!child.dependencies[0].optional);
+    }
+
+    #[test]
+    fn resolver_v2() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("resolver");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.dependencies[0].package.borrow().enabled_features,
+            HashSet::from(["normal".to_string()])
+        );
+        assert_eq!(
+            package.build_dependencies[0]
+                .package
+                .borrow()
+                .enabled_features,
+            HashSet::from(["build".to_string()])
+        );
+    }
+
+    #[test]
+    fn crate_types() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cdylib");
+
+        let package = Package::from_current_dir(path).unwrap();
+
+        assert_eq!(
+            package.crate_types,
+            vec!["cdylib".to_string(), "rlib".to_string()]
+        );
+    }
+
+    #[test]
+    fn examples() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("examples");
+
+        let package = Package::from_current_dir(path).unwrap();
+
+        assert_eq!(
+            package.examples,
+            vec![
+                Bin {
+                    name: "demo".to_string(),
+                    path: "examples/demo.rs".into(),
+                },
+                Bin {
+                    name: "server".to_string(),
+                    path: "examples/server/main.rs".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_path() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace")
+            .join("parent");
+
+        assert_eq!(
+            Package::from_manifest_path(path.join("Cargo.toml")).unwrap(),
+            Package::from_current_dir(path).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_lock_file() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("missing")
+            .join("Cargo.lock");
+
+        assert_eq!(
+            load_lock_file(path.clone()).unwrap_err().to_string(),
+            format!(
+                "no lock file found at {}. Run `cargo generate-lockfile` to create one",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_host_platform() {
+        assert_eq!(
+            host_platform(Platform::new("exotic-triple", TargetFeatures::Unknown))
+                .unwrap_err()
+                .to_string(),
+            "the host platform exotic-triple is not known, pass the triple to keep the dependencies of with `--platform`"
+        );
+    }
+
+    #[test]
+    fn nested_lock_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace_root = dir.path().join("nested");
+        fs::create_dir(&workspace_root).unwrap();
+
+        // The lock file of the super-project is used when the workspace root has none
+        fs::write(dir.path().join("Cargo.lock"), "").unwrap();
+        assert_eq!(
+            workspace_lock_file(&workspace_root),
+            dir.path().join("Cargo.lock")
+        );
+
+        // But the one in the workspace root comes first
+        fs::write(workspace_root.join("Cargo.lock"), "").unwrap();
+        assert_eq!(
+            workspace_lock_file(&workspace_root),
+            workspace_root.join("Cargo.lock")
+        );
+    }
+
+    #[test]
+    fn explicit_lock_file() {
+        let tests = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests");
+
+        let package = Package::from_current_dir_with_options(
+            tests.join("simple"),
+            &MetadataOptions {
+                lock_file: Some(tests.join("lockfiles").join("simple.lock")),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            package.dependencies[0].package.borrow().source,
+            Source::CratesIo(
+                "0000000000000000000000000000000000000000000000000000000000000000".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn mismatched_lock_file() {
+        let tests = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests");
+
+        // The lock file has another version of itoa than the one cargo resolved
+        let error = Package::from_current_dir_with_options(
+            tests.join("simple"),
+            &MetadataOptions {
+                lock_file: Some(tests.join("lockfiles").join("simple-outdated.lock")),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "lock file is out of date: it has no checksum for itoa 1.0.6 from registry+https://github.com/rust-lang/crates.io-index"
+        );
+    }
+
+    #[test]
+    fn lock_file_versions() {
+        let tests = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests");
+
+        // Version 1 keeps the checksums in a `[metadata]` table, and version 4 is read as version 3
+        for lock_file in ["simple-v1.lock", "simple.lock", "simple-v4.lock"] {
+            let package = Package::from_current_dir_with_options(
+                tests.join("simple"),
+                &MetadataOptions {
+                    lock_file: Some(tests.join("lockfiles").join(lock_file)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            assert_eq!(
+                package.dependencies[0].package.borrow().source,
+                Source::CratesIo(
+                    "0000000000000000000000000000000000000000000000000000000000000000".to_string()
+                ),
+                "{lock_file}"
+            );
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.lock");
+        fs::write(&path, "version = 4\n\n[[package]]\nname = \"itoa\"\n").unwrap();
+
+        // The packages are broken in any version
+        let error = load_lock_file(path.clone()).unwrap_err().to_string();
+
+        assert!(error.starts_with(&format!(
+            "cannot read the version 4 lock file at {}: parse error:",
+            path.display()
+        )));
+        assert!(error.ends_with("Regenerate it in version 3 with `cargo +1.82 generate-lockfile`"));
+    }
+
+    #[test]
+    fn crate_specs() {
+        assert_eq!(
+            "serde_json@1.0.96".parse::<CrateSpec>().unwrap(),
+            CrateSpec {
+                name: "serde_json".to_string(),
+                version: "1.0.96".parse().unwrap(),
+            }
+        );
+        assert!("serde_json".parse::<CrateSpec>().is_err());
+        assert!("@1.0.96".parse::<CrateSpec>().is_err());
+        assert_eq!(
+            "serde_json@1".parse::<CrateSpec>().unwrap_err().to_string(),
+            "invalid crate `serde_json@1`, expected NAME@VERSION"
+        );
+    }
+
+    #[test]
+    fn config_overrides() {
+        assert_eq!(
+            "net.git-fetch-with-cli = true"
+                .parse::<ConfigOverride>()
+                .unwrap(),
+            ConfigOverride {
+                key: "net.git-fetch-with-cli".to_string(),
+                value: "true".to_string(),
+            }
+        );
+        assert!("net.git-fetch-with-cli".parse::<ConfigOverride>().is_err());
+        assert!("=true".parse::<ConfigOverride>().is_err());
+        assert!("net.offline=".parse::<ConfigOverride>().is_err());
+
+        let options = MetadataOptions {
+            offline: true,
+            config: vec![
+                "net.git-fetch-with-cli=true".parse().unwrap(),
+                r#"registries.mirror.index="https://mirror.example/index""#
+                    .parse()
+                    .unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.cargo_flags(),
+            vec![
+                "--offline",
+                "--config",
+                "net.git-fetch-with-cli=true",
+                "--config",
+                r#"registries.mirror.index="https://mirror.example/index""#,
+            ]
+        );
+
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple");
+
+        Package::from_current_dir_with_options(path.clone(), &options).unwrap();
+
+        // Cargo checks the value
+        let error = Package::from_current_dir_with_options(
+            path,
+            &MetadataOptions {
+                config: vec!["net.offline=maybe".parse().unwrap()],
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("--config"), "{error}");
+    }
+
+    #[test]
+    fn outdated_lock_file() {
+        let error = metadata_error(cargo_metadata::Error::CargoMetadata {
+            stderr: "    Updating crates.io index\nerror: the lock file /simple/Cargo.lock needs to be updated but --locked was passed to prevent this\nIf you want to try to generate the lock file without accessing the network, remove the --locked flag and use --offline instead.\n".to_string(),
+        });
+
+        assert_eq!(
+            error.to_string(),
+            "lock file is out of date: the lock file /simple/Cargo.lock needs to be updated but --locked was passed to prevent this"
+        );
+    }
+
+    #[test]
+    fn vendored() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("vendored");
+
+        let package = Package::from_current_dir(path.clone()).unwrap();
+
+        // Vendored git crates are local too, instead of being fetched
+        assert_eq!(
+            package
+                .dependencies
+                .iter()
+                .map(|d| (d.name.as_str(), d.package.borrow().source.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("gitdep", path.join("vendor").join("gitdep").into()),
+                ("itoa", path.join("vendor").join("itoa").into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn inherited_fields() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("inherit")
+            .join("member");
+
+        let package = Package::from_current_dir(path).unwrap();
+
+        assert_eq!(package.edition, "2018");
+        assert_eq!(package.rust_version, Some("1.60.0".parse().unwrap()));
+    }
+
+    #[test]
+    fn msrv() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        package.check_msrv(&"1.68.0".parse().unwrap()).unwrap();
+        assert_eq!(
+            package
+                .check_msrv(&"1.60.0".parse().unwrap())
+                .unwrap_err()
+                .to_string(),
+            "arbitrary requires rust 1.63.0, but the toolchain is 1.60.0"
+        );
+    }
+
+    #[test]
+    fn replacements() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple");
+
+        assert_eq!(
+            "itoa@1.0.6=1.0.9:abcd".parse::<Replacement>().unwrap(),
+            Replacement {
+                name: "itoa".to_string(),
+                from: "1.0.6".parse().unwrap(),
+                to: "1.0.9".parse().unwrap(),
+                checksum: Some("abcd".to_string()),
+            }
+        );
+        assert!("itoa=1.0.9".parse::<Replacement>().is_err());
+        assert!("itoa@1.0.6".parse::<Replacement>().is_err());
+
+        // The checksum of the new version is read from the cargo cache when it is not given
+        let cache_dir = tempfile::tempdir().unwrap();
+        fs::write(cache_dir.path().join("itoa-1.0.9.crate"), "itoa").unwrap();
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        package
+            .replace_in(
+                &["itoa@1.0.6=1.0.9".parse().unwrap()],
+                &[cache_dir.path().to_path_buf()],
+            )
+            .unwrap();
+
+        let itoa = package.dependencies[0].package.borrow();
+
+        assert_eq!(itoa.version, "1.0.9".parse().unwrap());
+        assert_eq!(
+            itoa.source,
+            Source::CratesIo(
+                "a0a7eb79b1b3c30f8f2305963533f972fdb65420c43e902b1044bb2b625562d6".into()
+            )
+        );
+        drop(itoa);
+
+        assert_eq!(
+            package
+                .replace_in(&["itoa@1.0.6=1.0.9:abcd".parse().unwrap()], &[])
+                .unwrap_err()
+                .to_string(),
+            "the replacement of itoa@1.0.6 did not match any crate in the dependency tree"
+        );
+        assert_eq!(
+            package
+                .replace_in(&["itoa@1.0.9=1.0.10".parse().unwrap()], &[])
+                .unwrap_err()
+                .to_string(),
+            "no checksum for itoa 1.0.10. Download it with `cargo fetch` first or give its sha256"
+        );
+    }
+
+    #[test]
+    fn forced_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("forced");
+
+        assert_eq!(
+            "child/extra".parse::<ForcedFeature>().unwrap(),
+            ForcedFeature {
+                name: "child".to_string(),
+                feature: "extra".to_string(),
+            }
+        );
+        assert!("child".parse::<ForcedFeature>().is_err());
+        assert!("child/".parse::<ForcedFeature>().is_err());
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let child = package.dependencies[0].package.clone();
+        let grandchild = child.borrow().dependencies[0].package.clone();
+
+        assert_eq!(child.borrow().enabled_features, Default::default());
+        assert_eq!(grandchild.borrow().enabled_features, Default::default());
+
+        // The features turned on by the forced feature are resolved too
+        package
+            .force_features(&["child/extra".parse().unwrap()])
+            .unwrap();
+
+        assert_eq!(
+            child.borrow().enabled_features,
+            HashSet::from(["extra".to_string(), "grandchild".to_string()])
+        );
+        assert_eq!(
+            grandchild.borrow().enabled_features,
+            HashSet::from(["big".to_string()])
+        );
+
+        assert_eq!(
+            package
+                .force_features(&["grandchild/huge".parse().unwrap()])
+                .unwrap_err()
+                .to_string(),
+            "no crate in the dependency tree has the forced feature grandchild/huge"
+        );
+    }
+
+    #[test]
+    fn enabled_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("unified");
+
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        assert_eq!(package.name(), "unified");
+        assert_eq!(package.version(), &"0.1.0".parse().unwrap());
+        assert_eq!(package.enabled_features(), &HashSet::new());
+
+        // Both parents share the same child, so it has the features of both
+        assert_eq!(
+            package.enabled_features_of("child"),
+            BTreeMap::from([(
+                "0.1.0".parse().unwrap(),
+                HashSet::from(["left".to_string(), "right".to_string()])
+            )])
+        );
+        assert_eq!(package.enabled_features_of("missing"), BTreeMap::new());
+    }
+
+    #[test]
+    fn target_predicates() {
+        let linux = Platform::new("x86_64-unknown-linux-gnu", TargetFeatures::Unknown).unwrap();
+        let matches = |target| target_matches("dep", target, &linux);
+
+        assert!(matches("x86_64-unknown-linux-gnu"));
+        assert!(!matches("aarch64-apple-darwin"));
+        assert!(matches(
+            r#"cfg(all(unix, target_arch = "x86_64", not(windows)))"#
+        ));
+        assert!(!matches(r#"cfg(any(windows, target_os = "macos"))"#));
+        assert!(matches(
+            r#"cfg(any(target_os = "macos", all(unix, target_env = "gnu")))"#
+        ));
+
+        // A false part decides the result even when another part cannot be evaluated
+        assert!(!matches(
+            r#"cfg(all(windows, target_feature = "crt-static"))"#
+        ));
+
+        // Cargo might need these, so they are kept rather than dropped
+        assert!(matches(r#"cfg(all(unix, target_feature = "crt-static"))"#));
+        assert!(matches(r#"cfg(not(target_feature = "sse2"))"#));
+    }
+
+    #[test]
+    fn registry_indexes() {
+        let source = |repr: &str| cargo_metadata::Source {
+            repr: repr.to_string(),
+        };
+
+        assert_eq!(
+            registry_index(&source(
+                "registry+https://github.com/rust-lang/crates.io-index"
+            )),
+            None
+        );
+        assert_eq!(
+            registry_index(&source("sparse+https://index.crates.io/")),
+            None
+        );
+        assert_eq!(
+            registry_index(&source("sparse+https://my.registry/index/")),
+            Some("sparse+https://my.registry/index".to_string())
+        );
+        assert_eq!(
+            registry_index(&source("registry+https://github.com/my/index")),
+            Some("registry+https://github.com/my/index".to_string())
+        );
+    }
+
+    // The derivation should not change when cargo lists the dependencies in another order
+    #[test]
+    fn stable_derivation_order() {
+        struct ReverseVisitor(HashSet<(String, String)>);
+
+        impl Visitor for ReverseVisitor {
+            fn visit_package(&mut self, package: &mut Package) {
+                // Shared packages are only reversed once
+                if self
+                    .0
+                    .insert((package.name.clone(), package.version.to_string()))
+                {
+                    package.dependencies.reverse();
+                    package.build_dependencies.reverse();
+                }
+            }
+        }
+
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace")
+            .join("parent");
+        let derivation = |reverse: bool| {
+            let mut package = Package::from_current_dir(path.clone()).unwrap();
+            package.resolve();
+
+            if reverse {
+                package.visit(&mut ReverseVisitor(Default::default()));
+            }
+
+            nix::Package::from(package).into_derivative().unwrap()
+        };
+
+        assert_eq!(derivation(true), derivation(false));
+    }
+
+    #[test]
+    fn accept_visitor() {
+        #[derive(Default)]
+        struct Policy {
+            visited: Vec<String>,
+        }
+
+        impl Visitor for Policy {
+            fn visit_package(&mut self, package: &mut Package) {
+                self.visited.push(package.name().to_string());
+                package
+                    .retain_dependencies(|dependency| dependency.package().name() != "arbitrary");
+            }
+
+            fn visit_dependency(&mut self, dependency: &Dependency) {
+                self.visited
+                    .push(format!("dependency {}", dependency.name()));
+            }
+
+            fn visit_build_dependency(&mut self, dependency: &Dependency) {
+                self.visited
+                    .push(format!("build dependency {}", dependency.name()));
+            }
+        }
+
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("simple");
+        let mut package = Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let mut policy = Policy::default();
+        package.accept(&mut policy);
+
+        // The banned build dependency is gone before the visitor gets to it
+        assert_eq!(policy.visited, ["simple", "dependency itoa", "itoa"]);
+        assert_eq!(
+            package
+                .dependencies_iter()
+                .map(|dependency| dependency.name())
+                .collect::<Vec<_>>(),
+            ["itoa"]
+        );
+    }
+
+    // Bootstrap crates, like `rustc-std-workspace-core`, can end up with a build dependency on themselves. Linking such
+    // a graph has to stop with an error instead of recursing forever.
+    #[test]
+    fn resolver_from_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.toml");
+        let package = "[package]\nname = \"root\"\nversion = \"0.1.0\"\n";
+
+        for (manifest, resolver) in [
+            (format!("{package}edition = \"2021\""), Resolver::V2),
+            (format!("{package}edition = \"2018\""), Resolver::V1),
+            (
+                format!(
+                    "{package}edition.workspace = true\n[workspace.package]\nedition = \"2021\""
+                ),
+                Resolver::V2,
+            ),
+            (
+                format!("{package}edition = \"2021\"\n[workspace]\nresolver = \"1\""),
+                Resolver::V1,
+            ),
+            (
+                "[workspace]\nmembers = [\"member\"]".to_string(),
+                Resolver::V1,
+            ),
+        ] {
+            fs::write(&path, &manifest).unwrap();
+
+            assert_eq!(
+                Resolver::from_manifest(&path).unwrap(),
+                resolver,
+                "{manifest}"
+            );
+        }
+    }
+
+    #[test]
+    fn self_build_dependency() {
+        let id = PackageId {
+            repr: "bootstrap 0.1.0 (path+file:///bootstrap)".to_string(),
+        };
+        let package = |host| {
+            (
+                (&id, host),
+                PreparedPackage {
+                    name: "bootstrap".to_string(),
+                    version: "0.1.0".parse().unwrap(),
+                    source: Source::Local("/bootstrap".into()),
+                    lib_name: Some("bootstrap".to_string()),
+                    lib_path: Some("src/lib.rs".into()),
+                    build_path: Some("build.rs".into()),
+                    proc_macro: false,
+                    crate_types: vec!["lib".to_string()],
+                    bins: Default::default(),
+                    examples: Default::default(),
+                    features: Default::default(),
+                    edition: "2021".to_string(),
+                    links: None,
+                    dropped: Vec::new(),
+                    rust_version: None,
+                    dependencies: Default::default(),
+                    build_dependencies: vec![PreparedDependency {
+                        id: &id,
+                        name: "bootstrap".to_string(),
+                        optional: false,
+                        uses_default_features: true,
+                        features: Default::default(),
+                        platforms: Default::default(),
+                    }],
+                },
+            )
+        };
+
+        for resolver in [Resolver::V1, Resolver::V2] {
+            let prepared = Prepared {
+                packages: BTreeMap::from_iter([package(false), package(true)]),
+                resolver,
+                cross: false,
+            };
+
+            let error = Package::get_package(
+                &id,
+                false,
+                &prepared,
+                &mut Default::default(),
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+
+            assert_eq!(
+                error.to_string(),
+                "dependency cycle detected: bootstrap 0.1.0 -> bootstrap 0.1.0",
+                "{resolver:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn git_sources() {
+        let source = cargo_metadata::Source {
+            repr: "git+https://github.com/owner/repo?branch=main#0123abcd".to_string(),
+        };
+
+        assert_eq!(
+            git_source(
+                &source,
+                &PathBuf::from("/home/user/.cargo/git/checkouts/repo-1a2b3c/0123abc")
+            ),
+            Source::Git {
+                url: "https://github.com/owner/repo".to_string(),
+                rev: "0123abcd".to_string(),
+                subdir: None,
+            }
+        );
+        assert_eq!(
+            git_source(
+                &source,
+                &PathBuf::from("/home/user/.cargo/git/checkouts/repo-1a2b3c/0123abc/crates/inner")
+            ),
+            Source::Git {
+                url: "https://github.com/owner/repo".to_string(),
+                rev: "0123abcd".to_string(),
+                subdir: Some("crates/inner".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn target_paths() {
+        assert_eq!(
+            relative_target_path("/repo/app".into(), "/repo/app/src/main.rs".into()),
+            "src/main.rs"
+        );
+
+        // A target outside of its package keeps the path of its manifest instead of panicking
+        assert_eq!(
+            relative_target_path("/repo/app".into(), "/repo/shared/main.rs".into()),
+            "../shared/main.rs"
+        );
+    }
 }
@@ -1,5 +1,10 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use cargo_lock::Version;
 use tracing::{info_span, trace};
 
+use crate::models::Source;
+
 use super::{Dependency, Package};
 
 /// A visitor over cargo packages
@@ -11,17 +16,28 @@ pub trait Visitor {
     {
         self.visit_package(package);
 
-        for dependency in package.dependencies_iter() {
+        let dependencies = package
+            .dependencies
+            .iter()
+            .map(|d| (d, false))
+            .chain(package.build_dependencies.iter().map(|d| (d, true)));
+
+        for (dependency, is_build) in dependencies {
             let dependency_span = info_span!(
                 "processing dependency",
                 name = dependency.name,
                 package_name = dependency.package.borrow().name,
                 optional = dependency.optional,
+                is_build,
             );
             let _dependency_span_guard = dependency_span.enter();
 
             if !dependency.optional {
-                self.visit_dependency(dependency);
+                if is_build {
+                    self.visit_build_dependency(dependency);
+                } else {
+                    self.visit_dependency(dependency);
+                }
 
                 dependency.package.borrow_mut().visit(self);
             }
@@ -33,6 +49,26 @@ pub trait Visitor {
 
     /// Visit a dependency of a package
     fn visit_dependency(&mut self, _dependency: &Dependency) {}
+
+    /// Visit a build dependency of a package. Defaults to visiting it like a normal dependency.
+    fn visit_build_dependency(&mut self, dependency: &Dependency) {
+        self.visit_dependency(dependency);
+    }
+}
+
+/// Lets a visitor behind a `dyn` reference use the default traversal of [Visitor::visit]
+impl Visitor for &mut dyn Visitor {
+    fn visit_package(&mut self, package: &mut Package) {
+        (**self).visit_package(package);
+    }
+
+    fn visit_dependency(&mut self, dependency: &Dependency) {
+        (**self).visit_dependency(dependency);
+    }
+
+    fn visit_build_dependency(&mut self, dependency: &Dependency) {
+        (**self).visit_build_dependency(dependency);
+    }
 }
 
 /// Visitor to resolve the enabled dependencies and the features on those dependencies
@@ -45,6 +81,8 @@ impl Visitor for ResolveVisitor {
     }
 
     fn visit_package(&mut self, package: &mut Package) {
+        // Every pass goes over all the enabled features again, so a `dep:` behind a chain of features is activated on
+        // the pass which enables the last feature of the chain. Stop once a pass finds nothing new.
         loop {
             let new_features = unpack_features(package);
 
@@ -57,10 +95,141 @@ impl Visitor for ResolveVisitor {
             }
         }
 
+        activate_implicit_optionals(package);
         unpack_optionals_features(package);
     }
 }
 
+/// Visitor to find the package with the highest minimum supported Rust version
+#[derive(Default)]
+pub struct MsrvVisitor {
+    pub(super) highest: Option<(String, Version)>,
+}
+
+impl Visitor for MsrvVisitor {
+    fn visit_package(&mut self, package: &mut Package) {
+        let Some(rust_version) = &package.rust_version else {
+            return;
+        };
+
+        if self
+            .highest
+            .as_ref()
+            .is_none_or(|(_, highest)| rust_version > highest)
+        {
+            self.highest = Some((package.name.clone(), rust_version.clone()));
+        }
+    }
+}
+
+/// Visitor to drop the build dependencies of every package. It runs before the optional dependencies are resolved, so
+/// it goes into every dependency, since any optional one can still be turned on.
+pub struct PruneBuildVisitor;
+
+impl Visitor for PruneBuildVisitor {
+    fn visit(&mut self, package: &mut Package) {
+        self.visit_package(package);
+
+        for dependency in &package.dependencies {
+            dependency.package.borrow_mut().visit(self);
+        }
+    }
+
+    fn visit_package(&mut self, package: &mut Package) {
+        package.build_dependencies.clear();
+    }
+}
+
+/// Visitor to swap crates for another version. The `replaced` set records which of the `replacements` were used.
+pub struct ReplaceVisitor {
+    pub(super) replacements: BTreeMap<(String, Version), (Version, String)>,
+    pub(super) replaced: BTreeSet<(String, Version)>,
+}
+
+impl Visitor for ReplaceVisitor {
+    fn visit_package(&mut self, package: &mut Package) {
+        let key = (package.name.clone(), package.version.clone());
+
+        if let Some((version, checksum)) = self.replacements.get(&key) {
+            trace!(name = package.name, %version, "replacing crate");
+
+            package.version = version.clone();
+            package.source = Source::CratesIo(checksum.clone());
+            self.replaced.insert(key);
+        }
+    }
+}
+
+/// Visitor to turn on features no matter what asked for them. The `forced` set records which of the `features` were
+/// found on a crate.
+pub struct ForceFeatureVisitor {
+    pub(super) features: BTreeSet<(String, String)>,
+    pub(super) forced: BTreeSet<(String, String)>,
+}
+
+impl Visitor for ForceFeatureVisitor {
+    fn visit_package(&mut self, package: &mut Package) {
+        for key in &self.features {
+            let (name, feature) = key;
+
+            if name == &package.name && package.features.contains_key(feature) {
+                trace!(name, feature, "forcing feature");
+
+                package.enabled_features.insert(feature.clone());
+                self.forced.insert(key.clone());
+            }
+        }
+    }
+}
+
+/// Visitor to find the crates which get their default features from some parents while other parents turned them off.
+/// The `parents` map has the names of the parents using the defaults and of those not using them, for every crate.
+#[derive(Default)]
+pub struct UnifiedDefaultsVisitor {
+    pub(super) parents: BTreeMap<(String, Version), (BTreeSet<String>, BTreeSet<String>)>,
+}
+
+impl UnifiedDefaultsVisitor {
+    /// Get the crates which have parents on both sides
+    pub(super) fn unified(
+        &self,
+    ) -> impl Iterator<Item = (&(String, Version), &BTreeSet<String>, &BTreeSet<String>)> {
+        self.parents
+            .iter()
+            .filter(|(_, (with, without))| !with.is_empty() && !without.is_empty())
+            .map(|(key, (with, without))| (key, with, without))
+    }
+}
+
+impl Visitor for UnifiedDefaultsVisitor {
+    fn visit_package(&mut self, package: &mut Package) {
+        for dependency in package
+            .dependencies
+            .iter()
+            .chain(&package.build_dependencies)
+            .filter(|d| !d.optional)
+        {
+            let child = dependency.package.borrow();
+
+            // Turning off an empty default does not change anything
+            if child.features.get("default").is_none_or(Vec::is_empty) {
+                continue;
+            }
+
+            let (with, without) = self
+                .parents
+                .entry((child.name.clone(), child.version.clone()))
+                .or_default();
+
+            if dependency.uses_default_features {
+                with.insert(package.name.clone());
+            } else {
+                without.insert(package.name.clone());
+            }
+        }
+    }
+}
+
 /// Add the "default" feature if default-features is not false
 /// https://doc.rust-lang.org/cargo/reference/specifying-dependencies.html#choosing-features
 fn add_default(dependency: &Dependency) {
@@ -105,17 +274,18 @@ fn unpack_features(package: &mut Package) -> Vec<String> {
         .iter()
         .filter_map(|f| package.features.get(f))
         .flatten()
+        .filter(|&f| !package.enabled_features.contains(f)) // Don't process a "leaf" feature
         .cloned()
-        .filter(|f| !package.enabled_features.contains(f)) // Don't process a "leaf" feature
         .filter_map(|f| {
             // Activate an optional dependency that is turned on by a feature
             // https://doc.rust-lang.org/cargo/reference/features.html#optional-dependencies
             if let Some(dependency_name) = f.strip_prefix("dep:") {
-                if let Some(dependency) = package
+                // A crate can be both a normal and a build dependency, so activate every matching edge
+                for dependency in package
                     .dependencies
                     .iter_mut()
                     .chain(package.build_dependencies.iter_mut())
-                    .find(|d| d.name == dependency_name)
+                    .filter(|d| d.name == dependency_name)
                 {
                     trace!(name = dependency_name, "activating optional dependency");
                     dependency.optional = false;
@@ -123,15 +293,21 @@ fn unpack_features(package: &mut Package) -> Vec<String> {
 
                 // We are activating an optional dependency and not enabling a new feature
                 return None;
+            } else if f.contains("?/") {
+                // A weak dependency feature is only applied once something else activates the optional dependency,
+                // which `unpack_optionals_features` takes care of. It must not be split like a strong one below.
+                return Some(f);
             } else {
                 // Activate a dependency's features
                 // https://doc.rust-lang.org/cargo/reference/features.html#dependency-features
                 if let Some((dependency_name, feature)) = f.split_once('/') {
-                    if let Some(dependency) = package
+                    let mut found = false;
+
+                    for dependency in package
                         .dependencies
                         .iter_mut()
                         .chain(package.build_dependencies.iter_mut())
-                        .find(|d| d.name == dependency_name)
+                        .filter(|d| d.name == dependency_name)
                     {
                         let feature = feature.to_string();
 
@@ -139,6 +315,10 @@ fn unpack_features(package: &mut Package) -> Vec<String> {
                             dependency.features.push(feature);
                         }
 
+                        found = true;
+                    }
+
+                    if found {
                         return Some(dependency_name.to_string());
                     }
                 }
@@ -150,6 +330,38 @@ fn unpack_features(package: &mut Package) -> Vec<String> {
         .collect()
 }
 
+/// Activate optional dependencies which are turned on by their implicit feature. Cargo creates a feature with the same
+/// name as an optional dependency, unless the dependency is referenced with `dep:` in another feature. Cargo metadata
+/// normally lists these implicit features, but a package without them in its features table should still work.
+/// https://doc.rust-lang.org/cargo/reference/features.html#optional-dependencies
+fn activate_implicit_optionals(package: &mut Package) {
+    for dependency in package
+        .dependencies
+        .iter_mut()
+        .chain(package.build_dependencies.iter_mut())
+        .filter(|d| d.optional)
+        .filter(|d| package.enabled_features.contains(&d.name))
+        .filter(|d| !package.features.contains_key(&d.name))
+    {
+        let dep_feature = format!("dep:{}", dependency.name);
+
+        if package
+            .features
+            .values()
+            .flatten()
+            .any(|f| f == &dep_feature)
+        {
+            continue;
+        }
+
+        trace!(
+            name = dependency.name,
+            "activating implicit optional dependency"
+        );
+        dependency.optional = false;
+    }
+}
+
 /// Activate features on optional dependencies where the dependencies was made non-optional by a previous feature
 /// https://doc.rust-lang.org/cargo/reference/features.html#dependency-features
 fn unpack_optionals_features(package: &mut Package) {
@@ -161,9 +373,10 @@ fn unpack_optionals_features(package: &mut Package) {
         .collect();
 
     for (dependency_name, feature) in new_dependencies_features {
-        if let Some(dependency) = package
+        // Both the normal and build dependency edges need the feature
+        for dependency in package
             .dependencies_iter_mut()
-            .find(|d| d.name == dependency_name && !d.optional)
+            .filter(|d| d.name == dependency_name && !d.optional)
         {
             if !dependency.features.contains(&feature) {
                 dependency.features.push(feature.clone());
@@ -184,10 +397,16 @@ fn unpack_optionals_features(package: &mut Package) {
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, collections::HashMap, rc::Rc};
+    use std::{
+        cell::RefCell,
+        collections::{BTreeSet, HashMap},
+        rc::Rc,
+    };
 
     use crate::models::cargo::{Dependency, Package};
 
+    use super::UnifiedDefaultsVisitor;
+
     use pretty_assertions::assert_eq;
 
     fn make_package_node(
@@ -209,6 +428,9 @@ mod tests {
             lib_path: None,
             build_path: None,
             proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies,
             build_dependencies: Default::default(),
             features: HashMap::from_iter(features.into_iter().map(|(b, d)| {
@@ -218,7 +440,10 @@ mod tests {
                 )
             })),
             enabled_features: Default::default(),
+            rust_version: None,
             edition: "2021".to_string(),
+            links: None,
+            dropped: Vec::new(),
         }
     }
 
@@ -244,6 +469,7 @@ mod tests {
                 optional: false,
                 uses_default_features: false,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -259,6 +485,7 @@ mod tests {
                 optional: false,
                 uses_default_features: false,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -287,6 +514,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
 
@@ -306,6 +534,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
 
@@ -334,6 +563,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
 
@@ -353,6 +583,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
 
@@ -382,6 +613,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         input.build_dependencies.push(Dependency {
@@ -390,6 +622,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         input.resolve();
@@ -403,6 +636,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         expected.build_dependencies.push(Dependency {
@@ -411,6 +645,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         assert_eq!(input, expected);
@@ -431,6 +666,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         input.build_dependencies.push(Dependency {
@@ -439,6 +675,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         input.resolve();
@@ -452,6 +689,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         expected.build_dependencies.push(Dependency {
@@ -460,6 +698,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         assert_eq!(input, expected);
@@ -492,6 +731,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         input.build_dependencies.push(Dependency {
@@ -500,6 +740,7 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         input.resolve();
@@ -520,6 +761,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         expected.build_dependencies.push(Dependency {
@@ -528,6 +770,7 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         assert_eq!(input, expected);
@@ -552,6 +795,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec!["feature".to_string()],
+                platforms: Default::default(),
             }),
         );
         let mut build = make_package_node(
@@ -563,6 +807,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec!["build_feature".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -575,6 +820,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         input.build_dependencies.push(Dependency {
@@ -583,6 +829,7 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         input.resolve();
@@ -611,6 +858,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
         expected.build_dependencies.push(Dependency {
@@ -619,6 +867,7 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec!["hi".to_string()],
+            platforms: Default::default(),
         });
 
         assert_eq!(input, expected);
@@ -641,6 +890,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         child.build_dependencies.push(Dependency {
@@ -649,6 +899,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         let mut input = make_package_node(
@@ -660,6 +911,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["new_name".to_string(), "new_build_name".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -682,6 +934,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["new_name".to_string(), "new_build_name".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -710,6 +963,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         child.build_dependencies.push(Dependency {
@@ -718,6 +972,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         let mut input = make_package_node(
@@ -729,6 +984,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -767,6 +1023,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -799,6 +1056,7 @@ mod tests {
                 optional: true,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         child.build_dependencies.push(Dependency {
@@ -807,6 +1065,7 @@ mod tests {
             optional: true,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         let mut input = make_package_node(
@@ -818,6 +1077,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -852,6 +1112,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -884,6 +1145,7 @@ mod tests {
                 optional: true,
                 uses_default_features: false,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         child.build_dependencies.push(Dependency {
@@ -892,6 +1154,7 @@ mod tests {
             optional: true,
             uses_default_features: false,
             features: vec![],
+            platforms: Default::default(),
         });
 
         let mut input = make_package_node(
@@ -903,6 +1166,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -927,12 +1191,77 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["one".to_string()],
+                platforms: Default::default(),
             }),
         );
 
         assert_eq!(input, expected);
     }
 
+    // A weak `a?/b` should not be read as a strong `a/b`, even when the crate also has a strong `a/c`
+    #[test]
+    fn weak_and_strong_dependency_features() {
+        let resolve = |features: &[&str]| {
+            let a = make_package_node("a", vec![("b", vec![]), ("c", vec![])], None);
+            let child = make_package_node(
+                "child",
+                vec![
+                    ("a", vec!["dep:a"]),
+                    ("weak", vec!["a?/b"]),
+                    ("strong", vec!["a/c"]),
+                ],
+                Some(Dependency {
+                    name: "a".to_string(),
+                    package: RefCell::new(a).into(),
+                    optional: true,
+                    uses_default_features: false,
+                    features: vec![],
+                    platforms: Default::default(),
+                }),
+            );
+            let mut input = make_package_node(
+                "parent",
+                vec![],
+                Some(Dependency {
+                    name: "child".to_string(),
+                    package: RefCell::new(child).into(),
+                    optional: false,
+                    uses_default_features: true,
+                    features: features.iter().map(ToString::to_string).collect(),
+                    platforms: Default::default(),
+                }),
+            );
+
+            input.resolve();
+
+            let child = input.dependencies[0].package.borrow();
+            let a = &child.dependencies[0];
+            let mut enabled: Vec<_> = a
+                .package
+                .borrow()
+                .enabled_features
+                .iter()
+                .cloned()
+                .collect();
+            enabled.sort();
+
+            (a.optional, a.features.clone(), enabled)
+        };
+
+        // On its own the weak feature leaves the dependency off
+        assert_eq!(resolve(&["weak"]), (true, vec![], vec![]));
+
+        // The strong feature turns it on, which lets the weak feature apply too
+        assert_eq!(
+            resolve(&["weak", "strong"]),
+            (
+                false,
+                vec!["c".to_string(), "b".to_string()],
+                vec!["b".to_string(), "c".to_string()]
+            )
+        );
+    }
+
     // Features on optional dependencies should be enabled if the dependency is enabled
     #[test]
     fn feature_on_optional_dependency() {
@@ -962,6 +1291,7 @@ mod tests {
                 optional: true,
                 uses_default_features: false,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         child.build_dependencies.push(Dependency {
@@ -970,6 +1300,7 @@ mod tests {
             optional: true,
             uses_default_features: false,
             features: vec![],
+            platforms: Default::default(),
         });
 
         let mut input = make_package_node(
@@ -985,6 +1316,7 @@ mod tests {
                     "build_optional".to_string(),
                     "hi".to_string(),
                 ],
+                platforms: Default::default(),
             }),
         );
 
@@ -1025,6 +1357,210 @@ mod tests {
                     "build_optional".to_string(),
                     "hi".to_string(),
                 ],
+                platforms: Default::default(),
+            }),
+        );
+
+        assert_eq!(input, expected);
+    }
+
+    // Features on optional build dependencies should be enabled on every edge of the dependency. Even when the same
+    // name is also used by a normal dependency.
+    #[test]
+    fn feature_on_optional_build_dependency() {
+        let optional = make_package_node("shared", vec![("enabled", vec![])], None);
+        let mut build_optional = make_package_node("shared", vec![("enabled", vec![])], None);
+        build_optional.version = "0.2.0".parse().unwrap();
+
+        let mut child = make_package_node(
+            "child",
+            vec![
+                ("shared", vec!["dep:shared"]),
+                ("hi", vec!["shared?/enabled"]),
+            ],
+            Some(Dependency {
+                name: "shared".to_string(),
+                package: RefCell::new(optional.clone()).into(),
+                optional: true,
+                uses_default_features: false,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+        child.build_dependencies.push(Dependency {
+            name: "shared".to_string(),
+            package: RefCell::new(build_optional.clone()).into(),
+            optional: true,
+            uses_default_features: false,
+            features: vec![],
+            platforms: Default::default(),
+        });
+
+        let mut input = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child.clone()).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec!["shared".to_string(), "hi".to_string()],
+                platforms: Default::default(),
+            }),
+        );
+
+        input.resolve();
+
+        child.dependencies[0].optional = false;
+        child.dependencies[0].package = RefCell::new(optional).into();
+        child.dependencies[0]
+            .package
+            .borrow_mut()
+            .enabled_features
+            .extend(["enabled".to_string()]);
+        child.dependencies[0].features = vec!["enabled".to_string()];
+        child.build_dependencies[0].optional = false;
+        child.build_dependencies[0].package = RefCell::new(build_optional).into();
+        child.build_dependencies[0]
+            .package
+            .borrow_mut()
+            .enabled_features
+            .extend(["enabled".to_string()]);
+        child.build_dependencies[0].features = vec!["enabled".to_string()];
+        child
+            .enabled_features
+            .extend(["shared".to_string(), "hi".to_string()]);
+
+        let expected = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child.clone()).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec!["shared".to_string(), "hi".to_string()],
+                platforms: Default::default(),
+            }),
+        );
+
+        assert_eq!(input, expected);
+    }
+
+    // An optional dependency should be enabled by its implicit feature when it is missing from the features table
+    #[test]
+    fn implicit_optional_dependency() {
+        let optional = make_package_node("optional", vec![], None);
+        let mut child = make_package_node(
+            "child",
+            vec![("default", vec!["optional"])],
+            Some(Dependency {
+                name: "optional".to_string(),
+                package: RefCell::new(optional.clone()).into(),
+                optional: true,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+
+        let mut input = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child.clone()).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+
+        input.resolve();
+
+        child.dependencies[0].optional = false;
+        child
+            .enabled_features
+            .extend(["default".to_string(), "optional".to_string()]);
+
+        let expected = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child.clone()).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+
+        assert_eq!(input, expected);
+    }
+
+    // An optional dependency only referenced with `dep:` is activated when the feature naming it is enabled through a
+    // chain of other features
+    #[test]
+    fn dep_optional_through_features() {
+        let mut optional = make_package_node(
+            "optional",
+            vec![("default", vec!["std"]), ("std", vec![])],
+            None,
+        );
+        let mut child = make_package_node(
+            "child",
+            vec![
+                ("default", vec!["b"]),
+                ("b", vec!["a"]),
+                ("a", vec!["dep:optional"]),
+            ],
+            Some(Dependency {
+                name: "optional".to_string(),
+                package: RefCell::new(optional.clone()).into(),
+                optional: true,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+
+        let mut input = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child.clone()).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
+            }),
+        );
+
+        input.resolve();
+
+        // The activated dependency is resolved too
+        optional
+            .enabled_features
+            .extend(["default".to_string(), "std".to_string()]);
+        child.dependencies[0].optional = false;
+        child.dependencies[0].package = RefCell::new(optional).into();
+        child
+            .enabled_features
+            .extend(["default".to_string(), "b".to_string(), "a".to_string()]);
+
+        let expected = make_package_node(
+            "parent",
+            vec![],
+            Some(Dependency {
+                name: "child".to_string(),
+                package: RefCell::new(child).into(),
+                optional: false,
+                uses_default_features: true,
+                features: vec![],
+                platforms: Default::default(),
             }),
         );
 
@@ -1065,6 +1601,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["other".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -1077,6 +1614,7 @@ mod tests {
                 optional: false,
                 uses_default_features: false,
                 features: vec!["other".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -1089,6 +1627,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         input.dependencies.push(Dependency {
@@ -1097,6 +1636,7 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         input.resolve();
@@ -1119,6 +1659,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec!["other".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -1131,6 +1672,7 @@ mod tests {
                 optional: false,
                 uses_default_features: false,
                 features: vec!["other".to_string()],
+                platforms: Default::default(),
             }),
         );
 
@@ -1143,6 +1685,7 @@ mod tests {
                 optional: false,
                 uses_default_features: true,
                 features: vec![],
+                platforms: Default::default(),
             }),
         );
         expected.dependencies.push(Dependency {
@@ -1151,8 +1694,81 @@ mod tests {
             optional: false,
             uses_default_features: true,
             features: vec![],
+            platforms: Default::default(),
         });
 
         assert_eq!(input, expected);
+
+        // The defaults being on anyway is reported
+        let mut visitor = UnifiedDefaultsVisitor::default();
+        input.visit(&mut visitor);
+
+        assert_eq!(
+            visitor
+                .unified()
+                .map(|((name, version), with, without)| (
+                    name.as_str(),
+                    version.to_string(),
+                    with.clone(),
+                    without.clone()
+                ))
+                .collect::<Vec<_>>(),
+            vec![(
+                "child",
+                "0.1.0".to_string(),
+                BTreeSet::from(["layer1_1".to_string()]),
+                BTreeSet::from(["layer1_2".to_string()])
+            )]
+        );
+    }
+
+    // Resolving an already resolved package should not change it
+    #[test]
+    fn resolve_twice() {
+        let make_input = || {
+            let a = make_package_node(
+                "a",
+                vec![("default", vec!["b"]), ("b", vec![]), ("c", vec![])],
+                None,
+            );
+            let child = make_package_node(
+                "child",
+                vec![
+                    ("a", vec!["dep:a"]),
+                    ("weak", vec!["a?/c"]),
+                    ("strong", vec!["a/b"]),
+                ],
+                Some(Dependency {
+                    name: "a".to_string(),
+                    package: RefCell::new(a).into(),
+                    optional: true,
+                    uses_default_features: true,
+                    features: vec![],
+                    platforms: Default::default(),
+                }),
+            );
+
+            make_package_node(
+                "parent",
+                vec![],
+                Some(Dependency {
+                    name: "child".to_string(),
+                    package: RefCell::new(child).into(),
+                    optional: false,
+                    uses_default_features: true,
+                    features: vec!["weak".to_string(), "strong".to_string()],
+                    platforms: Default::default(),
+                }),
+            )
+        };
+
+        let mut once = make_input();
+        once.resolve();
+
+        let mut twice = make_input();
+        twice.resolve();
+        twice.resolve();
+
+        assert_eq!(twice, once);
     }
 }
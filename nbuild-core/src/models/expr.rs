@@ -0,0 +1,189 @@
+//! A small pretty-printer for the nix values in the derivation. The output follows the layout of [nixpkgs-fmt], so that
+//! formatting a derivation does not change it.
+//!
+//! [nixpkgs-fmt]: https://github.com/nix-community/nixpkgs-fmt
+
+/// Values which do not fit on the rest of a line within this width are spread over more lines
+const MAX_WIDTH: usize = 100;
+
+/// A nix value
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Expr {
+    /// Anything which is printed as is, like an identifier, a path or an already quoted string
+    Raw(String),
+    List(Vec<Expr>),
+
+    /// An attribute set with the attribute names as they should be printed
+    Attrs(Vec<(String, Expr)>),
+
+    /// Lists joined with `++`
+    Concat(Vec<Expr>),
+
+    /// A value after some text, like a function call on the value or a lambda returning it
+    Prefixed(String, Box<Expr>),
+}
+
+impl Expr {
+    pub(super) fn raw(value: impl Into<String>) -> Self {
+        Self::Raw(value.into())
+    }
+
+    pub(super) fn prefixed(prefix: impl Into<String>, value: Expr) -> Self {
+        Self::Prefixed(prefix.into(), Box::new(value))
+    }
+
+    /// Get the `name = value;` binding of this value for a line indented by `indent` spaces
+    pub(super) fn binding(&self, name: &str, indent: usize) -> String {
+        format!(
+            "{name} = {};",
+            self.pretty(indent, indent + name.len() + " = ".len())
+        )
+    }
+
+    /// Print the value on a single line
+    pub(super) fn inline(&self) -> String {
+        match self {
+            Self::Raw(value) => value.clone(),
+            Self::List(items) if items.is_empty() => "[ ]".to_string(),
+            Self::List(items) => format!(
+                "[ {} ]",
+                items.iter().map(Self::inline).collect::<Vec<_>>().join(" ")
+            ),
+            Self::Attrs(attrs) if attrs.is_empty() => "{ }".to_string(),
+            Self::Attrs(attrs) => format!(
+                "{{ {} }}",
+                attrs
+                    .iter()
+                    .map(|(name, value)| format!("{name} = {};", value.inline()))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Self::Concat(parts) => parts
+                .iter()
+                .map(Self::inline)
+                .collect::<Vec<_>>()
+                .join(" ++ "),
+            Self::Prefixed(prefix, value) => format!("{prefix}{}", value.inline()),
+        }
+    }
+
+    /// Print the value starting at `column` on a line which is indented by `indent` spaces. Room is kept for the `;`
+    /// which usually follows.
+    fn pretty(&self, indent: usize, column: usize) -> String {
+        let inline = self.inline();
+
+        if column + inline.len() < MAX_WIDTH {
+            return inline;
+        }
+
+        let padding = " ".repeat(indent + 2);
+
+        match self {
+            Self::Raw(value) => value.clone(),
+            Self::List(items) if items.is_empty() => inline,
+            Self::List(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| format!("{padding}{}\n", item.pretty(indent + 2, indent + 2)))
+                    .collect::<String>();
+
+                format!("[\n{items}{}]", " ".repeat(indent))
+            }
+            Self::Attrs(attrs) if attrs.is_empty() => inline,
+            Self::Attrs(attrs) => {
+                let attrs = attrs
+                    .iter()
+                    .map(|(name, value)| format!("{padding}{}\n", value.binding(name, indent + 2)))
+                    .collect::<String>();
+
+                format!("{{\n{attrs}{}}}", " ".repeat(indent))
+            }
+            // Every list is spread over more lines, so that all of them line up
+            Self::Concat(parts) => parts
+                .iter()
+                .map(|part| part.pretty(indent, MAX_WIDTH))
+                .collect::<Vec<_>>()
+                .join(" ++ "),
+            Self::Prefixed(prefix, value) => {
+                format!("{prefix}{}", value.pretty(indent, column + prefix.len()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expr;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn pretty() {
+        let short = Expr::List(vec![Expr::raw("a"), Expr::raw("b")]);
+
+        assert_eq!(short.binding("dependencies", 4), "dependencies = [ a b ];");
+        assert_eq!(Expr::List(vec![]).binding("crateBin", 4), "crateBin = [ ];");
+        assert_eq!(
+            Expr::Attrs(vec![("rename".to_string(), Expr::raw("\"old\""))]).inline(),
+            r#"{ rename = "old"; }"#
+        );
+
+        let long = Expr::Concat(vec![
+            Expr::List(
+                (0..10)
+                    .map(|i| Expr::raw(format!("dependency_{i}_0_0")))
+                    .collect(),
+            ),
+            Expr::prefixed("pkgs.lib.optionals true ", short),
+        ]);
+
+        assert_eq!(
+            long.binding("dependencies", 4),
+            r#"dependencies = [
+      dependency_0_0_0
+      dependency_1_0_0
+      dependency_2_0_0
+      dependency_3_0_0
+      dependency_4_0_0
+      dependency_5_0_0
+      dependency_6_0_0
+      dependency_7_0_0
+      dependency_8_0_0
+      dependency_9_0_0
+    ] ++ pkgs.lib.optionals true [
+      a
+      b
+    ];"#
+        );
+
+        let nested = Expr::Attrs(vec![(
+            "\"itoa\"".to_string(),
+            Expr::List(vec![
+                Expr::Attrs(vec![
+                    (
+                        "rename".to_string(),
+                        Expr::raw("\"itoa_old_version_for_the_tests\""),
+                    ),
+                    ("version".to_string(), Expr::raw("\"0.4.8\"")),
+                ]),
+                Expr::Attrs(vec![
+                    (
+                        "rename".to_string(),
+                        Expr::raw("\"itoa_new_version_for_the_tests\""),
+                    ),
+                    ("version".to_string(), Expr::raw("\"1.0.6\"")),
+                ]),
+            ]),
+        )]);
+
+        assert_eq!(
+            nested.binding("crateRenames", 4),
+            r#"crateRenames = {
+      "itoa" = [
+        { rename = "itoa_old_version_for_the_tests"; version = "0.4.8"; }
+        { rename = "itoa_new_version_for_the_tests"; version = "1.0.6"; }
+      ];
+    };"#
+        );
+    }
+}
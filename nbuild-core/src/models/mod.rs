@@ -1,12 +1,22 @@
 //! Models to reason about the cargo inputs and the nix outputs
 
-use std::{cell::RefCell, collections::BTreeMap, path::PathBuf, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::PathBuf,
+    rc::Rc,
+};
 
 use cargo_lock::Version;
+use cargo_metadata::camino::Utf8PathBuf;
 use tracing::{instrument, trace};
 
 pub mod cargo;
+mod expr;
+mod nar;
 pub mod nix;
+pub mod plan;
 
 /// Where does the crate's code come from
 #[derive(Debug, PartialEq, Clone)]
@@ -26,14 +36,137 @@ pub enum Source {
     /// dependency = "0.2.0"
     /// ```
     CratesIo(String),
+
+    /// It is from an alternative registry. The `index` keeps the protocol prefix (`registry+` or `sparse+`) so that
+    /// the download location can be read from the registry's `config.json`.
+    ///
+    /// ```toml
+    /// [dependencies]
+    /// dependency = { version = "0.2.0", registry = "my-registry" }
+    /// ```
+    Registry { index: String, sha256: String },
+
+    /// It is from a git repository, either directly or through a `[patch]` section. The `subdir` is set when the
+    /// crate is not at the root of the repository.
+    ///
+    /// ```toml
+    /// [patch.crates-io]
+    /// dependency = { git = "https://github.com/owner/dependency", rev = "abc123" }
+    /// ```
+    Git {
+        url: String,
+        rev: String,
+        subdir: Option<PathBuf>,
+    },
+}
+
+/// A binary target of a crate
+#[derive(Debug, PartialEq, Clone)]
+pub struct Bin {
+    pub(super) name: String,
+
+    /// Path to the binary's main file, relative to the crate's root
+    pub(super) path: Utf8PathBuf,
+}
+
+/// Get the directories in the local cargo home which hold the downloaded `.crate` files, one for each registry
+fn crate_cache_dirs() -> Vec<PathBuf> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))
+        .unwrap_or_default();
+
+    fs::read_dir(cargo_home.join("registry").join("cache"))
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default()
 }
 
-/// Convert the cargo package to a nix package for output
+/// Convert the cargo package to a nix package for output. Like cargo, every crate is built once with the union of the
+/// features its dependents ask for, so each crate gets one flat `features` list rather than a list per dependency edge.
+/// Only the v2 resolver keeps the host features apart, which is handled by splitting the crate into two derivations.
+///
+/// There is no separate `buildFeatures` list either. `buildRustCrate` gives the build script of a crate the features
+/// of the crate itself, which is what cargo does too: with the v1 resolver they include the features asked for by
+/// build dependencies, and with the v2 resolver those only end up in the `_host` crate.
 impl From<cargo::Package> for nix::Package {
     fn from(package: cargo::Package) -> Self {
+        Self::convert(package, false)
+    }
+}
+
+impl nix::Package {
+    /// Convert a package which was read with a [cargo::MetadataOptions::target] to cross-compile it. Every crate used
+    /// by a build script or proc-macro then gets a host derivation, since it has to run on the platform doing the
+    /// build.
+    pub fn from_cross(package: cargo::Package) -> Self {
+        Self::convert(package, true)
+    }
+
+    fn convert(package: cargo::Package, cross: bool) -> Self {
+        let split = if cross {
+            Split::All
+        } else {
+            let mut feature_sets = BTreeMap::<_, BTreeSet<_>>::new();
+            let mut dependencies = BTreeMap::<_, BTreeSet<_>>::new();
+
+            // The normal dependencies of a crate are linked by its host variant, unless it is a proc-macro which always
+            // links the host variant of its dependencies
+            package.for_each_package(|package| {
+                let key = (package.name.clone(), package.version.clone());
+                let mut features = package.enabled_features.iter().cloned().collect::<Vec<_>>();
+                features.sort();
+
+                let crate_dependencies = dependencies.entry(key.clone()).or_default();
+
+                if !package.proc_macro {
+                    crate_dependencies.extend(
+                        package
+                            .dependencies
+                            .iter()
+                            .filter(|d| !d.optional)
+                            .map(|d| {
+                                let dependency = d.package.borrow();
+
+                                (dependency.name.clone(), dependency.version.clone())
+                            }),
+                    );
+                }
+
+                feature_sets.entry(key).or_default().insert(features);
+            });
+
+            // Crates which have more than one set of features were split into a host and target variant by the v2
+            // resolver
+            let mut crates = feature_sets
+                .into_iter()
+                .filter(|(_, sets)| sets.len() > 1)
+                .map(|(key, _)| key)
+                .collect::<BTreeSet<_>>();
+
+            // A crate which depends on a split crate needs a host variant too, which links the host variant of that
+            // crate, even when its own features are the same for both
+            loop {
+                let dependents = dependencies
+                    .iter()
+                    .filter(|(key, dependencies)| {
+                        !crates.contains(*key) && dependencies.iter().any(|d| crates.contains(d))
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+
+                if dependents.is_empty() {
+                    break;
+                }
+
+                crates.extend(dependents);
+            }
+
+            Split::Crates(crates)
+        };
+
         let mut converted = Default::default();
 
-        let result = cargo_to_nix(package, &mut converted);
+        let result = cargo_to_nix(package, false, &split, &mut converted);
 
         // Drop what was converted so that we can unwrap from the Rc
         drop(converted);
@@ -42,12 +175,34 @@ impl From<cargo::Package> for nix::Package {
     }
 }
 
+/// The crates which get their own host derivation
+enum Split {
+    /// Only these crates, which the v2 resolver built with other features for the host, or which depend on such a crate
+    Crates(BTreeSet<(String, Version)>),
+
+    /// Every crate built for the host, including the proc-macros themselves, since the package is cross-compiled
+    All,
+}
+
+impl Split {
+    /// Check if a crate reached as part of a build dependency (or proc-macro) when `host` is set, is a host variant
+    fn is_host_variant(&self, host: bool, name: &str, version: &Version, proc_macro: bool) -> bool {
+        match self {
+            Self::Crates(crates) => host && crates.contains(&(name.to_string(), version.clone())),
+            Self::All => host || proc_macro,
+        }
+    }
+}
+
 /// Recursively convert a cargo package to a nix package. Also ensure a crate is only converted once by using the
-/// `converted` cache to lookup crates that have already been converted.
+/// `converted` cache to lookup crates that have already been converted. The `host` flag tracks whether we are inside
+/// a build dependency (or proc-macro), which is used to give `split` crates their own host derivation.
 #[instrument(skip_all, fields(name = %cargo_package.name))]
 fn cargo_to_nix(
     cargo_package: cargo::Package,
-    converted: &mut BTreeMap<(String, Version), Rc<RefCell<nix::Package>>>,
+    host: bool,
+    split: &Split,
+    converted: &mut BTreeMap<(String, Version, bool), Rc<RefCell<nix::Package>>>,
 ) -> Rc<RefCell<nix::Package>> {
     let cargo::Package {
         name,
@@ -57,25 +212,35 @@ fn cargo_to_nix(
         lib_path,
         build_path,
         proc_macro,
+        crate_types,
+        bins,
+        examples,
         features: _, // We only care about the features that were enabled at the end
         enabled_features,
         dependencies,
         build_dependencies,
         edition,
+        rust_version: _, // Only needed before the conversion
+        links,
+        dropped: _, // Only reported from the cargo package
     } = cargo_package;
 
-    match converted.get(&(name.clone(), version.clone())) {
+    let host_variant = split.is_host_variant(host, &name, &version, proc_macro);
+
+    match converted.get(&(name.clone(), version.clone(), host_variant)) {
         Some(package) => Rc::clone(package),
         None => {
             let dependencies = dependencies
                 .iter()
                 .filter(|d| !d.optional)
-                .map(|dependency| convert_dependency(dependency, converted))
+                .map(|dependency| {
+                    convert_dependency(dependency, host || proc_macro, split, converted)
+                })
                 .collect();
             let build_dependencies = build_dependencies
                 .iter()
                 .filter(|d| !d.optional)
-                .map(|dependency| convert_dependency(dependency, converted))
+                .map(|dependency| convert_dependency(dependency, true, split, converted))
                 .collect();
 
             // Handle libs that rename themselves
@@ -99,15 +264,19 @@ fn cargo_to_nix(
                 lib_path,
                 build_path,
                 proc_macro,
+                crate_types,
+                bins,
+                examples,
                 features,
                 dependencies,
                 build_dependencies,
                 edition,
-                printed: false,
+                links,
+                host: host_variant,
             })
             .into();
 
-            converted.insert((name, version), Rc::clone(&package));
+            converted.insert((name, version, host_variant), Rc::clone(&package));
 
             package
         }
@@ -116,10 +285,12 @@ fn cargo_to_nix(
 
 fn convert_dependency(
     dependency: &cargo::Dependency,
-    converted: &mut BTreeMap<(String, Version), Rc<RefCell<nix::Package>>>,
+    host: bool,
+    split: &Split,
+    converted: &mut BTreeMap<(String, Version, bool), Rc<RefCell<nix::Package>>>,
 ) -> nix::Dependency {
     let cargo_package = Rc::clone(&dependency.package).borrow().clone();
-    let package = cargo_to_nix(cargo_package, converted);
+    let package = cargo_to_nix(cargo_package, host, split, converted);
 
     let rename = if dependency.name == package.borrow().name {
         None
@@ -129,7 +300,11 @@ fn convert_dependency(
         Some(dependency.name.to_string())
     };
 
-    nix::Dependency { package, rename }
+    nix::Dependency {
+        package,
+        rename,
+        platforms: dependency.platforms.clone(),
+    }
 }
 
 #[cfg(test)]
@@ -137,12 +312,13 @@ mod tests {
     use std::{
         cell::RefCell,
         collections::{HashMap, HashSet},
+        fs,
         path::PathBuf,
         rc::Rc,
         str::FromStr,
     };
 
-    use crate::models::{cargo, nix};
+    use crate::models::{cargo, nix, Source};
 
     use pretty_assertions::assert_eq;
 
@@ -162,6 +338,9 @@ mod tests {
             lib_path: Some("src/lib.rs".into()),
             build_path: Some("build.rs".into()),
             proc_macro: false,
+            crate_types: vec!["lib".to_string()],
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies: Default::default(),
             build_dependencies: Default::default(),
             features: HashMap::from([
@@ -181,7 +360,10 @@ mod tests {
                 ),
             ]),
             enabled_features: Default::default(),
+            rust_version: None,
             edition: "2015".to_string(),
+            links: None,
+            dropped: Vec::new(),
         })
         .into();
         let optional = RefCell::new(cargo::Package {
@@ -192,6 +374,9 @@ mod tests {
             lib_path: Some("src/lib.rs".into()),
             build_path: None,
             proc_macro: false,
+            crate_types: vec!["lib".to_string()],
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies: Default::default(),
             build_dependencies: Default::default(),
             features: HashMap::from([
@@ -199,7 +384,10 @@ mod tests {
                 ("default".to_string(), vec!["std".to_string()]),
             ]),
             enabled_features: Default::default(),
+            rust_version: None,
             edition: "2021".to_string(),
+            links: None,
+            dropped: Vec::new(),
         })
         .into();
 
@@ -211,6 +399,9 @@ mod tests {
             lib_path: None,
             build_path: None,
             proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies: vec![
                 cargo::Dependency {
                     name: "child".to_string(),
@@ -222,6 +413,9 @@ mod tests {
                         lib_path: Some("src/lib.rs".into()),
                         build_path: None,
                         proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: Default::default(),
                         dependencies: vec![
                             cargo::Dependency {
                                 name: "fnv".to_string(),
@@ -233,6 +427,9 @@ mod tests {
                                     lib_path: Some("lib.rs".into()),
                                     build_path: None,
                                     proc_macro: false,
+                                    crate_types: vec!["lib".to_string()],
+                                    bins: Default::default(),
+                                    examples: Default::default(),
                                     dependencies: Default::default(),
                                     build_dependencies: Default::default(),
                                     features: HashMap::from([
@@ -240,12 +437,16 @@ mod tests {
                                         ("std".to_string(), vec![]),
                                     ]),
                                     enabled_features: Default::default(),
+                                    rust_version: None,
                                     edition: "2015".to_string(),
+                                    links: None,
+                                    dropped: Vec::new(),
                                 })
                                 .into(),
                                 optional: false,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                             cargo::Dependency {
                                 name: "itoa".to_string(),
@@ -257,6 +458,9 @@ mod tests {
                                     lib_path: Some("src/lib.rs".into()),
                                     build_path: None,
                                     proc_macro: false,
+                                    crate_types: vec!["lib".to_string()],
+                                    bins: Default::default(),
+                                    examples: Default::default(),
                                     dependencies: Default::default(),
                                     build_dependencies: Default::default(),
                                     features: HashMap::from([(
@@ -264,12 +468,16 @@ mod tests {
                                         vec!["dep:no-panic".to_string()],
                                     )]),
                                     enabled_features: Default::default(),
+                                    rust_version: None,
                                     edition: "2018".to_string(),
+                                    links: None,
+                                    dropped: Vec::new(),
                                 })
                                 .into(),
                                 optional: false,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                             cargo::Dependency {
                                 name: "libc".to_string(),
@@ -277,6 +485,7 @@ mod tests {
                                 optional: false,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                             cargo::Dependency {
                                 name: "optional".to_string(),
@@ -284,6 +493,7 @@ mod tests {
                                 optional: true,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                             cargo::Dependency {
                                 name: "new_name".to_string(),
@@ -295,16 +505,23 @@ mod tests {
                                     lib_path: Some("src/lib.rs".into()),
                                     build_path: None,
                                     proc_macro: false,
+                                    crate_types: vec!["lib".to_string()],
+                                    bins: Default::default(),
+                                    examples: Default::default(),
                                     dependencies: Default::default(),
                                     build_dependencies: Default::default(),
                                     features: Default::default(),
                                     enabled_features: Default::default(),
+                                    rust_version: None,
                                     edition: "2021".to_string(),
+                                    links: None,
+                                    dropped: Vec::new(),
                                 })
                                 .into(),
                                 optional: false,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                             cargo::Dependency {
                                 name: "rustversion".to_string(),
@@ -316,16 +533,23 @@ mod tests {
                                     lib_path: Some("src/lib.rs".into()),
                                     build_path: Some("build/build.rs".into()),
                                     proc_macro: true,
+                                    crate_types: vec!["proc-macro".to_string()],
+                                    bins: Default::default(),
+                                    examples: Default::default(),
                                     dependencies: Default::default(),
                                     build_dependencies: Default::default(),
                                     features: Default::default(),
                                     enabled_features: Default::default(),
+                                    rust_version: None,
                                     edition: "2018".to_string(),
+                                    links: None,
+                                    dropped: Vec::new(),
                                 })
                                 .into(),
                                 optional: false,
                                 uses_default_features: true,
                                 features: Default::default(),
+                                platforms: Default::default(),
                             },
                         ],
                         build_dependencies: vec![cargo::Dependency {
@@ -338,6 +562,9 @@ mod tests {
                                 lib_path: Some("src/lib.rs".into()),
                                 build_path: None,
                                 proc_macro: false,
+                                crate_types: vec!["lib".to_string()],
+                                bins: Default::default(),
+                                examples: Default::default(),
                                 dependencies: Default::default(),
                                 build_dependencies: Default::default(),
                                 features: HashMap::from([
@@ -348,12 +575,16 @@ mod tests {
                                     ),
                                 ]),
                                 enabled_features: Default::default(),
+                                rust_version: None,
                                 edition: "2018".to_string(),
+                                links: None,
+                                dropped: Vec::new(),
                             })
                             .into(),
                             optional: false,
                             uses_default_features: true,
                             features: Default::default(),
+                            platforms: Default::default(),
                         }],
                         features: HashMap::from([
                             (
@@ -369,11 +600,15 @@ mod tests {
                             "new_name".to_string(),
                         ]),
                         edition: "2021".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
+                        rust_version: None,
                     })
                     .into(),
                     optional: false,
                     uses_default_features: false,
                     features: vec!["one".to_string()],
+                    platforms: Default::default(),
                 },
                 cargo::Dependency {
                     name: "itoa".to_string(),
@@ -385,6 +620,9 @@ mod tests {
                         lib_path: Some("src/lib.rs".into()),
                         build_path: None,
                         proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: Default::default(),
                         dependencies: Default::default(),
                         build_dependencies: Default::default(),
                         features: HashMap::from([
@@ -394,12 +632,16 @@ mod tests {
                             ("i128".to_string(), vec![]),
                         ]),
                         enabled_features: Default::default(),
+                        rust_version: None,
                         edition: "2018".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
                     })
                     .into(),
                     optional: false,
                     uses_default_features: true,
                     features: Default::default(),
+                    platforms: Default::default(),
                 },
                 cargo::Dependency {
                     name: "libc".to_string(),
@@ -407,6 +649,7 @@ mod tests {
                     optional: false,
                     uses_default_features: true,
                     features: Default::default(),
+                    platforms: Default::default(),
                 },
                 cargo::Dependency {
                     name: "optional".to_string(),
@@ -414,6 +657,7 @@ mod tests {
                     optional: true,
                     uses_default_features: true,
                     features: Default::default(),
+                    platforms: Default::default(),
                 },
                 cargo::Dependency {
                     name: "targets".to_string(),
@@ -425,6 +669,9 @@ mod tests {
                         lib_path: Some("src/lib.rs".into()),
                         build_path: None,
                         proc_macro: false,
+                        crate_types: Default::default(),
+                        bins: Default::default(),
+                        examples: Default::default(),
                         dependencies: Default::default(),
                         build_dependencies: Default::default(),
                         features: HashMap::from([
@@ -432,18 +679,25 @@ mod tests {
                             ("windows".to_string(), vec![]),
                         ]),
                         enabled_features: HashSet::from(["unix".to_string()]),
+                        rust_version: None,
                         edition: "2021".to_string(),
+                        links: None,
+                        dropped: Vec::new(),
                     })
                     .into(),
                     optional: false,
                     uses_default_features: true,
                     features: vec!["unix".to_string()],
+                    platforms: Default::default(),
                 },
             ],
             build_dependencies: Default::default(),
             features: Default::default(),
             enabled_features: Default::default(),
+            rust_version: None,
             edition: "2021".to_string(),
+            links: None,
+            dropped: Vec::new(),
         };
 
         let actual: nix::Package = input.into();
@@ -456,11 +710,15 @@ mod tests {
             lib_path: None,
             build_path: None,
             proc_macro: false,
+            crate_types: vec!["lib".to_string()],
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies: Default::default(),
             build_dependencies: Default::default(),
             features: Default::default(),
             edition: "2015".to_string(),
-            printed: false,
+            links: None,
+            host: false,
         })
         .into();
         let expected = nix::Package {
@@ -471,6 +729,9 @@ mod tests {
             lib_path: None,
             build_path: None,
             proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
             dependencies: vec![
                 nix::Package {
                     name: "child".to_string(),
@@ -480,6 +741,9 @@ mod tests {
                     lib_path: None,
                     build_path: None,
                     proc_macro: false,
+                    crate_types: vec!["lib".to_string()],
+                    bins: Default::default(),
+                    examples: Default::default(),
                     dependencies: vec![
                         nix::Package {
                             name: "fnv".to_string(),
@@ -489,11 +753,15 @@ mod tests {
                             lib_path: Some("lib.rs".into()),
                             build_path: None,
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: Default::default(),
                             edition: "2015".to_string(),
-                            printed: false,
+                            links: None,
+                            host: false,
                         }
                         .into(),
                         nix::Package {
@@ -504,16 +772,21 @@ mod tests {
                             lib_path: None,
                             build_path: None,
                             proc_macro: false,
+                            crate_types: vec!["lib".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: Default::default(),
                             edition: "2018".to_string(),
-                            printed: false,
+                            links: None,
+                            host: false,
                         }
                         .into(),
                         nix::Dependency {
                             package: Rc::clone(&libc),
                             rename: None,
+                            platforms: Default::default(),
                         },
                         nix::Dependency {
                             package: RefCell::new(nix::Package {
@@ -524,14 +797,19 @@ mod tests {
                                 lib_path: None,
                                 build_path: None,
                                 proc_macro: false,
+                                crate_types: vec!["lib".to_string()],
+                                bins: Default::default(),
+                                examples: Default::default(),
                                 dependencies: Default::default(),
                                 build_dependencies: Default::default(),
                                 features: Default::default(),
                                 edition: "2021".to_string(),
-                                printed: false,
+                                links: None,
+                                host: false,
                             })
                             .into(),
                             rename: Some("new_name".to_string()),
+                            platforms: Default::default(),
                         },
                         nix::Package {
                             name: "rustversion".to_string(),
@@ -541,11 +819,15 @@ mod tests {
                             lib_path: None,
                             build_path: Some("build/build.rs".into()),
                             proc_macro: true,
+                            crate_types: vec!["proc-macro".to_string()],
+                            bins: Default::default(),
+                            examples: Default::default(),
                             dependencies: Default::default(),
                             build_dependencies: Default::default(),
                             features: Default::default(),
                             edition: "2018".to_string(),
-                            printed: false,
+                            links: None,
+                            host: false,
                         }
                         .into(),
                     ],
@@ -557,16 +839,21 @@ mod tests {
                         lib_path: None,
                         build_path: None,
                         proc_macro: false,
+                        crate_types: vec!["lib".to_string()],
+                        bins: Default::default(),
+                        examples: Default::default(),
                         dependencies: Default::default(),
                         build_dependencies: Default::default(),
                         features: Default::default(),
                         edition: "2018".to_string(),
-                        printed: false,
+                        links: None,
+                        host: false,
                     }
                     .into()],
                     features: vec!["new_name".to_string(), "one".to_string()],
                     edition: "2021".to_string(),
-                    printed: false,
+                    links: None,
+                    host: false,
                 }
                 .into(),
                 nix::Package {
@@ -577,16 +864,21 @@ mod tests {
                     lib_path: None,
                     build_path: None,
                     proc_macro: false,
+                    crate_types: vec!["lib".to_string()],
+                    bins: Default::default(),
+                    examples: Default::default(),
                     dependencies: Default::default(),
                     build_dependencies: Default::default(),
                     features: Default::default(),
                     edition: "2018".to_string(),
-                    printed: false,
+                    links: None,
+                    host: false,
                 }
                 .into(),
                 nix::Dependency {
                     package: libc,
                     rename: None,
+                    platforms: Default::default(),
                 },
                 nix::Package {
                     name: "targets".to_string(),
@@ -596,18 +888,23 @@ mod tests {
                     lib_path: None,
                     build_path: None,
                     proc_macro: false,
+                    crate_types: Default::default(),
+                    bins: Default::default(),
+                    examples: Default::default(),
                     dependencies: Default::default(),
                     build_dependencies: Default::default(),
                     features: vec!["unix".to_string()],
                     edition: "2021".to_string(),
-                    printed: false,
+                    links: None,
+                    host: false,
                 }
                 .into(),
             ],
             build_dependencies: Default::default(),
             features: Default::default(),
             edition: "2021".to_string(),
-            printed: false,
+            links: None,
+            host: false,
         };
 
         assert_eq!(actual, expected);
@@ -620,4 +917,784 @@ mod tests {
             actual.dependencies[0].package.borrow().dependencies[2]
         );
     }
+
+    #[test]
+    fn resolver_v2() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("resolver");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        let child = package.dependencies[0].package.borrow();
+        let child_host = package.build_dependencies[0].package.borrow();
+
+        assert!(!child.host);
+        assert_eq!(child.features, vec!["normal".to_string()]);
+        assert!(child_host.host);
+        assert_eq!(child_host.features, vec!["build".to_string()]);
+    }
+
+    #[test]
+    fn host_dependents() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("host_dependents");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        {
+            let mid = package.dependencies[0].package.borrow();
+            let mid_host = package.build_dependencies[1].package.borrow();
+
+            assert_eq!(mid.name, "mid");
+            assert!(!mid.host);
+            assert!(!mid.dependencies[0].package.borrow().host);
+            assert!(mid_host.host);
+            assert!(mid_host.dependencies[0].package.borrow().host);
+        }
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"  mid_0_1_0 = buildRustCrate rec {
+    crateName = "mid";"#
+        ));
+        assert!(derivation.contains(
+            r#"  mid_0_1_0_host = buildRustCrate rec {
+    crateName = "mid";"#
+        ));
+        assert!(derivation.contains("    dependencies = [ leaf_0_1_0_host ];"));
+        assert!(derivation.contains("    dependencies = [ leaf_0_1_0 ];"));
+        assert!(derivation.contains("    buildDependencies = [ leaf_0_1_0_host mid_0_1_0_host ];"));
+    }
+
+    #[test]
+    fn platforms() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("platforms");
+
+        let mut package = cargo::Package::from_current_dir_with_options(
+            path,
+            &cargo::MetadataOptions {
+                platforms: vec![
+                    "x86_64-unknown-linux-gnu".to_string(),
+                    "aarch64-apple-darwin".to_string(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    dependencies = [
+      common_0_1_0
+    ] ++ pkgs.lib.optionals (builtins.elem pkgs.stdenv.hostPlatform.rust.rustcTarget [ "aarch64-apple-darwin" ]) [
+      mac_0_1_0
+    ] ++ pkgs.lib.optionals (builtins.elem pkgs.stdenv.hostPlatform.rust.rustcTarget [ "x86_64-unknown-linux-gnu" ]) [
+      linux_0_1_0
+    ];"#
+        ));
+        assert!(derivation.contains("  linux_0_1_0 = buildRustCrate"));
+        assert!(derivation.contains("  mac_0_1_0 = buildRustCrate"));
+    }
+
+    #[test]
+    fn proc_macro_dependencies() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("proc_macro");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        {
+            let derive = package.dependencies[0].package.borrow();
+            let helper = package.dependencies[1].package.borrow();
+            let helper_host = derive.dependencies[0].package.borrow();
+
+            assert!(derive.proc_macro);
+            assert!(!derive.host);
+            assert!(!helper.host);
+            assert_eq!(helper.features, vec!["normal".to_string()]);
+            assert!(helper_host.host);
+            assert_eq!(helper_host.features, vec!["macro".to_string()]);
+        }
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains("    dependencies = [ helper_0_1_0_host ];"));
+        assert!(derivation.contains("  helper_0_1_0 = buildRustCrate"));
+        assert!(derivation.contains("  helper_0_1_0_host = buildRustCrate"));
+    }
+
+    // The host has to be another platform than the target for the crates to be built apart
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn cross_compile() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cross");
+
+        let mut package = cargo::Package::from_current_dir_with_options(
+            path,
+            &cargo::MetadataOptions {
+                target: Some("aarch64-unknown-linux-gnu".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        package.resolve();
+
+        let package = nix::Package::from_cross(package);
+
+        {
+            let arm = package.dependencies[0].package.borrow();
+            let derive = package.dependencies[1].package.borrow();
+            let helper = package.dependencies[2].package.borrow();
+            let helper_host = derive.dependencies[0].package.borrow();
+            let x86_host = derive.dependencies[1].package.borrow();
+
+            assert_eq!(arm.name, "arm");
+            assert!(!arm.host);
+            assert!(derive.host);
+            assert!(!helper.host);
+            assert!(helper_host.host);
+            assert_eq!(x86_host.name, "x86");
+            assert!(x86_host.host);
+        }
+
+        let derivation = package
+            .into_derivative_with_options(&nix::DerivationOptions {
+                target: Some("aarch64-unknown-linux-gnu".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(derivation.contains(r#"  crossSystem.config = "aarch64-unknown-linux-gnu";"#));
+        assert!(derivation.contains(
+            r#"  rustVersion = pkgs.buildPackages.rust-bin.stable."1.68.0".default.override { targets = [ "aarch64-unknown-linux-gnu" ]; };"#
+        ));
+        assert!(derivation
+            .contains("  buildRustCrateHost = pkgs.buildPackages.buildRustCrate.override {"));
+        assert!(derivation.contains("  derive_0_1_0_host = buildRustCrateHost"));
+        assert!(derivation.contains("    dependencies = [ helper_0_1_0_host x86_0_1_0_host ];"));
+        assert!(derivation.contains("  helper_0_1_0 = buildRustCrate rec"));
+        assert!(derivation.contains("  helper_0_1_0_host = buildRustCrateHost"));
+    }
+
+    #[test]
+    fn build_script_paths() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("build_script");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        assert_eq!(package.build_path, Some("tools/build/main.rs".into()));
+        assert_eq!(
+            package.dependencies[0].package.borrow().build_path,
+            Some("scripts/codegen/build.rs".into())
+        );
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"/tests/build_script;
+    };
+    build = "tools/build/main.rs";"#
+        ));
+
+        // The build script of the core crate gets its own dependencies, which are not linked into the crate itself
+        assert!(derivation.contains(
+            r#"    dependencies = [ child_0_1_0 ];
+    buildDependencies = [ codegen_0_1_0 ];"#
+        ));
+        assert!(derivation.contains("  codegen_0_1_0 = buildRustCrate rec"));
+        assert!(derivation.contains(
+            r#"/tests/build_script/child;
+    };
+    build = "scripts/codegen/build.rs";"#
+        ));
+    }
+
+    #[test]
+    fn unified_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("unified");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        let left = package.dependencies[0].package.borrow();
+        let right = package.dependencies[1].package.borrow();
+
+        assert!(Rc::ptr_eq(
+            &left.dependencies[0].package,
+            &right.dependencies[0].package
+        ));
+        assert_eq!(
+            left.dependencies[0].package.borrow().features,
+            vec!["left".to_string(), "right".to_string()]
+        );
+    }
+
+    #[test]
+    fn root_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("root_features");
+
+        // The default features are on unless they are turned off
+        let mut package = cargo::Package::from_current_dir(path.clone()).unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.enabled_features(),
+            &HashSet::from(["default".to_string(), "small".to_string()])
+        );
+
+        let package: nix::Package = package.into();
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    features = [ "default" "small" ];
+
+    dependencies = [ ];"#
+        ));
+
+        // The optional dependency is only in the metadata when its feature is asked for
+        let mut package = cargo::Package::from_current_dir_with_options(
+            path.clone(),
+            &cargo::MetadataOptions {
+                features: vec!["big".to_string()],
+                no_default_features: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        package.resolve();
+
+        assert_eq!(
+            package.enabled_features(),
+            &HashSet::from(["big".to_string()])
+        );
+
+        let package: nix::Package = package.into();
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    features = [ "big" ];
+
+    dependencies = [ extra_0_1_0 ];"#
+        ));
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+
+        assert_eq!(
+            package
+                .enable_features(&["huge".to_string()], true)
+                .unwrap_err()
+                .to_string(),
+            "package root_features does not have the feature huge"
+        );
+    }
+
+    #[test]
+    fn dependency_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("dependency_features");
+
+        // The feature turns on the optional derive dependency of codec
+        let mut package = cargo::Package::from_current_dir_with_options(
+            path.clone(),
+            &cargo::MetadataOptions {
+                features: vec!["codec/derive".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        package.resolve();
+
+        assert!(package
+            .enabled_features_of("codec")
+            .values()
+            .all(|features| features.contains("derive")));
+        assert_eq!(
+            package.enabled_features_of("codec_derive").len(),
+            1,
+            "codec_derive is built"
+        );
+
+        let package: nix::Package = package.into();
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(r#"features = [ "codec_derive" "default" "derive" "std" ];"#));
+        assert!(derivation.contains("dependencies = [ codec_derive_0_1_0 ];"));
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+
+        assert_eq!(
+            package
+                .enable_features(&["codec_json/std".to_string()], true)
+                .unwrap_err()
+                .to_string(),
+            "package dependency_features does not have the feature codec_json/std"
+        );
+    }
+
+    #[test]
+    fn metadata_cache() {
+        let fixture = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("root_features");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+
+        for file in [
+            "Cargo.toml",
+            "src/main.rs",
+            "extra/Cargo.toml",
+            "extra/src/lib.rs",
+        ] {
+            fs::create_dir_all(path.join(file).parent().unwrap()).unwrap();
+            fs::copy(fixture.join(file), path.join(file)).unwrap();
+        }
+
+        // The cache is keyed on the lock file
+        fs::write(
+            path.join("Cargo.lock"),
+            r#"# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "extra"
+version = "0.1.0"
+
+[[package]]
+name = "root_features"
+version = "0.1.0"
+dependencies = [
+ "extra",
+]
+"#,
+        )
+        .unwrap();
+
+        // The cache is kept in the target directory of the cargo config, unless `CARGO_TARGET_DIR` moves it
+        fs::create_dir(path.join(".cargo")).unwrap();
+        fs::write(
+            path.join(".cargo").join("config.toml"),
+            "[build]\ntarget-dir = \"build-out\"\n",
+        )
+        .unwrap();
+
+        let cache_dir = cargo::CargoConfig::discover(path)
+            .unwrap()
+            .target_dir
+            .unwrap()
+            .join("nbuild-cache");
+
+        let derivation = |cache: bool| {
+            let mut package = cargo::Package::from_current_dir_with_options(
+                path,
+                &cargo::MetadataOptions {
+                    cache,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            package.resolve();
+
+            let package: nix::Package = package.into();
+            package.into_derivative().unwrap()
+        };
+        // A shared `CARGO_TARGET_DIR` can have the entries of other runs
+        let entries = || fs::read_dir(&cache_dir).map_or(0, |dir| dir.count());
+        let before = entries();
+
+        let cold = derivation(false);
+        assert_eq!(entries(), before);
+
+        assert_eq!(derivation(true), cold);
+        assert_eq!(entries(), before + 1);
+        assert_eq!(derivation(true), cold);
+        assert_eq!(entries(), before + 1);
+
+        // A manifest change which leaves the lock file alone is not hidden by the cache
+        let manifest = fs::read_to_string(path.join("Cargo.toml"))
+            .unwrap()
+            .replace(r#"default = ["small"]"#, "default = []");
+        fs::write(path.join("Cargo.toml"), manifest).unwrap();
+
+        let changed = derivation(true);
+        assert_eq!(changed, derivation(false));
+        assert!(changed.contains(r#"features = [ "default" ];"#));
+
+        // So is a new target which cargo finds without a manifest change
+        fs::create_dir(path.join("src").join("bin")).unwrap();
+        fs::write(path.join("src").join("bin").join("tool.rs"), "fn main() {}").unwrap();
+
+        let changed = derivation(true);
+        assert_eq!(changed, derivation(false));
+        assert!(changed.contains(r#"{ name = "tool"; path = "src/bin/tool.rs"; }"#));
+        assert!(!path.join("target").exists());
+
+        // The cache can also be kept somewhere else
+        let cache_dir = dir.path().join("elsewhere");
+        cargo::Package::from_current_dir_with_options(
+            path,
+            &cargo::MetadataOptions {
+                cache: true,
+                cache_dir: Some(cache_dir.clone()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(fs::read_dir(cache_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn bin_only() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("bin_only");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        assert_eq!(package.lib_name, None);
+        assert_eq!(package.lib_path, None);
+
+        // Every bin is built, including the one buildRustCrate would not find on its own
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    dependencies = [ greeting_0_1_0 numbers_0_1_0 ];
+    crateBin = [
+      { name = "bin_only"; path = "src/main.rs"; }
+      { name = "tool"; path = "tools/tool.rs"; }
+    ];"#
+        ));
+        assert!(derivation.contains("  greeting_0_1_0 = buildRustCrate rec"));
+        assert!(derivation.contains("  numbers_0_1_0 = buildRustCrate rec"));
+    }
+
+    #[test]
+    fn explicit_targets() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("explicit_targets");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        assert_eq!(package.lib_name, Some("engine".to_string()));
+        assert_eq!(package.lib_path, Some("core/engine.rs".into()));
+
+        // The bin in `src/bin` is not a target since `autobins` is off
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    crateName = "explicit-targets";
+    libName = "engine";
+    version = "0.1.0";
+"#
+        ));
+        assert!(derivation.contains(
+            r#"    };
+    libPath = "core/engine.rs";
+
+    dependencies = [ ];
+    crateBin = [ { name = "tool"; path = "cli/main.rs"; } ];"#
+        ));
+    }
+
+    #[test]
+    fn bin_paths() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("bins");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        // Every bin needs its path, since none of them is at `src/main.rs`
+        assert!(package.into_derivative().unwrap().contains(
+            r#"    crateBin = [
+      { name = "admin"; path = "tools/admin/main.rs"; }
+      { name = "worker"; path = "src/bin/worker.rs"; }
+    ];"#
+        ));
+    }
+
+    #[test]
+    fn build_features() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("build_features");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        let child = package.dependencies[0].package.borrow();
+
+        assert!(Rc::ptr_eq(
+            &package.dependencies[0].package,
+            &package.build_dependencies[0].package
+        ));
+        assert_eq!(
+            child.features,
+            vec!["build".to_string(), "normal".to_string()]
+        );
+        drop(child);
+
+        // The build script sees the unified features too, like it does with cargo's v1 resolver
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(r#"    features = [ "build" "normal" ];"#));
+        assert!(!derivation.contains("buildFeatures"));
+    }
+
+    #[test]
+    fn build_features_v2() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("resolver");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        // The build features only end up in the host crate, whose build script sees them
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"  child_0_1_0 = buildRustCrate rec {
+    crateName = "child";
+    version = "0.1.0";
+"#
+        ));
+        assert!(derivation.contains(r#"    features = [ "normal" ];"#));
+        assert!(derivation.contains(
+            r#"  child_0_1_0_host = buildRustCrate rec {
+    crateName = "child";
+    version = "0.1.0";
+"#
+        ));
+        assert!(derivation.contains(r#"    features = [ "build" ];"#));
+        assert!(derivation.contains("    dependencies = [ child_0_1_0 ];"));
+        assert!(derivation.contains("    buildDependencies = [ child_0_1_0_host ];"));
+        assert!(!derivation.contains("buildFeatures"));
+    }
+
+    #[test]
+    fn no_build_dependencies() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("build_features");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.prune_build_dependencies();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        assert!(package.build_dependencies.is_empty());
+        assert_eq!(
+            package.dependencies[0].package.borrow().features,
+            vec!["normal".to_string()]
+        );
+        assert!(!package
+            .into_derivative()
+            .unwrap()
+            .contains("buildDependencies"));
+    }
+
+    #[test]
+    fn no_optional_build_dependencies() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("optional_build");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.prune_build_dependencies();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        // The optional helper is turned on by the default feature, without the build dependency of its build script
+        assert_eq!(package.dependencies.len(), 1);
+
+        {
+            let helper = package.dependencies[0].package.borrow();
+
+            assert_eq!(helper.name, "helper");
+            assert!(helper.build_dependencies.is_empty());
+        }
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(!derivation.contains("buildDependencies"));
+        assert!(!derivation.contains("codegen_0_1_0"));
+    }
+
+    #[test]
+    fn dev_dependency_cycle() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("cycle");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+
+        // The dev-dependency of the child back onto the root is not part of the build
+        assert_eq!(package.dependencies.len(), 1);
+        assert_eq!(package.build_dependencies.len(), 0);
+
+        {
+            let child = package.dependencies[0].package.borrow();
+
+            assert_eq!(child.name, "child");
+            assert_eq!(child.dependencies.len(), 0);
+            assert_eq!(child.build_dependencies.len(), 0);
+        }
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    crateName = "cycle";
+    version = "0.1.0";
+"#
+        ));
+        assert!(derivation.contains("    dependencies = [ child_0_1_0 ];"));
+        assert!(derivation.contains("  child_0_1_0 = buildRustCrate rec"));
+        assert_eq!(derivation.matches("buildRustCrate rec").count(), 2);
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let package = |name: &str| {
+            Rc::new(RefCell::new(nix::Package::new(
+                name,
+                "0.1.0",
+                Source::Local(name.into()),
+            )))
+        };
+        let dependency = |package: &Rc<RefCell<nix::Package>>| nix::Dependency {
+            package: Rc::clone(package),
+            rename: None,
+            platforms: Default::default(),
+        };
+
+        let first = package("first");
+        let second = package("second");
+
+        first.borrow_mut().dependencies.push(dependency(&second));
+        second.borrow_mut().dependencies.push(dependency(&first));
+
+        let mut root = nix::Package::new("root", "0.1.0", Source::Local("root".into()));
+        root.dependencies.push(dependency(&first));
+
+        assert_eq!(
+            root.into_derivative().unwrap_err().to_string(),
+            "dependency cycle detected: first_0_1_0 -> second_0_1_0 -> first_0_1_0"
+        );
+
+        // Break the cycle so that the packages are dropped
+        second.borrow_mut().dependencies.clear();
+    }
+
+    #[test]
+    fn renamed_versions() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("renames");
+
+        let mut package = cargo::Package::from_current_dir(path).unwrap();
+        package.resolve();
+
+        let package: nix::Package = package.into();
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    crateRenames = {
+      "itoa" = [
+        { rename = "itoa_old"; version = "0.4.8"; }
+        { rename = "itoa_new"; version = "1.0.6"; }
+      ];
+    };"#
+        ));
+    }
+
+    #[test]
+    fn deterministic() {
+        let path = PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .join("tests")
+            .join("workspace")
+            .join("parent");
+
+        let derivation = || {
+            let mut package = cargo::Package::from_current_dir(path.clone()).unwrap();
+            package.resolve();
+
+            let package: nix::Package = package.into();
+            package.into_derivative().unwrap()
+        };
+
+        assert_eq!(derivation(), derivation());
+    }
 }
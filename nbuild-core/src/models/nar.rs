@@ -0,0 +1,127 @@
+//! Hash a directory like nix does for a fixed-output path, by serializing it to a [NAR] archive
+//!
+//! [NAR]: https://nixos.org/manual/nix/stable/glossary.html#gloss-nar
+
+use std::{
+    fs::{self, FileType},
+    io,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+/// Get the sha256 of the NAR serialization of `path`, as a hex string. Entries below `path` for which `keep` returns
+/// `false` are left out, like the `filter` of `builtins.path`.
+pub(crate) fn hash_path(
+    path: &Path,
+    keep: &impl Fn(&Path, &FileType) -> bool,
+) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+
+    write_str(&mut hasher, b"nix-archive-1");
+    write_node(&mut hasher, path, keep)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write a single file, symlink or directory
+fn write_node(
+    hasher: &mut Sha256,
+    path: &Path,
+    keep: &impl Fn(&Path, &FileType) -> bool,
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    write_str(hasher, b"(");
+    write_str(hasher, b"type");
+
+    if file_type.is_symlink() {
+        write_str(hasher, b"symlink");
+        write_str(hasher, b"target");
+        write_str(hasher, fs::read_link(path)?.as_os_str().as_bytes());
+    } else if file_type.is_dir() {
+        write_str(hasher, b"directory");
+
+        // Nix sorts the entries by their raw names
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        for name in entries {
+            let entry_path = path.join(&name);
+
+            if !keep(&entry_path, &fs::symlink_metadata(&entry_path)?.file_type()) {
+                continue;
+            }
+
+            write_str(hasher, b"entry");
+            write_str(hasher, b"(");
+            write_str(hasher, b"name");
+            write_str(hasher, name.as_bytes());
+            write_str(hasher, b"node");
+            write_node(hasher, &entry_path, keep)?;
+            write_str(hasher, b")");
+        }
+    } else {
+        write_str(hasher, b"regular");
+
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(hasher, b"executable");
+            write_str(hasher, b"");
+        }
+
+        write_str(hasher, b"contents");
+        write_str(hasher, &fs::read(path)?);
+    }
+
+    write_str(hasher, b")");
+
+    Ok(())
+}
+
+/// Strings are prefixed with their length and padded to a multiple of 8 bytes
+fn write_str(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+    hasher.update(&[0; 8][..(8 - bytes.len() % 8) % 8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::hash_path;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+
+        fs::create_dir(path.join("src")).unwrap();
+        fs::write(path.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(path.join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(path.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("src/main.rs", path.join("link")).unwrap();
+
+        let hash = hash_path(path, &|_, _| true).unwrap();
+
+        assert_eq!(
+            hash,
+            "45b1e84df6dc808a35eaad6184c9f862a0cd068c0c52ea473f3bd9479e9f641e"
+        );
+
+        // Leaving out an entry is the same as it not being there
+        fs::write(path.join("notes.txt"), "skip me").unwrap();
+
+        assert_eq!(
+            hash_path(path, &|path, _| !path.ends_with("notes.txt")).unwrap(),
+            hash
+        );
+        assert_ne!(hash_path(path, &|_, _| true).unwrap(), hash);
+    }
+}
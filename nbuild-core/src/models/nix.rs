@@ -1,10 +1,75 @@
 //! This model is used to create / print a nix derivation.
 
-use std::{cell::RefCell, fs, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    fs::{self, FileType},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use cargo_metadata::{camino::Utf8PathBuf, semver::Version};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-use super::Source;
+use crate::Error;
+
+use super::{crate_cache_dirs, expr::Expr, nar, Bin, Source};
+
+/// Version of the stable rust toolchain used to build all the crates
+pub const RUST_VERSION: &str = "1.68.0";
+
+/// The rustup component with the cranelift codegen backend
+const CRANELIFT_COMPONENT: &str = "rustc-codegen-cranelift-preview";
+
+/// Where crates.io crates are downloaded from by default
+pub const CRATES_IO_MIRROR: &str = "https://static.crates.io/crates";
+
+/// Nix helper to fetch a crate from an alternative registry. The download location is read from the `dl` key in the
+/// registry's [config.json].
+///
+/// [config.json]: https://doc.rust-lang.org/cargo/reference/registry-index.html#index-configuration
+const REGISTRY_FETCHER: &str = r#"
+  fetchRegistryCrate = index: { crateName, version, sha256 }:
+    let
+      config = builtins.fromJSON (builtins.readFile (
+        if pkgs.lib.hasPrefix "sparse+" index
+        then builtins.fetchurl "${pkgs.lib.removePrefix "sparse+" index}/config.json"
+        else "${builtins.fetchGit (pkgs.lib.removePrefix "registry+" index)}/config.json"
+      ));
+      markers = [ "{crate}" "{version}" "{prefix}" "{lowerprefix}" "{sha256-checksum}" ];
+      length = builtins.stringLength crateName;
+      prefix =
+        if length <= 2 then toString length
+        else if length == 3 then "3/${builtins.substring 0 1 crateName}"
+        else "${builtins.substring 0 2 crateName}/${builtins.substring 2 2 crateName}";
+      url =
+        if builtins.any (marker: pkgs.lib.hasInfix marker config.dl) markers
+        then builtins.replaceStrings markers [ crateName version prefix (pkgs.lib.toLower prefix) sha256 ] config.dl
+        else "${config.dl}/${crateName}/${version}/download";
+    in
+      pkgs.fetchurl {
+        name = "${crateName}-${version}.tar.gz";
+        inherit url sha256;
+      };"#;
+
+/// The default `fetchCrate` function, which downloads crates.io crates from `@mirror@`
+const FETCH_CRATE: &str = r#"{ crateName, version, sha256 }: pkgs.fetchurl {
+    # https://www.pietroalbini.org/blog/downloading-crates-io/
+    # Not rate-limited, CDN URL.
+    name = "${crateName}-${version}.tar.gz";
+    url = "@mirror@/${crateName}/${crateName}-${version}.crate";
+    inherit sha256;
+  }"#;
+
+/// Nix packages, relative to `pkgs`, which common crates need to build. Each entry is the crate name with its
+/// `buildInputs` and `nativeBuildInputs`, which are added to its overrides when the crate is in the dependency tree.
+const BUILTIN_INPUTS: [(&str, &[&str], &[&str]); 1] =
+    [("openssl-sys", &["openssl"], &["pkg-config"])];
+
+/// Where crane is imported from for the [Backend::Crane] derivations
+const CRANE_URL: &str = "https://github.com/ipetkov/crane/archive/master.tar.gz";
 
 /// A package for a nix [buildRustCrate] block.
 ///
@@ -18,822 +83,4957 @@ pub struct Package {
     pub(super) lib_path: Option<Utf8PathBuf>,
     pub(super) build_path: Option<Utf8PathBuf>,
     pub(super) proc_macro: bool,
+    pub(super) crate_types: Vec<String>,
+    pub(super) bins: Vec<Bin>,
+    pub(super) examples: Vec<Bin>,
     pub(super) features: Vec<String>,
     pub(super) dependencies: Vec<Dependency>,
     pub(super) build_dependencies: Vec<Dependency>,
     pub(super) edition: String,
-    pub(super) printed: bool,
-}
 
-/// Used to keep track of the dependencies of a package and whether they have any renames.
-#[derive(Debug, PartialEq)]
-pub struct Dependency {
-    pub(super) package: Rc<RefCell<Package>>,
-    pub(super) rename: Option<String>,
+    /// The native library the crate links, from the `links` key of its manifest
+    pub(super) links: Option<String>,
+
+    /// Set when the v2 resolver built this crate with other features for the host than for the target. The host
+    /// variant then gets its own derivation.
+    pub(super) host: bool,
 }
 
-impl Package {
-    /// Write the package to a derivation file at `.nbuild.nix`
-    pub fn into_file(self) -> Result<(), std::io::Error> {
-        let expr = self.into_derivative();
+/// Options to customize the generated derivation. The default options reproduce the plain derivation.
+///
+/// The options can also be read from a `.nbuild.toml` config file, using kebab-case keys.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DerivationOptions {
+    /// The nix builder to build the package with
+    pub backend: Backend,
 
-        fs::write(".nbuild.nix", expr)
-    }
+    /// The compilation profile to build all the crates with
+    pub profile: Profile,
 
-    /// The name of the package
-    pub fn name(&self) -> &str {
-        &self.name
-    }
+    /// Version of the stable rust toolchain to build with, or `nightly` for the latest nightly toolchain and
+    /// `nightly-<date>` for the one of that date. Defaults to [RUST_VERSION].
+    pub rust_version: Option<String>,
 
-    /// Turn the package into a derivation string.
-    pub fn into_derivative(self) -> String {
-        let Self {
-            name,
-            version,
-            source,
-            lib_name: _,
-            lib_path: _,
-            build_path: _,
-            proc_macro: _,
-            features: _,
-            dependencies,
-            build_dependencies,
-            edition,
-            printed: _,
-        } = self;
+    /// Codegen backend for rustc to use, like `cranelift`. It is passed to every crate with `-Z codegen-backend`, so
+    /// the rust version has to be a nightly one.
+    pub codegen_backend: Option<String>,
 
-        // Used to append all the dependency details unto
-        let mut build_details = Default::default();
+    /// Arguments for the linker of every crate, passed to rustc with `-C link-arg`
+    pub link_args: Vec<String>,
 
-        let dep_idents: Vec<_> = dependencies
-            .into_iter()
-            .map(|d| {
-                let identifier = d.package.borrow().identifier();
-                Self::to_details(&d, &mut build_details);
-                identifier
-            })
-            .collect();
+    /// Hooks for every crate
+    pub hooks: Hooks,
 
-        let build_deps = if build_dependencies.is_empty() {
-            Default::default()
-        } else {
-            let dep_idents: Vec<_> = build_dependencies
-                .into_iter()
-                .map(|d| {
-                    let identifier = d.package.borrow().identifier();
-                    Self::to_details(&d, &mut build_details);
-                    identifier
-                })
-                .collect();
-            format!("\n    buildDependencies = [{}];", dep_idents.join(" "))
-        };
+    /// Overrides for individual crates, keyed by `name@version` or just `name`
+    pub crates: BTreeMap<String, CrateOptions>,
 
-        format!(
-            r#"{{ pkgs ? import <nixpkgs> {{
-  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
-}} }}:
+    /// Extra environment variables for the core crate only
+    pub env: BTreeMap<String, String>,
 
-let
-  sourceFilter = name: type:
-    let
-      baseName = builtins.baseNameOf (builtins.toString name);
-    in
-      ! (
-        # Filter out git
-        baseName == ".gitignore"
-        || (type == "directory" && baseName == ".git")
-
-        # Filter out build results
-        || (
-          type == "directory" && baseName == "target"
-        )
+    /// Only build this binary of the core crate, like `cargo build --bin`
+    pub bin: Option<String>,
 
-        # Filter out nix-build result symlinks
-        || (
-          type == "symlink" && pkgs.lib.hasPrefix "result" baseName
-        )
-      );
-  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
-  defaultCrateOverrides = pkgs.defaultCrateOverrides // {{
-    opentelemetry-proto = attrs: {{ buildInputs = [ pkgs.protobuf ]; }};
-  }};
-  fetchCrate = {{ crateName, version, sha256 }}: pkgs.fetchurl {{
-    # https://www.pietroalbini.org/blog/downloading-crates-io/
-    # Not rate-limited, CDN URL.
-    name = "${{crateName}}-${{version}}.tar.gz";
-    url = "https://static.crates.io/crates/${{crateName}}/${{crateName}}-${{version}}.crate";
-    inherit sha256;
-  }};
-  buildRustCrate = pkgs.buildRustCrate.override {{
-    rustc = rustVersion;
-    inherit defaultCrateOverrides fetchCrate;
-  }};
-  preBuild = "rustc -vV";
+    /// Build this example of the core crate instead of its binaries, like `cargo build --example`
+    pub example: Option<String>,
 
-  # Core
-  {} = buildRustCrate rec {{
-    crateName = "{}";
-    version = "{}";
+    /// Add a `default` attribute to the derivation of [Package::merge_into_derivative], pointing at the only package
+    /// with a bin target, so that `nix build` gives the app of a workspace instead of one of its libraries
+    pub workspace_default: bool,
 
-    {}
+    /// Name of the attribute holding the core crate, which is also what the file evaluates to. Defaults to the
+    /// crate's name. Dashes are replaced like they are for the crate names.
+    pub attr_name: Option<String>,
 
-    dependencies = [
-      {}
-    ];{}
-    edition = "{}";
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  }};
+    /// Add the crate overrides needed by shuttle projects
+    pub shuttle_defaults: bool,
 
-  # Dependencies
-{}
-in
-{}
-"#,
-            name,
-            name,
-            version,
-            Self::get_source(&source),
-            dep_idents.join("\n      "),
-            build_deps,
-            edition,
-            build_details.join("\n"),
-            name
-        )
-    }
+    /// Add `pkg-config` to the `nativeBuildInputs` of every crate which is named `*-sys` or declares `links`, since
+    /// their build scripts usually look for the native library with it
+    pub sys_heuristics: bool,
 
-    /// Recursively add a dependency unto `details`
-    fn to_details(dependency: &Dependency, build_details: &mut Vec<String>) {
-        let mut this = dependency.package.borrow_mut();
+    /// Build the test binaries of the core crate, which `buildRustCrate` puts in `$out/tests`
+    pub build_tests: bool,
 
-        // Only print once
-        if this.printed {
-            return;
+    /// Set `doctest` on the core crate. `buildRustCrate` does not run doctests itself, but passes the attribute on to
+    /// the derivation so that overrides and hooks can see it. Nothing is emitted when it is not set.
+    pub doctest: Option<bool>,
+
+    /// Strip the binaries of the core crate. Nix already strips the debug info from them, which `true` turns into
+    /// stripping all the symbols with `stripAllList`, while `false` keeps everything with `dontStrip`. Nothing is
+    /// emitted when it is not set.
+    pub strip: Option<bool>,
+
+    /// Base URL to download crates.io crates from. Defaults to [CRATES_IO_MIRROR].
+    pub crates_mirror: Option<String>,
+
+    /// Nix function to download crates.io crates with, which replaces the default [FETCH_CRATE]. It gets the
+    /// `crateName`, `version` and `sha256` of a crate, and `@mirror@` is replaced by the `crates_mirror`. This allows
+    /// another fetcher, or passing `curlOpts` for a mirror which needs authentication.
+    pub fetch_crate: Option<String>,
+
+    /// Take the crates.io crates from the `.crate` files in the local cargo cache instead of downloading them, for
+    /// builds without any network access. Every crate has to be in the cache already.
+    pub local_crates: bool,
+
+    /// Directories with the `.crate` files for `local_crates`. Defaults to the registry caches of `CARGO_HOME` when
+    /// empty.
+    #[serde(skip)]
+    pub crate_cache_dirs: Vec<PathBuf>,
+
+    /// Globs of extra file and directory names to leave out of local sources, on top of the `.git`, `target` and
+    /// `result*` entries which are always left out
+    pub ignore: Vec<String>,
+
+    /// Pin local crates to the sha256 of their filtered source, and refer to them relative to the `source_root`. The
+    /// derivation then does not depend on where the checkout lives.
+    pub hash_local_sources: bool,
+
+    /// Directory the derivation file is written to, which local sources are relative to when they are hashed
+    #[serde(skip)]
+    pub source_root: Option<PathBuf>,
+
+    /// Directory of the workspace the package is in, which the crane backend builds from so that cargo has the lock
+    /// file and the other members. The package's own directory is used when it is not set.
+    #[serde(skip)]
+    pub workspace_root: Option<PathBuf>,
+
+    /// Absolute path of the target directory of the workspace, from the cargo config or `CARGO_TARGET_DIR`. It is left
+    /// out of local sources on top of any `target` directory, since it can have another name.
+    #[serde(skip)]
+    pub target_dir: Option<PathBuf>,
+
+    /// Flags from the cargo config or the environment which are passed to rustc for every crate, before the
+    /// `extra-rustc-opts` of a crate
+    #[serde(skip)]
+    pub rustflags: Vec<String>,
+
+    /// Hash of the lock file the derivation is made from, as returned by [lock_file_hash]. It is written in a header
+    /// comment so that a stale derivation can be detected with [is_up_to_date].
+    #[serde(skip)]
+    pub lock_hash: Option<String>,
+
+    /// Features of the package from [cargo::MetadataOptions::features], which the crane backend passes on to cargo.
+    /// The `buildRustCrate` backend gets them from the resolved crates instead.
+    ///
+    /// [cargo::MetadataOptions::features]: super::cargo::MetadataOptions::features
+    #[serde(skip)]
+    pub features: Vec<String>,
+
+    /// Leave the default features of the package off in a crane build, like
+    /// [cargo::MetadataOptions::no_default_features]
+    ///
+    /// [cargo::MetadataOptions::no_default_features]: super::cargo::MetadataOptions::no_default_features
+    #[serde(skip)]
+    pub no_default_features: bool,
+
+    /// Triple to cross-compile for, as returned by [cargo::MetadataOptions::cross_target]. The package has to be
+    /// converted with [Package::from_cross] so that the crates for the host are built apart.
+    ///
+    /// [cargo::MetadataOptions::cross_target]: super::cargo::MetadataOptions::cross_target
+    #[serde(skip)]
+    pub target: Option<String>,
+
+    /// Nix system to import nixpkgs for, like `x86_64-linux`, when the derivation is one output of a
+    /// [combine_systems] file. Defaults to the system nix evaluates on.
+    #[serde(skip)]
+    pub system: Option<String>,
+}
+
+/// The nix builders a derivation can be made for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// A `buildRustCrate` derivation for every crate, so that nix can cache and rebuild each crate on its own
+    #[default]
+    BuildRustCrate,
+
+    /// A single [crane] `buildPackage` in which cargo builds all the crates, from a vendor directory made from
+    /// `Cargo.lock`. Cargo then picks the features itself, and only the bin, example, features, env, profile and
+    /// source options apply. Cross-compiling is not supported.
+    ///
+    /// [crane]: https://github.com/ipetkov/crane
+    Crane,
+}
+
+impl DerivationOptions {
+    /// Get the system to import nixpkgs for. That is the `crossSystem` when cross-compiling, or the `system` of one
+    /// output of a [combine_systems] file.
+    fn nixpkgs_system(&self) -> String {
+        match (&self.target, &self.system) {
+            (Some(target), _) => format!("\n  crossSystem.config = {};", nix_string(target)),
+            (None, Some(system)) => format!("\n  system = {};", nix_string(system)),
+            (None, None) => Default::default(),
         }
+    }
 
-        let features = if this.features.is_empty() {
-            Default::default()
-        } else {
-            format!(
-                "\n    features = [{}];",
-                this.features
-                    .iter()
-                    .map(|f| format!("\"{f}\""))
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            )
-        };
+    /// Get the version of the rust toolchain, which is the configured one or else [RUST_VERSION]
+    pub fn toolchain_version(&self) -> &str {
+        self.rust_version.as_deref().unwrap_or(RUST_VERSION)
+    }
 
-        let lib_name = if let Some(lib_name) = &this.lib_name {
-            format!("\n    libName = \"{lib_name}\";")
-        } else {
-            Default::default()
-        };
-        let lib_path = if let Some(lib_path) = &this.lib_path {
-            format!("\n    libPath = \"{lib_path}\";")
-        } else {
-            Default::default()
-        };
-        let build_path = if let Some(build_path) = &this.build_path {
-            format!("\n    build = \"{build_path}\";")
-        } else {
-            Default::default()
-        };
-        let proc_macro = if this.proc_macro {
-            "\n    procMacro = true;"
-        } else {
-            Default::default()
+    /// Check if the rust version is a nightly one
+    pub fn is_nightly(&self) -> bool {
+        self.toolchain_version().starts_with("nightly")
+    }
+
+    /// Get the rust toolchain. When cross-compiling it runs on the build platform and has the std of the target. The
+    /// cranelift backend is a component which has to be added to the toolchain.
+    fn rust_version(&self) -> String {
+        let (channel, version) = match self.toolchain_version().strip_prefix("nightly") {
+            Some("") => ("nightly", "latest".to_string()),
+            Some(date) => ("nightly", nix_string(date.trim_start_matches('-'))),
+            None => ("stable", nix_string(self.toolchain_version())),
         };
 
-        let mut renames = Vec::new();
+        let mut overrides = Vec::new();
 
-        let deps = if this.dependencies.is_empty() {
-            Default::default()
-        } else {
-            let dep_idents: Vec<_> = this
-                .dependencies
-                .iter()
-                .map(|d| {
-                    if let Some(rename) = &d.rename {
-                        renames.push((
-                            d.package.borrow().name.clone(),
-                            rename.clone(),
-                            d.package.borrow().version.to_string(),
-                        ));
-                    }
+        if let Some(target) = &self.target {
+            overrides.push(format!("targets = [ {} ];", nix_string(target)));
+        }
+        if self.codegen_backend.as_deref() == Some("cranelift") {
+            overrides.push(format!(
+                "extensions = [ {} ];",
+                nix_string(CRANELIFT_COMPONENT)
+            ));
+        }
 
-                    d.package.borrow().identifier()
-                })
-                .collect();
-            format!("\n    dependencies = [{}];", dep_idents.join(" "))
-        };
-        let build_deps = if this.build_dependencies.is_empty() {
-            Default::default()
+        let pkgs = if self.target.is_some() {
+            "pkgs.buildPackages"
         } else {
-            let dep_idents: Vec<_> = this
-                .build_dependencies
-                .iter()
-                .map(|d| {
-                    if let Some(rename) = &d.rename {
-                        renames.push((
-                            d.package.borrow().name.clone(),
-                            rename.clone(),
-                            d.package.borrow().version.to_string(),
-                        ));
-                    }
-
-                    d.package.borrow().identifier()
-                })
-                .collect();
-            format!("\n    buildDependencies = [{}];", dep_idents.join(" "))
+            "pkgs"
         };
-
-        let crate_renames = if renames.is_empty() {
+        let overrides = if overrides.is_empty() {
             Default::default()
         } else {
-            let renames = renames
-                .into_iter()
-                .map(|(name, rename, version)| {
-                    format!("\"{name}\" = [{{ rename = \"{rename}\"; version = \"{version}\"; }}];")
-                })
-                .collect::<Vec<_>>()
-                .join(" ");
-
-            format!("\n    crateRenames = {{{renames}}};")
+            format!(".override {{ {} }}", overrides.join(" "))
         };
 
-        let details = format!(
-            r#"  {} = buildRustCrate rec {{
-    crateName = "{}";{}
-    version = "{}";
-
-    {}{}{}{}{}{}{}{}
-    edition = "{}";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  }};"#,
-            this.identifier(),
-            this.name,
-            lib_name,
-            this.version,
-            Self::get_source(&this.source),
-            lib_path,
-            build_path,
-            proc_macro,
-            deps,
-            build_deps,
-            crate_renames,
-            features,
-            this.edition,
-        );
+        format!("{pkgs}.rust-bin.{channel}.{version}.default{overrides}")
+    }
 
-        build_details.push(details);
+    /// Make sure the argument pattern of the `fetch_crate` template takes all the arguments `buildRustCrate` passes to
+    /// it. A function which takes the arguments as a single attrset gets all of them.
+    fn check_fetch_crate(&self) -> Result<(), Error> {
+        let Some(formals) = self.fetch_crate.as_deref().and_then(function_formals) else {
+            return Ok(());
+        };
 
-        for dependency in this
-            .dependencies
-            .iter()
-            .chain(this.build_dependencies.iter())
+        match ["crateName", "version", "sha256"]
+            .into_iter()
+            .find(|argument| !formals.contains(argument))
         {
-            Self::to_details(dependency, build_details);
+            Some(argument) => Err(Error::InvalidFetchCrate(argument)),
+            None => Ok(()),
         }
-
-        this.printed = true;
     }
 
-    /// Helper to get a deterministic identifier for a package
-    fn identifier(&self) -> String {
-        format!(
-            "{}_{}",
-            self.name,
-            self.version.to_string().replace(['.', '+'], "_")
-        )
+    /// Get the `fetchCrate` function which downloads crates.io crates from the `crates_mirror`
+    fn fetch_crate(&self, crates_mirror: &str) -> String {
+        self.fetch_crate
+            .as_deref()
+            .unwrap_or(FETCH_CRATE)
+            .trim()
+            .replace("@mirror@", crates_mirror)
     }
 
-    /// Helper to get the source definition
-    fn get_source(source: &Source) -> String {
-        match source {
-            Source::Local(path) => format!(
-                "src = pkgs.lib.cleanSourceWith {{ filter = sourceFilter;  src = {}; }};",
-                path.display()
-            ),
-            Source::CratesIo(sha256) => format!("sha256 = \"{sha256}\";"),
+    /// Make sure the options can be passed on to cargo in a crane build of the `name` crate. Only the plain dev and
+    /// release profiles map to a cargo profile, and only the env of the core crate can be set.
+    fn check_crane(&self, name: &str, version: &Version) -> Result<(), Error> {
+        if self.target.is_some() {
+            return Err(Error::UnsupportedCraneOption("cross-compiling"));
+        }
+        if self.codegen_backend.is_some() {
+            return Err(Error::UnsupportedCraneOption("a codegen backend"));
+        }
+        if !self.link_args.is_empty() {
+            return Err(Error::UnsupportedCraneOption("link args"));
+        }
+        if ![Profile::default(), Profile::dev(), Profile::release()].contains(&self.profile) {
+            return Err(Error::UnsupportedCraneOption(
+                "profile settings other than the dev and release profiles",
+            ));
+        }
+        if self.hooks != Hooks::default() {
+            return Err(Error::UnsupportedCraneOption("hooks"));
+        }
+        if self.build_tests || self.doctest.is_some() {
+            return Err(Error::UnsupportedCraneOption("building tests"));
+        }
+        if self.strip.is_some() {
+            return Err(Error::UnsupportedCraneOption("strip"));
+        }
+        if self.fetch_crate.is_some() {
+            return Err(Error::UnsupportedCraneOption("a fetch-crate template"));
+        }
+        if self.local_crates {
+            return Err(Error::UnsupportedCraneOption("local crates"));
+        }
+        if self.sys_heuristics {
+            return Err(Error::UnsupportedCraneOption("the sys heuristics"));
+        }
+        if self.shuttle_defaults {
+            return Err(Error::UnsupportedCraneOption("the shuttle defaults"));
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{path::PathBuf, str::FromStr};
+        for (key, crate_options) in &self.crates {
+            let CrateOptions {
+                hooks,
+                extra_rustc_opts,
+                link_args,
+                skip_source_filter: _,
+                build_inputs,
+                native_build_inputs,
+                skip_builtin_inputs: _,
+                env,
+                edition,
+            } = crate_options;
+            let is_root = key == name || *key == format!("{name}@{version}");
 
-    use super::*;
+            if *hooks != Hooks::default() {
+                return Err(Error::UnsupportedCraneOption("per-crate hooks"));
+            }
+            if !extra_rustc_opts.is_empty() || !link_args.is_empty() {
+                return Err(Error::UnsupportedCraneOption("per-crate rustc options"));
+            }
+            if !build_inputs.is_empty() || !native_build_inputs.is_empty() {
+                return Err(Error::UnsupportedCraneOption("per-crate build inputs"));
+            }
+            if edition.is_some() {
+                return Err(Error::UnsupportedCraneOption("per-crate editions"));
+            }
+            if !is_root && !env.is_empty() {
+                return Err(Error::UnsupportedCraneOption(
+                    "env for crates other than the core crate",
+                ));
+            }
+        }
 
-    use pretty_assertions::assert_eq;
+        Ok(())
+    }
 
-    impl From<Package> for Dependency {
-        fn from(package: Package) -> Self {
-            Self {
-                package: Rc::new(RefCell::new(package)),
-                rename: None,
-            }
+    /// Make sure the codegen backend can be used with the rust version
+    fn check_codegen_backend(&self) -> Result<(), Error> {
+        match &self.codegen_backend {
+            Some(backend) if !self.is_nightly() => Err(Error::CodegenBackendNeedsNightly {
+                backend: backend.clone(),
+                toolchain: self.toolchain_version().to_string(),
+            }),
+            _ => Ok(()),
         }
     }
 
-    impl From<PathBuf> for Source {
-        fn from(path: PathBuf) -> Self {
-            Self::Local(path)
+    /// Get the binding of the builder for the crates which run on the build platform when cross-compiling
+    fn host_builder(&self) -> &'static str {
+        if self.target.is_some() {
+            "
+  buildRustCrateHost = pkgs.buildPackages.buildRustCrate.override {
+    rustc = rustVersion;
+    inherit defaultCrateOverrides fetchCrate;
+  };"
+        } else {
+            Default::default()
         }
     }
 
-    impl From<&str> for Source {
-        fn from(sha: &str) -> Self {
-            Self::CratesIo(sha.to_string())
+    /// Get the builder for a crate. Host crates only need another builder when cross-compiling.
+    fn builder(&self, host: bool) -> &'static str {
+        if host && self.target.is_some() {
+            "buildRustCrateHost"
+        } else {
+            "buildRustCrate"
         }
     }
 
-    #[test]
-    fn simple_package() {
-        let package = Package {
+    /// Read the options from a config file. The default options are used when the file does not exist.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            return Ok(Default::default());
+        }
+
+        toml::from_str(&fs::read_to_string(path)?).map_err(Error::Config)
+    }
+
+    /// Read the options from the `[package.metadata.nbuild]` table of a manifest, as returned by
+    /// [nbuild_metadata], with the config file at `path` on top of it. Both use the same keys.
+    ///
+    /// [nbuild_metadata]: super::cargo::nbuild_metadata
+    pub fn from_metadata_and_file(
+        metadata: &serde_json::Value,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        if metadata.is_null() {
+            return Self::from_file(path);
+        }
+
+        // Catch mistakes in the manifest with an error pointing there
+        Self::deserialize(metadata).map_err(Error::ManifestConfig)?;
+
+        let mut config = toml::Table::deserialize(metadata).map_err(Error::ManifestConfig)?;
+
+        if path.exists() {
+            merge_config(
+                &mut config,
+                toml::from_str(&fs::read_to_string(path)?).map_err(Error::Config)?,
+            );
+        }
+
+        toml::Value::Table(config).try_into().map_err(Error::Config)
+    }
+
+    /// Put the `overrides` of a caller, like the command line flags, on top of the options read from the config. The
+    /// options which are set in the `overrides` win, lists and maps are extended and flags are turned on by either.
+    /// The backend, profile and hooks of the `overrides` only win when they are not the defaults.
+    pub fn layer(mut self, overrides: &Self) -> Self {
+        if overrides.backend != Backend::default() {
+            self.backend = overrides.backend;
+        }
+        if overrides.profile != Profile::default() {
+            self.profile = overrides.profile.clone();
+        }
+        if overrides.hooks != Hooks::default() {
+            self.hooks = overrides.hooks.clone();
+        }
+
+        let Self {
+            rust_version,
+            codegen_backend,
+            link_args,
+            crates,
+            env,
+            bin,
+            example,
+            workspace_default,
+            attr_name,
+            shuttle_defaults,
+            sys_heuristics,
+            build_tests,
+            doctest,
+            strip,
+            crates_mirror,
+            fetch_crate,
+            local_crates,
+            crate_cache_dirs,
+            ignore,
+            hash_local_sources,
+            source_root,
+            workspace_root,
+            target_dir,
+            rustflags,
+            lock_hash,
+            features,
+            no_default_features,
+            target,
+            system,
+            ..
+        } = overrides.clone();
+
+        self.rust_version = rust_version.or(self.rust_version);
+        self.codegen_backend = codegen_backend.or(self.codegen_backend);
+        self.link_args.extend(link_args);
+        self.crates.extend(crates);
+        self.env.extend(env);
+        self.bin = bin.or(self.bin);
+        self.example = example.or(self.example);
+        self.workspace_default |= workspace_default;
+        self.attr_name = attr_name.or(self.attr_name);
+        self.shuttle_defaults |= shuttle_defaults;
+        self.sys_heuristics |= sys_heuristics;
+        self.build_tests |= build_tests;
+        self.doctest = doctest.or(self.doctest);
+        self.strip = strip.or(self.strip);
+        self.crates_mirror = crates_mirror.or(self.crates_mirror);
+        self.fetch_crate = fetch_crate.or(self.fetch_crate);
+        self.local_crates |= local_crates;
+        self.crate_cache_dirs.extend(crate_cache_dirs);
+        self.ignore.extend(ignore);
+        self.hash_local_sources |= hash_local_sources;
+        self.source_root = source_root.or(self.source_root);
+        self.workspace_root = workspace_root.or(self.workspace_root);
+        self.target_dir = target_dir.or(self.target_dir);
+        self.rustflags.extend(rustflags);
+        self.lock_hash = lock_hash.or(self.lock_hash);
+        self.features.extend(features);
+        self.no_default_features |= no_default_features;
+        self.target = target.or(self.target);
+        self.system = system.or(self.system);
+
+        self
+    }
+
+    /// Get the overrides for a crate. An entry for the exact version wins over an entry for just the name.
+    fn crate_options(&self, name: &str, version: &Version) -> Option<&CrateOptions> {
+        self.crates
+            .get(&format!("{name}@{version}"))
+            .or_else(|| self.crates.get(name))
+    }
+
+    /// Get the edition to build a crate with, which is the `edition` of its manifest unless the config overrides it
+    fn edition<'a>(&self, name: &str, version: &Version, edition: &'a str) -> &'a str {
+        self.crate_options(name, version)
+            .and_then(|c| c.edition)
+            .map_or(edition, |edition| edition.as_str())
+    }
+
+    /// Get the profile attributes of a crate, including its extra rustc options
+    fn profile_attributes(
+        &self,
+        name: &str,
+        version: &Version,
+        is_root: bool,
+        proc_macro: bool,
+    ) -> String {
+        let mut extra_opts = self.rustflags.clone();
+        extra_opts.extend(
+            self.codegen_backend
+                .iter()
+                .map(|backend| format!("-Z codegen-backend={backend}")),
+        );
+        extra_opts.extend(
+            self.link_args
+                .iter()
+                .map(|arg| format!("-C link-arg={arg}")),
+        );
+
+        if let Some(crate_options) = self.crate_options(name, version) {
+            extra_opts.extend(crate_options.extra_rustc_opts.iter().cloned());
+            extra_opts.extend(
+                crate_options
+                    .link_args
+                    .iter()
+                    .map(|arg| format!("-C link-arg={arg}")),
+            );
+        }
+
+        self.profile.to_attributes(is_root, proc_macro, &extra_opts)
+    }
+
+    /// Get the extra condition of the source filter for the `ignore` globs
+    fn ignore_filter(&self) -> String {
+        if self.ignore.is_empty() {
+            return Default::default();
+        }
+
+        let patterns = self
+            .ignore
+            .iter()
+            .map(|glob| nix_string(&glob_to_regex(glob)))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!(
+            "\n\n      # Filter out the configured globs\n      || builtins.any (pattern: builtins.match pattern baseName != null) [ {patterns} ]"
+        )
+    }
+
+    /// Get the extra condition of the source filter for the `target_dir`. Like the local sources, it is relative to
+    /// the `source_root` when there is one, so that the derivation can be moved along with the workspace.
+    fn target_dir_filter(&self) -> String {
+        let Some(target_dir) = &self.target_dir else {
+            return Default::default();
+        };
+
+        let target_dir = match &self.source_root {
+            Some(root) => {
+                let canonicalize =
+                    |path: &Path| fs::canonicalize(path).unwrap_or(path.to_path_buf());
+
+                format!(
+                    "builtins.toString ({})",
+                    relative_nix_path(&canonicalize(root), &canonicalize(target_dir))
+                )
+            }
+            None => nix_string(&target_dir.to_string_lossy()),
+        };
+
+        format!(
+            "\n\n      # Filter out the target directory of the workspace\n      || (type == \"directory\" && builtins.toString name == {target_dir})"
+        )
+    }
+
+    /// Get the `sourceFilter` binding, which leaves files out of local sources that do not affect the build
+    fn source_filter(&self) -> String {
+        format!(
+            r#"  sourceFilter = name: type:
+    let
+      baseName = builtins.baseNameOf (builtins.toString name);
+    in
+    ! (
+      # Filter out git
+      baseName == ".gitignore"
+      || (type == "directory" && baseName == ".git")
+
+      # Filter out build results
+      || (
+        type == "directory" && baseName == "target"
+      )
+
+      # Filter out nix-build result symlinks
+      || (
+        type == "symlink" && pkgs.lib.hasPrefix "result" baseName
+      ){}{}
+    );"#,
+            self.target_dir_filter(),
+            self.ignore_filter()
+        )
+    }
+
+    /// Same as the `sourceFilter` in the derivation, to leave the same entries out of the hashed sources
+    fn keep_in_source(&self, path: &Path, file_type: &FileType) -> bool {
+        let Some(base_name) = path.file_name().and_then(|n| n.to_str()) else {
+            return true;
+        };
+
+        !(base_name == ".gitignore"
+            || (file_type.is_dir() && (base_name == ".git" || base_name == "target"))
+            || (file_type.is_dir() && self.target_dir.as_deref() == Some(path))
+            || (file_type.is_symlink() && base_name.starts_with("result"))
+            || self.ignore.iter().any(|glob| glob_matches(glob, base_name)))
+    }
+
+    /// Get the source definition of a crate. Local crates are hashed when `hash_local_sources` is set, and crates.io
+    /// crates are taken from the cargo cache when `local_crates` is set.
+    fn source(
+        &self,
+        name: &str,
+        version: &Version,
+        source: &Source,
+        filter: bool,
+    ) -> Result<String, Error> {
+        let path = match source {
+            Source::Local(path) if self.hash_local_sources => path,
+            Source::CratesIo(sha256) if self.local_crates => {
+                return self.local_crate(name, version, sha256)
+            }
+            source => return Ok(Package::get_source(source, filter)),
+        };
+
+        let hash = nar::hash_path(path, &|path, file_type| {
+            !filter || self.keep_in_source(path, file_type)
+        })?;
+        let nix_path = match &self.source_root {
+            Some(root) => relative_nix_path(&fs::canonicalize(root)?, &fs::canonicalize(path)?),
+            None => path.display().to_string(),
+        };
+        let mut attrs = vec![
+            ("path".to_string(), Expr::raw(nix_path)),
+            ("name".to_string(), Expr::raw("\"source\"")),
+        ];
+
+        if filter {
+            attrs.push(("filter".to_string(), Expr::raw("sourceFilter")));
+        }
+
+        attrs.push(("sha256".to_string(), Expr::Raw(nix_string(&hash))));
+
+        Ok(Expr::prefixed("builtins.path ", Expr::Attrs(attrs)).binding("src", 4))
+    }
+
+    /// Get the source of a crates.io crate from its `.crate` file in the cargo cache. The file is copied to the store
+    /// as is, so its flat hash is the checksum from the lock file.
+    fn local_crate(&self, name: &str, version: &Version, sha256: &str) -> Result<String, Error> {
+        let file_name = format!("{name}-{version}.crate");
+        let default_dirs;
+        let cache_dirs = if self.crate_cache_dirs.is_empty() {
+            default_dirs = crate_cache_dirs();
+            &default_dirs
+        } else {
+            &self.crate_cache_dirs
+        };
+
+        let path = cache_dirs
+            .iter()
+            .map(|dir| dir.join(&file_name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| Error::MissingLocalCrate {
+                name: name.to_string(),
+                version: version.to_string(),
+            })?;
+        let attrs = vec![
+            (
+                "path".to_string(),
+                Expr::Raw(nix_string(&path.display().to_string())),
+            ),
+            (
+                "name".to_string(),
+                Expr::Raw(nix_string(&format!("{name}-{version}.tar.gz"))),
+            ),
+            ("recursive".to_string(), Expr::raw("false")),
+            ("sha256".to_string(), Expr::Raw(nix_string(sha256))),
+        ];
+
+        Ok(Expr::prefixed("builtins.path ", Expr::Attrs(attrs)).binding("src", 4))
+    }
+
+    /// Check if the source of a local crate should go through the source filter
+    fn filter_source(&self, name: &str, version: &Version) -> bool {
+        !self
+            .crate_options(name, version)
+            .is_some_and(|c| c.skip_source_filter)
+    }
+
+    /// Get the value of the `defaultCrateOverrides` binding. Crates with build inputs get an override on top of the
+    /// nixpkgs defaults, which appends the inputs to those of the nixpkgs override of the crate. The [BUILTIN_INPUTS]
+    /// are only added for the `crates` in the dependency tree, and the `sys_heuristics` for those of them which are
+    /// named `*-sys` or are in the `links` set. A crate which skips the built-in inputs replaces the nixpkgs override.
+    fn crate_overrides<'a>(
+        &'a self,
+        crates: impl IntoIterator<Item = &'a str>,
+        links: &BTreeSet<String>,
+    ) -> Expr {
+        // The `buildInputs` and `nativeBuildInputs` of each crate
+        let mut inputs: BTreeMap<&str, (BTreeSet<&str>, BTreeSet<&str>)> = BTreeMap::new();
+
+        let crates: BTreeSet<_> = crates.into_iter().collect();
+
+        for (name, build_inputs, native_build_inputs) in BUILTIN_INPUTS {
+            if crates.contains(name) && !self.skip_builtin_inputs(name) {
+                let (build, native) = inputs.entry(name).or_default();
+                build.extend(build_inputs);
+                native.extend(native_build_inputs);
+            }
+        }
+
+        if self.sys_heuristics {
+            for name in &crates {
+                if (name.ends_with("-sys") || links.contains(*name))
+                    && !self.skip_builtin_inputs(name)
+                {
+                    inputs.entry(name).or_default().1.insert("pkg-config");
+                }
+            }
+        }
+
+        if self.shuttle_defaults {
+            inputs
+                .entry("opentelemetry-proto")
+                .or_default()
+                .0
+                .insert("protobuf");
+        }
+
+        // Overrides apply to all versions of a crate
+        for (key, crate_options) in &self.crates {
+            let (build, native) = inputs.entry(crate_name(key)).or_default();
+
+            build.extend(crate_options.build_inputs.iter().map(String::as_str));
+            native.extend(crate_options.native_build_inputs.iter().map(String::as_str));
+        }
+
+        let overrides: Vec<_> = inputs
+            .into_iter()
+            .filter(|(_, (build, native))| !build.is_empty() || !native.is_empty())
+            .map(|(name, (build, native))| {
+                let replace = self.skip_builtin_inputs(name);
+                let to_list = |attribute: &str, inputs: BTreeSet<&str>| {
+                    let list = Expr::List(
+                        inputs
+                            .into_iter()
+                            .map(|input| Expr::raw(format!("pkgs.{input}")))
+                            .collect(),
+                    );
+
+                    if replace {
+                        list
+                    } else {
+                        Expr::Concat(vec![
+                            Expr::raw(format!("(old.{attribute} or [ ])")),
+                            list,
+                        ])
+                    }
+                };
+                let mut attrs = Vec::new();
+
+                for (attribute, inputs) in [("buildInputs", build), ("nativeBuildInputs", native)] {
+                    if !inputs.is_empty() {
+                        attrs.push((attribute.to_string(), to_list(attribute, inputs)));
+                    }
+                }
+
+                let attribute = nix_attribute(name);
+                let prefix = if replace {
+                    "attrs: ".to_string()
+                } else {
+                    format!(
+                        "attrs: let old = (pkgs.defaultCrateOverrides.{attribute} or (_: {{ }})) attrs; in old // "
+                    )
+                };
+
+                (attribute, Expr::prefixed(prefix, Expr::Attrs(attrs)))
+            })
+            .collect();
+
+        if overrides.is_empty() {
+            Expr::raw("pkgs.defaultCrateOverrides")
+        } else {
+            Expr::prefixed("pkgs.defaultCrateOverrides // ", Expr::Attrs(overrides))
+        }
+    }
+
+    /// Check if the [BUILTIN_INPUTS] of a crate are replaced by the config
+    fn skip_builtin_inputs(&self, name: &str) -> bool {
+        self.crates.iter().any(|(key, crate_options)| {
+            crate_name(key) == name && crate_options.skip_builtin_inputs
+        })
+    }
+
+    /// Get the hook, test and environment attributes of a crate
+    fn crate_attributes(&self, name: &str, version: &Version, is_root: bool) -> String {
+        format!(
+            "{}{}{}",
+            self.hooks
+                .to_attributes(self.crate_options(name, version).map(|c| &c.hooks)),
+            if is_root {
+                self.test_attributes() + self.strip_attributes()
+            } else {
+                Default::default()
+            },
+            self.env_attributes(name, version, is_root)
+        )
+    }
+
+    /// Get the test attributes of the core crate
+    fn test_attributes(&self) -> String {
+        let mut attributes = String::new();
+
+        if self.build_tests {
+            attributes.push_str("\n    buildTests = true;");
+        }
+
+        if let Some(doctest) = self.doctest {
+            attributes.push_str(&format!("\n    doctest = {doctest};"));
+        }
+
+        attributes
+    }
+
+    /// Get the strip attributes of the core crate
+    fn strip_attributes(&self) -> &'static str {
+        match self.strip {
+            Some(true) => "\n    stripAllList = [ \"bin\" ];",
+            Some(false) => "\n    dontStrip = true;",
+            None => "",
+        }
+    }
+
+    /// Get the environment attributes of a crate. The core crate also gets the global `env`.
+    fn env_attributes(&self, name: &str, version: &Version, is_root: bool) -> String {
+        let mut env = self
+            .crate_options(name, version)
+            .map(|c| c.env.clone())
+            .unwrap_or_default();
+
+        if is_root {
+            env.extend(self.env.clone());
+        }
+
+        env.into_iter()
+            .map(|(key, value)| format!("\n    {} = {};", nix_attribute(&key), nix_string(&value)))
+            .collect()
+    }
+
+    /// Get the bindings for the crane library. A crates mirror replaces the download location of crates.io.
+    fn crane_bindings(&self) -> String {
+        let Some(crates_mirror) = &self.crates_mirror else {
+            return "craneLib = crane.overrideToolchain (p: rustVersion);".to_string();
+        };
+
+        let registry = Expr::prefixed(
+            "crane.registryFromDownloadUrl ",
+            Expr::Attrs(vec![
+                (
+                    "indexUrl".to_string(),
+                    Expr::raw("\"https://github.com/rust-lang/crates.io-index\""),
+                ),
+                (
+                    "dl".to_string(),
+                    Expr::Raw(nix_string(&format!(
+                        "{}/{{crate}}/{{crate}}-{{version}}.crate",
+                        crates_mirror.trim_end_matches('/')
+                    ))),
+                ),
+            ]),
+        );
+
+        format!(
+            "{}\n  craneLib = (crane.overrideToolchain (p: rustVersion)).appendCrateRegistries [ cratesMirror ];",
+            registry.binding("cratesMirror", 2)
+        )
+    }
+}
+
+/// Overrides for a single crate
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct CrateOptions {
+    /// Hooks which replace the global hooks for this crate
+    pub hooks: Hooks,
+
+    /// Extra flags for rustc, which are added after the flags of the profile
+    pub extra_rustc_opts: Vec<String>,
+
+    /// Arguments for the linker of this crate, which are added after the global `link-args`
+    pub link_args: Vec<String>,
+
+    /// Use the local source of this crate as is, without filtering out any files
+    pub skip_source_filter: bool,
+
+    /// Nix packages, relative to `pkgs`, which are needed to build this crate. These are added to the
+    /// `defaultCrateOverrides` of the crate.
+    pub build_inputs: Vec<String>,
+
+    /// Nix packages, relative to `pkgs`, which run while building this crate, like `pkg-config` or `cmake`. These
+    /// are added to the `defaultCrateOverrides` of the crate.
+    pub native_build_inputs: Vec<String>,
+
+    /// Leave out the inputs nbuild adds for well-known crates, like `openssl-sys`, so that only the inputs of the
+    /// config are used
+    pub skip_builtin_inputs: bool,
+
+    /// Environment variables to set while building this crate. `buildRustCrate` passes unknown attributes on to the
+    /// derivation, which makes them available to build scripts too.
+    pub env: BTreeMap<String, String>,
+
+    /// Build this crate with another edition than the one in its manifest
+    pub edition: Option<Edition>,
+}
+
+/// The rust editions a crate can be built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Edition {
+    #[serde(rename = "2015")]
+    E2015,
+    #[serde(rename = "2018")]
+    E2018,
+    #[serde(rename = "2021")]
+    E2021,
+    #[serde(rename = "2024")]
+    E2024,
+}
+
+impl Edition {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::E2015 => "2015",
+            Self::E2018 => "2018",
+            Self::E2021 => "2021",
+            Self::E2024 => "2024",
+        }
+    }
+}
+
+/// Shell snippets to run at the phases of a `buildRustCrate` build
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Hooks {
+    /// Runs before the build script of the crate. The hook of a crate replaces the global one.
+    pub pre_configure: Option<String>,
+
+    /// Runs after the build script, before rustc compiles the crate. Defaults to `rustc -vV` when used for every
+    /// crate. The hook of a crate replaces the global one.
+    pub pre_build: Option<String>,
+
+    /// Runs after rustc compiled the crate, before it is installed. The hook of a crate replaces the global one.
+    pub post_build: Option<String>,
+}
+
+impl Hooks {
+    /// Get the `(attribute, script)` pairs of the hooks which are set
+    fn entries(&self) -> Vec<(&'static str, &str)> {
+        Self::set_entries([
+            ("preConfigure", self.pre_configure.as_deref()),
+            ("preBuild", self.pre_build.as_deref()),
+            ("postBuild", self.post_build.as_deref()),
+        ])
+    }
+
+    /// Get the hooks shared by every crate. These become bindings in the `let` block of the derivation.
+    fn global_entries(&self) -> Vec<(&'static str, &str)> {
+        Self::set_entries([
+            ("preConfigure", self.pre_configure.as_deref()),
+            (
+                "preBuild",
+                Some(self.pre_build.as_deref().unwrap_or("rustc -vV")),
+            ),
+            ("postBuild", self.post_build.as_deref()),
+        ])
+    }
+
+    fn set_entries<'a>(
+        entries: [(&'static str, Option<&'a str>); 3],
+    ) -> Vec<(&'static str, &'a str)> {
+        entries
+            .into_iter()
+            .filter_map(|(attribute, script)| script.map(|s| (attribute, s)))
+            .collect()
+    }
+
+    /// Get the `let` bindings for the global hooks
+    fn to_bindings(&self) -> String {
+        self.global_entries()
+            .into_iter()
+            .map(|(attribute, script)| format!("  {attribute} = {};", nix_string(script)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Get the hook attributes of a crate. Global hooks are inherited unless the crate overrides them.
+    fn to_attributes(&self, crate_hooks: Option<&Hooks>) -> String {
+        let crate_entries = crate_hooks.map(Hooks::entries).unwrap_or_default();
+
+        let inherited: Vec<_> = self
+            .global_entries()
+            .into_iter()
+            .map(|(attribute, _)| attribute)
+            .filter(|attribute| !crate_entries.iter().any(|(a, _)| a == attribute))
+            .collect();
+
+        let mut attributes: Vec<_> = crate_entries
+            .into_iter()
+            .map(|(attribute, script)| format!("{attribute} = {};", nix_string(script)))
+            .collect();
+
+        if !inherited.is_empty() {
+            attributes.push(format!("inherit {};", inherited.join(" ")));
+        }
+
+        attributes.join("\n    ")
+    }
+}
+
+/// Quote a string for nix, escaping anything that would otherwise be interpreted. Hooks are plain shell scripts, so
+/// `${VAR}` is left for the shell to expand.
+fn nix_string(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+/// Get a list of quoted strings
+fn nix_strings(values: &[impl AsRef<str>]) -> Expr {
+    Expr::List(
+        values
+            .iter()
+            .map(|value| Expr::Raw(nix_string(value.as_ref())))
+            .collect(),
+    )
+}
+
+/// A part of a glob from the `ignore` config. Only `*` and `?` are special in the glob.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GlobToken {
+    /// `*`, which matches any number of characters
+    Any,
+
+    /// `?`, which matches a single character
+    One,
+
+    Char(char),
+}
+
+/// Split a glob into its tokens, which both [glob_to_regex] and [glob_matches] work from so that they always agree
+fn glob_tokens(glob: &str) -> Vec<GlobToken> {
+    glob.chars()
+        .map(|c| match c {
+            '*' => GlobToken::Any,
+            '?' => GlobToken::One,
+            c => GlobToken::Char(c),
+        })
+        .collect()
+}
+
+/// Get the names in the `{ a, b ? default, ... }:` argument pattern of a nix function, or `None` when the function does
+/// not start with a pattern, like `args: ...`. The pattern can be bound to a name with `args@{ ... }:`.
+fn function_formals(function: &str) -> Option<Vec<&str>> {
+    let function = function.trim_start();
+    let pattern = match function.split_once('@') {
+        Some((name, pattern))
+            if !name.is_empty()
+                && name
+                    .trim_end()
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\'')) =>
+        {
+            pattern.trim_start()
+        }
+        _ => function,
+    };
+    let (formals, _) = pattern.strip_prefix('{')?.split_once('}')?;
+
+    Some(
+        formals
+            .split(',')
+            .filter_map(|formal| formal.split('?').next())
+            .map(str::trim)
+            .filter(|formal| !formal.is_empty() && *formal != "...")
+            .collect(),
+    )
+}
+
+/// Turn a glob into a regex for `builtins.match`
+fn glob_to_regex(glob: &str) -> String {
+    glob_tokens(glob)
+        .into_iter()
+        .map(|token| match token {
+            GlobToken::Any => ".*".to_string(),
+            GlobToken::One => ".".to_string(),
+            GlobToken::Char(c) if "\\^$.|+()[]{}".contains(c) => format!("\\{c}"),
+            GlobToken::Char(c) => c.to_string(),
+        })
+        .collect()
+}
+
+/// Check if a name matches a glob, in the same way as the regex from [glob_to_regex] does
+fn glob_matches(glob: &str, name: &str) -> bool {
+    fn matches(glob: &[GlobToken], name: &[char]) -> bool {
+        match glob.split_first() {
+            None => name.is_empty(),
+            Some((GlobToken::Any, rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+            Some((GlobToken::One, rest)) => !name.is_empty() && matches(rest, &name[1..]),
+            Some((GlobToken::Char(c), rest)) => {
+                name.first() == Some(c) && matches(rest, &name[1..])
+            }
+        }
+    }
+
+    matches(&glob_tokens(glob), &name.chars().collect::<Vec<_>>())
+}
+
+/// Get a nix path expression for `path` relative to the `root` the derivation file is in. Both need to be absolute.
+fn relative_nix_path(root: &Path, path: &Path) -> String {
+    let common = root
+        .components()
+        .zip(path.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let relative = root
+        .components()
+        .skip(common)
+        .map(|_| "..".to_string())
+        .chain(
+            path.components()
+                .skip(common)
+                .map(|c| c.as_os_str().to_string_lossy().to_string()),
+        )
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let is_literal = relative
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-+/".contains(c));
+
+    match relative.as_str() {
+        "" => "./.".to_string(),
+        relative if !is_literal => format!("./. + {}", nix_string(&format!("/{relative}"))),
+        relative if relative.starts_with("..") => relative.to_string(),
+        relative => format!("./{relative}"),
+    }
+}
+
+/// Get the crate name of a key in the `crates` config, which is either `name` or `name@version`
+fn crate_name(key: &str) -> &str {
+    key.split_once('@').map_or(key, |(name, _)| name)
+}
+
+/// Get a nix attribute name, which only needs to be quoted when it is not a valid identifier
+fn nix_attribute(name: &str) -> String {
+    let mut chars = name.chars();
+    let is_identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+
+    if is_identifier {
+        name.to_string()
+    } else {
+        nix_string(name)
+    }
+}
+
+/// Put the `overrides` on top of the `config`. Tables are merged key by key, while any other value is replaced.
+fn merge_config(config: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(value)) => {
+                merge_config(table, value)
+            }
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Get the sha256 of a lock file
+pub fn lock_file_hash(path: impl AsRef<Path>) -> Result<String, Error> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(path)?)))
+}
+
+/// Check if a derivation was generated from the lock file with `lock_hash` by this version of nbuild
+pub fn is_up_to_date(derivation: &str, lock_hash: &str) -> bool {
+    derivation.lines().next() == Some(header(lock_hash).as_str())
+}
+
+/// The header comment of a derivation made from the lock file with `lock_hash`
+fn header(lock_hash: &str) -> String {
+    format!(
+        "# Generated by nbuild {} from Cargo.lock with sha256 {lock_hash}",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Get the nix system of a target triple, like `x86_64-linux` for `x86_64-unknown-linux-gnu`. Only the triples nixpkgs
+/// builds natively for a system have one, others like musl need to be cross-compiled.
+pub fn nix_system(triple: &str) -> Option<String> {
+    let (arch, os) = match triple.split('-').collect::<Vec<_>>()[..] {
+        [arch, "unknown", "linux", "gnu"] => (arch, "linux"),
+        [arch, "apple", "darwin"] => (arch, "darwin"),
+        _ => return None,
+    };
+
+    Some(format!("{arch}-{os}"))
+}
+
+/// Put the derivations of the same package for different systems into one file, which evaluates to an attribute set
+/// keyed by system. Each derivation is called with its default `pkgs`, so it should have been made for its own system.
+/// The crate sources are fixed-output derivations, so nix only fetches the ones the systems share once.
+pub fn combine_systems(derivations: Vec<(String, String)>, lock_hash: Option<&str>) -> String {
+    let header = lock_hash
+        .map(|lock_hash| format!("{}\n", header(lock_hash)))
+        .unwrap_or_default();
+
+    let outputs: String = derivations
+        .into_iter()
+        .map(|(system, derivation)| {
+            let derivation: Vec<_> = derivation
+                .lines()
+                .map(|line| match line {
+                    "" => String::new(),
+                    line => format!("    {line}"),
+                })
+                .collect();
+
+            format!(
+                "  {} = (\n{}\n  ) {{ }};\n",
+                nix_attribute(&system),
+                derivation.join("\n")
+            )
+        })
+        .collect();
+
+    format!("{header}{{\n{outputs}}}\n")
+}
+
+/// Turn a crate name into a nix identifier by replacing its dashes, which would need quoting in some places
+fn nix_identifier(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Compilation settings for all the crates, similar to a [cargo profile][profile]. Settings which are `None` are left
+/// to the `buildRustCrate` defaults.
+///
+/// [profile]: https://doc.rust-lang.org/cargo/reference/profiles.html
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Profile {
+    /// Maps to the `release` attribute of `buildRustCrate`
+    pub release: Option<bool>,
+    pub opt_level: Option<String>,
+    pub debug: Option<bool>,
+
+    /// Only applied to the core crate. The dependencies then keep their bitcode so that it can be used when linking.
+    pub lto: Option<String>,
+
+    /// Not applied to proc-macro crates since they need to unwind inside the compiler
+    pub panic: Option<String>,
+    pub codegen_units: u32,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            release: None,
+            opt_level: None,
+            debug: None,
+            lto: None,
+            panic: None,
+            codegen_units: 16,
+        }
+    }
+}
+
+impl Profile {
+    /// The settings of cargo's `dev` profile
+    pub fn dev() -> Self {
+        Self {
+            release: Some(false),
+            opt_level: Some("0".to_string()),
+            debug: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// The settings of cargo's `release` profile
+    pub fn release() -> Self {
+        Self {
+            release: Some(true),
+            opt_level: Some("3".to_string()),
+            debug: Some(false),
+            ..Default::default()
+        }
+    }
+
+    /// Get the `buildRustCrate` attributes for a crate built with this profile. The `extra_opts` of the crate are
+    /// added after the options from the profile.
+    fn to_attributes(&self, is_root: bool, proc_macro: bool, extra_opts: &[String]) -> String {
+        let mut attributes = Vec::new();
+
+        if let Some(release) = self.release {
+            attributes.push(format!("release = {release};"));
+        }
+
+        attributes.push(format!("codegenUnits = {};", self.codegen_units));
+
+        let mut opts = Vec::new();
+
+        // Bitcode is needed by the linker when doing link time optimizations
+        if self.lto.is_none() {
+            opts.push("-C embed-bitcode=no".to_string());
+        }
+
+        if let Some(opt_level) = &self.opt_level {
+            opts.push(format!("-C opt-level={opt_level}"));
+        }
+
+        if let Some(debug) = self.debug {
+            opts.push(format!("-C debuginfo={}", if debug { 2 } else { 0 }));
+        }
+
+        if let (Some(lto), true) = (&self.lto, is_root) {
+            opts.push(format!("-C lto={lto}"));
+        }
+
+        if let (Some(panic), false) = (&self.panic, proc_macro) {
+            opts.push(format!("-C panic={panic}"));
+        }
+
+        opts.extend(extra_opts.iter().cloned());
+
+        attributes.push(nix_strings(&opts).binding("extraRustcOpts", 4));
+
+        attributes.join("\n    ")
+    }
+}
+
+/// Used to keep track of the dependencies of a package and whether they have any renames.
+#[derive(Debug, PartialEq)]
+pub struct Dependency {
+    pub(super) package: Rc<RefCell<Package>>,
+    pub(super) rename: Option<String>,
+
+    /// The target triples this dependency is limited to. It is empty when the dependency is used on all platforms.
+    pub(super) platforms: Vec<String>,
+}
+
+/// Constructors to assemble packages by hand, so that tests outside this crate can check the derivation of a known
+/// graph. Enable the `testing` feature to use them.
+#[cfg(any(test, feature = "testing"))]
+impl Package {
+    /// Create a library crate without features or dependencies. Panics when the `version` is not valid semver.
+    pub fn new(name: impl Into<String>, version: &str, source: Source) -> Self {
+        Self {
+            name: name.into(),
+            version: version.parse().expect("a valid version"),
+            source,
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        }
+    }
+
+    /// Set the enabled features
+    pub fn with_features(mut self, features: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.features = features.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add a binary target
+    pub fn with_bin(mut self, name: impl Into<String>, path: impl Into<Utf8PathBuf>) -> Self {
+        self.bins.push(Bin {
+            name: name.into(),
+            path: path.into(),
+        });
+        self
+    }
+
+    /// Set the edition
+    pub fn with_edition(mut self, edition: impl Into<String>) -> Self {
+        self.edition = edition.into();
+        self
+    }
+
+    /// Set the native library the crate links
+    pub fn with_links(mut self, links: impl Into<String>) -> Self {
+        self.links = Some(links.into());
+        self
+    }
+
+    /// Mark the crate as a proc-macro
+    pub fn with_proc_macro(mut self) -> Self {
+        self.proc_macro = true;
+        self
+    }
+
+    /// Add a normal dependency. The `rename` is the name the crate is imported as, when it differs from its own.
+    pub fn with_dependency(mut self, package: Package, rename: Option<&str>) -> Self {
+        self.dependencies.push(Dependency::new(package, rename));
+        self
+    }
+
+    /// Add a build dependency
+    pub fn with_build_dependency(mut self, package: Package) -> Self {
+        self.build_dependencies.push(Dependency::new(package, None));
+        self
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Dependency {
+    fn new(package: Package, rename: Option<&str>) -> Self {
+        Self {
+            package: Rc::new(RefCell::new(package)),
+            rename: rename.map(String::from),
+            platforms: Default::default(),
+        }
+    }
+}
+
+impl Package {
+    /// Write the package to a derivation file at `path`
+    pub fn into_file(
+        self,
+        path: impl AsRef<Path>,
+        options: &DerivationOptions,
+    ) -> Result<(), Error> {
+        let expr = self.into_derivative_with_options(options)?;
+
+        fs::write(path, expr)?;
+
+        Ok(())
+    }
+
+    /// The name of the package
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Call `f` with every crate which is compiled to build this package, starting with the package itself. Crates
+    /// are told apart by their identifier, like the attributes of the derivation, so a crate which is reached through
+    /// different `Rc`s is only passed once, while one which is built apart for the host is passed twice. The borrows
+    /// only live for the call, since the dependencies are behind a [RefCell].
+    pub fn for_each_crate(&self, mut f: impl FnMut(&str, &Version, &Source)) {
+        let mut seen = HashSet::from([self.identifier()]);
+
+        f(&self.name, &self.version, &self.source);
+        Self::find_crates(&self.dependencies, &mut seen, &mut f);
+        Self::find_crates(&self.build_dependencies, &mut seen, &mut f);
+    }
+
+    /// Get every crate which [Package::for_each_crate] passes, with their sources cloned
+    pub fn all_crates(&self) -> Vec<(String, Version, Source)> {
+        let mut crates = Vec::new();
+
+        self.for_each_crate(|name, version, source| {
+            crates.push((name.to_string(), version.clone(), source.clone()))
+        });
+
+        crates
+    }
+
+    /// Recursively call `f` with the packages of the `dependencies`. The `seen` set makes sure each identifier is only
+    /// passed once.
+    fn find_crates(
+        dependencies: &[Dependency],
+        seen: &mut HashSet<String>,
+        f: &mut impl FnMut(&str, &Version, &Source),
+    ) {
+        for dependency in dependencies {
+            let package = dependency.package.borrow();
+
+            if !seen.insert(package.identifier()) {
+                continue;
+            }
+
+            f(&package.name, &package.version, &package.source);
+
+            Self::find_crates(&package.dependencies, seen, f);
+            Self::find_crates(&package.build_dependencies, seen, f);
+        }
+    }
+
+    /// Turn the package into a derivation string. Fails if the dependencies form a cycle.
+    pub fn into_derivative(self) -> Result<String, Error> {
+        self.into_derivative_with_options(&Default::default())
+    }
+
+    /// Turn the package into a derivation string using custom options
+    pub fn into_derivative_with_options(
+        self,
+        options: &DerivationOptions,
+    ) -> Result<String, Error> {
+        if options.backend == Backend::Crane {
+            return self.into_crane_derivative(options);
+        }
+
+        let root = nix_identifier(options.attr_name.as_deref().unwrap_or(&self.name));
+
+        Self::into_derivative_of_roots(vec![(root.clone(), self)], options, &root)
+    }
+
+    /// Turn several packages, which were resolved on their own, into a single derivation. Crates shared between them
+    /// are only built once. The derivation evaluates to an attrset with an attribute for each package, named like
+    /// its crate, and a `default` one for the package with a bin target when `workspace_default` is set. The bin and
+    /// example options apply to all of them, and the attribute name and backend options are not used.
+    pub fn merge_into_derivative(
+        packages: Vec<Package>,
+        options: &DerivationOptions,
+    ) -> Result<String, Error> {
+        let mut roots: Vec<(String, Package)> = Vec::new();
+
+        for package in packages {
+            let root = nix_identifier(&package.name);
+
+            if let Some((_, first)) = roots.iter().find(|(other, _)| *other == root) {
+                return Err(Error::IdentifierCollision {
+                    identifier: root,
+                    first: first.name.clone(),
+                    second: package.name,
+                });
+            }
+
+            roots.push((root, package));
+        }
+
+        let default = if options.workspace_default {
+            format!("\n  default = {};", Self::default_root(&roots)?)
+        } else {
+            Default::default()
+        };
+        let result = format!(
+            "{{\n  inherit {};{default}\n}}",
+            roots
+                .iter()
+                .map(|(root, _)| root.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        Self::into_derivative_of_roots(roots, options, &result)
+    }
+
+    /// Get the attribute of the only root with a bin target
+    fn default_root(roots: &[(String, Package)]) -> Result<&str, Error> {
+        let bins: Vec<_> = roots
+            .iter()
+            .filter(|(_, package)| !package.bins.is_empty())
+            .collect();
+
+        match bins.as_slice() {
+            [(root, _)] => Ok(root),
+            [] => Err(Error::DefaultPackage(
+                "none of the packages has a bin target".to_string(),
+            )),
+            _ => Err(Error::DefaultPackage(format!(
+                "the packages {} all have a bin target",
+                bins.iter()
+                    .map(|(_, package)| package.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
+
+    /// Make the derivation of the `roots`, which are keyed by their attribute, with `result` as what it evaluates to
+    fn into_derivative_of_roots(
+        roots: Vec<(String, Package)>,
+        options: &DerivationOptions,
+        result: &str,
+    ) -> Result<String, Error> {
+        options.check_codegen_backend()?;
+        options.check_fetch_crate()?;
+
+        let source_filter = options.source_filter();
+        let fetch_crate = options.fetch_crate(
+            options
+                .crates_mirror
+                .as_deref()
+                .unwrap_or(CRATES_IO_MIRROR)
+                .trim_end_matches('/'),
+        );
+
+        // Alternative registries need a helper to read the download location from the registry's config
+        let mut seen = Default::default();
+        let registry_fetcher = if roots.iter().any(|(_, root)| {
+            Self::has_registry_source(&root.dependencies, &mut seen)
+                || Self::has_registry_source(&root.build_dependencies, &mut seen)
+        }) {
+            REGISTRY_FETCHER
+        } else {
+            Default::default()
+        };
+
+        // The crates which declare `links`, for the sys heuristics of the crate overrides
+        let mut links = BTreeSet::new();
+        let mut seen = Default::default();
+        for (_, root) in &roots {
+            if root.links.is_some() {
+                links.insert(root.name.clone());
+            }
+
+            Self::find_links(&root.dependencies, &mut seen, &mut links);
+            Self::find_links(&root.build_dependencies, &mut seen, &mut links);
+        }
+
+        // The details of all the dependencies, keyed by their identifier so that they are written in the same order no
+        // matter in which order the graph is walked
+        let mut build_details = Default::default();
+
+        // A crate can be reached through many paths, and even through different `Rc`s for the same identifier. So
+        // keep track of what has been emitted to not have duplicate attributes. The roots are emitted as cores, so a
+        // root which another root depends on is not emitted again as a dependency, and the edge points at its core.
+        let mut emitted = HashMap::new();
+        let mut root_attributes = HashMap::new();
+
+        for (root, package) in &roots {
+            let identifier = package.identifier();
+
+            if let Some(other) = emitted.insert(identifier.clone(), package.name.clone()) {
+                return Err(Error::IdentifierCollision {
+                    identifier,
+                    first: other,
+                    second: package.name.clone(),
+                });
+            }
+
+            root_attributes.insert(identifier, root.clone());
+        }
+
+        let mut cores = Vec::new();
+
+        for (root, package) in roots {
+            let identifier = package.identifier();
+
+            cores.push(package.into_core(
+                &root,
+                identifier,
+                options,
+                &mut build_details,
+                &mut emitted,
+                &root_attributes,
+            )?);
+        }
+
+        let crate_overrides = options
+            .crate_overrides(emitted.values().map(String::as_str), &links)
+            .binding("defaultCrateOverrides", 2);
+        let nixpkgs_system = options.nixpkgs_system();
+        let rust_version = options.rust_version();
+        let host_builder = options.host_builder();
+        let header = options
+            .lock_hash
+            .as_deref()
+            .map(|lock_hash| format!("{}\n", header(lock_hash)))
+            .unwrap_or_default();
+
+        Ok(format!(
+            r#"{header}{{ pkgs ? import <nixpkgs> {{
+  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];{nixpkgs_system}
+}} }}:
+
+let
+{source_filter}
+  rustVersion = {rust_version};
+  {crate_overrides}
+  fetchCrate = {fetch_crate};{}
+  buildRustCrate = pkgs.buildRustCrate.override {{
+    rustc = rustVersion;
+    inherit defaultCrateOverrides fetchCrate;
+  }};{host_builder}
+{}
+
+  # Core
+{}
+
+  # Dependencies
+{}
+in
+{result}
+"#,
+            registry_fetcher,
+            options.hooks.to_bindings(),
+            cores.join("\n\n"),
+            build_details.into_values().collect::<Vec<_>>().join("\n"),
+        ))
+    }
+
+    /// Get the attribute of a core crate named `root`, and add the details of its dependencies unto `build_details`.
+    /// Dependencies on the crates in `root_attributes`, which are keyed by their identifier, use the root attribute.
+    fn into_core(
+        self,
+        root: &str,
+        identifier: String,
+        options: &DerivationOptions,
+        build_details: &mut BTreeMap<String, String>,
+        emitted: &mut HashMap<String, String>,
+        root_attributes: &HashMap<String, String>,
+    ) -> Result<String, Error> {
+        // The root is on the path too so that a dependency pointing back to it is also caught
+        let mut path = vec![identifier];
+
+        let Self {
+            name,
+            version,
+            source,
+            lib_name,
+            lib_path,
+            build_path,
+            proc_macro,
+            crate_types,
+            bins,
+            examples,
+            features,
+            dependencies,
+            build_dependencies,
+            edition,
+            links: _, // Only needed for the crate overrides
+            host: _,
+        } = self;
+
+        let (lib_name, lib_path) = Self::get_root_lib(&name, lib_name, lib_path);
+        let crate_bin = match options.example.as_deref() {
+            Some(example) => Self::get_crate_example(&examples, example)?,
+            None => Self::get_crate_bin(&bins, options.bin.as_deref())?,
+        };
+
+        let dep_idents = dependencies
+            .into_iter()
+            .map(|d| {
+                let identifier = d.package.borrow().identifier();
+                Self::to_details(
+                    &d,
+                    options,
+                    build_details,
+                    &mut path,
+                    emitted,
+                    root_attributes,
+                )?;
+                Ok((identifier, d.platforms))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let deps = Self::get_dependencies(dep_idents, root_attributes).binding("dependencies", 4);
+
+        let build_deps = if build_dependencies.is_empty() {
+            Default::default()
+        } else {
+            let dep_idents = build_dependencies
+                .into_iter()
+                .map(|d| {
+                    let identifier = d.package.borrow().identifier();
+                    Self::to_details(
+                        &d,
+                        options,
+                        build_details,
+                        &mut path,
+                        emitted,
+                        root_attributes,
+                    )?;
+                    Ok((identifier, d.platforms))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            format!(
+                "\n    {}",
+                Self::get_dependencies(dep_idents, root_attributes).binding("buildDependencies", 4)
+            )
+        };
+
+        Ok(format!(
+            r#"  {root} = buildRustCrate rec {{
+    crateName = "{}";{}
+    version = "{}";
+
+    {}{}{}{}{}
+
+    {}{}{}
+    edition = "{}";
+    {}
+    {}
+  }};"#,
+            name,
+            lib_name,
+            version,
+            options.source(
+                &name,
+                &version,
+                &source,
+                options.filter_source(&name, &version)
+            )?,
+            lib_path,
+            Self::get_crate_type(&crate_types),
+            Self::get_build_path(build_path.as_ref()),
+            Self::get_features("features", &features),
+            deps,
+            build_deps,
+            crate_bin,
+            options.edition(&name, &version, &edition),
+            options.profile_attributes(&name, &version, true, proc_macro),
+            options.crate_attributes(&name, &version, true),
+        ))
+    }
+
+    /// Turn the package into a derivation which lets cargo build it inside a single crane `buildPackage`. Crane
+    /// vendors the crates.io and git crates from `Cargo.lock`, so the graph is only used to check that every crate
+    /// can be found. The source is the `workspace_root`, so that the lock file and the other members are in it.
+    fn into_crane_derivative(self, options: &DerivationOptions) -> Result<String, Error> {
+        options.check_crane(&self.name, &self.version)?;
+
+        let source = match (&options.workspace_root, &self.source) {
+            (Some(workspace_root), Source::Local(_)) => Source::Local(workspace_root.clone()),
+            (_, source) => source.clone(),
+        };
+
+        if let Source::Local(root_dir) = &source {
+            let mut seen = Default::default();
+            Self::check_crane_sources(root_dir, &self.dependencies, &mut seen)?;
+            Self::check_crane_sources(root_dir, &self.build_dependencies, &mut seen)?;
+        }
+
+        let mut cargo_args = vec!["--locked".to_string(), format!("--package {}", self.name)];
+
+        if let Some(example) = &options.example {
+            cargo_args.push(format!(
+                "--example {}",
+                Self::find_example(&self.examples, example)?.name
+            ));
+        } else if let Some(bin) = &options.bin {
+            cargo_args.push(format!("--bin {}", Self::find_bin(&self.bins, bin)?.name));
+        }
+
+        if !options.features.is_empty() {
+            cargo_args.push(format!("--features {}", options.features.join(",")));
+        }
+        if options.no_default_features {
+            cargo_args.push("--no-default-features".to_string());
+        }
+
+        let cargo_profile = match options.profile.release {
+            Some(false) => "\n    CARGO_PROFILE = \"dev\";",
+            _ => Default::default(),
+        };
+        let root = nix_identifier(options.attr_name.as_deref().unwrap_or(&self.name));
+        let header = options
+            .lock_hash
+            .as_deref()
+            .map(|lock_hash| format!("{}\n", header(lock_hash)))
+            .unwrap_or_default();
+
+        let nixpkgs_system = options.nixpkgs_system();
+
+        Ok(format!(
+            r#"{header}{{ pkgs ? import <nixpkgs> {{
+  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];{nixpkgs_system}
+}} }}:
+
+let
+{}
+  rustVersion = {};
+  crane = import (builtins.fetchTarball "{CRANE_URL}") {{ inherit pkgs; }};
+  {}
+
+  # Core
+  {root} = craneLib.buildPackage rec {{
+    pname = "{}";
+    version = "{}";
+
+    {}
+    cargoVendorDir = craneLib.vendorCargoDeps {{ inherit src; }};
+    cargoExtraArgs = {};
+    strictDeps = true;{cargo_profile}{}
+  }};
+in
+{root}
+"#,
+            options.source_filter(),
+            options.rust_version(),
+            options.crane_bindings(),
+            self.name,
+            self.version,
+            options.source(
+                &self.name,
+                &self.version,
+                &source,
+                options.filter_source(&self.name, &self.version)
+            )?,
+            nix_string(&cargo_args.join(" ")),
+            options.env_attributes(&self.name, &self.version, true),
+        ))
+    }
+
+    /// Check that crane can find all the `dependencies` (recursively). Crates from alternative registries would need
+    /// their own configuration, and local crates outside `root_dir` are not part of the source cargo builds. The
+    /// `seen` set makes sure shared packages are only checked once.
+    fn check_crane_sources(
+        root_dir: &Path,
+        dependencies: &[Dependency],
+        seen: &mut HashSet<String>,
+    ) -> Result<(), Error> {
+        for dependency in dependencies {
+            let package = dependency.package.borrow();
+
+            if !seen.insert(package.identifier()) {
+                continue;
+            }
+
+            let origin = match &package.source {
+                Source::Registry { index, .. } => Some(format!("the registry {index}")),
+                Source::Local(path) if !path.starts_with(root_dir) => {
+                    Some(path.display().to_string())
+                }
+                _ => None,
+            };
+
+            if let Some(origin) = origin {
+                return Err(Error::UnsupportedCraneSource {
+                    name: package.name.clone(),
+                    version: package.version.to_string(),
+                    origin,
+                });
+            }
+
+            Self::check_crane_sources(root_dir, &package.dependencies, seen)?;
+            Self::check_crane_sources(root_dir, &package.build_dependencies, seen)?;
+        }
+
+        Ok(())
+    }
+
+    /// Recursively add a dependency unto `details`. The `path` holds the identifiers of the packages currently being
+    /// printed, so that a package depending on itself is reported. The `emitted` map makes sure each identifier is only
+    /// printed once, and is used to catch two crates which end up with the same identifier.
+    fn to_details(
+        dependency: &Dependency,
+        options: &DerivationOptions,
+        build_details: &mut BTreeMap<String, String>,
+        path: &mut Vec<String>,
+        emitted: &mut HashMap<String, String>,
+        root_attributes: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let identifier = dependency.package.borrow().identifier();
+
+        if let Some(start) = path.iter().position(|i| i == &identifier) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(identifier);
+
+            return Err(Error::DependencyCycle(cycle.join(" -> ")));
+        }
+
+        let this = dependency.package.borrow();
+
+        // Only print once
+        if let Some(name) = emitted.get(&identifier) {
+            if name != &this.name {
+                return Err(Error::IdentifierCollision {
+                    identifier,
+                    first: name.clone(),
+                    second: this.name.clone(),
+                });
+            }
+
+            return Ok(());
+        }
+
+        emitted.insert(identifier.clone(), this.name.clone());
+
+        Self::print_details(&this, options, build_details, root_attributes)?;
+
+        path.push(identifier);
+
+        for dependency in this
+            .dependencies
+            .iter()
+            .chain(this.build_dependencies.iter())
+        {
+            Self::to_details(
+                dependency,
+                options,
+                build_details,
+                path,
+                emitted,
+                root_attributes,
+            )?;
+        }
+
+        path.pop();
+
+        Ok(())
+    }
+
+    /// Add the details of a single package unto `build_details`
+    fn print_details(
+        this: &Package,
+        options: &DerivationOptions,
+        build_details: &mut BTreeMap<String, String>,
+        root_attributes: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let features = Self::get_features("features", &this.features);
+
+        let lib_name = if let Some(lib_name) = &this.lib_name {
+            format!("\n    libName = \"{lib_name}\";")
+        } else {
+            Default::default()
+        };
+        let lib_path = if let Some(lib_path) = &this.lib_path {
+            format!("\n    libPath = \"{lib_path}\";")
+        } else {
+            Default::default()
+        };
+        let build_path = Self::get_build_path(this.build_path.as_ref());
+        let proc_macro = if this.proc_macro {
+            "\n    procMacro = true;"
+        } else {
+            Default::default()
+        };
+
+        // A crate can be renamed at more than one version, so group the renames by the crate name
+        let mut renames = BTreeMap::new();
+
+        let deps = if this.dependencies.is_empty() {
+            Default::default()
+        } else {
+            let dep_idents: Vec<_> = this
+                .dependencies
+                .iter()
+                .map(|d| {
+                    if let Some(rename) = &d.rename {
+                        Self::add_rename(&mut renames, d, rename);
+                    }
+
+                    (d.package.borrow().identifier(), d.platforms.clone())
+                })
+                .collect();
+
+            format!(
+                "\n    {}",
+                Self::get_dependencies(dep_idents, root_attributes).binding("dependencies", 4)
+            )
+        };
+        let build_deps = if this.build_dependencies.is_empty() {
+            Default::default()
+        } else {
+            let dep_idents: Vec<_> = this
+                .build_dependencies
+                .iter()
+                .map(|d| {
+                    if let Some(rename) = &d.rename {
+                        Self::add_rename(&mut renames, d, rename);
+                    }
+
+                    (d.package.borrow().identifier(), d.platforms.clone())
+                })
+                .collect();
+
+            format!(
+                "\n    {}",
+                Self::get_dependencies(dep_idents, root_attributes).binding("buildDependencies", 4)
+            )
+        };
+
+        let crate_renames = if renames.is_empty() {
+            Default::default()
+        } else {
+            let renames = renames
+                .into_iter()
+                .map(|(name, renames)| {
+                    let renames = renames
+                        .into_iter()
+                        .map(|(rename, version)| {
+                            Expr::Attrs(vec![
+                                ("rename".to_string(), Expr::Raw(nix_string(&rename))),
+                                ("version".to_string(), Expr::Raw(nix_string(&version))),
+                            ])
+                        })
+                        .collect();
+
+                    (nix_string(&name), Expr::List(renames))
+                })
+                .collect();
+
+            format!("\n    {}", Expr::Attrs(renames).binding("crateRenames", 4))
+        };
+
+        let details = format!(
+            r#"  {} = {} rec {{
+    crateName = "{}";{}
+    version = "{}";
+
+    {}{}{}{}{}{}{}{}{}
+    edition = "{}";
+    crateBin = [ ];
+    {}
+    {}
+  }};"#,
+            this.identifier(),
+            options.builder(this.host),
+            this.name,
+            lib_name,
+            this.version,
+            options.source(
+                &this.name,
+                &this.version,
+                &this.source,
+                options.filter_source(&this.name, &this.version)
+            )?,
+            lib_path,
+            build_path,
+            proc_macro,
+            Self::get_crate_type(&this.crate_types),
+            deps,
+            build_deps,
+            crate_renames,
+            features,
+            options.edition(&this.name, &this.version, &this.edition),
+            options.profile_attributes(&this.name, &this.version, false, this.proc_macro),
+            options.crate_attributes(&this.name, &this.version, false),
+        );
+
+        build_details.insert(this.identifier(), details);
+
+        Ok(())
+    }
+
+    /// Get the list of dependency identifiers. Those which are limited to some platforms are appended to the
+    /// dependencies used on all platforms, and are only added when building for one of their platforms. The
+    /// identifiers are sorted so that the order of the dependencies in the metadata does not matter. A dependency on
+    /// one of the `root_attributes`, keyed by identifier, uses the attribute of the root instead.
+    fn get_dependencies(
+        dependencies: Vec<(String, Vec<String>)>,
+        root_attributes: &HashMap<String, String>,
+    ) -> Expr {
+        let mut idents = BTreeSet::new();
+        let mut platform_idents: BTreeMap<Vec<String>, BTreeSet<String>> = BTreeMap::new();
+
+        for (identifier, platforms) in dependencies {
+            let identifier = root_attributes
+                .get(&identifier)
+                .cloned()
+                .unwrap_or(identifier);
+
+            if platforms.is_empty() {
+                idents.insert(identifier);
+            } else {
+                platform_idents
+                    .entry(platforms)
+                    .or_default()
+                    .insert(identifier);
+            }
+        }
+
+        let platform_deps = platform_idents.into_iter().map(|(platforms, idents)| {
+            Expr::prefixed(
+                format!(
+                    "pkgs.lib.optionals (builtins.elem pkgs.stdenv.hostPlatform.rust.rustcTarget {}) ",
+                    nix_strings(&platforms).inline()
+                ),
+                Expr::List(idents.into_iter().map(Expr::Raw).collect()),
+            )
+        });
+
+        Expr::Concat(
+            [Expr::List(idents.into_iter().map(Expr::Raw).collect())]
+                .into_iter()
+                .chain(platform_deps)
+                .collect(),
+        )
+    }
+
+    /// Get the `attribute` with a list of features. Nothing is emitted for an empty list.
+    fn get_features(attribute: &str, features: &[String]) -> String {
+        if features.is_empty() {
+            Default::default()
+        } else {
+            format!("\n    {}", nix_strings(features).binding(attribute, 4))
+        }
+    }
+
+    /// Add the rename of a dependency unto `renames`, which is keyed by the crate name
+    fn add_rename(
+        renames: &mut BTreeMap<String, Vec<(String, String)>>,
+        dependency: &Dependency,
+        rename: &str,
+    ) {
+        let package = dependency.package.borrow();
+        let entry = (rename.to_string(), package.version.to_string());
+        let renames = renames.entry(package.name.clone()).or_default();
+
+        // The same dependency can be both a normal and build dependency
+        if !renames.contains(&entry) {
+            renames.push(entry);
+        }
+    }
+
+    /// Verify the checksums of the crates.io crates against the `.crate` files in the local cargo cache. This catches a
+    /// stale or tampered `Cargo.lock` before nix fails with an opaque hash mismatch. Crates which are not in the cache
+    /// only have the format of their checksum checked.
+    pub fn verify_checksums(&self) -> Result<(), Error> {
+        self.verify_checksums_in(&crate_cache_dirs(), &mut Default::default())
+    }
+
+    /// Recursively verify the checksums using the `.crate` files in `cache_dirs`. The `seen` set makes sure shared
+    /// packages are only checked once.
+    fn verify_checksums_in(
+        &self,
+        cache_dirs: &[PathBuf],
+        seen: &mut HashSet<String>,
+    ) -> Result<(), Error> {
+        if let Source::CratesIo(expected) = &self.source {
+            let is_sha256 = expected.len() == 64 && expected.chars().all(|c| c.is_ascii_hexdigit());
+
+            if !is_sha256 {
+                return Err(Error::InvalidChecksum {
+                    name: self.name.clone(),
+                    version: self.version.to_string(),
+                    checksum: expected.clone(),
+                });
+            }
+
+            let file_name = format!("{}-{}.crate", self.name, self.version);
+
+            if let Some(path) = cache_dirs
+                .iter()
+                .map(|dir| dir.join(&file_name))
+                .find(|path| path.is_file())
+            {
+                let actual = format!("{:x}", Sha256::digest(fs::read(path)?));
+
+                if !actual.eq_ignore_ascii_case(expected) {
+                    return Err(Error::ChecksumMismatch {
+                        name: self.name.clone(),
+                        version: self.version.to_string(),
+                        expected: expected.clone(),
+                        actual,
+                    });
+                }
+            }
+        }
+
+        for dependency in self
+            .dependencies
+            .iter()
+            .chain(self.build_dependencies.iter())
+        {
+            let package = dependency.package.borrow();
+
+            if seen.insert(package.identifier()) {
+                package.verify_checksums_in(cache_dirs, seen)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Helper to get a deterministic identifier for a package. Dashes are replaced too so that the identifier is always
+    /// a plain nix attribute name.
+    pub(super) fn identifier(&self) -> String {
+        let mut identifier = format!(
+            "{}_{}",
+            nix_identifier(&self.name),
+            self.version.to_string().replace(['.', '+', '-'], "_")
+        );
+
+        // A crate from an alternative registry can have the same name and version as one from crates.io or another
+        // registry, so it is told apart by its index
+        if let Source::Registry { index, .. } = &self.source {
+            identifier = format!(
+                "{identifier}_{}",
+                &format!("{:x}", Sha256::digest(index))[..8]
+            );
+        }
+
+        if self.host {
+            format!("{identifier}_host")
+        } else {
+            identifier
+        }
+    }
+
+    /// Helper to get the `type` attribute when a library is not just a plain `lib`. Proc-macros are already covered
+    /// by `procMacro`.
+    fn get_crate_type(crate_types: &[String]) -> String {
+        match crate_types {
+            [] => Default::default(),
+            [crate_type] if crate_type == "lib" || crate_type == "proc-macro" => Default::default(),
+            crate_types => format!("\n    {}", nix_strings(crate_types).binding("type", 4)),
+        }
+    }
+
+    /// Helper to get the `libName` and `libPath` of the core crate, like they are emitted for the dependencies. The
+    /// conversion from cargo already leaves out the default path, and the name is also left out when it only differs
+    /// from the crate name by its dashes, since `buildRustCrate` replaces those itself.
+    fn get_root_lib(
+        name: &str,
+        lib_name: Option<String>,
+        lib_path: Option<Utf8PathBuf>,
+    ) -> (String, String) {
+        let lib_name = lib_name
+            .filter(|lib_name| *lib_name != name.replace('-', "_"))
+            .map(|lib_name| format!("\n    libName = \"{lib_name}\";"))
+            .unwrap_or_default();
+        let lib_path = lib_path
+            .map(|lib_path| format!("\n    libPath = \"{lib_path}\";"))
+            .unwrap_or_default();
+
+        (lib_name, lib_path)
+    }
+
+    /// Helper to get the `build` attribute for a build script which is not at the default `build.rs`
+    fn get_build_path(build_path: Option<&Utf8PathBuf>) -> String {
+        build_path
+            .map(|build_path| format!("\n    build = \"{build_path}\";"))
+            .unwrap_or_default()
+    }
+
+    /// Helper to get the `crateBin` of the core crate, which is only the selected binary when there is one. The bins
+    /// are listed with their paths, since `buildRustCrate` only finds the ones at the usual paths on its own.
+    fn get_crate_bin(bins: &[Bin], selected: Option<&str>) -> Result<String, Error> {
+        match selected {
+            Some(selected) => Ok(Self::crate_bin_binding(&[Self::find_bin(bins, selected)?])),
+            None if bins.is_empty() => Ok(Default::default()),
+            None => Ok(Self::crate_bin_binding(&bins.iter().collect::<Vec<_>>())),
+        }
+    }
+
+    /// Get the `crateBin` binding for the `targets` to build
+    fn crate_bin_binding(targets: &[&Bin]) -> String {
+        let targets = targets
+            .iter()
+            .map(|target| {
+                Expr::Attrs(vec![
+                    ("name".to_string(), Expr::Raw(nix_string(&target.name))),
+                    (
+                        "path".to_string(),
+                        Expr::Raw(nix_string(target.path.as_str())),
+                    ),
+                ])
+            })
+            .collect();
+
+        format!("\n    {}", Expr::List(targets).binding("crateBin", 4))
+    }
+
+    /// Find the bin target named `selected`
+    fn find_bin<'a>(bins: &'a [Bin], selected: &str) -> Result<&'a Bin, Error> {
+        bins.iter()
+            .find(|b| b.name == selected)
+            .ok_or_else(|| Error::UnknownBin {
+                name: selected.to_string(),
+                available: bins
+                    .iter()
+                    .map(|b| b.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+    }
+
+    /// Helper to build an example as the only `crateBin` of the core crate
+    fn get_crate_example(examples: &[Bin], selected: &str) -> Result<String, Error> {
+        Ok(Self::crate_bin_binding(&[Self::find_example(
+            examples, selected,
+        )?]))
+    }
+
+    /// Find the example target named `selected`
+    fn find_example<'a>(examples: &'a [Bin], selected: &str) -> Result<&'a Bin, Error> {
+        examples
+            .iter()
+            .find(|e| e.name == selected)
+            .ok_or_else(|| Error::UnknownExample {
+                name: selected.to_string(),
+                available: examples
+                    .iter()
+                    .map(|e| e.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            })
+    }
+
+    /// Helper to get the source definition
+    fn get_source(source: &Source, filter: bool) -> String {
+        match source {
+            Source::Local(path) if filter => Expr::prefixed(
+                "pkgs.lib.cleanSourceWith ",
+                Expr::Attrs(vec![
+                    ("filter".to_string(), Expr::raw("sourceFilter")),
+                    ("src".to_string(), Expr::raw(path.display().to_string())),
+                ]),
+            )
+            .binding("src", 4),
+            Source::Local(path) => format!("src = {};", path.display()),
+            Source::CratesIo(sha256) => format!("sha256 = \"{sha256}\";"),
+            Source::Git { url, rev, subdir } => {
+                let fetch = format!("builtins.fetchGit {{ url = \"{url}\"; rev = \"{rev}\"; allRefs = true; }}");
+
+                match subdir {
+                    Some(subdir) => format!("src = \"${{{fetch}}}/{}\";", subdir.display()),
+                    None => format!("src = {fetch};"),
+                }
+            }
+            Source::Registry { index, sha256 } => format!(
+                "src = fetchRegistryCrate \"{index}\" {{ inherit crateName version; sha256 = \"{sha256}\"; }};"
+            ),
+        }
+    }
+
+    /// Check if any of the dependencies (recursively) comes from an alternative registry. The `seen` set makes sure
+    /// shared packages are only checked once.
+    fn has_registry_source(dependencies: &[Dependency], seen: &mut HashSet<String>) -> bool {
+        dependencies.iter().any(|dependency| {
+            let package = dependency.package.borrow();
+
+            if !seen.insert(package.identifier()) {
+                return false;
+            }
+
+            matches!(package.source, Source::Registry { .. })
+                || Self::has_registry_source(&package.dependencies, seen)
+                || Self::has_registry_source(&package.build_dependencies, seen)
+        })
+    }
+
+    /// Find the names of the dependencies (recursively) which declare `links`. The `seen` set makes sure shared
+    /// packages are only checked once.
+    fn find_links(
+        dependencies: &[Dependency],
+        seen: &mut HashSet<String>,
+        links: &mut BTreeSet<String>,
+    ) {
+        for dependency in dependencies {
+            let package = dependency.package.borrow();
+
+            if !seen.insert(package.identifier()) {
+                continue;
+            }
+
+            if package.links.is_some() {
+                links.insert(package.name.clone());
+            }
+
+            Self::find_links(&package.dependencies, seen, links);
+            Self::find_links(&package.build_dependencies, seen, links);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    impl From<Package> for Dependency {
+        fn from(package: Package) -> Self {
+            Self {
+                package: Rc::new(RefCell::new(package)),
+                rename: None,
+                platforms: Default::default(),
+            }
+        }
+    }
+
+    impl From<PathBuf> for Source {
+        fn from(path: PathBuf) -> Self {
+            Self::Local(path)
+        }
+    }
+
+    impl From<&str> for Source {
+        fn from(sha: &str) -> Self {
+            Self::CratesIo(sha.to_string())
+        }
+    }
+
+    #[test]
+    fn simple_package() {
+        let package = Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from_str("/cargo-nbuild/nbuild-core/tests/simple")
+                .unwrap()
+                .into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            dependencies: vec![Package {
+                name: "itoa".to_string(),
+                version: "1.0.6".parse().unwrap(),
+                source: "itoa_sha".into(),
+                lib_name: None,
+                lib_path: None,
+                build_path: None,
+                proc_macro: false,
+                crate_types: Default::default(),
+                bins: Default::default(),
+                examples: Default::default(),
+                dependencies: Default::default(),
+                build_dependencies: Default::default(),
+                features: Default::default(),
+                edition: "2018".to_string(),
+                links: None,
+                host: false,
+            }
+            .into()],
+            build_dependencies: vec![Package {
+                name: "arbitrary".to_string(),
+                version: "1.3.0".parse().unwrap(),
+                source: "arbitrary_sha".into(),
+                lib_name: None,
+                lib_path: None,
+                build_path: None,
+                proc_macro: false,
+                crate_types: Default::default(),
+                bins: Default::default(),
+                examples: Default::default(),
+                dependencies: Default::default(),
+                build_dependencies: Default::default(),
+                features: Default::default(),
+                edition: "2018".to_string(),
+                links: None,
+                host: false,
+            }
+            .into()],
+            features: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let actual = package.into_derivative().unwrap();
+
+        assert_eq!(
+            actual,
+            r#"{ pkgs ? import <nixpkgs> {
+  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
+} }:
+
+let
+  sourceFilter = name: type:
+    let
+      baseName = builtins.baseNameOf (builtins.toString name);
+    in
+    ! (
+      # Filter out git
+      baseName == ".gitignore"
+      || (type == "directory" && baseName == ".git")
+
+      # Filter out build results
+      || (
+        type == "directory" && baseName == "target"
+      )
+
+      # Filter out nix-build result symlinks
+      || (
+        type == "symlink" && pkgs.lib.hasPrefix "result" baseName
+      )
+    );
+  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
+  defaultCrateOverrides = pkgs.defaultCrateOverrides;
+  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
+    # https://www.pietroalbini.org/blog/downloading-crates-io/
+    # Not rate-limited, CDN URL.
+    name = "${crateName}-${version}.tar.gz";
+    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";
+    inherit sha256;
+  };
+  buildRustCrate = pkgs.buildRustCrate.override {
+    rustc = rustVersion;
+    inherit defaultCrateOverrides fetchCrate;
+  };
+  preBuild = "rustc -vV";
+
+  # Core
+  simple = buildRustCrate rec {
+    crateName = "simple";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/simple;
+    };
+
+    dependencies = [ itoa_1_0_6 ];
+    buildDependencies = [ arbitrary_1_3_0 ];
+    edition = "2021";
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+
+  # Dependencies
+  arbitrary_1_3_0 = buildRustCrate rec {
+    crateName = "arbitrary";
+    version = "1.3.0";
+
+    sha256 = "arbitrary_sha";
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  itoa_1_0_6 = buildRustCrate rec {
+    crateName = "itoa";
+    version = "1.0.6";
+
+    sha256 = "itoa_sha";
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+in
+simple
+"#
+        );
+    }
+
+    #[test]
+    fn workspace() {
+        let base = PathBuf::from_str("/cargo-nbuild/nbuild-core/tests/workspace").unwrap();
+
+        let libc = RefCell::new(Package {
+            name: "libc".to_string(),
+            version: "0.2.144".parse().unwrap(),
+            source: "sha".into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            features: Default::default(),
+            edition: "2015".to_string(),
+            links: None,
+            host: false,
+        })
+        .into();
+
+        let package = Package {
+            name: "parent".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: base.join("parent").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            dependencies: vec![
+                Package {
+                    name: "child".to_string(),
+                    version: "0.1.0".parse().unwrap(),
+                    source: base.join("child").into(),
+                    lib_name: None,
+                    lib_path: None,
+                    build_path: None,
+                    proc_macro: false,
+                    crate_types: Default::default(),
+                    bins: Default::default(),
+                    examples: Default::default(),
+                    dependencies: vec![
+                        Package {
+                            name: "fnv".to_string(),
+                            version: "1.0.7".parse().unwrap(),
+                            source: "sha".into(),
+                            lib_name: None,
+                            lib_path: Some("lib.rs".into()),
+                            build_path: None,
+                            proc_macro: false,
+                            crate_types: Default::default(),
+                            bins: Default::default(),
+                            examples: Default::default(),
+                            dependencies: Default::default(),
+                            build_dependencies: Default::default(),
+                            features: Default::default(),
+                            edition: "2015".to_string(),
+                            links: None,
+                            host: false,
+                        }
+                        .into(),
+                        Package {
+                            name: "itoa".to_string(),
+                            version: "1.0.6".parse().unwrap(),
+                            source: "sha".into(),
+                            lib_name: None,
+                            lib_path: None,
+                            build_path: None,
+                            proc_macro: false,
+                            crate_types: Default::default(),
+                            bins: Default::default(),
+                            examples: Default::default(),
+                            dependencies: Default::default(),
+                            build_dependencies: Default::default(),
+                            features: Default::default(),
+                            edition: "2018".to_string(),
+                            links: None,
+                            host: false,
+                        }
+                        .into(),
+                        Dependency {
+                            package: Rc::clone(&libc),
+                            rename: None,
+                            platforms: Default::default(),
+                        },
+                        Dependency {
+                            package: RefCell::new(Package {
+                                name: "rename".to_string(),
+                                version: "0.1.0".parse().unwrap(),
+                                source: base.join("rename").into(),
+                                lib_name: Some("lib_rename".to_string()),
+                                lib_path: None,
+                                build_path: None,
+                                proc_macro: false,
+                                crate_types: Default::default(),
+                                bins: Default::default(),
+                                examples: Default::default(),
+                                dependencies: Default::default(),
+                                build_dependencies: Default::default(),
+                                features: Default::default(),
+                                edition: "2021".to_string(),
+                                links: None,
+                                host: false,
+                            })
+                            .into(),
+                            rename: Some("new_name".to_string()),
+                            platforms: Default::default(),
+                        },
+                        Package {
+                            name: "rustversion".to_string(),
+                            version: "1.0.12".parse().unwrap(),
+                            source: "sha".into(),
+                            lib_name: None,
+                            lib_path: None,
+                            build_path: Some("build/build.rs".into()),
+                            proc_macro: true,
+                            crate_types: Default::default(),
+                            bins: Default::default(),
+                            examples: Default::default(),
+                            dependencies: Default::default(),
+                            build_dependencies: Default::default(),
+                            features: Default::default(),
+                            edition: "2018".to_string(),
+                            links: None,
+                            host: false,
+                        }
+                        .into(),
+                    ],
+                    build_dependencies: vec![Package {
+                        name: "arbitrary".to_string(),
+                        version: "1.3.0".parse().unwrap(),
+                        source: "sha".into(),
+                        lib_name: None,
+                        lib_path: None,
+                        build_path: None,
+                        proc_macro: false,
+                        crate_types: Default::default(),
+                        bins: Default::default(),
+                        examples: Default::default(),
+                        dependencies: Default::default(),
+                        build_dependencies: Default::default(),
+                        features: Default::default(),
+                        edition: "2018".to_string(),
+                        links: None,
+                        host: false,
+                    }
+                    .into()],
+                    features: vec!["one".to_string()],
+                    edition: "2021".to_string(),
+                    links: None,
+                    host: false,
+                }
+                .into(),
+                Package {
+                    name: "itoa".to_string(),
+                    version: "0.4.8".parse().unwrap(),
+                    source: "sha".into(),
+                    lib_name: None,
+                    lib_path: None,
+                    build_path: None,
+                    proc_macro: false,
+                    crate_types: Default::default(),
+                    bins: Default::default(),
+                    examples: Default::default(),
+                    dependencies: Default::default(),
+                    build_dependencies: Default::default(),
+                    features: Default::default(),
+                    edition: "2018".to_string(),
+                    links: None,
+                    host: false,
+                }
+                .into(),
+                Dependency {
+                    package: libc,
+                    rename: None,
+                    platforms: Default::default(),
+                },
+                Package {
+                    name: "targets".to_string(),
+                    version: "0.1.0".parse().unwrap(),
+                    source: base.join("targets").into(),
+                    lib_name: None,
+                    lib_path: None,
+                    build_path: None,
+                    proc_macro: false,
+                    crate_types: Default::default(),
+                    bins: Default::default(),
+                    examples: Default::default(),
+                    dependencies: Default::default(),
+                    build_dependencies: Default::default(),
+                    features: vec!["unix".to_string()],
+                    edition: "2021".to_string(),
+                    links: None,
+                    host: false,
+                }
+                .into(),
+            ],
+            build_dependencies: Default::default(),
+            features: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let actual = package.into_derivative().unwrap();
+
+        assert_eq!(
+            actual,
+            r#"{ pkgs ? import <nixpkgs> {
+  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
+} }:
+
+let
+  sourceFilter = name: type:
+    let
+      baseName = builtins.baseNameOf (builtins.toString name);
+    in
+    ! (
+      # Filter out git
+      baseName == ".gitignore"
+      || (type == "directory" && baseName == ".git")
+
+      # Filter out build results
+      || (
+        type == "directory" && baseName == "target"
+      )
+
+      # Filter out nix-build result symlinks
+      || (
+        type == "symlink" && pkgs.lib.hasPrefix "result" baseName
+      )
+    );
+  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
+  defaultCrateOverrides = pkgs.defaultCrateOverrides;
+  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
+    # https://www.pietroalbini.org/blog/downloading-crates-io/
+    # Not rate-limited, CDN URL.
+    name = "${crateName}-${version}.tar.gz";
+    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";
+    inherit sha256;
+  };
+  buildRustCrate = pkgs.buildRustCrate.override {
+    rustc = rustVersion;
+    inherit defaultCrateOverrides fetchCrate;
+  };
+  preBuild = "rustc -vV";
+
+  # Core
+  parent = buildRustCrate rec {
+    crateName = "parent";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/workspace/parent;
+    };
+
+    dependencies = [ child_0_1_0 itoa_0_4_8 libc_0_2_144 targets_0_1_0 ];
+    edition = "2021";
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+
+  # Dependencies
+  arbitrary_1_3_0 = buildRustCrate rec {
+    crateName = "arbitrary";
+    version = "1.3.0";
+
+    sha256 = "sha";
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  child_0_1_0 = buildRustCrate rec {
+    crateName = "child";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/workspace/child;
+    };
+    dependencies = [ fnv_1_0_7 itoa_1_0_6 libc_0_2_144 rename_0_1_0 rustversion_1_0_12 ];
+    buildDependencies = [ arbitrary_1_3_0 ];
+    crateRenames = { "rename" = [ { rename = "new_name"; version = "0.1.0"; } ]; };
+    features = [ "one" ];
+    edition = "2021";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  fnv_1_0_7 = buildRustCrate rec {
+    crateName = "fnv";
+    version = "1.0.7";
+
+    sha256 = "sha";
+    libPath = "lib.rs";
+    edition = "2015";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  itoa_0_4_8 = buildRustCrate rec {
+    crateName = "itoa";
+    version = "0.4.8";
+
+    sha256 = "sha";
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  itoa_1_0_6 = buildRustCrate rec {
+    crateName = "itoa";
+    version = "1.0.6";
+
+    sha256 = "sha";
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  libc_0_2_144 = buildRustCrate rec {
+    crateName = "libc";
+    version = "0.2.144";
+
+    sha256 = "sha";
+    edition = "2015";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  rename_0_1_0 = buildRustCrate rec {
+    crateName = "rename";
+    libName = "lib_rename";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/workspace/rename;
+    };
+    edition = "2021";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  rustversion_1_0_12 = buildRustCrate rec {
+    crateName = "rustversion";
+    version = "1.0.12";
+
+    sha256 = "sha";
+    build = "build/build.rs";
+    procMacro = true;
+    edition = "2018";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+  targets_0_1_0 = buildRustCrate rec {
+    crateName = "targets";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/workspace/targets;
+    };
+    features = [ "unix" ];
+    edition = "2021";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+in
+parent
+"#
+        );
+    }
+
+    #[test]
+    fn registry() {
+        let package = Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from_str("/cargo-nbuild/nbuild-core/tests/simple")
+                .unwrap()
+                .into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            dependencies: vec![Package {
+                name: "private".to_string(),
+                version: "1.0.0".parse().unwrap(),
+                source: Source::Registry {
+                    index: "sparse+https://my.registry/index".to_string(),
+                    sha256: "private_sha".to_string(),
+                },
+                lib_name: None,
+                lib_path: None,
+                build_path: None,
+                proc_macro: false,
+                crate_types: Default::default(),
+                bins: Default::default(),
+                examples: Default::default(),
+                dependencies: Default::default(),
+                build_dependencies: Default::default(),
+                features: Default::default(),
+                edition: "2021".to_string(),
+                links: None,
+                host: false,
+            }
+            .into()],
+            build_dependencies: Default::default(),
+            features: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let actual = package.into_derivative().unwrap();
+
+        assert_eq!(
+            actual,
+            r#"{ pkgs ? import <nixpkgs> {
+  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
+} }:
+
+let
+  sourceFilter = name: type:
+    let
+      baseName = builtins.baseNameOf (builtins.toString name);
+    in
+    ! (
+      # Filter out git
+      baseName == ".gitignore"
+      || (type == "directory" && baseName == ".git")
+
+      # Filter out build results
+      || (
+        type == "directory" && baseName == "target"
+      )
+
+      # Filter out nix-build result symlinks
+      || (
+        type == "symlink" && pkgs.lib.hasPrefix "result" baseName
+      )
+    );
+  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
+  defaultCrateOverrides = pkgs.defaultCrateOverrides;
+  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
+    # https://www.pietroalbini.org/blog/downloading-crates-io/
+    # Not rate-limited, CDN URL.
+    name = "${crateName}-${version}.tar.gz";
+    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";
+    inherit sha256;
+  };
+  fetchRegistryCrate = index: { crateName, version, sha256 }:
+    let
+      config = builtins.fromJSON (builtins.readFile (
+        if pkgs.lib.hasPrefix "sparse+" index
+        then builtins.fetchurl "${pkgs.lib.removePrefix "sparse+" index}/config.json"
+        else "${builtins.fetchGit (pkgs.lib.removePrefix "registry+" index)}/config.json"
+      ));
+      markers = [ "{crate}" "{version}" "{prefix}" "{lowerprefix}" "{sha256-checksum}" ];
+      length = builtins.stringLength crateName;
+      prefix =
+        if length <= 2 then toString length
+        else if length == 3 then "3/${builtins.substring 0 1 crateName}"
+        else "${builtins.substring 0 2 crateName}/${builtins.substring 2 2 crateName}";
+      url =
+        if builtins.any (marker: pkgs.lib.hasInfix marker config.dl) markers
+        then builtins.replaceStrings markers [ crateName version prefix (pkgs.lib.toLower prefix) sha256 ] config.dl
+        else "${config.dl}/${crateName}/${version}/download";
+    in
+      pkgs.fetchurl {
+        name = "${crateName}-${version}.tar.gz";
+        inherit url sha256;
+      };
+  buildRustCrate = pkgs.buildRustCrate.override {
+    rustc = rustVersion;
+    inherit defaultCrateOverrides fetchCrate;
+  };
+  preBuild = "rustc -vV";
+
+  # Core
+  simple = buildRustCrate rec {
+    crateName = "simple";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith {
+      filter = sourceFilter;
+      src = /cargo-nbuild/nbuild-core/tests/simple;
+    };
+
+    dependencies = [ private_1_0_0_1738d8e2 ];
+    edition = "2021";
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+
+  # Dependencies
+  private_1_0_0_1738d8e2 = buildRustCrate rec {
+    crateName = "private";
+    version = "1.0.0";
+
+    src = fetchRegistryCrate "sparse+https://my.registry/index" { inherit crateName version; sha256 = "private_sha"; };
+    edition = "2021";
+    crateBin = [ ];
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];
+    inherit preBuild;
+  };
+in
+simple
+"#
+        );
+    }
+
+    #[test]
+    fn root_lib_name() {
+        let package = |name: &str, lib_name: &str| {
+            let mut package = Package::new(name, "0.1.0", Source::Local("/root".into()));
+            package.lib_name = Some(lib_name.to_string());
+            package
+        };
+
+        assert!(package("server", "engine")
+            .into_derivative()
+            .unwrap()
+            .contains(
+                r#"    crateName = "server";
+    libName = "engine";
+    version = "0.1.0";"#
+            ));
+        assert!(package("my-server", "my_server")
+            .into_derivative()
+            .unwrap()
+            .contains(
+                r#"    crateName = "my-server";
+    version = "0.1.0";"#
+            ));
+    }
+
+    #[test]
+    fn merge_into_derivative() {
+        let itoa = || Package::new("itoa", "1.0.6", Source::CratesIo("sha".to_string()));
+        let server = Package::new("server", "0.1.0", Source::Local("/ws/server".into()))
+            .with_dependency(itoa(), None);
+        let cli = Package::new("cli", "0.2.0", Source::Local("/ws/cli".into()))
+            .with_dependency(itoa(), None)
+            .with_dependency(
+                Package::new("clap", "4.3.0", Source::CratesIo("clap_sha".to_string())),
+                None,
+            );
+
+        let derivation =
+            Package::merge_into_derivative(vec![server, cli], &Default::default()).unwrap();
+
+        // The shared crate is only emitted once
+        assert_eq!(derivation.matches("itoa_1_0_6 = buildRustCrate").count(), 1);
+        assert_eq!(derivation.matches("clap_4_3_0 = buildRustCrate").count(), 1);
+        assert!(derivation.contains(
+            r#"  # Core
+  server = buildRustCrate rec {
+    crateName = "server";"#
+        ));
+        assert!(derivation.contains(
+            r#"  };
+
+  cli = buildRustCrate rec {
+    crateName = "cli";"#
+        ));
+        assert!(derivation.ends_with(
+            r#"in
+{
+  inherit server cli;
+}
+"#
+        ));
+
+        let twice = |version| Package::new("server", version, Source::Local("/ws".into()));
+
+        assert_eq!(
+            Package::merge_into_derivative(
+                vec![twice("0.1.0"), twice("0.2.0")],
+                &Default::default()
+            )
+            .unwrap_err()
+            .to_string(),
+            "the crates server and server both use the identifier server"
+        );
+    }
+
+    #[test]
+    fn merge_member_dependency() {
+        let itoa = || Package::new("itoa", "1.0.6", Source::CratesIo("sha".to_string()));
+        let server = || {
+            Package::new("server", "0.1.0", Source::Local("/ws/server".into()))
+                .with_dependency(itoa(), None)
+        };
+        let cli = Package::new("cli", "0.2.0", Source::Local("/ws/cli".into()))
+            .with_dependency(server(), None)
+            .with_dependency(itoa(), None);
+
+        let derivation =
+            Package::merge_into_derivative(vec![server(), cli], &Default::default()).unwrap();
+
+        // The member is only built as a core, and the other member depends on that core
+        assert!(!derivation.contains("server_0_1_0"));
+        assert_eq!(derivation.matches("itoa_1_0_6 = buildRustCrate").count(), 1);
+        assert!(derivation.contains(
+            r#"  cli = buildRustCrate rec {
+    crateName = "cli";
+    version = "0.2.0";
+
+    src = pkgs.lib.cleanSourceWith { filter = sourceFilter; src = /ws/cli; };
+
+    dependencies = [ itoa_1_0_6 server ];"#
+        ));
+    }
+
+    #[test]
+    fn workspace_default() {
+        let lib = || Package::new("shared", "0.1.0", Source::Local("/ws/shared".into()));
+        let app = |name: &str| {
+            Package::new(name, "0.1.0", Source::Local(format!("/ws/{name}").into()))
+                .with_bin(name, "src/main.rs")
+        };
+        let options = DerivationOptions {
+            workspace_default: true,
+            ..Default::default()
+        };
+
+        // The app is picked over the library
+        assert!(
+            Package::merge_into_derivative(vec![lib(), app("server")], &options)
+                .unwrap()
+                .ends_with(
+                    r#"in
+{
+  inherit shared server;
+  default = server;
+}
+"#
+                )
+        );
+
+        assert_eq!(
+            Package::merge_into_derivative(vec![app("server"), app("cli")], &options)
+                .unwrap_err()
+                .to_string(),
+            "cannot pick the default package: the packages server, cli all have a bin target"
+        );
+        assert_eq!(
+            Package::merge_into_derivative(vec![lib()], &options)
+                .unwrap_err()
+                .to_string(),
+            "cannot pick the default package: none of the packages has a bin target"
+        );
+    }
+
+    #[test]
+    fn multiple_registries() {
+        let shared = |source| Package::new("shared", "1.0.0", source);
+        let package = Package::new("simple", "0.1.0", Source::Local("/simple".into()))
+            .with_dependency(shared(Source::CratesIo("public_sha".to_string())), None)
+            .with_dependency(
+                shared(Source::Registry {
+                    index: "sparse+https://one.registry/index".to_string(),
+                    sha256: "one_sha".to_string(),
+                }),
+                Some("shared_one"),
+            )
+            .with_dependency(
+                shared(Source::Registry {
+                    index: "sparse+https://two.registry/index".to_string(),
+                    sha256: "two_sha".to_string(),
+                }),
+                Some("shared_two"),
+            );
+
+        let derivation = package.into_derivative().unwrap();
+
+        // Every registry gets its own crate, fetched from where it came from
+        for (identifier, src) in [
+            ("shared_1_0_0", r#"sha256 = "public_sha";"#),
+            (
+                "shared_1_0_0_b385d817",
+                r#"src = fetchRegistryCrate "sparse+https://one.registry/index" { inherit crateName version; sha256 = "one_sha"; };"#,
+            ),
+            (
+                "shared_1_0_0_d6639148",
+                r#"src = fetchRegistryCrate "sparse+https://two.registry/index" { inherit crateName version; sha256 = "two_sha"; };"#,
+            ),
+        ] {
+            assert!(derivation.contains(&format!(
+                r#"  {identifier} = buildRustCrate rec {{
+    crateName = "shared";
+    version = "1.0.0";
+
+    {src}"#
+            )));
+        }
+    }
+
+    #[test]
+    fn git_source() {
+        assert_eq!(
+            Package::get_source(
+                &Source::Git {
+                    url: "https://github.com/owner/repo".to_string(),
+                    rev: "0123abcd".to_string(),
+                    subdir: None,
+                },
+                true
+            ),
+            r#"src = builtins.fetchGit { url = "https://github.com/owner/repo"; rev = "0123abcd"; allRefs = true; };"#
+        );
+        assert_eq!(
+            Package::get_source(
+                &Source::Git {
+                    url: "https://github.com/owner/repo".to_string(),
+                    rev: "0123abcd".to_string(),
+                    subdir: Some("crates/inner".into()),
+                },
+                true
+            ),
+            r#"src = "${builtins.fetchGit { url = "https://github.com/owner/repo"; rev = "0123abcd"; allRefs = true; }}/crates/inner";"#
+        );
+    }
+
+    #[test]
+    fn source_filter() {
+        let options: DerivationOptions = toml::from_str(
+            r#"
+ignore = ["assets", "*.log"]
+
+[crates.data]
+skip-source-filter = true
+"#,
+        )
+        .unwrap();
+        let version = "0.1.0".parse().unwrap();
+
+        assert_eq!(DerivationOptions::default().ignore_filter(), "");
+        assert_eq!(
+            options.ignore_filter(),
+            r#"
+
+      # Filter out the configured globs
+      || builtins.any (pattern: builtins.match pattern baseName != null) [ "assets" ".*\\.log" ]"#
+        );
+
+        assert!(options.filter_source("simple", &version));
+        assert!(!options.filter_source("data", &version));
+        assert_eq!(
+            Package::get_source(&PathBuf::from("/data").into(), false),
+            "src = /data;"
+        );
+        assert_eq!(
+            Package::get_source(&PathBuf::from("/data").into(), true),
+            "src = pkgs.lib.cleanSourceWith { filter = sourceFilter; src = /data; };"
+        );
+    }
+
+    #[test]
+    fn hash_local_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("crates").join("data");
+
+        fs::create_dir_all(crate_dir.join("target")).unwrap();
+        fs::write(crate_dir.join("lib.rs"), "").unwrap();
+        fs::write(crate_dir.join("target").join("out"), "").unwrap();
+
+        let mut options = DerivationOptions {
+            hash_local_sources: true,
+            source_root: Some(dir.path().join("nix")),
+            ..Default::default()
+        };
+        fs::create_dir(dir.path().join("nix")).unwrap();
+
+        let source = crate_dir.clone().into();
+        let version = "0.1.0".parse().unwrap();
+        let filtered = options.source("data", &version, &source, true).unwrap();
+
+        assert_eq!(
+            filtered,
+            r#"src = builtins.path {
+      path = ../crates/data;
+      name = "source";
+      filter = sourceFilter;
+      sha256 = "2e8e76d280e632f198e3f9442382c18065dcb636ea4c73f7ed5a6d8a1497d6a9";
+    };"#
+        );
+
+        // The build results are only hashed without the filter
+        assert_ne!(
+            options.source("data", &version, &source, false).unwrap(),
+            filtered
+        );
+
+        options.ignore = vec!["*.rs".to_string()];
+        assert_ne!(
+            options.source("data", &version, &source, true).unwrap(),
+            filtered
+        );
+        options.ignore.clear();
+
+        // The target directory of the workspace is left out whatever its name
+        fs::create_dir(crate_dir.join("build-out")).unwrap();
+        fs::write(crate_dir.join("build-out").join("out"), "").unwrap();
+        assert_ne!(
+            options.source("data", &version, &source, true).unwrap(),
+            filtered
+        );
+
+        options.target_dir = Some(crate_dir.join("build-out"));
+        assert_eq!(
+            options.source("data", &version, &source, true).unwrap(),
+            filtered
+        );
+        assert!(options.source_filter().contains(
+            "|| (type == \"directory\" && builtins.toString name == builtins.toString (../crates/data/build-out))"
+        ));
+
+        options.source_root = None;
+        assert!(options.source_filter().contains(&format!(
+            "|| (type == \"directory\" && builtins.toString name == \"{}\")",
+            crate_dir.join("build-out").display()
+        )));
+
+        assert_eq!(
+            relative_nix_path(Path::new("/repo"), Path::new("/repo")),
+            "./."
+        );
+        assert_eq!(
+            relative_nix_path(Path::new("/repo"), Path::new("/repo/crates/data")),
+            "./crates/data"
+        );
+        assert_eq!(
+            relative_nix_path(Path::new("/repo/nix"), Path::new("/other/data")),
+            "../../other/data"
+        );
+        assert_eq!(
+            relative_nix_path(Path::new("/repo"), Path::new("/repo/my data")),
+            r#"./. + "/my data""#
+        );
+
+        assert!(glob_matches("*.log", "build.log"));
+        assert!(glob_matches("data?", "data1"));
+        assert!(!glob_matches("data?", "data"));
+        assert!(!glob_matches("*.log", "build.log.gz"));
+    }
+
+    #[test]
+    fn glob_regex_agrees() {
+        let globs = [
+            "*.log",
+            "data?",
+            "a.b",
+            "x+y",
+            "(a)",
+            "[ab]",
+            "{1}",
+            "a|b",
+            "^$",
+            r"back\slash",
+            "*",
+        ];
+        let names = [
+            "build.log",
+            "data1",
+            "data",
+            "a.b",
+            "axb",
+            "x+y",
+            "xxy",
+            "(a)",
+            "a",
+            "[ab]",
+            "b",
+            "{1}",
+            "1",
+            "a|b",
+            "^$",
+            r"back\slash",
+            "",
+        ];
+
+        // `builtins.match` has to match the whole name, like the glob
+        for glob in globs {
+            let regex = regex::Regex::new(&format!("^(?:{})$", glob_to_regex(glob))).unwrap();
+
+            for name in names {
+                assert_eq!(
+                    regex.is_match(name),
+                    glob_matches(glob, name),
+                    "{glob} on {name}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let package = |name: &str| Package {
+            name: name.to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: "sha".into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let a = Rc::new(RefCell::new(package("a")));
+        let b = Rc::new(RefCell::new(package("b")));
+
+        a.borrow_mut().dependencies.push(Dependency {
+            package: Rc::clone(&b),
+            rename: None,
+            platforms: Default::default(),
+        });
+        b.borrow_mut().dependencies.push(Dependency {
+            package: Rc::clone(&a),
+            rename: None,
+            platforms: Default::default(),
+        });
+
+        let mut root = package("root");
+        root.dependencies.push(Dependency {
+            package: a,
+            rename: None,
+            platforms: Default::default(),
+        });
+
+        let actual = root.into_derivative().unwrap_err();
+
+        assert_eq!(
+            actual.to_string(),
+            "dependency cycle detected: a_0_1_0 -> b_0_1_0 -> a_0_1_0"
+        );
+    }
+
+    #[test]
+    fn profile() {
+        assert_eq!(
+            Profile::default().to_attributes(true, false, &[]),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];"#
+        );
+        assert_eq!(
+            Profile::dev().to_attributes(false, false, &[]),
+            r#"release = false;
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" "-C opt-level=0" "-C debuginfo=2" ];"#
+        );
+
+        let profile = Profile {
+            lto: Some("fat".to_string()),
+            panic: Some("abort".to_string()),
+            ..Profile::release()
+        };
+
+        assert_eq!(
+            profile.to_attributes(true, false, &[]),
+            r#"release = true;
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C opt-level=3" "-C debuginfo=0" "-C lto=fat" "-C panic=abort" ];"#
+        );
+        assert_eq!(
+            profile.to_attributes(false, true, &[]),
+            r#"release = true;
+    codegenUnits = 16;
+    extraRustcOpts = [ "-C opt-level=3" "-C debuginfo=0" ];"#
+        );
+    }
+
+    #[test]
+    fn hooks() {
+        let options: DerivationOptions = toml::from_str(
+            r#"
+[hooks]
+post-build = "echo done"
+
+[crates.openssl-sys.hooks]
+pre-build = "echo \"${OUT_DIR}\""
+
+[crates."itoa@1.0.6".hooks]
+pre-configure = "echo itoa"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.hooks.to_bindings(),
+            r#"  preBuild = "rustc -vV";
+  postBuild = "echo done";"#
+        );
+        assert_eq!(
+            options.hooks.to_attributes(None),
+            "inherit preBuild postBuild;"
+        );
+        assert_eq!(
+            options.hooks.to_attributes(
+                options
+                    .crate_options("openssl-sys", &"0.9.88".parse().unwrap())
+                    .map(|c| &c.hooks)
+            ),
+            r#"preBuild = "echo \"\${OUT_DIR}\"";
+    inherit postBuild;"#
+        );
+        assert_eq!(
+            options.hooks.to_attributes(
+                options
+                    .crate_options("itoa", &"1.0.6".parse().unwrap())
+                    .map(|c| &c.hooks)
+            ),
+            r#"preConfigure = "echo itoa";
+    inherit preBuild postBuild;"#
+        );
+        assert!(options
+            .crate_options("itoa", &"0.4.8".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn env() {
+        let mut options: DerivationOptions = toml::from_str(
+            r#"
+[crates.rdkafka.env]
+CMAKE = "/usr/bin/cmake"
+"weird.key" = "value"
+"#,
+        )
+        .unwrap();
+        options
+            .env
+            .insert("OPENSSL_NO_VENDOR".to_string(), "1".to_string());
+
+        let version = "0.1.0".parse().unwrap();
+
+        assert_eq!(
+            options.crate_attributes("rdkafka", &version, false),
+            r#"inherit preBuild;
+    CMAKE = "/usr/bin/cmake";
+    "weird.key" = "value";"#
+        );
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            r#"inherit preBuild;
+    OPENSSL_NO_VENDOR = "1";"#
+        );
+        assert_eq!(
+            options.crate_attributes("other", &version, false),
+            "inherit preBuild;"
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut options = DerivationOptions::default();
+        let version = "0.1.0".parse().unwrap();
+
+        // Nothing changes by default
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            "inherit preBuild;"
+        );
+
+        options = toml::from_str(
+            r#"
+build-tests = true
+doctest = false
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            r#"inherit preBuild;
+    buildTests = true;
+    doctest = false;"#
+        );
+        assert_eq!(
+            options.crate_attributes("other", &version, false),
+            "inherit preBuild;"
+        );
+    }
+
+    #[test]
+    fn strip_attributes() {
+        let mut options = DerivationOptions::default();
+        let version = "0.1.0".parse().unwrap();
+
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            "inherit preBuild;"
+        );
+
+        options.strip = Some(true);
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            r#"inherit preBuild;
+    stripAllList = [ "bin" ];"#
+        );
+        assert_eq!(
+            options.crate_attributes("other", &version, false),
+            "inherit preBuild;"
+        );
+
+        options.strip = Some(false);
+        assert_eq!(
+            options.crate_attributes("root", &version, true),
+            r#"inherit preBuild;
+    dontStrip = true;"#
+        );
+    }
+
+    #[test]
+    fn crate_overrides() {
+        let mut options = DerivationOptions::default();
+
+        assert_eq!(
+            options
+                .crate_overrides(["itoa"], &Default::default())
+                .inline(),
+            "pkgs.defaultCrateOverrides"
+        );
+
+        // Well-known crates get their inputs when they are in the dependency tree
+        assert_eq!(
+            options
+                .crate_overrides(["itoa", "openssl-sys"], &Default::default())
+                .binding("defaultCrateOverrides", 2),
+            r#"defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+    openssl-sys = attrs: let old = (pkgs.defaultCrateOverrides.openssl-sys or (_: { })) attrs; in old // {
+      buildInputs = (old.buildInputs or [ ]) ++ [ pkgs.openssl ];
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+    };
+  };"#
+        );
+
+        options = toml::from_str(
+            r#"
+shuttle-defaults = true
+
+[crates.openssl-sys]
+build-inputs = ["openssl_3"]
+
+[crates."openssl-sys@0.9.87"]
+native-build-inputs = ["perl"]
+
+[crates.libz-sys]
+native-build-inputs = ["cmake", "pkg-config"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options
+                .crate_overrides(["openssl-sys"], &Default::default())
+                .binding("defaultCrateOverrides", 2),
+            r#"defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+    libz-sys = attrs: let old = (pkgs.defaultCrateOverrides.libz-sys or (_: { })) attrs; in old // {
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.cmake pkgs.pkg-config ];
+    };
+    openssl-sys = attrs: let old = (pkgs.defaultCrateOverrides.openssl-sys or (_: { })) attrs; in old // {
+      buildInputs = (old.buildInputs or [ ]) ++ [ pkgs.openssl pkgs.openssl_3 ];
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.perl pkgs.pkg-config ];
+    };
+    opentelemetry-proto = attrs: let old = (pkgs.defaultCrateOverrides.opentelemetry-proto or (_: { })) attrs; in old // {
+      buildInputs = (old.buildInputs or [ ]) ++ [ pkgs.protobuf ];
+    };
+  };"#
+        );
+
+        // The built-in inputs can be replaced, together with the nixpkgs override
+        options = toml::from_str(
+            r#"
+[crates.openssl-sys]
+skip-builtin-inputs = true
+build-inputs = ["openssl_3"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            options
+                .crate_overrides(["openssl-sys"], &Default::default())
+                .binding("defaultCrateOverrides", 2),
+            r#"defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+    openssl-sys = attrs: { buildInputs = [ pkgs.openssl_3 ]; };
+  };"#
+        );
+    }
+
+    #[test]
+    fn all_crates() {
+        let itoa = Rc::new(RefCell::new(Package::new(
+            "itoa",
+            "1.0.6",
+            "itoa_sha".into(),
+        )));
+        let shared = |package: &Rc<RefCell<Package>>| Dependency {
+            package: Rc::clone(package),
+            rename: None,
+            platforms: Default::default(),
+        };
+        let mut child = Package::new("child", "0.1.0", Source::Local("/parent/child".into()));
+        child.dependencies.push(shared(&itoa));
+
+        let mut package = Package::new("parent", "0.1.0", Source::Local("/parent".into()))
+            .with_dependency(child, None)
+            .with_dependency(Package::new("itoa", "1.0.6", "itoa_sha".into()), None);
+        package.dependencies.push(shared(&itoa));
+        package.build_dependencies.push(shared(&itoa));
+
+        let mut sources = Vec::new();
+        package.for_each_crate(|name, _, source| {
+            if let Source::CratesIo(sha256) = source {
+                sources.push(format!("{name} {sha256}"));
+            }
+        });
+
+        assert_eq!(sources, vec!["itoa itoa_sha".to_string()]);
+
+        // The shared crate is only listed once, even when it is reached through another `Rc`
+        assert_eq!(
+            package
+                .all_crates()
+                .into_iter()
+                .map(|(name, version, source)| (name, version.to_string(), source))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "parent".to_string(),
+                    "0.1.0".to_string(),
+                    Source::Local("/parent".into())
+                ),
+                (
+                    "child".to_string(),
+                    "0.1.0".to_string(),
+                    Source::Local("/parent/child".into())
+                ),
+                (
+                    "itoa".to_string(),
+                    "1.0.6".to_string(),
+                    Source::CratesIo("itoa_sha".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sys_heuristics() {
+        let package = || {
+            Package::new("simple", "0.1.0", Source::Local("/simple".into()))
+                .with_dependency(Package::new("libz-sys", "1.1.8", "libz_sha".into()), None)
+                .with_dependency(
+                    Package::new("ring", "0.16.20", "ring_sha".into()).with_links("ring-asm"),
+                    None,
+                )
+                .with_dependency(Package::new("itoa", "1.0.6", "itoa_sha".into()), None)
+        };
+        let mut options = DerivationOptions::default();
+
+        // Nothing is guessed by default
+        assert!(!package()
+            .into_derivative_with_options(&options)
+            .unwrap()
+            .contains("nativeBuildInputs"));
+
+        options = toml::from_str(
+            r#"
+sys-heuristics = true
+
+[crates.ring]
+skip-builtin-inputs = true
+"#,
+        )
+        .unwrap();
+
+        assert!(package()
+            .into_derivative_with_options(&options)
+            .unwrap()
+            .contains(
+                r#"defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+    libz-sys = attrs: let old = (pkgs.defaultCrateOverrides.libz-sys or (_: { })) attrs; in old // {
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+    };
+  };"#
+            ));
+
+        options.crates.clear();
+
+        assert!(package()
+            .into_derivative_with_options(&options)
+            .unwrap()
+            .contains(
+                r#"defaultCrateOverrides = pkgs.defaultCrateOverrides // {
+    libz-sys = attrs: let old = (pkgs.defaultCrateOverrides.libz-sys or (_: { })) attrs; in old // {
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+    };
+    ring = attrs: let old = (pkgs.defaultCrateOverrides.ring or (_: { })) attrs; in old // {
+      nativeBuildInputs = (old.nativeBuildInputs or [ ]) ++ [ pkgs.pkg-config ];
+    };
+  };"#
+            ));
+    }
+
+    #[test]
+    fn systems() {
+        assert_eq!(
+            nix_system("x86_64-unknown-linux-gnu").as_deref(),
+            Some("x86_64-linux")
+        );
+        assert_eq!(
+            nix_system("aarch64-apple-darwin").as_deref(),
+            Some("aarch64-darwin")
+        );
+        assert_eq!(nix_system("x86_64-unknown-linux-musl"), None);
+        assert_eq!(nix_system("wasm32-unknown-unknown"), None);
+
+        assert_eq!(
+            combine_systems(
+                vec![
+                    (
+                        "x86_64-linux".to_string(),
+                        "{ pkgs }:\n\nlet\n  a = 1;\nin\na\n".to_string()
+                    ),
+                    (
+                        "x86_64-unknown-linux-musl".to_string(),
+                        "{ pkgs }:\n\n2\n".to_string()
+                    ),
+                ],
+                None
+            ),
+            r#"{
+  x86_64-linux = (
+    { pkgs }:
+
+    let
+      a = 1;
+    in
+    a
+  ) { };
+  x86_64-unknown-linux-musl = (
+    { pkgs }:
+
+    2
+  ) { };
+}
+"#
+        );
+    }
+
+    #[test]
+    fn crate_type() {
+        assert_eq!(Package::get_crate_type(&[]), "");
+        assert_eq!(Package::get_crate_type(&["lib".to_string()]), "");
+        assert_eq!(Package::get_crate_type(&["proc-macro".to_string()]), "");
+        assert_eq!(
+            Package::get_crate_type(&["cdylib".to_string(), "rlib".to_string()]),
+            "\n    type = [ \"cdylib\" \"rlib\" ];"
+        );
+    }
+
+    #[test]
+    fn features() {
+        assert_eq!(Package::get_features("features", &[]), "");
+        assert_eq!(
+            Package::get_features("features", &["build".to_string(), "std".to_string()]),
+            "\n    features = [ \"build\" \"std\" ];"
+        );
+    }
+
+    #[test]
+    fn crate_bin() {
+        let bins = vec![
+            Bin {
+                name: "server".to_string(),
+                path: "src/main.rs".into(),
+            },
+            Bin {
+                name: "cli".to_string(),
+                path: "src/bin/cli.rs".into(),
+            },
+        ];
+
+        assert_eq!(Package::get_crate_bin(&[], None).unwrap(), "");
+        assert_eq!(
+            Package::get_crate_bin(&bins, None).unwrap(),
+            r#"
+    crateBin = [
+      { name = "server"; path = "src/main.rs"; }
+      { name = "cli"; path = "src/bin/cli.rs"; }
+    ];"#
+        );
+        assert_eq!(
+            Package::get_crate_bin(&bins, Some("cli")).unwrap(),
+            "\n    crateBin = [ { name = \"cli\"; path = \"src/bin/cli.rs\"; } ];"
+        );
+        assert_eq!(
+            Package::get_crate_bin(&bins, Some("missing"))
+                .unwrap_err()
+                .to_string(),
+            "no bin target named `missing`. Available bins: server, cli"
+        );
+    }
+
+    #[test]
+    fn crate_example() {
+        let examples = vec![Bin {
+            name: "demo".to_string(),
+            path: "examples/demo.rs".into(),
+        }];
+
+        assert_eq!(
+            Package::get_crate_example(&examples, "demo").unwrap(),
+            "\n    crateBin = [ { name = \"demo\"; path = \"examples/demo.rs\"; } ];"
+        );
+        assert_eq!(
+            Package::get_crate_example(&examples, "missing")
+                .unwrap_err()
+                .to_string(),
+            "no example target named `missing`. Available examples: demo"
+        );
+    }
+
+    #[test]
+    fn verify_checksums() {
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(cache.path().join("itoa-1.0.6.crate"), "crate contents").unwrap();
+
+        let package = |sha256: &str| Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from("/simple").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: vec![Package {
+                name: "itoa".to_string(),
+                version: "1.0.6".parse().unwrap(),
+                source: sha256.into(),
+                lib_name: None,
+                lib_path: None,
+                build_path: None,
+                proc_macro: false,
+                crate_types: Default::default(),
+                bins: Default::default(),
+                examples: Default::default(),
+                features: Default::default(),
+                dependencies: Default::default(),
+                build_dependencies: Default::default(),
+                edition: "2018".to_string(),
+                links: None,
+                host: false,
+            }
+            .into()],
+            build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+        let cache_dirs = [cache.path().to_path_buf()];
+
+        assert!(
+            package("9b20f231dc2ba967ecae4ec8e5dd416ec3bbe245cc5ebdf8b0b0e2381ac44613")
+                .verify_checksums_in(&cache_dirs, &mut Default::default())
+                .is_ok()
+        );
+        assert_eq!(
+            package("0000000000000000000000000000000000000000000000000000000000000000")
+                .verify_checksums_in(&cache_dirs, &mut Default::default())
+                .unwrap_err()
+                .to_string(),
+            "checksum mismatch for itoa 1.0.6: Cargo.lock has 0000000000000000000000000000000000000000000000000000000000000000, but the downloaded crate has 9b20f231dc2ba967ecae4ec8e5dd416ec3bbe245cc5ebdf8b0b0e2381ac44613"
+        );
+        assert_eq!(
+            package("itoa_sha")
+                .verify_checksums_in(&cache_dirs, &mut Default::default())
+                .unwrap_err()
+                .to_string(),
+            "checksum of itoa 1.0.6 is not a valid sha256: itoa_sha"
+        );
+    }
+
+    #[test]
+    fn local_crates() {
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(cache.path().join("itoa-1.0.6.crate"), "crate contents").unwrap();
+
+        let options = DerivationOptions {
+            local_crates: true,
+            crate_cache_dirs: vec![cache.path().to_path_buf()],
+            ..Default::default()
+        };
+        let package = |name| {
+            Package::new("simple", "0.1.0", Source::Local("/simple".into())).with_dependency(
+                Package::new(name, "1.0.6", Source::CratesIo("itoa_sha".to_string())),
+                None,
+            )
+        };
+
+        let derivation = package("itoa")
+            .into_derivative_with_options(&options)
+            .unwrap();
+
+        assert!(derivation.contains(&format!(
+            r#"  itoa_1_0_6 = buildRustCrate rec {{
+    crateName = "itoa";
+    version = "1.0.6";
+
+    src = builtins.path {{
+      path = "{}";
+      name = "itoa-1.0.6.tar.gz";
+      recursive = false;
+      sha256 = "itoa_sha";
+    }};"#,
+            cache.path().join("itoa-1.0.6.crate").display()
+        )));
+
+        // Every crate has to be in the cache
+        assert_eq!(
+            package("ryu")
+                .into_derivative_with_options(&options)
+                .unwrap_err()
+                .to_string(),
+            "ryu 1.0.6 is not in the local cargo cache, run `cargo fetch` to download it first"
+        );
+    }
+
+    #[test]
+    fn duplicate_identifiers() {
+        let itoa = || Package {
+            name: "itoa".to_string(),
+            version: "1.0.6".parse().unwrap(),
+            source: "itoa_sha".into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            edition: "2018".to_string(),
+            links: None,
+            host: false,
+        };
+        let package = Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from("/simple").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: vec![itoa().into()],
+            build_dependencies: vec![itoa().into()],
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let actual = package.into_derivative().unwrap();
+
+        assert_eq!(actual.matches("itoa_1_0_6 = buildRustCrate").count(), 1);
+        assert!(actual.contains("buildDependencies = [ itoa_1_0_6 ];"));
+    }
+
+    #[test]
+    fn lock_hash() {
+        let package = || Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from("/simple").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+        let lock_hash = lock_file_hash(
+            PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+                .unwrap()
+                .join("tests")
+                .join("lockfiles")
+                .join("simple.lock"),
+        )
+        .unwrap();
+
+        assert_eq!(lock_hash.len(), 64);
+
+        let options = DerivationOptions {
+            lock_hash: Some(lock_hash.clone()),
+            ..Default::default()
+        };
+        let actual = package().into_derivative_with_options(&options).unwrap();
+
+        assert!(actual.starts_with(&format!(
+            "# Generated by nbuild {} from Cargo.lock with sha256 {lock_hash}\n{{ pkgs ? import",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(is_up_to_date(&actual, &lock_hash));
+        assert!(!is_up_to_date(&actual, &"0".repeat(64)));
+        assert!(!is_up_to_date(
+            &package().into_derivative().unwrap(),
+            &lock_hash
+        ));
+    }
+
+    #[test]
+    fn crates_mirror() {
+        let package = || Package {
             name: "simple".to_string(),
             version: "0.1.0".parse().unwrap(),
-            source: PathBuf::from_str("/cargo-nbuild/nbuild-core/tests/simple")
-                .unwrap()
-                .into(),
+            source: PathBuf::from("/simple").into(),
             lib_name: None,
             lib_path: None,
             build_path: None,
             proc_macro: false,
-            dependencies: vec![Package {
-                name: "itoa".to_string(),
-                version: "1.0.6".parse().unwrap(),
-                source: "itoa_sha".into(),
-                lib_name: None,
-                lib_path: None,
-                build_path: None,
-                proc_macro: false,
-                dependencies: Default::default(),
-                build_dependencies: Default::default(),
-                features: Default::default(),
-                edition: "2018".to_string(),
-                printed: false,
-            }
-            .into()],
-            build_dependencies: vec![Package {
-                name: "arbitrary".to_string(),
-                version: "1.3.0".parse().unwrap(),
-                source: "arbitrary_sha".into(),
-                lib_name: None,
-                lib_path: None,
-                build_path: None,
-                proc_macro: false,
-                dependencies: Default::default(),
-                build_dependencies: Default::default(),
-                features: Default::default(),
-                edition: "2018".to_string(),
-                printed: false,
-            }
-            .into()],
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
             features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
             edition: "2021".to_string(),
-            printed: false,
+            links: None,
+            host: false,
         };
 
-        let actual = package.into_derivative();
+        assert!(package().into_derivative().unwrap().contains(
+            r#"    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";"#
+        ));
 
-        assert_eq!(
-            actual,
-            r#"{ pkgs ? import <nixpkgs> {
-  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
-} }:
+        let options = DerivationOptions {
+            crates_mirror: Some("https://mirror.example/crates/".to_string()),
+            ..Default::default()
+        };
 
-let
-  sourceFilter = name: type:
-    let
-      baseName = builtins.baseNameOf (builtins.toString name);
-    in
-      ! (
-        # Filter out git
-        baseName == ".gitignore"
-        || (type == "directory" && baseName == ".git")
-
-        # Filter out build results
-        || (
-          type == "directory" && baseName == "target"
-        )
+        assert!(package()
+            .into_derivative_with_options(&options)
+            .unwrap()
+            .contains(
+                r#"    url = "https://mirror.example/crates/${crateName}/${crateName}-${version}.crate";"#
+            ));
+    }
 
-        # Filter out nix-build result symlinks
-        || (
-          type == "symlink" && pkgs.lib.hasPrefix "result" baseName
+    #[test]
+    fn fetch_crate() {
+        let package = || Package::new("simple", "0.1.0", Source::Local("/simple".into()));
+        let mut options: DerivationOptions = toml::from_str(
+            r#"
+crates-mirror = "https://mirror.example/crates"
+fetch-crate = """
+{ crateName, version, sha256 }: pkgs.fetchurl {
+    url = "@mirror@/${crateName}/${version}/download";
+    curlOpts = "--netrc-file /etc/nix/netrc";
+    inherit sha256;
+  }
+"""
+"#,
         )
-      );
-  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
-  defaultCrateOverrides = pkgs.defaultCrateOverrides // {
-    opentelemetry-proto = attrs: { buildInputs = [ pkgs.protobuf ]; };
-  };
-  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
-    # https://www.pietroalbini.org/blog/downloading-crates-io/
-    # Not rate-limited, CDN URL.
-    name = "${crateName}-${version}.tar.gz";
-    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";
+        .unwrap();
+
+        assert!(package()
+            .into_derivative_with_options(&options)
+            .unwrap()
+            .contains(
+                r#"  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
+    url = "https://mirror.example/crates/${crateName}/${version}/download";
+    curlOpts = "--netrc-file /etc/nix/netrc";
     inherit sha256;
   };
-  buildRustCrate = pkgs.buildRustCrate.override {
-    rustc = rustVersion;
-    inherit defaultCrateOverrides fetchCrate;
-  };
-  preBuild = "rustc -vV";
+"#
+            ));
 
-  # Core
-  simple = buildRustCrate rec {
-    crateName = "simple";
-    version = "0.1.0";
+        // The arguments can come in any order, with defaults and more arguments, or as a single attrset
+        for fetch_crate in [
+            "{ sha256, version, crateName }: pkgs.fetchurl { }",
+            "{ crateName, version, sha256, curlOpts ? \"\", ... }: pkgs.fetchurl { }",
+            "args@{ crateName, version, sha256, ... }: pkgs.fetchurl { }",
+            "args: pkgs.fetchurl { inherit (args) sha256; }",
+        ] {
+            options.fetch_crate = Some(fetch_crate.to_string());
 
-    src = pkgs.lib.cleanSourceWith { filter = sourceFilter;  src = /cargo-nbuild/nbuild-core/tests/simple; };
+            assert!(package().into_derivative_with_options(&options).is_ok());
+        }
 
-    dependencies = [
-      itoa_1_0_6
-    ];
-    buildDependencies = [arbitrary_1_3_0];
-    edition = "2021";
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
+        // Only the argument pattern counts, not the names used in the body
+        options.fetch_crate =
+            Some("{ crateName, version, ... }: pkgs.fetchurl { inherit sha256; }".to_string());
 
-  # Dependencies
-  itoa_1_0_6 = buildRustCrate rec {
-    crateName = "itoa";
-    version = "1.0.6";
+        assert_eq!(
+            package()
+                .into_derivative_with_options(&options)
+                .unwrap_err()
+                .to_string(),
+            "the argument pattern of the fetch-crate template does not take the `sha256` argument of fetchCrate"
+        );
+    }
 
-    sha256 = "itoa_sha";
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
+    #[test]
+    fn crane_backend() {
+        let package = || {
+            Package::new("parent", "0.1.0", Source::Local("/parent".into()))
+                .with_bin("parent", "src/main.rs")
+                .with_dependency(
+                    Package::new("child", "0.1.0", Source::Local("/parent/child".into()))
+                        .with_dependency(
+                            Package::new("itoa", "1.0.6", Source::CratesIo("sha".to_string())),
+                            None,
+                        ),
+                    None,
+                )
+        };
+        let options = DerivationOptions {
+            backend: Backend::Crane,
+            bin: Some("parent".to_string()),
+            crates_mirror: Some("https://mirror.example/crates/".to_string()),
+            ..Default::default()
+        };
+
+        let derivation = package().into_derivative_with_options(&options).unwrap();
+
+        assert!(derivation.contains(
+            r#"  cratesMirror = crane.registryFromDownloadUrl {
+    indexUrl = "https://github.com/rust-lang/crates.io-index";
+    dl = "https://mirror.example/crates/{crate}/{crate}-{version}.crate";
   };
-  arbitrary_1_3_0 = buildRustCrate rec {
-    crateName = "arbitrary";
-    version = "1.3.0";
+  craneLib = (crane.overrideToolchain (p: rustVersion)).appendCrateRegistries [ cratesMirror ];
 
-    sha256 = "arbitrary_sha";
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
+  # Core
+  parent = craneLib.buildPackage rec {
+    pname = "parent";
+    version = "0.1.0";
+
+    src = pkgs.lib.cleanSourceWith { filter = sourceFilter; src = /parent; };
+    cargoVendorDir = craneLib.vendorCargoDeps { inherit src; };
+    cargoExtraArgs = "--locked --package parent --bin parent";
+    strictDeps = true;
   };
 in
-simple
+parent
 "#
-        );
-    }
+        ));
 
-    #[test]
-    fn workspace() {
-        let base = PathBuf::from_str("/cargo-nbuild/nbuild-core/tests/workspace").unwrap();
+        // Cargo builds every crate, so they do not get their own derivations
+        assert!(!derivation.contains("buildRustCrate"));
 
-        let libc = RefCell::new(Package {
-            name: "libc".to_string(),
-            version: "0.2.144".parse().unwrap(),
-            source: "sha".into(),
-            lib_name: None,
-            lib_path: None,
-            build_path: None,
-            proc_macro: false,
-            dependencies: Default::default(),
-            build_dependencies: Default::default(),
-            features: Default::default(),
-            edition: "2015".to_string(),
-            printed: false,
-        })
-        .into();
+        // A member of a workspace is built from the workspace root, where its siblings are
+        let member = Package::new("server", "0.1.0", Source::Local("/repo/server".into()))
+            .with_dependency(
+                Package::new("shared", "0.1.0", Source::Local("/repo/shared".into())),
+                None,
+            );
+        let derivation = member
+            .into_derivative_with_options(&DerivationOptions {
+                backend: Backend::Crane,
+                workspace_root: Some("/repo".into()),
+                ..Default::default()
+            })
+            .unwrap();
 
-        let package = Package {
-            name: "parent".to_string(),
-            version: "0.1.0".parse().unwrap(),
-            source: base.join("parent").into(),
-            lib_name: None,
-            lib_path: None,
-            build_path: None,
-            proc_macro: false,
-            dependencies: vec![
-                Package {
-                    name: "child".to_string(),
-                    version: "0.1.0".parse().unwrap(),
-                    source: base.join("child").into(),
-                    lib_name: None,
-                    lib_path: None,
-                    build_path: None,
-                    proc_macro: false,
-                    dependencies: vec![
-                        Package {
-                            name: "fnv".to_string(),
-                            version: "1.0.7".parse().unwrap(),
-                            source: "sha".into(),
-                            lib_name: None,
-                            lib_path: Some("lib.rs".into()),
-                            build_path: None,
-                            proc_macro: false,
-                            dependencies: Default::default(),
-                            build_dependencies: Default::default(),
-                            features: Default::default(),
-                            edition: "2015".to_string(),
-                            printed: false,
-                        }
-                        .into(),
-                        Package {
-                            name: "itoa".to_string(),
-                            version: "1.0.6".parse().unwrap(),
-                            source: "sha".into(),
-                            lib_name: None,
-                            lib_path: None,
-                            build_path: None,
-                            proc_macro: false,
-                            dependencies: Default::default(),
-                            build_dependencies: Default::default(),
-                            features: Default::default(),
-                            edition: "2018".to_string(),
-                            printed: false,
-                        }
-                        .into(),
-                        Dependency {
-                            package: Rc::clone(&libc),
-                            rename: None,
-                        },
-                        Dependency {
-                            package: RefCell::new(Package {
-                                name: "rename".to_string(),
-                                version: "0.1.0".parse().unwrap(),
-                                source: base.join("rename").into(),
-                                lib_name: Some("lib_rename".to_string()),
-                                lib_path: None,
-                                build_path: None,
-                                proc_macro: false,
-                                dependencies: Default::default(),
-                                build_dependencies: Default::default(),
-                                features: Default::default(),
-                                edition: "2021".to_string(),
-                                printed: false,
-                            })
-                            .into(),
-                            rename: Some("new_name".to_string()),
-                        },
-                        Package {
-                            name: "rustversion".to_string(),
-                            version: "1.0.12".parse().unwrap(),
-                            source: "sha".into(),
-                            lib_name: None,
-                            lib_path: None,
-                            build_path: Some("build/build.rs".into()),
-                            proc_macro: true,
-                            dependencies: Default::default(),
-                            build_dependencies: Default::default(),
-                            features: Default::default(),
-                            edition: "2018".to_string(),
-                            printed: false,
-                        }
-                        .into(),
-                    ],
-                    build_dependencies: vec![Package {
-                        name: "arbitrary".to_string(),
-                        version: "1.3.0".parse().unwrap(),
-                        source: "sha".into(),
-                        lib_name: None,
-                        lib_path: None,
-                        build_path: None,
-                        proc_macro: false,
-                        dependencies: Default::default(),
-                        build_dependencies: Default::default(),
-                        features: Default::default(),
-                        edition: "2018".to_string(),
-                        printed: false,
-                    }
-                    .into()],
-                    features: vec!["one".to_string()],
-                    edition: "2021".to_string(),
-                    printed: false,
-                }
-                .into(),
-                Package {
-                    name: "itoa".to_string(),
-                    version: "0.4.8".parse().unwrap(),
-                    source: "sha".into(),
-                    lib_name: None,
-                    lib_path: None,
-                    build_path: None,
-                    proc_macro: false,
-                    dependencies: Default::default(),
-                    build_dependencies: Default::default(),
-                    features: Default::default(),
-                    edition: "2018".to_string(),
-                    printed: false,
-                }
+        assert!(derivation.contains(
+            r#"    src = pkgs.lib.cleanSourceWith { filter = sourceFilter; src = /repo; };
+    cargoVendorDir = craneLib.vendorCargoDeps { inherit src; };
+    cargoExtraArgs = "--locked --package server";
+"#
+        ));
+
+        // The features of the package are passed on to cargo, and so is the env of the core crate
+        let derivation = package()
+            .into_derivative_with_options(&DerivationOptions {
+                features: vec!["json".to_string(), "child/std".to_string()],
+                no_default_features: true,
+                crates: [(
+                    "parent@0.1.0".to_string(),
+                    CrateOptions {
+                        env: [("KEY".to_string(), "value".to_string())].into(),
+                        ..Default::default()
+                    },
+                )]
                 .into(),
-                Dependency {
-                    package: libc,
-                    rename: None,
+                ..options.clone()
+            })
+            .unwrap();
+
+        assert!(derivation.contains(
+            r#"    cargoExtraArgs = "--locked --package parent --bin parent --features json,child/std --no-default-features";
+    strictDeps = true;
+    KEY = "value";
+"#
+        ));
+
+        // Options cargo would not get are refused
+        for (options, option) in [
+            (
+                DerivationOptions {
+                    target: Some("aarch64-unknown-linux-gnu".to_string()),
+                    ..options.clone()
+                },
+                "cross-compiling",
+            ),
+            (
+                DerivationOptions {
+                    codegen_backend: Some("cranelift".to_string()),
+                    ..options.clone()
+                },
+                "a codegen backend",
+            ),
+            (
+                DerivationOptions {
+                    link_args: vec!["-fuse-ld=mold".to_string()],
+                    ..options.clone()
+                },
+                "link args",
+            ),
+            (
+                DerivationOptions {
+                    profile: Profile {
+                        lto: Some("fat".to_string()),
+                        ..Profile::release()
+                    },
+                    ..options.clone()
+                },
+                "profile settings other than the dev and release profiles",
+            ),
+            (
+                DerivationOptions {
+                    hooks: Hooks {
+                        pre_build: Some("echo hi".to_string()),
+                        ..Default::default()
+                    },
+                    ..options.clone()
+                },
+                "hooks",
+            ),
+            (
+                DerivationOptions {
+                    strip: Some(true),
+                    ..options.clone()
                 },
-                Package {
-                    name: "targets".to_string(),
-                    version: "0.1.0".parse().unwrap(),
-                    source: base.join("targets").into(),
-                    lib_name: None,
-                    lib_path: None,
-                    build_path: None,
-                    proc_macro: false,
-                    dependencies: Default::default(),
-                    build_dependencies: Default::default(),
-                    features: vec!["unix".to_string()],
-                    edition: "2021".to_string(),
-                    printed: false,
-                }
-                .into(),
-            ],
+                "strip",
+            ),
+            (
+                DerivationOptions {
+                    fetch_crate: Some("args: pkgs.fetchCrate args".to_string()),
+                    ..options.clone()
+                },
+                "a fetch-crate template",
+            ),
+            (
+                DerivationOptions {
+                    local_crates: true,
+                    ..options.clone()
+                },
+                "local crates",
+            ),
+            (
+                DerivationOptions {
+                    sys_heuristics: true,
+                    ..options.clone()
+                },
+                "the sys heuristics",
+            ),
+            (
+                DerivationOptions {
+                    shuttle_defaults: true,
+                    ..options.clone()
+                },
+                "the shuttle defaults",
+            ),
+            (
+                DerivationOptions {
+                    crates: [(
+                        "itoa".to_string(),
+                        CrateOptions {
+                            extra_rustc_opts: vec!["-Copt-level=3".to_string()],
+                            ..Default::default()
+                        },
+                    )]
+                    .into(),
+                    ..options.clone()
+                },
+                "per-crate rustc options",
+            ),
+            (
+                DerivationOptions {
+                    crates: [(
+                        "parent".to_string(),
+                        CrateOptions {
+                            build_inputs: vec!["zlib".to_string()],
+                            ..Default::default()
+                        },
+                    )]
+                    .into(),
+                    ..options.clone()
+                },
+                "per-crate build inputs",
+            ),
+            (
+                DerivationOptions {
+                    crates: [(
+                        "child".to_string(),
+                        CrateOptions {
+                            edition: Some(Edition::E2021),
+                            ..Default::default()
+                        },
+                    )]
+                    .into(),
+                    ..options.clone()
+                },
+                "per-crate editions",
+            ),
+            (
+                DerivationOptions {
+                    crates: [(
+                        "child".to_string(),
+                        CrateOptions {
+                            env: [("KEY".to_string(), "value".to_string())].into(),
+                            ..Default::default()
+                        },
+                    )]
+                    .into(),
+                    ..options.clone()
+                },
+                "env for crates other than the core crate",
+            ),
+        ] {
+            assert_eq!(
+                package()
+                    .into_derivative_with_options(&options)
+                    .unwrap_err()
+                    .to_string(),
+                format!("the crane backend does not support {option}")
+            );
+        }
+
+        let outside = Package::new("parent", "0.1.0", Source::Local("/parent".into()))
+            .with_dependency(
+                Package::new("shared", "0.1.0", Source::Local("/shared".into())),
+                None,
+            );
+
+        assert_eq!(
+            outside
+                .into_derivative_with_options(&options)
+                .unwrap_err()
+                .to_string(),
+            "the crane backend only finds crates from crates.io, git and the workspace's directory, but shared 0.1.0 comes from /shared"
+        );
+    }
+
+    #[test]
+    fn constructors() {
+        let child = Package::new("child", "0.1.0", Source::Local("/child".into())).with_dependency(
+            Package::new("itoa", "1.0.6", Source::CratesIo("sha".to_string()))
+                .with_features(["std"]),
+            Some("numbers"),
+        );
+        let package = Package::new("parent", "0.1.0", Source::Local("/parent".into()))
+            .with_bin("parent", "src/main.rs")
+            .with_dependency(child, None)
+            .with_build_dependency(
+                Package::new("derive", "0.2.0", Source::CratesIo("sha".to_string()))
+                    .with_edition("2018")
+                    .with_proc_macro(),
+            );
+
+        let derivation = package.into_derivative().unwrap();
+
+        assert!(derivation.contains(
+            r#"    dependencies = [ child_0_1_0 ];
+    buildDependencies = [ derive_0_2_0 ];"#
+        ));
+        assert!(derivation.contains(
+            r#"    crateRenames = { "itoa" = [ { rename = "numbers"; version = "1.0.6"; } ]; };"#
+        ));
+        assert!(derivation.contains(
+            r#"  itoa_1_0_6 = buildRustCrate rec {
+    crateName = "itoa";
+    version = "1.0.6";
+
+    sha256 = "sha";
+    features = [ "std" ];"#
+        ));
+        assert!(derivation.contains(
+            r#"    procMacro = true;
+    edition = "2018";"#
+        ));
+    }
+
+    #[test]
+    fn attr_name() {
+        let package = || Package {
+            name: "simple".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from("/simple").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
+            build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+
+        let derivation = package().into_derivative().unwrap();
+
+        assert!(derivation.contains("\n  simple = buildRustCrate rec {\n"));
+        assert!(derivation.ends_with("\nin\nsimple\n"));
+
+        let options = DerivationOptions {
+            attr_name: Some("my-app".to_string()),
+            ..Default::default()
+        };
+        let derivation = package().into_derivative_with_options(&options).unwrap();
+
+        assert!(derivation.contains("\n  my_app = buildRustCrate rec {\n"));
+        assert!(derivation.ends_with("\nin\nmy_app\n"));
+    }
+
+    #[test]
+    fn dashed_names() {
+        let dependency = |name: &str| Package {
+            name: name.to_string(),
+            version: "0.12.0-alpha.1".parse().unwrap(),
+            source: "sha".into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
+            features: Default::default(),
+            dependencies: Default::default(),
             build_dependencies: Default::default(),
+            edition: "2021".to_string(),
+            links: None,
+            host: false,
+        };
+        let package = |dependencies: Vec<Package>| Package {
+            name: "my-app".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            source: PathBuf::from("/my-app").into(),
+            lib_name: None,
+            lib_path: None,
+            build_path: None,
+            proc_macro: false,
+            crate_types: Default::default(),
+            bins: Default::default(),
+            examples: Default::default(),
             features: Default::default(),
+            dependencies: dependencies.into_iter().map(Into::into).collect(),
+            build_dependencies: Default::default(),
             edition: "2021".to_string(),
-            printed: false,
+            links: None,
+            host: false,
         };
 
-        let actual = package.into_derivative();
+        let actual = package(vec![dependency("opentelemetry-proto")])
+            .into_derivative()
+            .unwrap();
+
+        assert!(actual.contains("  my_app = buildRustCrate rec {\n    crateName = \"my-app\";"));
+        assert!(actual.contains("    dependencies = [ opentelemetry_proto_0_12_0_alpha_1 ];"));
+        assert!(actual.contains(
+            "  opentelemetry_proto_0_12_0_alpha_1 = buildRustCrate rec {\n    crateName = \"opentelemetry-proto\";"
+        ));
+        assert!(actual.ends_with("in\nmy_app\n"));
 
         assert_eq!(
-            actual,
-            r#"{ pkgs ? import <nixpkgs> {
-  overlays = [ (import (builtins.fetchTarball "https://github.com/oxalica/rust-overlay/archive/master.tar.gz")) ];
-} }:
+            package(vec![dependency("foo-bar"), dependency("foo_bar")])
+                .into_derivative()
+                .unwrap_err()
+                .to_string(),
+            "the crates foo-bar and foo_bar both use the identifier foo_bar_0_12_0_alpha_1"
+        );
+    }
 
-let
-  sourceFilter = name: type:
-    let
-      baseName = builtins.baseNameOf (builtins.toString name);
-    in
-      ! (
-        # Filter out git
-        baseName == ".gitignore"
-        || (type == "directory" && baseName == ".git")
-
-        # Filter out build results
-        || (
-          type == "directory" && baseName == "target"
+    #[test]
+    fn extra_rustc_opts() {
+        let options: DerivationOptions = toml::from_str(
+            r#"
+[crates.openssl-sys]
+extra-rustc-opts = ["-C", "target-feature=+crt-static", "--cap-lints=warn"]
+"#,
         )
+        .unwrap();
 
-        # Filter out nix-build result symlinks
-        || (
-          type == "symlink" && pkgs.lib.hasPrefix "result" baseName
-        )
-      );
-  rustVersion = pkgs.rust-bin.stable."1.68.0".default;
-  defaultCrateOverrides = pkgs.defaultCrateOverrides // {
-    opentelemetry-proto = attrs: { buildInputs = [ pkgs.protobuf ]; };
-  };
-  fetchCrate = { crateName, version, sha256 }: pkgs.fetchurl {
-    # https://www.pietroalbini.org/blog/downloading-crates-io/
-    # Not rate-limited, CDN URL.
-    name = "${crateName}-${version}.tar.gz";
-    url = "https://static.crates.io/crates/${crateName}/${crateName}-${version}.crate";
-    inherit sha256;
-  };
-  buildRustCrate = pkgs.buildRustCrate.override {
-    rustc = rustVersion;
-    inherit defaultCrateOverrides fetchCrate;
-  };
-  preBuild = "rustc -vV";
+        assert_eq!(
+            options.profile_attributes("openssl-sys", &"0.9.88".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" "-C" "target-feature=+crt-static" "--cap-lints=warn" ];"#
+        );
+        assert_eq!(
+            options.profile_attributes("itoa", &"1.0.6".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];"#
+        );
+    }
 
-  # Core
-  parent = buildRustCrate rec {
-    crateName = "parent";
-    version = "0.1.0";
+    #[test]
+    fn link_args() {
+        let mut options: DerivationOptions = toml::from_str(
+            r#"
+link-args = ["-Wl,--no-as-needed"]
 
-    src = pkgs.lib.cleanSourceWith { filter = sourceFilter;  src = /cargo-nbuild/nbuild-core/tests/workspace/parent; };
+[crates.openssl-sys]
+extra-rustc-opts = ["--cap-lints=warn"]
+link-args = ["-lssl"]
+"#,
+        )
+        .unwrap();
 
-    dependencies = [
-      child_0_1_0
-      itoa_0_4_8
-      libc_0_2_144
-      targets_0_1_0
-    ];
-    edition = "2021";
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
+        assert_eq!(
+            options.profile_attributes("openssl-sys", &"0.9.88".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [
+      "-C embed-bitcode=no"
+      "-C link-arg=-Wl,--no-as-needed"
+      "--cap-lints=warn"
+      "-C link-arg=-lssl"
+    ];"#
+        );
+        assert_eq!(
+            options.profile_attributes("itoa", &"1.0.6".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" "-C link-arg=-Wl,--no-as-needed" ];"#
+        );
 
-  # Dependencies
-  child_0_1_0 = buildRustCrate rec {
-    crateName = "child";
-    version = "0.1.0";
+        options.link_args.clear();
 
-    src = pkgs.lib.cleanSourceWith { filter = sourceFilter;  src = /cargo-nbuild/nbuild-core/tests/workspace/child; };
-    dependencies = [fnv_1_0_7 itoa_1_0_6 libc_0_2_144 rename_0_1_0 rustversion_1_0_12];
-    buildDependencies = [arbitrary_1_3_0];
-    crateRenames = {"rename" = [{ rename = "new_name"; version = "0.1.0"; }];};
-    features = ["one"];
-    edition = "2021";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  fnv_1_0_7 = buildRustCrate rec {
-    crateName = "fnv";
-    version = "1.0.7";
+        assert_eq!(
+            options.profile_attributes("itoa", &"1.0.6".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" ];"#
+        );
+    }
 
-    sha256 = "sha";
-    libPath = "lib.rs";
-    edition = "2015";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  itoa_1_0_6 = buildRustCrate rec {
-    crateName = "itoa";
-    version = "1.0.6";
+    #[test]
+    fn rustflags() {
+        let mut options: DerivationOptions = toml::from_str(
+            r#"
+[crates.openssl-sys]
+extra-rustc-opts = ["--cap-lints=warn"]
+"#,
+        )
+        .unwrap();
+        options.rustflags = vec!["-C".to_string(), "target-cpu=native".to_string()];
 
-    sha256 = "sha";
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  libc_0_2_144 = buildRustCrate rec {
-    crateName = "libc";
-    version = "0.2.144";
+        // The flags go to every crate, after the flags of the profile and before those of the crate
+        let derivation = Package::new("simple", "0.1.0", Source::Local("/simple".into()))
+            .with_dependency(
+                Package::new(
+                    "openssl-sys",
+                    "0.9.88",
+                    Source::CratesIo("openssl_sha".to_string()),
+                ),
+                None,
+            )
+            .into_derivative_with_options(&options)
+            .unwrap();
 
-    sha256 = "sha";
-    edition = "2015";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  rename_0_1_0 = buildRustCrate rec {
-    crateName = "rename";
-    libName = "lib_rename";
-    version = "0.1.0";
+        assert!(derivation
+            .contains(r#"extraRustcOpts = [ "-C embed-bitcode=no" "-C" "target-cpu=native" ];"#));
+        assert!(derivation.contains(
+            r#"extraRustcOpts = [ "-C embed-bitcode=no" "-C" "target-cpu=native" "--cap-lints=warn" ];"#
+        ));
+    }
 
-    src = pkgs.lib.cleanSourceWith { filter = sourceFilter;  src = /cargo-nbuild/nbuild-core/tests/workspace/rename; };
-    edition = "2021";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  rustversion_1_0_12 = buildRustCrate rec {
-    crateName = "rustversion";
-    version = "1.0.12";
+    #[test]
+    fn edition_override() {
+        let options: DerivationOptions = toml::from_str(
+            r#"
+[crates."itoa@1.0.6"]
+edition = "2015"
+"#,
+        )
+        .unwrap();
 
-    sha256 = "sha";
-    build = "build/build.rs";
-    procMacro = true;
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  arbitrary_1_3_0 = buildRustCrate rec {
-    crateName = "arbitrary";
-    version = "1.3.0";
+        let derivation = Package::new("simple", "0.1.0", Source::Local("/simple".into()))
+            .with_dependency(
+                Package::new("itoa", "1.0.6", Source::CratesIo("itoa_sha".to_string()))
+                    .with_edition("2018"),
+                None,
+            )
+            .into_derivative_with_options(&options)
+            .unwrap();
 
-    sha256 = "sha";
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  itoa_0_4_8 = buildRustCrate rec {
-    crateName = "itoa";
-    version = "0.4.8";
+        assert!(derivation.contains("    edition = \"2015\";"));
+        assert!(!derivation.contains("    edition = \"2018\";"));
 
-    sha256 = "sha";
-    edition = "2018";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-  targets_0_1_0 = buildRustCrate rec {
-    crateName = "targets";
-    version = "0.1.0";
+        // Other versions keep the edition of their manifest
+        assert_eq!(
+            options.edition("itoa", &"1.0.7".parse().unwrap(), "2018"),
+            "2018"
+        );
 
-    src = pkgs.lib.cleanSourceWith { filter = sourceFilter;  src = /cargo-nbuild/nbuild-core/tests/workspace/targets; };
-    features = ["unix"];
-    edition = "2021";
-    crateBin = [];
-    codegenUnits = 16;
-    extraRustcOpts = [ "-C embed-bitcode=no" ];
-    inherit preBuild;
-  };
-in
-parent
+        assert_eq!(
+            toml::from_str::<DerivationOptions>(
+                r#"
+[crates.itoa]
+edition = "2017"
 "#
+            )
+            .unwrap_err()
+            .message(),
+            "unknown variant `2017`, expected one of `2015`, `2018`, `2021`, `2024`"
+        );
+    }
+
+    #[test]
+    fn codegen_backend() {
+        let mut options = DerivationOptions {
+            codegen_backend: Some("cranelift".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.check_codegen_backend().unwrap_err().to_string(),
+            "the codegen backend cranelift needs a nightly rust version, but the rust version is 1.68.0"
+        );
+
+        options.rust_version = Some("nightly-2023-06-01".to_string());
+        options.check_codegen_backend().unwrap();
+
+        assert_eq!(
+            options.rust_version(),
+            r#"pkgs.rust-bin.nightly."2023-06-01".default.override { extensions = [ "rustc-codegen-cranelift-preview" ]; }"#
+        );
+        assert_eq!(
+            options.profile_attributes("itoa", &"1.0.6".parse().unwrap(), false, false),
+            r#"codegenUnits = 16;
+    extraRustcOpts = [ "-C embed-bitcode=no" "-Z codegen-backend=cranelift" ];"#
+        );
+
+        options.rust_version = Some("nightly".to_string());
+        options.codegen_backend = None;
+
+        assert_eq!(
+            options.rust_version(),
+            "pkgs.rust-bin.nightly.latest.default"
         );
     }
 }
@@ -0,0 +1,162 @@
+//! The crate derivations a build consists of, to estimate the work before running a long `nix build`
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use super::{nix, Source};
+
+/// The crates of a package in the order nix builds them: every crate comes after its dependencies, with the core
+/// crate last
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BuildPlan {
+    pub crates: Vec<PlannedCrate>,
+}
+
+/// A crate derivation of a [BuildPlan]
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PlannedCrate {
+    /// The attribute of the crate in the derivation file
+    pub identifier: String,
+    pub name: String,
+    pub version: String,
+    pub source: SourceKind,
+    pub features: Vec<String>,
+
+    /// Whether nix still has to build the crate, or `None` when the nix store was not checked
+    pub fresh: Option<bool>,
+}
+
+/// Where the code of a planned crate comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+    Local,
+    CratesIo,
+    Registry,
+    Git,
+}
+
+impl From<&Source> for SourceKind {
+    fn from(source: &Source) -> Self {
+        match source {
+            Source::Local(_) => Self::Local,
+            Source::CratesIo(_) => Self::CratesIo,
+            Source::Registry { .. } => Self::Registry,
+            Source::Git { .. } => Self::Git,
+        }
+    }
+}
+
+impl BuildPlan {
+    /// Get the crate derivations which are needed to build all the `packages`. Crates they share are only listed once.
+    pub fn of(packages: &[nix::Package]) -> Self {
+        let mut plan = Self {
+            crates: Default::default(),
+        };
+        let mut seen = Default::default();
+
+        for package in packages {
+            package.add_to_plan(&mut plan, &mut seen);
+        }
+
+        plan
+    }
+
+    /// Mark which crates nix still has to build, from the output of `nix build --dry-run`. Crates which are not in its
+    /// list of derivations to build are already in the nix store or a binary cache.
+    pub fn mark_fresh(&mut self, dry_run: &str) {
+        // `buildRustCrate` names each derivation `rust_<crate name>-<version>`
+        let to_build: HashSet<_> = dry_run
+            .lines()
+            .filter_map(|line| line.trim().strip_suffix(".drv"))
+            .filter_map(|path| path.split_once("-rust_").map(|(_, name)| name))
+            .collect();
+
+        for planned in &mut self.crates {
+            planned.fresh =
+                Some(to_build.contains(format!("{}-{}", planned.name, planned.version).as_str()));
+        }
+    }
+}
+
+impl nix::Package {
+    /// Get the crate derivations which are needed to build this package
+    pub fn build_plan(&self) -> BuildPlan {
+        BuildPlan::of(std::slice::from_ref(self))
+    }
+
+    /// Add the dependencies of this package to the `plan` and then the package itself. The `seen` set makes sure
+    /// shared packages are only added once.
+    fn add_to_plan(&self, plan: &mut BuildPlan, seen: &mut HashSet<String>) {
+        let identifier = self.identifier();
+
+        if !seen.insert(identifier.clone()) {
+            return;
+        }
+
+        for dependency in self.dependencies.iter().chain(&self.build_dependencies) {
+            dependency.package.borrow().add_to_plan(plan, seen);
+        }
+
+        plan.crates.push(PlannedCrate {
+            identifier,
+            name: self.name.clone(),
+            version: self.version.to_string(),
+            source: (&self.source).into(),
+            features: self.features.clone(),
+            fresh: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::{nix::Package, Source};
+
+    use super::SourceKind;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn build_plan() {
+        let itoa = || Package::new("itoa", "1.0.6", Source::CratesIo("sha".to_string()));
+        let package = Package::new("parent", "0.1.0", Source::Local("/parent".into()))
+            .with_dependency(
+                Package::new("child", "0.1.0", Source::Local("/parent/child".into()))
+                    .with_dependency(itoa().with_features(["std"]), None),
+                None,
+            )
+            .with_build_dependency(itoa().with_features(["std"]));
+
+        let mut plan = package.build_plan();
+
+        // The shared crate is only built once, before the crates depending on it
+        assert_eq!(
+            plan.crates
+                .iter()
+                .map(|c| (c.identifier.as_str(), c.source, c.features.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("itoa_1_0_6", SourceKind::CratesIo, vec!["std".to_string()]),
+                ("child_0_1_0", SourceKind::Local, vec![]),
+                ("parent_0_1_0", SourceKind::Local, vec![]),
+            ]
+        );
+        assert!(plan.crates.iter().all(|c| c.fresh.is_none()));
+
+        plan.mark_fresh(
+            "these 2 derivations will be built:
+  /nix/store/4b7vcyzw6fxfx1l0ivd0wsc5vxkgz1x2-rust_child-0.1.0.drv
+  /nix/store/m5bwqzlmfbcbq8ymm20ngvl7y5fb1z4z-rust_parent-0.1.0.drv
+these 2 paths will be fetched (0.05 MiB download, 0.20 MiB unpacked):
+  /nix/store/sfg4i4ix8hbwh56s8g1v4wwrd4bzvn4h-rust_itoa-1.0.6-lib
+",
+        );
+
+        assert_eq!(
+            plan.crates.iter().map(|c| c.fresh).collect::<Vec<_>>(),
+            vec![Some(false), Some(true), Some(true)]
+        );
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    println!("cargo:warning={}", child::mode());
+}
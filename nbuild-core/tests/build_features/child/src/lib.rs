@@ -0,0 +1,7 @@
+pub fn mode() -> &'static str {
+    if cfg!(feature = "build") {
+        "build"
+    } else {
+        "normal"
+    }
+}
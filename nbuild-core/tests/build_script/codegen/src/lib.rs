@@ -0,0 +1,3 @@
+pub fn message() -> &'static str {
+    "generated"
+}
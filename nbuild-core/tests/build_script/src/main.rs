@@ -0,0 +1,3 @@
+fn main() {
+    println!("{} {}", env!("ROOT_BUILD"), child::GENERATED);
+}
@@ -0,0 +1,4 @@
+#[no_mangle]
+pub extern "C" fn add(left: u8, right: u8) -> u8 {
+    left + right
+}
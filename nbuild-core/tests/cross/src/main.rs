@@ -0,0 +1,5 @@
+fn main() {
+    println!("{}", derive::answer!());
+    println!("{}", helper::answer());
+    println!("{}", arm::NAME);
+}
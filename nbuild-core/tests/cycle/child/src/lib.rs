@@ -0,0 +1,11 @@
+pub fn value() -> u8 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn value() {
+        assert_eq!(cycle::value(), 5);
+    }
+}
@@ -0,0 +1,2 @@
+#[cfg(feature = "derive")]
+pub use codec_derive::derive;
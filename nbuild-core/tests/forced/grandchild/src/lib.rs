@@ -0,0 +1,7 @@
+pub fn answer() -> u32 {
+    if cfg!(feature = "big") {
+        420
+    } else {
+        42
+    }
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "build")]
+pub fn mode() -> &'static str {
+    "build"
+}
+
+#[cfg(not(feature = "build"))]
+pub fn mode() -> &'static str {
+    "normal"
+}
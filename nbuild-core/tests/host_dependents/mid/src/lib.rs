@@ -0,0 +1,3 @@
+pub fn mode() -> &'static str {
+    leaf::mode()
+}
@@ -0,0 +1,4 @@
+#[cfg(feature = "itoa")]
+pub fn format(value: u8) -> String {
+    itoa::Buffer::new().format(value).to_string()
+}
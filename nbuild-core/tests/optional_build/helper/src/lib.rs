@@ -0,0 +1 @@
+pub const VERSION: &str = env!("HELPER_VERSION");
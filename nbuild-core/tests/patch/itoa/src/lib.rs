@@ -0,0 +1,3 @@
+pub fn patched() -> &'static str {
+    "patched"
+}
@@ -0,0 +1,4 @@
+fn main() {
+    println!("{}", derive::answer!());
+    println!("{}", helper::answer());
+}
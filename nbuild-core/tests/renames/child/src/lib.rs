@@ -0,0 +1,11 @@
+use std::io::Write;
+
+pub fn format(value: u8) -> String {
+    let mut old = Vec::new();
+    itoa_old::write(&mut old, value).unwrap();
+    old.write_all(b" ").unwrap();
+
+    let mut new = itoa_new::Buffer::new();
+
+    format!("{}{}", String::from_utf8(old).unwrap(), new.format(value))
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "normal")]
+pub fn mode() -> &'static str {
+    "normal"
+}
+
+#[cfg(feature = "build")]
+pub fn mode() -> &'static str {
+    "build"
+}
@@ -0,0 +1,4 @@
+fn main() {
+    #[cfg(feature = "big")]
+    println!("{}", extra::size());
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "left")]
+pub fn left() -> &'static str {
+    "left"
+}
+
+#[cfg(feature = "right")]
+pub fn right() -> &'static str {
+    "right"
+}
@@ -0,0 +1,3 @@
+fn main() {
+    println!("{} {}", left::name(), right::name());
+}
@@ -1,59 +1,514 @@
-use std::{env::current_dir, error::Error, process::Stdio};
+use std::{env::current_dir, error::Error, fs, path::PathBuf, time::Duration};
 
-use nbuild_core::models::{cargo, nix};
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
+use clap::{builder::FalseyValueParser, ArgAction, Parser, ValueEnum};
+use nbuild_core::{
+    build::{self, BuildOptions},
+    models::{cargo, nix},
 };
-use tracing_subscriber::prelude::*;
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let fmt_layer = tracing_subscriber::fmt::layer().pretty().with_ansi(false);
-    let filter_layer = tracing_subscriber::EnvFilter::from_default_env();
-
-    tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt_layer)
-        .init();
-
-    let mut package = cargo::Package::from_current_dir(current_dir()?)?;
-    package.resolve();
-
-    let package: nix::Package = package.into();
-    package.into_file()?;
-
-    let mut cmd = Command::new("nix");
-    cmd.args([
-        "build",
-        "--file",
-        ".nbuild.nix",
-        "--max-jobs",
-        "auto",
-        "--cores",
-        "0",
-    ])
-    .stdout(Stdio::piped());
-
-    let mut child = cmd.spawn()?;
-    let stdout = child.stdout.take().expect("to get handle on stdout");
-
-    let mut reader = BufReader::new(stdout).lines();
-
-    // Drive process forward
-    tokio::spawn(async move {
-        let status = child.wait().await.expect("build to finish");
-
-        if status.success() {
-            println!("Build done");
-        } else {
-            println!("Build failed");
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+/// Cargo passes the subcommand name as the first argument, so `cargo nbuild` is parsed as `cargo-nbuild nbuild`
+#[derive(Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+enum Cli {
+    Nbuild(Args),
+}
+
+/// Build a Rust project using the nix package manager
+#[derive(clap::Args)]
+#[command(version)]
+struct Args {
+    /// Build this crate from crates.io instead of the local package
+    #[arg(value_name = "NAME@VERSION", conflicts_with_all = ["manifest_path", "check", "explain_drops"])]
+    published: Option<cargo::CrateSpec>,
+
+    /// Path to the Cargo.toml of the package to build
+    #[arg(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+
+    /// Write a derivation with an attribute for every member of the workspace, like `cargo build --workspace`
+    #[arg(long, conflicts_with_all = ["published", "stats", "explain_drops", "dump_graph"])]
+    workspace: bool,
+
+    /// Leave this member out of the attributes of the `--workspace` derivation. It is still built when another
+    /// member depends on it. Can be given more than once
+    #[arg(long, value_name = "PACKAGE", requires = "workspace")]
+    exclude: Vec<String>,
+
+    /// Leave the members which fail to resolve out of the `--workspace` derivation, and report them at the end
+    /// instead of stopping at the first one
+    #[arg(long, requires = "workspace", conflicts_with_all = ["print", "plan", "diff"])]
+    keep_going: bool,
+
+    /// Add a `default` attribute to the `--workspace` derivation for the only member with a bin target, and build it
+    #[arg(long, requires = "workspace")]
+    workspace_default: bool,
+
+    /// Use the vendored crates in this directory instead of fetching them
+    #[arg(long, value_name = "DIR")]
+    vendor_dir: Option<PathBuf>,
+
+    /// Read the crate checksums from this lock file instead of the workspace's Cargo.lock
+    #[arg(long, value_name = "PATH")]
+    locked_file: Option<PathBuf>,
+
+    /// Require Cargo.lock to be up-to-date and do not access the network
+    #[arg(long)]
+    frozen: bool,
+
+    /// Require Cargo.lock to be up-to-date
+    #[arg(long)]
+    locked: bool,
+
+    /// Run without accessing the network
+    #[arg(long)]
+    offline: bool,
+
+    /// Override a cargo config value while reading the package metadata, like `net.git-fetch-with-cli=true`. Passed
+    /// on to cargo as `--config`. Can be given more than once
+    #[arg(long = "cargo-config", value_name = "KEY=VALUE")]
+    cargo_config: Vec<cargo::ConfigOverride>,
+
+    /// Give up when reading the package metadata takes longer than this many seconds. Network errors while reading
+    /// it are retried a couple of times either way
+    #[arg(long, value_name = "SECONDS")]
+    metadata_timeout: Option<u64>,
+
+    /// Keep the dependencies of this target triple. Can be given more than once to generate a derivation which works
+    /// on all of them
+    #[arg(long = "platform", value_name = "TRIPLE")]
+    platforms: Vec<String>,
+
+    /// Cross-compile for this target triple. Build scripts and proc-macros are still built for the current platform
+    #[arg(long, value_name = "TRIPLE", conflicts_with = "platforms")]
+    target: Option<String>,
+
+    /// Comma or space separated list of features of the package to turn on
+    #[arg(
+        long,
+        short = 'F',
+        value_name = "FEATURES",
+        value_delimiter = ',',
+        env = "NBUILD_FEATURES"
+    )]
+    features: Vec<String>,
+
+    /// Do not turn on the default features of the package
+    #[arg(long, env = "NBUILD_NO_DEFAULT_FEATURES", value_parser = FalseyValueParser::new())]
+    no_default_features: bool,
+
+    /// Always run `cargo metadata` instead of reusing its output from `nbuild-cache` in the target directory
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Resolve the package for each of these comma separated triples, and write a single derivation with an output
+    /// for each of them. Outputs are named after their nix system, like `x86_64-linux`, or after the triple when it is
+    /// cross-compiled. Only the output for the current system is built
+    #[arg(
+        long,
+        value_name = "TRIPLE,...",
+        value_delimiter = ',',
+        conflicts_with_all = ["platforms", "target", "backend", "plan"]
+    )]
+    targets: Vec<String>,
+
+    /// Run `cargo generate-lockfile` when the package does not have a Cargo.lock yet
+    #[arg(long)]
+    generate_lockfile: bool,
+
+    /// Print the resolved dependency tree in the given format instead of building
+    #[arg(long, value_name = "FORMAT")]
+    dump_graph: Option<GraphFormat>,
+
+    /// Write the derivation and print the crates it builds as JSON, in build order, instead of building. Unless
+    /// `--no-build` is given, nix is asked which crates are not built yet
+    #[arg(long, conflicts_with = "print")]
+    plan: bool,
+
+    /// Print a summary of the crates in the resolved dependency tree instead of building
+    #[arg(long)]
+    stats: bool,
+
+    /// Print the dependencies which are left out of the resolved dependency tree, and why, instead of building
+    #[arg(long)]
+    explain_drops: bool,
+
+    /// Check that the derivation file was generated from the current Cargo.lock, and fail when it is stale
+    #[arg(long)]
+    check: bool,
+
+    /// Print what would change in the derivation file, without writing it, and fail when it differs
+    #[arg(long, conflicts_with_all = ["check", "print"])]
+    diff: bool,
+
+    /// Only generate the derivation file without building it
+    #[arg(long, visible_alias = "generate-only")]
+    no_build: bool,
+
+    /// Check that the derivation parses with `nix-instantiate --parse` before building it
+    #[arg(long, conflicts_with = "print")]
+    check_eval: bool,
+
+    /// Where nix links the build output instead of `result`
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["no_link", "no_build"])]
+    out_link: Option<PathBuf>,
+
+    /// Do not let nix link the build output, like to only fill the nix store or a cache
+    #[arg(long, conflicts_with = "no_build")]
+    no_link: bool,
+
+    /// Write the derivation to this file
+    #[arg(long, value_name = "PATH", default_value = build::DERIVATION_FILE)]
+    output: PathBuf,
+
+    /// Print the derivation to stdout instead of writing and building it
+    #[arg(long, conflicts_with_all = ["output", "no_build"])]
+    print: bool,
+
+    /// The nix builder to make the derivation for
+    #[arg(long, value_name = "BACKEND", conflicts_with = "target")]
+    backend: Option<BackendName>,
+
+    /// Build all the crates with the settings of this cargo profile
+    #[arg(long, value_name = "PROFILE")]
+    profile: Option<ProfileName>,
+
+    /// Build with this version of the stable rust toolchain, or with `nightly` or `nightly-<DATE>`
+    #[arg(long, value_name = "VERSION")]
+    rust_version: Option<String>,
+
+    /// Have rustc use this codegen backend, like `cranelift`. Needs a nightly rust version
+    #[arg(long, value_name = "NAME")]
+    codegen_backend: Option<String>,
+
+    /// Pass an argument to the linker of every crate, like `-Wl,--no-as-needed`
+    #[arg(long = "link-arg", value_name = "ARG", allow_hyphen_values = true)]
+    link_args: Vec<String>,
+
+    /// Only build the specified binary
+    #[arg(long, value_name = "NAME")]
+    bin: Option<String>,
+
+    /// Build the specified example instead of the binaries
+    #[arg(long, value_name = "NAME", conflicts_with = "bin")]
+    example: Option<String>,
+
+    /// Name of the attribute the derivation file evaluates to. Defaults to the crate name
+    #[arg(long, value_name = "NAME")]
+    attr_name: Option<String>,
+
+    /// Add `doctest = false` to the core crate, for overrides and hooks which run its tests
+    #[arg(long)]
+    no_doctest: bool,
+
+    /// Strip all the symbols from the binaries of the core crate, instead of only the debug info
+    #[arg(long, conflicts_with = "no_strip")]
+    strip: bool,
+
+    /// Keep the debug info and symbols in the binaries of the core crate
+    #[arg(long)]
+    no_strip: bool,
+
+    /// Set an environment variable while building the core crate
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Download crates.io crates from this mirror instead of static.crates.io
+    #[arg(long, value_name = "URL")]
+    crates_mirror: Option<String>,
+
+    /// Take the crates.io crates from the local cargo cache instead of downloading them
+    #[arg(long, conflicts_with = "crates_mirror")]
+    local_crates: bool,
+
+    /// Add the crate overrides needed by shuttle projects
+    #[arg(long)]
+    shuttle_defaults: bool,
+
+    /// Add `pkg-config` to the native build inputs of `*-sys` crates and crates which declare `links`
+    #[arg(long)]
+    sys_heuristics: bool,
+
+    /// Check the crates.io checksums in Cargo.lock against the locally downloaded crates
+    #[arg(long)]
+    verify_checksums: bool,
+
+    /// Swap a crate for another crates.io version, like `openssl@0.10.54=0.10.55`. The sha256 of the new version can
+    /// be added after a `:`, otherwise it is read from the local cargo cache. Can be given more than once
+    #[arg(long = "replace", value_name = "NAME@VERSION=VERSION[:SHA256]")]
+    replacements: Vec<cargo::Replacement>,
+
+    /// Turn on a feature of a crate anywhere in the dependency tree, like `tokio/tracing`, together with the features
+    /// it enables. This is an escape hatch which can make the build differ from `cargo build`. Can be given more than
+    /// once
+    #[arg(long = "force-feature", value_name = "CRATE/FEATURE")]
+    forced_features: Vec<cargo::ForcedFeature>,
+
+    /// Pin local crates to the hash of their source so that the derivation does not depend on the checkout location
+    #[arg(long)]
+    hash_local_sources: bool,
+
+    /// Leave out the build dependencies, to inspect the crates needed at runtime. The derivation cannot build crates
+    /// with build scripts
+    #[arg(long)]
+    no_build_deps: bool,
+
+    /// Check that the rust toolchain is new enough for the `rust-version` of every crate
+    #[arg(long)]
+    check_msrv: bool,
+
+    /// Only print errors, and hide the output of the nix build
+    #[arg(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log more details. Can be given more than once for even more details. `RUST_LOG` takes precedence
+    #[arg(long, short, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// The format of the log lines
+    #[arg(long, value_name = "FORMAT", default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// The nix binary to build with
+    #[arg(long, value_name = "PATH", default_value = "nix")]
+    nix_bin: PathBuf,
+
+    /// Extra arguments to pass to `nix build`
+    #[arg(last = true, value_name = "NIX_ARGS")]
+    nix_args: Vec<String>,
+}
+
+/// Formats the dependency tree can be dumped in
+#[derive(Clone, Copy, ValueEnum)]
+enum GraphFormat {
+    /// A Graphviz digraph
+    Dot,
+}
+
+/// Formats the logs can be written in
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Multi-line logs for humans
+    Pretty,
+    /// A JSON object per line
+    Json,
+}
+
+/// Nix builders the derivation can be made for
+#[derive(Clone, Copy, ValueEnum)]
+enum BackendName {
+    /// A buildRustCrate derivation for every crate
+    BuildRustCrate,
+    /// A single crane buildPackage of the vendored crates
+    Crane,
+}
+
+/// Cargo profiles which can be selected
+#[derive(Clone, Copy, ValueEnum)]
+enum ProfileName {
+    Dev,
+    Release,
+}
+
+/// Parse a `KEY=VALUE` pair
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{value}`"))
+}
+
+/// Setup the logs for the requested verbosity and format
+fn init_tracing(quiet: bool, verbose: u8, format: LogFormat) {
+    let level = match (quiet, verbose) {
+        (true, _) | (false, 0) => LevelFilter::ERROR,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter_layer = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+
+    let registry = tracing_subscriber::registry().with(filter_layer);
+
+    match format {
+        LogFormat::Pretty => registry
+            .with(tracing_subscriber::fmt::layer().pretty().with_ansi(false))
+            .init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let Cli::Nbuild(args) = Cli::parse();
+
+    init_tracing(args.quiet, args.verbose, args.log_format);
+
+    let options = BuildOptions {
+        manifest_path: args.manifest_path,
+        published: args.published,
+        metadata: cargo::MetadataOptions {
+            vendor_dirs: args.vendor_dir.into_iter().collect(),
+            lock_file: args.locked_file,
+            frozen: args.frozen,
+            locked: args.locked,
+            offline: args.offline,
+            platforms: args.platforms,
+            target: args.target,
+            timeout: args.metadata_timeout.map(Duration::from_secs),
+            config: args.cargo_config,
+            features: args
+                .features
+                .iter()
+                .flat_map(|features| features.split_whitespace())
+                .map(String::from)
+                .collect(),
+            no_default_features: args.no_default_features,
+            cache: !args.no_cache,
+            cache_dir: None,
+        },
+        generate_lockfile: args.generate_lockfile,
+        replacements: args.replacements,
+        forced_features: args.forced_features,
+        no_build_deps: args.no_build_deps,
+        check_msrv: args.check_msrv,
+        verify_checksums: args.verify_checksums,
+        check_eval: args.check_eval,
+        derivation_path: args.output,
+        workspace: args.workspace,
+        exclude: args.exclude,
+        keep_going: args.keep_going,
+        targets: args.targets,
+        build: !args.no_build,
+        nix_bin: args.nix_bin,
+        out_link: args.out_link,
+        no_link: args.no_link,
+        nix_args: args.nix_args,
+        quiet: args.quiet,
+        derivation: nix::DerivationOptions {
+            profile: match args.profile {
+                Some(ProfileName::Dev) => nix::Profile::dev(),
+                Some(ProfileName::Release) => nix::Profile::release(),
+                None => Default::default(),
+            },
+            backend: match args.backend {
+                Some(BackendName::Crane) => nix::Backend::Crane,
+                Some(BackendName::BuildRustCrate) | None => nix::Backend::BuildRustCrate,
+            },
+            env: args.env.into_iter().collect(),
+            bin: args.bin,
+            example: args.example,
+            attr_name: args.attr_name,
+            shuttle_defaults: args.shuttle_defaults,
+            sys_heuristics: args.sys_heuristics,
+            workspace_default: args.workspace_default,
+            doctest: args.no_doctest.then_some(false),
+            strip: (args.strip || args.no_strip).then_some(args.strip),
+            hash_local_sources: args.hash_local_sources,
+            rust_version: args.rust_version,
+            codegen_backend: args.codegen_backend,
+            link_args: args.link_args,
+            crates_mirror: args.crates_mirror,
+            local_crates: args.local_crates,
+            ..Default::default()
+        },
+    };
+
+    if args.check {
+        let derivation = fs::read_to_string(&options.derivation_path)?;
+
+        return match options.lock_hash()? {
+            Some(lock_hash) if nix::is_up_to_date(&derivation, &lock_hash) => {
+                if !args.quiet {
+                    println!("Derivation is up to date");
+                }
+                Ok(())
+            }
+            _ => Err("derivation is stale, run `cargo nbuild --no-build` to regenerate it".into()),
+        };
+    }
+
+    if args.diff {
+        return match build::diff(&options)? {
+            Some(diff) => {
+                print!("{diff}");
+                Err(
+                    "derivation is out of date, run `cargo nbuild --no-build` to regenerate it"
+                        .into(),
+                )
+            }
+            None => Ok(()),
+        };
+    }
+
+    if args.stats {
+        println!("{}", build::load(&options)?.stats());
+
+        return Ok(());
+    }
+
+    if args.explain_drops {
+        for dropped in build::load(&options)?.dropped_dependencies() {
+            println!("{dropped}");
+        }
+
+        return Ok(());
+    }
+
+    if let Some(format) = args.dump_graph {
+        match format {
+            GraphFormat::Dot => print!("{}", build::load(&options)?.to_dot()),
+        }
+
+        return Ok(());
+    }
+
+    if args.print {
+        print!("{}", build::generate(&options)?);
+
+        return Ok(());
+    }
+
+    if args.plan {
+        println!("{}", serde_json::to_string_pretty(&build::plan(&options)?)?);
+
+        return Ok(());
+    }
+
+    let outcome = build::build(&options)?;
+
+    if !args.quiet {
+        match (&outcome.out_path, &outcome.out_link) {
+            (Some(out_path), Some(out_link)) => println!(
+                "Build done: {} (linked at {})",
+                out_path.display(),
+                out_link.display()
+            ),
+            (Some(out_path), None) => println!("Build done: {}", out_path.display()),
+            (None, _) => println!(
+                "Derivation written to {}",
+                current_dir()?.join(&outcome.derivation_path).display()
+            ),
         }
-    });
+    }
+
+    if outcome.failed_members.is_empty() {
+        return Ok(());
+    }
 
-    while let Some(line) = reader.next_line().await.expect("to get line") {
-        println!("{line}");
+    // The summary is printed even when quiet, since the run fails
+    eprintln!("Left out the workspace members which failed to resolve:");
+    for failed in &outcome.failed_members {
+        eprintln!("  {}: {}", failed.name, failed.error);
     }
 
-    Ok(())
+    Err(format!(
+        "{} workspace members failed to resolve",
+        outcome.failed_members.len()
+    )
+    .into())
 }